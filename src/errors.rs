@@ -0,0 +1,426 @@
+use crate::*;
+use std::fmt;
+
+/// contract errors
+///
+/// Every panic raised by the marketplace goes through this enum so the
+/// messages stay in one place. The variant name is the stable identifier;
+/// `as_ref` gives the fixed message and `Display` appends any values.
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ContractError {
+    // access
+    NotOwner,
+    NotSeller,
+    NotSellerOrOwner,
+    NotBidderOrOwner,
+    NotOfferBuyer,
+    NotTopBidder,
+    NotCrossContractCall,
+    NotTrustedCaller,
+    NotTrustedDelegate,
+    OwnerNotSigner,
+
+    // approvals
+    NftContractNotApproved,
+    FtTokenNotApproved,
+    NearOnly,
+    AuctionNearOnly,
+    OpenModeTestnetOnly,
+    ContractPaused,
+    MarketplacePaused,
+    KycRequired,
+    FtTokenMismatch,
+    SettlementFtNotSupported,
+    FtDecimalsUnknown,
+    InvalidFtDecimals,
+    OracleNotSet,
+
+    // listings
+    ListingNotFound,
+    BatchTooLarge {
+        max: u64,
+    },
+    ListingIsAuction {
+        bids: u64,
+    },
+    AuctionModeLocked,
+    ListingNotAuction,
+    AuctionAlreadyStarted,
+    AuctionEndRequired,
+    AuctionNotEnded,
+    SettlementInFlight,
+    AuctionTooShort {
+        min_sec: u64,
+    },
+    AuctionTooLong {
+        max_sec: u64,
+    },
+    InvalidAuctionDurationBounds,
+    CannotBuyOwnSale,
+    ResaleCooldownActive,
+    SaleNotStarted,
+    SaleEnded,
+    InvalidStartedAt,
+    StartedAtTooFar {
+        max_sec: u64,
+    },
+    InvalidEndedAt,
+    InvalidTimeRange,
+    PriceTooHigh {
+        max: Balance,
+    },
+    PriceTooLow {
+        min: Balance,
+    },
+    PriceMismatch,
+    PriceUpdateTooSoon {
+        cooldown_sec: u64,
+    },
+    PriceNotSpecified,
+    InvalidMarketArgs,
+    InvalidAltCurrency,
+    OffersInOtherCurrency,
+    PricesInListingCurrency,
+    SellerNoteTooLong {
+        max: u64,
+    },
+    MinParticipantsNeedHeldBids,
+    InvalidBuyoutPrice,
+    NoBuyoutPrice,
+    BuyoutBidExists {
+        bid: Balance,
+    },
+    InvalidDutchAuction,
+    InvalidReservePrice,
+
+    // deposits
+    OverpayNotConfirmed {
+        max: Balance,
+    },
+    DepositLessThanPrice {
+        price: Balance,
+    },
+    DepositNotEqualPrice,
+    FtAmountNotEqualPrice {
+        price: Balance,
+    },
+    DepositLessThanAmount,
+    DepositBelowMinimum {
+        minimum: Balance,
+    },
+    WithdrawMoreThanFree {
+        available: Balance,
+    },
+    InsufficientStorage {
+        paid: Balance,
+        required: Balance,
+        rate: Balance,
+    },
+    InvalidStorageBatch,
+    InvalidMinStorageDeposit,
+    NoFailedRefund,
+
+    // bids
+    NoBids,
+    ReserveNotMet,
+    ReserveMet,
+    CannotBidOwnListing,
+    InvalidRecipient,
+    BidTooSoon,
+    BidPriceMoved {
+        current: Balance,
+    },
+    LeaderBidLocked,
+    BidTooRecent {
+        min_age_sec: u64,
+    },
+    BidNotHigherThanCurrent {
+        current: Balance,
+    },
+    BidBelowMinIncrement {
+        min: Balance,
+    },
+    BidBelowStartingPrice {
+        price: Balance,
+    },
+
+    // offers
+    OffersPaused,
+    CollectionOffersDisabled,
+    OfferNotFound,
+    OfferPriceMismatch,
+    OfferNotDormant {
+        dormancy_sec: u64,
+    },
+    InvalidOfferExpiry,
+    OfferExpired,
+    OfferNotExpired,
+    RejectReasonTooLong {
+        max: u64,
+    },
+    OfferBelowMinimum {
+        min: Balance,
+    },
+    TooManyOffers {
+        max: u64,
+    },
+    BuyerNotSpecified,
+    TokenIdMismatch,
+    InvalidTokenId,
+
+    // admin
+    FeeTooHigh,
+    RoyaltyTooHigh,
+    FeeStepTooLarge {
+        max_step: u16,
+    },
+    NoStateToMigrate,
+}
+
+impl ContractError {
+    pub fn panic(&self) -> ! {
+        let message = self.to_string();
+        env::panic_str(&message)
+    }
+}
+
+impl AsRef<str> for ContractError {
+    fn as_ref(&self) -> &str {
+        match self {
+            ContractError::NotOwner => "Error: Owner only",
+            ContractError::NotSeller => "Error: Seller only",
+            ContractError::NotSellerOrOwner => "Error: Seller or owner only",
+            ContractError::NotBidderOrOwner => "Error: Bidder or owner only",
+            ContractError::NotOfferBuyer => "Error: Caller not offer's buyer",
+            ContractError::NotTopBidder => "Error: Caller is not the highest bidder",
+            ContractError::NotCrossContractCall => {
+                "Error: nft_on_approve should only be called via cross-contract call"
+            }
+            ContractError::NotTrustedCaller => {
+                "Error: only the marketplace or a trusted caller may call this"
+            }
+            ContractError::NotTrustedDelegate => "Error: only a trusted delegate may list for sellers",
+            ContractError::OwnerNotSigner => "Error: owner_id should be signer_id",
+
+            ContractError::NftContractNotApproved => "Error: nft_contract_id is not approved",
+            ContractError::FtTokenNotApproved => "Error: ft_token_id not approved",
+            ContractError::NearOnly => "Error: Only NEAR is supported",
+            ContractError::AuctionNearOnly => "Error: auctions are only listed in NEAR",
+            ContractError::OpenModeTestnetOnly => "Error: open mode is only available on testnet",
+            ContractError::ContractPaused => "Error: trading is paused for this nft_contract_id",
+            ContractError::MarketplacePaused => "Error: marketplace paused",
+            ContractError::KycRequired => {
+                "Error: this nft_contract_id only trades with KYC approved accounts"
+            }
+            ContractError::FtTokenMismatch => "Error: ft_token_id differs",
+            ContractError::SettlementFtNotSupported => {
+                "Error: only wnear_id can be the settlement ft_token_id"
+            }
+            ContractError::FtDecimalsUnknown => "Error: ft_token_id decimals not set",
+            ContractError::InvalidFtDecimals => "Error: decimals higher than 38",
+            ContractError::OracleNotSet => "Error: price oracle not set",
+
+            ContractError::ListingNotFound => "Error: Market data does not exist",
+            ContractError::BatchTooLarge { .. } => "Error: the batch has more items than",
+            ContractError::ListingIsAuction { .. } => {
+                "Error: the NFT is on auction, place a bid with add_bid instead of buy"
+            }
+            ContractError::ListingNotAuction => "Error: the NFT is not on auction",
+            ContractError::AuctionEndRequired => "Error: auction requires ended_at",
+            ContractError::AuctionNotEnded => "Error: auction has not ended yet",
+            ContractError::SettlementInFlight => {
+                "Error: accept_bid is already waiting on nft_is_approved for this auction"
+            }
+            ContractError::AuctionTooShort { .. } => "Error: auction window is shorter than",
+            ContractError::AuctionTooLong { .. } => "Error: auction window is longer than",
+            ContractError::InvalidAuctionDurationBounds => {
+                "Error: the minimum auction duration can't be above the maximum"
+            }
+            ContractError::AuctionAlreadyStarted => {
+                "Error: auction has already started or has bids"
+            }
+            ContractError::AuctionModeLocked => {
+                "Error: is_auction cannot change while the listing has bids"
+            }
+            ContractError::CannotBuyOwnSale => "Error: Cannot buy your own sale",
+            ContractError::ResaleCooldownActive => {
+                "Error: Buyer bought this token too recently, resale cooldown active"
+            }
+            ContractError::SaleNotStarted => "Error: Sale has not started yet",
+            ContractError::SaleEnded => "Error: Sale has ended",
+            ContractError::InvalidStartedAt => "Error: started_at is in the past",
+            ContractError::StartedAtTooFar { .. } => "Error: started_at is further ahead than",
+            ContractError::InvalidEndedAt => "Error: ended_at is in the past",
+            ContractError::InvalidTimeRange => "Error: started_at must be before ended_at",
+            ContractError::PriceTooHigh { .. } => "Error: price higher than max",
+            ContractError::PriceTooLow { .. } => {
+                "Error: price needs free_listing when lower than min"
+            }
+            ContractError::PriceMismatch => "Error: price differs",
+            ContractError::PriceUpdateTooSoon { .. } => {
+                "Error: listing was updated too recently, cooldown"
+            }
+            ContractError::PriceNotSpecified => "Error: price not specified",
+            ContractError::InvalidMarketArgs => "Error: Not valid MarketArgs",
+            ContractError::InvalidAltCurrency => {
+                "Error: alt_ft_token_id and alt_price must be set together for a fixed price sale in another currency"
+            }
+            ContractError::OffersInOtherCurrency => {
+                "Error: the token has offers in a currency the listing would no longer use"
+            }
+            ContractError::PricesInListingCurrency => {
+                "Error: start_price, buyout_price and reserve_price are in the listing's currency, relist the token instead"
+            }
+            ContractError::SellerNoteTooLong { .. } => "Error: seller_note is longer than",
+            ContractError::MinParticipantsNeedHeldBids => {
+                "Error: min_participants needs the hold_until_settlement escrow_model"
+            }
+            ContractError::InvalidBuyoutPrice => {
+                "Error: buyout_price is for auctions only and can't be below the starting price"
+            }
+            ContractError::NoBuyoutPrice => "Error: the auction has no buyout_price",
+            ContractError::BuyoutBidExists { .. } => {
+                "Error: a bid already reaches the buyout price, settle the auction instead"
+            }
+            ContractError::InvalidReservePrice => {
+                "Error: reserve_price is for auctions only and must be between the starting price and the buyout_price"
+            }
+            ContractError::InvalidDutchAuction => {
+                "Error: start_price needs started_at and ended_at, must be above price and can't be used with bids or an alt currency"
+            }
+
+            ContractError::OverpayNotConfirmed { .. } => {
+                "Error: attached deposit is far above the price, set confirm_overpay to pay more than"
+            }
+            ContractError::DepositLessThanPrice { .. } => {
+                "Error: Attached deposit is less than price"
+            }
+            ContractError::FtAmountNotEqualPrice { .. } => {
+                "Error: transferred amount must equal the price"
+            }
+            ContractError::DepositNotEqualPrice => {
+                "Error: Attached deposit != price (plus missing storage)"
+            }
+            ContractError::DepositLessThanAmount => "Error: attached deposit is less than amount",
+            ContractError::DepositBelowMinimum { .. } => "Error: Requires minimum deposit",
+            ContractError::WithdrawMoreThanFree { .. } => {
+                "Error: amount is more than the storage balance not backing listings, available"
+            }
+            ContractError::InsufficientStorage { .. } => "Error: Insufficient storage paid",
+            ContractError::NoFailedRefund => "Error: no failed refund to claim",
+            ContractError::InvalidMinStorageDeposit => {
+                "Error: the minimum storage deposit must cover one or more listings"
+            }
+            ContractError::InvalidStorageBatch => {
+                "Error: account_ids must not be empty and amounts must match them"
+            }
+
+            ContractError::NoBids => "Error: Bids data does not exist",
+            ContractError::ReserveNotMet => "Error: reserve not met",
+            ContractError::ReserveMet => "Error: the top bid meets the reserve",
+            ContractError::CannotBidOwnListing => "Error: Owner cannot bid their own token",
+            ContractError::InvalidRecipient => {
+                "Error: recipient cannot be the seller or the marketplace"
+            }
+            ContractError::BidTooSoon => "Error: Only one bid per bidder per block",
+            ContractError::BidPriceMoved { .. } => {
+                "Error: the current bid no longer matches expected_current_price"
+            }
+            ContractError::LeaderBidLocked => "Error: the highest bid cannot be cancelled",
+            ContractError::BidTooRecent { .. } => {
+                "Error: the highest bid can't be accepted before it is"
+            }
+            ContractError::BidNotHigherThanCurrent { .. } => {
+                "Error: Can't pay less than or equal to current bid price"
+            }
+            ContractError::BidBelowMinIncrement { .. } => {
+                "Error: bid is below the minimum increment, the next bid must be at least"
+            }
+            ContractError::BidBelowStartingPrice { .. } => {
+                "Error: Can't pay less than starting price"
+            }
+
+            ContractError::OffersPaused => "Error: offers are paused",
+            ContractError::CollectionOffersDisabled => {
+                "Error: this collection doesn't take offers"
+            }
+            ContractError::OfferNotFound => "Error: Offer does not exist",
+            ContractError::OfferPriceMismatch => "Error: Offer price differs",
+            ContractError::OfferNotDormant { .. } => "Error: offer is younger than",
+            ContractError::InvalidOfferExpiry => "Error: expires_at must be in the future",
+            ContractError::OfferExpired => "Error: the offer has expired",
+            ContractError::OfferNotExpired => "Error: the offer hasn't expired",
+            ContractError::RejectReasonTooLong { .. } => "Error: reason is longer than",
+            ContractError::OfferBelowMinimum { .. } => "Error: Offer is below min_accept_price",
+            ContractError::TooManyOffers { .. } => {
+                "Error: Too many offers, cancel an existing offer first. Max offers per buyer"
+            }
+            ContractError::BuyerNotSpecified => "Error: Account id is not specified",
+            ContractError::TokenIdMismatch => "Error: token_id differs",
+            ContractError::InvalidTokenId => "Error: token_id must not contain ||",
+
+            ContractError::FeeTooHigh => "Error: fee is higher than 10_000",
+            ContractError::RoyaltyTooHigh => "Error: royalty is higher than 10_000",
+            ContractError::FeeStepTooLarge { .. } => "Error: fee change is larger than max step",
+            ContractError::NoStateToMigrate => "Error: no contract state to migrate",
+        }
+    }
+}
+
+impl fmt::Display for ContractError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_ref())?;
+        match self {
+            ContractError::PriceTooHigh { max } => write!(f, " {}", max),
+            ContractError::PriceTooLow { min } => write!(f, " {}", min),
+            ContractError::DepositLessThanPrice { price } => write!(f, " {}", price),
+            ContractError::OverpayNotConfirmed { max } => write!(f, " {}", max),
+            ContractError::BuyoutBidExists { bid } => write!(f, ": {}", bid),
+            ContractError::FtAmountNotEqualPrice { price } => write!(f, " {}", price),
+            ContractError::DepositBelowMinimum { minimum } => write!(f, " of {}", minimum),
+            ContractError::WithdrawMoreThanFree { available } => write!(f, ": {}", available),
+            ContractError::InsufficientStorage {
+                paid,
+                required,
+                rate,
+            } => write!(
+                f,
+                ": {}, for {} entries at {} rate of per entry",
+                paid,
+                required / rate,
+                rate
+            ),
+            ContractError::BidNotHigherThanCurrent { current } => write!(f, ": {}", current),
+            ContractError::BidBelowStartingPrice { price } => write!(f, ": {}", price),
+            ContractError::BidBelowMinIncrement { min } => write!(f, " {}", min),
+            ContractError::BidPriceMoved { current } => write!(f, ", now {}", current),
+            ContractError::FeeStepTooLarge { max_step } => write!(f, " {}", max_step),
+            ContractError::TooManyOffers { max } => write!(f, ": {}", max),
+            ContractError::BatchTooLarge { max } => write!(f, " {}", max),
+            ContractError::AuctionTooShort { min_sec } => write!(f, " {} seconds", min_sec),
+            ContractError::AuctionTooLong { max_sec } => write!(f, " {} seconds", max_sec),
+            ContractError::BidTooRecent { min_age_sec } => {
+                write!(f, " {} seconds old", min_age_sec)
+            }
+            ContractError::StartedAtTooFar { max_sec } => write!(f, " {} seconds", max_sec),
+            ContractError::ListingIsAuction { bids } => write!(f, ", current bids: {}", bids),
+            ContractError::OfferBelowMinimum { min } => write!(f, " {}", min),
+            ContractError::SellerNoteTooLong { max } => write!(f, " {} characters", max),
+            ContractError::OfferNotDormant { dormancy_sec } => {
+                write!(f, " {} seconds", dormancy_sec)
+            }
+            ContractError::RejectReasonTooLong { max } => write!(f, " {} characters", max),
+            ContractError::PriceUpdateTooSoon { cooldown_sec } => {
+                write!(f, " {} seconds", cooldown_sec)
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+pub(crate) fn require(condition: bool, error: ContractError) {
+    if !condition {
+        error.panic()
+    }
+}
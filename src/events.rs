@@ -0,0 +1,455 @@
+use crate::*;
+use near_sdk::serde_json::Value;
+
+// Every event goes through log_event as an NEP-297 log:
+// `EVENT_JSON:{"standard":"astro_marketplace","version":"1.0.0","event":..,"data":[params]}`.
+// While emit_legacy_events is on, the older `{"event":..,"params":..}` line
+// is logged right before it so existing indexers keep working.
+
+pub const EVENT_STANDARD: &str = "astro_marketplace";
+pub const EVENT_STANDARD_VERSION: &str = "1.0.0";
+
+// The params every event logs, with their JSON types. A `?` suffix means the
+// field can be null or left out. get_event_schema returns this table and
+// log_event checks each event against it in debug builds, so a new event or
+// field has to be added here first.
+
+type EventParams = &'static [(&'static str, &'static str)];
+
+const LISTING: EventParams = &[
+    ("owner_id", "AccountId"),
+    ("nft_contract_id", "AccountId"),
+    ("token_id", "TokenId"),
+    ("listing_nonce", "U64"),
+    ("ft_token_id", "AccountId"),
+];
+
+const ALLOWLIST_CHANGE: EventParams = &[
+    ("added", "AccountId[]"),
+    ("removed", "AccountId[]"),
+    ("ignored", "AccountId[]"),
+    ("size", "U64"),
+];
+
+const PAUSED: EventParams = &[("paused", "bool")];
+
+pub const EVENT_SCHEMA: &[(&str, &[EventParams])] = &[
+    (
+        "accept_bid_failed",
+        &[&[
+            ("owner_id", "AccountId"),
+            ("nft_contract_id", "AccountId"),
+            ("token_id", "TokenId"),
+            ("error", "string"),
+        ]],
+    ),
+    (
+        "accept_bid_not_approved",
+        &[&[
+            ("owner_id", "AccountId"),
+            ("nft_contract_id", "AccountId"),
+            ("token_id", "TokenId"),
+            ("ft_token_id", "AccountId"),
+        ]],
+    ),
+    (
+        "add_bid",
+        &[&[
+            ("bidder_id", "AccountId"),
+            ("nft_contract_id", "AccountId"),
+            ("token_id", "TokenId"),
+            ("listing_nonce", "U64"),
+            ("ft_token_id", "AccountId"),
+            ("amount", "U128"),
+            ("recipient", "AccountId?"),
+            ("ended_at", "U64?"),
+        ]],
+    ),
+    ("add_market_data", &[MARKET_DATA]),
+    (
+        "add_offer",
+        &[&[
+            ("buyer_id", "AccountId"),
+            ("nft_contract_id", "AccountId"),
+            ("token_id", "TokenId"),
+            ("ft_token_id", "AccountId"),
+            ("price", "U128"),
+            ("nonce", "U64?"),
+            ("expires_at", "U64?"),
+        ]],
+    ),
+    (
+        "auction_started",
+        &[
+            LISTING,
+            &[
+                ("started_at", "U64"),
+                ("ended_at", "U64?"),
+                ("price", "U128"),
+            ],
+        ],
+    ),
+    (
+        "bids_refunded",
+        &[
+            LISTING,
+            &[("refunds", "{bidder_id: AccountId, amount: U128}[]")],
+        ],
+    ),
+    (
+        "buy_now",
+        &[
+            LISTING,
+            &[
+                ("buyer_id", "AccountId"),
+                ("price", "U128"),
+                ("refunded_bids", "u64"),
+            ],
+        ],
+    ),
+    (
+        "cancel_auction",
+        &[
+            LISTING,
+            &[("min_participants", "u32?"), ("reason", "string")],
+        ],
+    ),
+    (
+        "cancel_bid",
+        &[&[
+            ("bidder_id", "AccountId"),
+            ("nft_contract_id", "AccountId"),
+            ("token_id", "TokenId"),
+            ("listing_nonce", "U64"),
+            ("ft_token_id", "AccountId"),
+        ]],
+    ),
+    (
+        "claim_failed_refund",
+        &[&[
+            ("account_id", "AccountId"),
+            ("receiver_id", "AccountId"),
+            ("ft_token_id", "AccountId?"),
+            ("amount", "U128"),
+        ]],
+    ),
+    (
+        "creator_royalty",
+        &[&[
+            ("nft_contract_id", "AccountId"),
+            ("ft_token_id", "AccountId"),
+            ("creator_id", "AccountId"),
+            ("amount", "U128"),
+        ]],
+    ),
+    ("delete_market_data", &[LISTING, &[("reason", "string?")]]),
+    (
+        "delete_offer",
+        &[&[
+            ("nft_contract_id", "AccountId"),
+            ("buyer_id", "AccountId"),
+            ("token_id", "TokenId"),
+            ("ft_token_id", "AccountId"),
+            ("reason", "string?"),
+        ]],
+    ),
+    (
+        "escheat_offer",
+        &[&[
+            ("nft_contract_id", "AccountId"),
+            ("buyer_id", "AccountId"),
+            ("token_id", "TokenId"),
+            ("ft_token_id", "AccountId"),
+            ("amount", "U128"),
+            ("refunded", "bool"),
+            ("recovery_id", "AccountId?"),
+        ]],
+    ),
+    (
+        "ft_transfer_refunded",
+        &[&[
+            ("ft_token_id", "AccountId"),
+            ("sender_id", "AccountId"),
+            ("amount", "U128"),
+            ("reason", "string"),
+        ]],
+    ),
+    (
+        "nft_sale",
+        &[&[
+            ("market_type", "string"),
+            ("nft_contract_id", "AccountId"),
+            ("token_id", "TokenId"),
+            ("owner_id", "AccountId"),
+            ("buyer_id", "AccountId"),
+            ("price", "U128"),
+            ("ft_token_id", "AccountId"),
+        ]],
+    ),
+    (
+        "open_mode",
+        &[&[("enabled", "bool"), ("warning", "string")]],
+    ),
+    (
+        "outbid",
+        &[&[
+            ("bidder_id", "AccountId"),
+            ("amount", "U128"),
+            ("new_bidder_id", "AccountId"),
+            ("nft_contract_id", "AccountId"),
+            ("token_id", "TokenId"),
+            ("ft_token_id", "AccountId"),
+            ("listing_nonce", "U64"),
+        ]],
+    ),
+    ("pause_nft_contract", &[&[("nft_contract_id", "AccountId")]]),
+    (
+        "payout_transfer",
+        &[&[
+            ("nft_contract_id", "AccountId"),
+            ("token_id", "TokenId"),
+            ("ft_token_id", "AccountId"),
+            ("receiver_id", "AccountId"),
+            ("amount", "U128"),
+        ]],
+    ),
+    (
+        "prune_account",
+        &[&[("account_id", "AccountId"), ("refunded", "U128")]],
+    ),
+    (
+        "reconcile_owner",
+        &[&[
+            ("account_id", "AccountId"),
+            ("previous_len", "u64"),
+            ("len", "u64"),
+        ]],
+    ),
+    (
+        "refund_failed",
+        &[&[
+            ("account_id", "AccountId"),
+            ("ft_token_id", "AccountId"),
+            ("amount", "U128"),
+        ]],
+    ),
+    (
+        "reject_offer",
+        &[&[
+            ("owner_id", "AccountId"),
+            ("nft_contract_id", "AccountId"),
+            ("buyer_id", "AccountId"),
+            ("token_id", "TokenId"),
+            ("ft_token_id", "AccountId"),
+            ("price", "U128"),
+            ("reason", "string?"),
+        ]],
+    ),
+    ("relist", &[MARKET_DATA]),
+    (
+        "reschedule_auction",
+        &[LISTING, &[("started_at", "U64"), ("ended_at", "U64?")]],
+    ),
+    (
+        "resolve_purchase",
+        &[LISTING, PURCHASE, &[("fee_recipient", "AccountId?")]],
+    ),
+    ("resolve_purchase_fail", &[LISTING, PURCHASE]),
+    ("resolve_purchase_fallback", &[LISTING, PURCHASE]),
+    (
+        "royalty_override",
+        &[&[
+            ("nft_contract_id", "AccountId"),
+            ("ft_token_id", "AccountId"),
+            ("receiver_id", "AccountId"),
+            ("amount", "U128"),
+        ]],
+    ),
+    (
+        "set_collection_offers_enabled",
+        &[&[("nft_contract_id", "AccountId"), ("enabled", "bool")]],
+    ),
+    (
+        "set_fee_limits",
+        &[&[
+            ("fee_change_delay", "U64"),
+            ("max_fee_step", "u16"),
+            ("effective_at", "U64"),
+        ]],
+    ),
+    (
+        "set_kyc_required",
+        &[&[("nft_contract_id", "AccountId"), ("required", "bool")]],
+    ),
+    ("set_offers_paused", &[PAUSED]),
+    ("set_paused", &[PAUSED]),
+    (
+        "set_transaction_fee",
+        &[&[
+            ("current_fee", "u16"),
+            ("next_fee", "u16"),
+            ("effective_at", "U64"),
+        ]],
+    ),
+    (
+        "set_usd_floor",
+        &[
+            LISTING,
+            &[("floor_usd_cents", "U128"), ("min_offer_price", "U128")],
+        ],
+    ),
+    (
+        "settle_auction",
+        &[
+            LISTING,
+            &[
+                ("bidder_id", "AccountId"),
+                ("recipient", "AccountId"),
+                ("price", "U128"),
+            ],
+        ],
+    ),
+    (
+        "storage_withdraw",
+        &[&[
+            ("account_id", "AccountId"),
+            ("amount", "U128"),
+            ("remaining", "U128"),
+        ]],
+    ),
+    (
+        "sweep_dust",
+        &[&[("treasury_id", "AccountId"), ("amount", "U128")]],
+    ),
+    (
+        "unpause_nft_contract",
+        &[&[("nft_contract_id", "AccountId")]],
+    ),
+    ("update_approved_ft_tokens", &[ALLOWLIST_CHANGE]),
+    ("update_approved_nft_contracts", &[ALLOWLIST_CHANGE]),
+    ("update_kyc_allowlist", &[ALLOWLIST_CHANGE]),
+    (
+        "update_market_data",
+        &[
+            LISTING,
+            &[
+                ("price", "U128"),
+                ("last_updated_at", "U64?"),
+                ("seller_note", "string?"),
+                ("started_at", "U64?"),
+                ("ended_at", "U64?"),
+                ("is_auction", "bool?"),
+            ],
+        ],
+    ),
+    ("update_trusted_callers", &[ALLOWLIST_CHANGE]),
+    ("update_trusted_delegates", &[ALLOWLIST_CHANGE]),
+    (
+        "verify_nft_contract",
+        &[&[("verified", "AccountId[]"), ("unverified", "AccountId[]")]],
+    ),
+];
+
+// add_market_data and relist log the whole listing as it was stored
+const MARKET_DATA: EventParams = &[
+    ("owner_id", "AccountId"),
+    ("approval_id", "u64"),
+    ("nft_contract_id", "AccountId"),
+    ("token_id", "TokenId"),
+    ("ft_token_id", "AccountId"),
+    ("price", "U128"),
+    ("started_at", "U64?"),
+    ("ended_at", "U64?"),
+    ("is_auction", "bool?"),
+    ("alt_ft_token_id", "AccountId?"),
+    ("alt_price", "U128?"),
+    ("min_participants", "u32?"),
+    ("fee_recipient", "AccountId?"),
+    ("seller_note", "string?"),
+    ("listing_nonce", "U64"),
+    ("free_listing", "bool"),
+    ("escrow_model", "EscrowModel"),
+    ("buyout_price", "U128?"),
+    ("start_price", "U128?"),
+    ("reserve_price", "U128?"),
+];
+
+// listings and offers settle through the same resolve events, only listings
+// carry a listing_nonce and a rebate and only offers set is_offer
+const PURCHASE: EventParams = &[
+    ("price", "U128"),
+    ("buyer_id", "AccountId"),
+    ("rebate", "U128?"),
+    ("is_offer", "bool?"),
+];
+
+fn event_params(
+    event: &str,
+) -> Option<impl Iterator<Item = &'static (&'static str, &'static str)>> {
+    EVENT_SCHEMA
+        .iter()
+        .find(|(name, _)| *name == event)
+        .map(|(_, params)| params.iter().flat_map(|params| params.iter()))
+}
+
+impl Contract {
+    pub(crate) fn log_event(&self, event: &str, params: Value) {
+        #[cfg(debug_assertions)]
+        assert_event_in_schema(event, &params);
+
+        if self.emit_legacy_events {
+            env::log_str(&json!({ "event": event, "params": params }).to_string());
+        }
+        env::log_str(&format!(
+            "EVENT_JSON:{}",
+            json!({
+                "standard": EVENT_STANDARD,
+                "version": EVENT_STANDARD_VERSION,
+                "event": event,
+                "data": [params],
+            })
+        ));
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Every event log_event emits with the JSON type of each params field
+    pub fn get_event_schema(&self) -> String {
+        let events: Vec<Value> = EVENT_SCHEMA
+            .iter()
+            .map(|(event, _)| {
+                let params: near_sdk::serde_json::Map<String, Value> = event_params(event)
+                    .into_iter()
+                    .flatten()
+                    .map(|(field, ty)| (field.to_string(), json!(ty)))
+                    .collect();
+                json!({ "event": event, "params": params })
+            })
+            .collect();
+        json!({
+            "standard": EVENT_STANDARD,
+            "version": EVENT_STANDARD_VERSION,
+            "events": events,
+        })
+        .to_string()
+    }
+}
+
+#[cfg(debug_assertions)]
+fn assert_event_in_schema(event: &str, params: &Value) {
+    let fields: Vec<&str> = event_params(event)
+        .unwrap_or_else(|| env::panic_str(&format!("event {} is missing from EVENT_SCHEMA", event)))
+        .map(|(field, _)| *field)
+        .collect();
+    if let Some(params) = params.as_object() {
+        for key in params.keys() {
+            assert!(
+                fields.contains(&key.as_str()),
+                "event {} logs {} which is missing from EVENT_SCHEMA",
+                event,
+                key
+            );
+        }
+    }
+}
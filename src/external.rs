@@ -3,14 +3,53 @@ use crate::*;
 /// external contract calls
 
 #[ext_contract(ext_contract)]
-trait ExtContract {
+pub trait ExtContract {
     fn nft_transfer_payout(
         &mut self,
         receiver_id: AccountId,
         token_id: TokenId,
         approval_id: Option<u64>,
+        memo: Option<String>,
         balance: Option<U128>,
         max_len_payout: Option<u32>,
     );
-    fn nft_transfer(&mut self, receiver_id: AccountId, token_id: TokenId, approval_id: Option<u64>);
+    fn nft_transfer(
+        &mut self,
+        receiver_id: AccountId,
+        token_id: TokenId,
+        approval_id: Option<u64>,
+        memo: Option<String>,
+    );
+    fn nft_metadata(&self);
+    fn nft_is_approved(
+        &self,
+        token_id: TokenId,
+        approved_account_id: AccountId,
+        approval_id: Option<u64>,
+    ) -> bool;
+    fn nft_token(&self, token_id: TokenId) -> Option<NftTokenOwner>;
+}
+
+/// the part of an nft_token result the marketplace reads
+#[derive(Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct NftTokenOwner {
+    pub owner_id: AccountId,
+}
+
+#[ext_contract(ext_oracle)]
+pub trait ExtOracle {
+    /// amount of `token` in its smallest unit worth one USD
+    fn get_price(&self, token: AccountId) -> U128;
+}
+
+#[ext_contract(ext_wnear)]
+pub trait ExtWnear {
+    fn near_deposit(&mut self);
+    fn ft_transfer(&mut self, receiver_id: AccountId, amount: U128, memo: Option<String>);
+}
+
+#[ext_contract(ext_ft)]
+pub trait ExtFt {
+    fn ft_transfer(&mut self, receiver_id: AccountId, amount: U128, memo: Option<String>);
 }
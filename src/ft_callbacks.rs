@@ -0,0 +1,143 @@
+use crate::*;
+
+// transfer callbacks from FT Contracts
+
+/// `msg` of ft_transfer_call, e.g.
+/// `{"version":"1","action":"buy","nft_contract_id":"nft.near","token_id":"1"}`.
+/// A new payload format gets a new version instead of changing V1. Like
+/// MarketArgs, `market_type` is read as `action`, and a msg without a
+/// version is V1, so `{"market_type":"buy",...}` parses too.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+#[serde(tag = "version")]
+pub enum FtMessage {
+    #[serde(rename = "1")]
+    V1(FtAction),
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum FtAction {
+    Buy {
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+    },
+    AddBid {
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+    },
+    AddOffer {
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+    },
+}
+
+impl FtMessage {
+    pub fn parse(msg: &str) -> Result<FtMessage, String> {
+        let mut value: near_sdk::serde_json::Value =
+            near_sdk::serde_json::from_str(msg).map_err(|error| error.to_string())?;
+        if let Some(fields) = value.as_object_mut() {
+            if !fields.contains_key("action") {
+                if let Some(market_type) = fields.remove("market_type") {
+                    fields.insert("action".to_string(), market_type);
+                }
+            }
+            fields.entry("version").or_insert_with(|| json!("1"));
+        }
+        near_sdk::serde_json::from_value(value).map_err(|error| error.to_string())
+    }
+}
+
+pub trait FungibleTokenReceiver {
+    fn ft_on_transfer(
+        &mut self,
+        sender_id: AccountId,
+        amount: U128,
+        msg: String,
+    ) -> PromiseOrValue<U128>;
+}
+
+#[near_bindgen]
+impl FungibleTokenReceiver for Contract {
+    /// Returns the unused amount, the FT contract refunds it to `sender_id`.
+    /// Nothing here panics so a bad transfer is always refunded in full. A buy
+    /// returns resolve_purchase, which leaves the price unused if the NFT
+    /// transfer fails.
+    fn ft_on_transfer(
+        &mut self,
+        sender_id: AccountId,
+        amount: U128,
+        msg: String,
+    ) -> PromiseOrValue<U128> {
+        let ft_token_id = env::predecessor_account_id();
+        let reason = match FtMessage::parse(&msg) {
+            Err(error) => error,
+            Ok(_) if !self.approved_ft_token_ids.contains(&ft_token_id) => {
+                ContractError::FtTokenNotApproved.to_string()
+            }
+            Ok(FtMessage::V1(FtAction::Buy {
+                nft_contract_id,
+                token_id,
+            })) => match self.internal_ft_buy(
+                &ft_token_id,
+                sender_id.clone(),
+                amount.0,
+                nft_contract_id,
+                token_id,
+            ) {
+                Ok(purchase) => return PromiseOrValue::Promise(purchase),
+                Err(error) => error.to_string(),
+            },
+            // bids and offers only settle in NEAR so far
+            Ok(FtMessage::V1(_)) => ContractError::NearOnly.to_string(),
+        };
+
+        self.log_event(
+            "ft_transfer_refunded",
+            json!({
+                "ft_token_id": ft_token_id,
+                "sender_id": sender_id,
+                "amount": amount,
+                "reason": reason,
+            }),
+        );
+        PromiseOrValue::Value(amount)
+    }
+}
+
+impl Contract {
+    /// `buy` paid with `amount` of `ft_token_id`, which must be exactly the
+    /// listing's price in that currency.
+    fn internal_ft_buy(
+        &mut self,
+        ft_token_id: &AccountId,
+        buyer_id: AccountId,
+        amount: Balance,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+    ) -> Result<Promise, ContractError> {
+        let contract_and_token_id = format!("{}{}{}", &nft_contract_id, DELIMETER, token_id);
+        let mut market_data = self
+            .market
+            .get(&contract_and_token_id)
+            .ok_or(ContractError::ListingNotFound)?;
+
+        let price =
+            self.internal_check_buy(&mut market_data, Some(&buyer_id), ft_token_id, amount)?;
+        if amount != price {
+            return Err(ContractError::FtAmountNotEqualPrice { price });
+        }
+
+        Ok(self.internal_process_purchase(
+            nft_contract_id,
+            token_id,
+            buyer_id,
+            None,
+            ft_token_id.clone(),
+            price,
+            None,
+            false,
+        ))
+    }
+}
@@ -1,24 +1,74 @@
+//! Attached deposit rules
+//!
+//! - payment methods (`buy`, `add_bid`, `add_offer`, `storage_deposit`,
+//!   `storage_deposit_batch`) read `env::attached_deposit()` as the payment
+//! - every other call that changes state requires exactly 1 yocto via
+//!   `assert_one_yocto()`, so it can't be made from a function-call access key
+//! - `process_ended_auctions` and `mark_auction_started` are the exceptions,
+//!   keepers may call them with a function-call key and they only do what the
+//!   auction rules already allow
+//! - callbacks (`resolve_*`, `nft_on_approve`, `ft_on_transfer`) are guarded by
+//!   `#[private]` or the predecessor checks instead
+
 use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
-use near_sdk::collections::{LookupMap, UnorderedMap, UnorderedSet};
+use near_sdk::collections::{LookupMap, TreeMap, UnorderedMap, UnorderedSet, Vector};
 use near_sdk::json_types::{U128, U64};
 use near_sdk::serde::{Deserialize, Serialize};
 use near_sdk::{
     assert_one_yocto, env, ext_contract, near_bindgen, serde_json::json, AccountId, Balance,
-    BorshStorageKey, CryptoHash, Gas, PanicOnDefault, Promise, Timestamp,
+    BorshStorageKey, CryptoHash, Gas, PanicOnDefault, Promise, PromiseOrValue, PromiseResult,
+    StorageUsage, Timestamp,
 };
 use near_sdk::{is_promise_success, promise_result_as_success};
-use std::collections::HashMap;
-
-use crate::external::*;
-
+use std::collections::{HashMap, HashSet};
+
+pub use crate::errors::*;
+pub use crate::events::*;
+pub use crate::external::*;
+pub use crate::ft_callbacks::*;
+pub use crate::migrate::*;
+pub use crate::nft_callbacks::*;
+pub use crate::versioned::*;
+
+mod errors;
+mod events;
 mod external;
+mod ft_callbacks;
+mod migrate;
 mod nft_callbacks;
+mod versioned;
 
 const GAS_FOR_NFT_TRANSFER: Gas = Gas(20_000_000_000_000);
 const BASE_GAS: Gas = Gas(5_000_000_000_000);
 const GAS_FOR_ROYALTIES: Gas = Gas(BASE_GAS.0 * 10u64);
+const GAS_FOR_NFT_METADATA: Gas = Gas(BASE_GAS.0 * 2);
+const GAS_FOR_NFT_IS_APPROVED: Gas = Gas(BASE_GAS.0 * 2);
+const GAS_FOR_NFT_TOKEN: Gas = Gas(BASE_GAS.0 * 2);
+const GAS_FOR_ORACLE_PRICE: Gas = Gas(BASE_GAS.0 * 2);
+const GAS_FOR_FT_TRANSFER: Gas = Gas(BASE_GAS.0 * 2);
+// an FT sale pays every payout share, the fee and the rebate with ft_transfer
+const GAS_FOR_FT_ROYALTIES: Gas = Gas(GAS_FOR_ROYALTIES.0 + GAS_FOR_FT_TRANSFER.0 * 12);
+// auction settlement keeps enough gas in the callback to retry once with the
+// runner-up bid, so callers of accept_bid should attach around 160 Tgas
+const GAS_FOR_AUCTION_ROYALTIES: Gas =
+    Gas(GAS_FOR_ROYALTIES.0 * 2 + GAS_FOR_NFT_TRANSFER.0 + BASE_GAS.0);
+const GAS_FOR_AUCTION_SETTLEMENT: Gas =
+    Gas(GAS_FOR_NFT_TRANSFER.0 + GAS_FOR_AUCTION_ROYALTIES.0 + BASE_GAS.0);
 const NO_DEPOSIT: Balance = 0;
+const ONE_YOCTO: Balance = 1;
 const MAX_PRICE: Balance = 1_000_000_000 * 10u128.pow(24);
+const MIN_PRICE: Balance = 1; // a lower price needs free_listing
+const DEFAULT_FEE_CHANGE_DELAY_SEC: u64 = 86_400;
+const DEFAULT_MAX_FEE_STEP: u16 = 500;
+const DEFAULT_OFFER_DORMANCY_SEC: u64 = 365 * 86_400;
+const DEFAULT_MAX_SCHEDULE_HORIZON_SEC: u64 = 365 * 86_400;
+const MAX_VIEW_LIMIT: u64 = 100;
+const MAX_DELETE_BATCH_LEN: usize = 50; // keeps delete_market_data_batch under the gas limit
+const MIN_AUCTION_DURATION_SEC: u64 = 3_600;
+const PAYOUT_TOLERANCE: u128 = 100; // yocto a payout may come short of the price
+const MAX_SELLER_NOTE_LEN: usize = 280; // chars
+const MAX_REJECT_REASON_LEN: usize = 64; // chars
+const PRICE_HISTORY_LEN: usize = 10; // settlements kept per token
 
 pub const STORAGE_ADD_MARKET_DATA: u128 = 8590000000000000000000;
 
@@ -35,23 +85,146 @@ pub struct Payout {
     pub payout: PayoutHashMap,
 }
 
-#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
 #[serde(crate = "near_sdk::serde")]
 pub struct Bid {
     pub bidder_id: AccountId,
     pub price: U128,
+    pub placed_at: U64, // block timestamp
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub recipient: Option<AccountId>, // receives the NFT if this bid wins, bidder_id by default
 }
 
 pub type Bids = Vec<Bid>;
 
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct RankedBid {
+    pub bid: Bid,
+    pub rank: u64,        // 1 for the highest bid
+    pub is_winning: bool, // highest and not below the starting price
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct LastSale {
+    pub seller_id: AccountId,
+    pub buyer_id: AccountId,
+    pub ft_token_id: AccountId,
+    pub price: U128,
+    pub sold_at: U64, // block timestamp of resolve_purchase or resolve_offer
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct AuctionResult {
+    pub winner_id: AccountId,
+    pub price: U128,
+    pub settled_at: U64, // block timestamp of resolve_purchase
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PendingFee {
+    pub fee: u16,
+    pub effective_at: U64,
+}
+
+/// fee_change_delay and max_fee_step waiting out the delay in force
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PendingFeeLimits {
+    pub fee_change_delay: u64, // seconds
+    pub max_fee_step: u16,
+    pub effective_at: U64,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Config {
+    pub owner_id: AccountId,
+    pub treasury_id: AccountId,
+    pub transaction_fee: u16,
+    pub pending_fee: Option<PendingFee>,
+    pub fee_change_delay: u64, // seconds
+    pub max_fee_step: u16,
+    pub max_offers_per_buyer: Option<u64>,
+    pub offers_paused: bool,
+    pub price_update_cooldown: u64, // seconds
+    pub allow_leader_cancel: bool,
+    pub offer_dormancy: u64, // seconds
+    pub recovery_id: AccountId,
+    pub buyer_rebate_bps: u16,
+    pub max_schedule_horizon: u64, // seconds
+    pub emit_legacy_events: bool,
+    pub min_bid_age: u64, // seconds
+    pub round_fee_up: bool,
+    pub min_storage_deposit: U128,
+    pub auction_extension_seconds: u32,
+    pub open_mode: bool,
+    pub min_bid_increment_bps: u16,
+    pub paused: bool,
+    pub max_overpay_factor: u32,
+    pub min_auction_duration: u64,
+    pub max_auction_duration: u64,
+    pub pending_fee_limits: Option<PendingFeeLimits>,
+}
+
+// set_config only changes the fields that are present
+#[derive(Serialize, Deserialize, Default)]
+#[serde(crate = "near_sdk::serde")]
+pub struct ConfigUpdate {
+    pub treasury_id: Option<AccountId>,
+    pub transaction_fee: Option<u16>,
+    pub fee_change_delay: Option<u64>,
+    pub max_fee_step: Option<u16>,
+    pub max_offers_per_buyer: Option<u64>,
+    pub price_update_cooldown: Option<u64>,
+    pub allow_leader_cancel: Option<bool>,
+    pub offer_dormancy: Option<u64>,
+    pub recovery_id: Option<AccountId>,
+    pub buyer_rebate_bps: Option<u16>,
+    pub max_schedule_horizon: Option<u64>,
+    pub emit_legacy_events: Option<bool>,
+    pub min_bid_age: Option<u64>,
+    pub round_fee_up: Option<bool>,
+    pub min_storage_listings: Option<u64>,
+    pub auction_extension_seconds: Option<u32>,
+    pub min_bid_increment_bps: Option<u16>,
+    pub max_overpay_factor: Option<u32>,
+    pub min_auction_duration: Option<u64>,
+    pub max_auction_duration: Option<u64>,
+}
+
 fn near_account() -> AccountId {
     AccountId::new_unchecked("near".to_string())
 }
 
 const DELIMETER: &str = "||";
 const NEAR: &str = "near";
+const NEAR_DECIMALS: u8 = 24;
+const MAX_FT_DECIMALS: u8 = 38; // 10^38 still fits in u128
 
-#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
+/// When an auction gives outbid bids back, chosen per listing.
+#[derive(
+    BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Copy, PartialEq, Debug, Default,
+)]
+#[serde(crate = "near_sdk::serde")]
+#[serde(rename_all = "snake_case")]
+pub enum EscrowModel {
+    /// Every bid stays escrowed until accept_bid, claim_won_auction or the
+    /// auction is cancelled. Outbid bidders may withdraw with cancel_bid,
+    /// the runner-up takes over if the winner's transfer fails, and all
+    /// bidders count towards min_participants.
+    #[default]
+    HoldUntilSettlement,
+    /// The previous leader is refunded as soon as a higher bid arrives, so
+    /// only the top bid is ever escrowed. There is no runner-up to fall back
+    /// to and min_participants can't be used.
+    RefundOnOutbid,
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
 #[serde(crate = "near_sdk::serde")]
 pub struct MarketData {
     pub owner_id: AccountId,
@@ -64,9 +237,98 @@ pub struct MarketData {
     pub started_at: Option<u64>,
     pub ended_at: Option<u64>,
     pub is_auction: Option<bool>,
+    pub alt_ft_token_id: Option<AccountId>, // second currency the sale also settles in
+    pub alt_price: Option<u128>,
+    pub min_participants: Option<u32>, // auction settles only with this many distinct bidders
+    pub fee_recipient: Option<AccountId>, // receives the transaction fee instead of treasury_id
+    pub last_updated_at: Option<u64>,  // last update_market_data
+    pub started_emitted: bool,         // auction_started already logged
+    pub seller_note: Option<String>,   // at most MAX_SELLER_NOTE_LEN chars
+    pub min_offer_price: Option<u128>, // in ft_token_id, see set_usd_floor
+    pub listing_nonce: u64,            // unique per listing, tells relistings apart
+    pub free_listing: bool,            // the seller allowed a price below MIN_PRICE
+    pub storage_extra: Balance,        // storage charged above STORAGE_ADD_MARKET_DATA
+    pub escrow_model: EscrowModel,     // auctions only
+    pub buyout_price: Option<u128>,    // auctions only, see buy_now
+    pub start_price: Option<u128>,     // Dutch listings only, see current_price
+    pub reserve_price: Option<u128>,   // auctions only, the top bid must reach it to settle
 }
 
-#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
+impl MarketData {
+    fn has_min_participants(&self) -> bool {
+        let min_participants = match self.min_participants {
+            Some(min_participants) => min_participants as usize,
+            None => return true,
+        };
+        let bidders: HashSet<&AccountId> = self
+            .bids
+            .iter()
+            .flatten()
+            .map(|bid| &bid.bidder_id)
+            .collect();
+        bidders.len() >= min_participants
+    }
+
+    /// true unless a reserve_price is set and the top bid is below it
+    fn reserve_met(&self) -> bool {
+        self.reserve_price.is_none_or(|reserve_price| {
+            self.bids
+                .as_ref()
+                .and_then(|bids| bids.last())
+                .is_some_and(|bid| bid.price.0 >= reserve_price)
+        })
+    }
+
+    /// Why an auction with bids is cancelled when it ends instead of settled
+    fn cancel_reason(&self) -> Option<&'static str> {
+        if !self.has_min_participants() {
+            Some("min_participants")
+        } else if !self.reserve_met() {
+            Some("reserve_not_met")
+        } else {
+            None
+        }
+    }
+
+    /// lowest amount add_bid accepts, None unless this is an auction. A
+    /// counter bid adds `increment_bps` of the top bid, and at least 1.
+    fn min_next_bid(&self, increment_bps: u16) -> Option<u128> {
+        if self.is_auction != Some(true) {
+            return None;
+        }
+        let highest_bid = self.bids.as_ref().and_then(|bids| bids.last());
+        Some(highest_bid.map_or(self.price, |bid| {
+            bid.price
+                .0
+                .saturating_add(bps_of(bid.price.0, increment_bps).max(1))
+        }))
+    }
+
+    /// What a Dutch listing costs at `now`: start_price at started_at,
+    /// declining linearly to price at ended_at. None for other listings.
+    fn dutch_price(&self, now: u64) -> Option<u128> {
+        let start_price = self.start_price?;
+        let (started_at, ended_at) = (self.started_at?, self.ended_at?);
+        let duration = ended_at.saturating_sub(started_at).max(1) as u128;
+        let elapsed = now.clamp(started_at, ended_at) as u128 - started_at as u128;
+        let decline = start_price.saturating_sub(self.price);
+        // split like bps_of so decline * elapsed can't overflow
+        let declined = decline / duration * elapsed + decline % duration * elapsed / duration;
+        Some(start_price - declined)
+    }
+
+    /// makes the alternative currency the one this listing settles in
+    fn swap_alt_currency(&mut self) {
+        if let (Some(alt_ft_token_id), Some(alt_price)) =
+            (self.alt_ft_token_id.take(), self.alt_price.take())
+        {
+            self.alt_ft_token_id = Some(std::mem::replace(&mut self.ft_token_id, alt_ft_token_id));
+            self.alt_price = Some(std::mem::replace(&mut self.price, alt_price));
+        }
+    }
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
 #[serde(crate = "near_sdk::serde")]
 pub struct OfferData {
     pub buyer_id: AccountId,
@@ -74,6 +336,15 @@ pub struct OfferData {
     pub token_id: TokenId,
     pub ft_token_id: AccountId, // "near" for NEAR token
     pub price: u128,
+    pub nonce: Option<u64>,      // client retry key, see add_offer
+    pub created_at: u64,         // block timestamp, see escheat_offer
+    pub expires_at: Option<u64>, // block timestamp, see prune_offer
+}
+
+impl OfferData {
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| now > expires_at)
+    }
 }
 
 #[derive(Serialize, Deserialize)]
@@ -84,6 +355,40 @@ pub struct OfferDataJson {
     token_id: TokenId,
     ft_token_id: AccountId, // "near" for NEAR token
     price: U128,
+    nonce: Option<U64>,
+    created_at: U64,
+    expires_at: Option<U64>,
+}
+
+impl From<OfferData> for OfferDataJson {
+    fn from(offer_data: OfferData) -> Self {
+        OfferDataJson {
+            buyer_id: offer_data.buyer_id,
+            nft_contract_id: offer_data.nft_contract_id,
+            token_id: offer_data.token_id,
+            ft_token_id: offer_data.ft_token_id,
+            price: U128(offer_data.price),
+            nonce: offer_data.nonce.map(U64),
+            created_at: offer_data.created_at.into(),
+            expires_at: offer_data.expires_at.map(U64),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct OfferVsListingJson {
+    #[serde(flatten)]
+    offer: OfferDataJson,
+    exceeds_listing: bool, // above the listing price, in the same currency
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct OfferContextJson {
+    offer: OfferDataJson,
+    listing_price: Option<U128>, // starting price for auctions
+    listing_ft_token_id: Option<AccountId>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -99,6 +404,149 @@ pub struct MarketDataJson {
     started_at: Option<U64>,
     ended_at: Option<U64>,
     is_auction: Option<bool>,
+    alt_ft_token_id: Option<AccountId>,
+    alt_price: Option<U128>,
+    min_participants: Option<u32>,
+    fee_recipient: Option<AccountId>,
+    last_updated_at: Option<U64>,
+    reserve_met: Option<bool>, // None when the listing has no reserve
+    seller_note: Option<String>,
+    min_offer_price: Option<U128>,
+    listing_nonce: U64,
+    min_next_bid: Option<U128>, // auctions only
+    free_listing: bool,
+    escrow_model: EscrowModel,
+    buyout_price: Option<U128>, // auctions only
+    start_price: Option<U128>,  // Dutch listings only
+}
+
+impl From<MarketData> for MarketDataJson {
+    fn from(market_data: MarketData) -> Self {
+        let min_next_bid = market_data.min_next_bid(0).map(U128);
+        let reserve_met = market_data.reserve_price.map(|_| market_data.reserve_met());
+        MarketDataJson {
+            owner_id: market_data.owner_id,
+            approval_id: market_data.approval_id.into(),
+            nft_contract_id: market_data.nft_contract_id,
+            token_id: market_data.token_id,
+            ft_token_id: market_data.ft_token_id, // "near" for NEAR token
+            price: market_data.price.into(),
+            bids: market_data.bids,
+            started_at: market_data.started_at.map(|x| x.into()),
+            ended_at: market_data.ended_at.map(|x| x.into()),
+            is_auction: market_data.is_auction,
+            alt_ft_token_id: market_data.alt_ft_token_id,
+            alt_price: market_data.alt_price.map(|x| x.into()),
+            min_participants: market_data.min_participants,
+            fee_recipient: market_data.fee_recipient,
+            last_updated_at: market_data.last_updated_at.map(|x| x.into()),
+            reserve_met,
+            seller_note: market_data.seller_note,
+            min_offer_price: market_data.min_offer_price.map(|x| x.into()),
+            listing_nonce: market_data.listing_nonce.into(),
+            min_next_bid,
+            free_listing: market_data.free_listing,
+            escrow_model: market_data.escrow_model,
+            buyout_price: market_data.buyout_price.map(U128),
+            start_price: market_data.start_price.map(U128),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct MarketDataDetailJson {
+    market_data: MarketDataJson,
+    min_next_bid: Option<U128>,  // auctions only
+    time_remaining: Option<U64>, // nanoseconds until ended_at
+    estimated_seller_proceeds: U128,
+}
+
+// the parts of a sale internal_settle doesn't get from the payout
+struct SettleContext<'a> {
+    nft_contract_id: &'a AccountId,
+    token_id: &'a TokenId,
+    ft_token_id: &'a AccountId, // the currency every share is paid in
+    buyer_id: &'a AccountId,
+    fee_recipient: &'a AccountId,
+    rebate_bps: u16, // share of the treasury fee back to the buyer, offers get none
+}
+
+// everything a token's detail page shows, offers are capped at MAX_VIEW_LIMIT
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct TokenActivity {
+    pub listing: Option<MarketDataJson>,
+    pub offers: Vec<OfferDataJson>,
+    pub top_bid: Option<Bid>,
+    pub last_sale: Option<LastSale>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub enum AccountActivity {
+    Listing(Box<MarketDataJson>), // boxed, listings are much larger than offers
+    Offer(OfferDataJson),
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub enum BuyCheck {
+    Ok { price: U128 },
+    Failed { error: String }, // the message `buy` would panic with
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct StorageBalanceBounds {
+    pub min: U128,
+    pub max: Option<U128>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub enum BidCheck {
+    Ok,
+    Failed { error: String }, // the message `add_bid` would panic with
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct MarketStats {
+    pub listings: U64,
+    pub offers: U64,
+    pub total_volume: U128, // NEAR only, FT sales aren't counted
+    pub treasury_accrued: U128,
+    pub approved_nft_contracts: U64,
+}
+
+// the transaction fee is global, there's no per-collection override
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct CollectionConfig {
+    pub nft_contract_id: AccountId,
+    pub approved: bool,
+    pub paused: bool,
+    pub kyc_required: bool,
+    pub strict_payout: bool,
+    pub transfer_only: bool,
+    pub offers_enabled: bool,
+    pub resale_cooldown: Option<u64>, // seconds
+    pub creator_royalty: Option<(AccountId, u16)>,
+    pub royalty_override: Option<(AccountId, u16)>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct HealthReport {
+    pub listings_scanned: u64,
+    pub offers_scanned: u64,
+    pub bid_escrow: U128,
+    pub offer_escrow: U128,
+    pub index_mismatches: u64, // listings/offers missing from their account's by_owner_id
+    pub free_balance: U128,    // balance above the storage staked for this contract
+    pub escrow_covered: bool,
+    pub next_index: Option<U64>, // None once listings and offers are fully scanned
 }
 
 #[near_bindgen]
@@ -106,13 +554,75 @@ pub struct MarketDataJson {
 pub struct Contract {
     pub owner_id: AccountId,
     pub treasury_id: AccountId,
-    pub market: UnorderedMap<ContractAndTokenId, MarketData>,
+    pub market: MarketMap,
     pub approved_ft_token_ids: UnorderedSet<AccountId>,
     pub approved_nft_contract_ids: UnorderedSet<AccountId>,
     pub storage_deposits: LookupMap<AccountId, Balance>,
     pub by_owner_id: LookupMap<AccountId, UnorderedSet<TokenId>>,
-    pub offers: UnorderedMap<ContractAccountIdTokenId, OfferData>,
-    pub transaction_fee: u16
+    pub offers: OfferMap,
+    pub transaction_fee: u16,
+    pub pending_fee: Option<PendingFee>,
+    pub fee_change_delay: u64, // seconds
+    pub max_fee_step: u16,
+    pub resale_cooldowns: LookupMap<AccountId, u64>, // nft_contract_id -> seconds
+    pub last_purchases: LookupMap<ContractAccountIdTokenId, Timestamp>,
+    pub max_offers_per_buyer: Option<u64>,
+    pub treasury_accrued: Balance,
+    pub ft_token_decimals: LookupMap<AccountId, u8>,
+    pub creator_royalties: LookupMap<AccountId, (AccountId, u16)>, // nft_contract_id -> (creator, bps)
+    pub paused_contracts: UnorderedSet<AccountId>,
+    // append-only listing order for get_markets, a slot is live while
+    // market_index_positions still points at it
+    pub market_index: Vector<ContractAndTokenId>,
+    pub market_index_positions: LookupMap<ContractAndTokenId, u64>,
+    pub strict_payout_contracts: UnorderedSet<AccountId>,
+    pub royalty_overrides: LookupMap<AccountId, (AccountId, u16)>, // nft_contract_id -> (receiver, bps)
+    pub offers_paused: bool,
+    pub by_nft_contract_offers: LookupMap<AccountId, UnorderedSet<ContractAccountIdTokenId>>,
+    pub price_update_cooldown: u64, // seconds between update_market_data calls on a listing
+    pub total_volume: Balance,      // settled NEAR sales and offers
+    pub price_oracle_id: Option<AccountId>,
+    pub allow_leader_cancel: bool, // highest bidder may withdraw their bid
+    pub failed_refunds: LookupMap<AccountId, Balance>,
+    pub refund_gas: u64,
+    pub next_listing_nonce: u64,
+    pub kyc_required_contracts: UnorderedSet<AccountId>,
+    pub kyc_allowlist: UnorderedSet<AccountId>,
+    pub offer_dormancy: u64, // seconds before escheat_offer may touch an offer
+    pub recovery_id: Option<AccountId>, // escheated offers, treasury_id if unset
+    pub escheated: LookupMap<AccountId, Balance>, // buyer_id -> amount sent to recovery
+    pub buyer_rebate_bps: u16, // share of the treasury fee returned to buyers of listings
+    pub max_schedule_horizon: u64, // seconds from now a started_at may be
+    pub trusted_callers: UnorderedSet<AccountId>, // may call resolve_purchase and resolve_offer
+    pub emit_legacy_events: bool, // also log the pre NEP-297 format, see events.rs
+    pub wnear_id: Option<AccountId>,
+    pub settlement_preferences: LookupMap<AccountId, AccountId>, // account_id -> ft_token_id
+    pub last_sales: LookupMap<ContractAndTokenId, LastSale>,
+    pub dust_accrued: Balance, // security yoctos kept by accept_bid, see sweep_dust
+    // listed auctions with an ended_at, ordered by it
+    pub auction_ends: TreeMap<(u64, ContractAndTokenId), ()>,
+    pub storage_extra: LookupMap<AccountId, Balance>, // sum of the account's listings' storage_extra
+    pub min_bid_age: u64, // seconds the top bid must stand before accept_bid settles a running auction
+    pub trusted_delegates: UnorderedSet<AccountId>, // may list for sellers with delegate_list
+    // (price, sold_at) of the last PRICE_HISTORY_LEN settlements, oldest first
+    pub price_history: LookupMap<ContractAndTokenId, Vec<(u128, u64)>>,
+    pub round_fee_up: bool, // the treasury fee rounds up instead of down
+    // auctions whose accept_bid is waiting on nft_is_approved
+    pub settlements_in_flight: UnorderedSet<ContractAndTokenId>,
+    pub min_storage_deposit: Balance, // a multiple of STORAGE_ADD_MARKET_DATA
+    pub by_nft_contract: LookupMap<AccountId, UnorderedSet<ContractAndTokenId>>, // listing keys
+    pub auction_extension_seconds: u32, // a bid this close to ended_at pushes it back as much
+    pub open_mode: bool,              // testnet only, any NFT contract may list and take offers
+    pub min_bid_increment_bps: u16,   // a counter bid beats the top bid by this much
+    pub failed_ft_refunds: LookupMap<String, Balance>, // account_id||ft_token_id
+    pub offers_disabled_contracts: UnorderedSet<AccountId>,
+    pub last_auction_results: LookupMap<ContractAndTokenId, AuctionResult>, // latest per token
+    pub paused: bool,              // every collection, see set_paused
+    pub max_overpay_factor: u32, // buy deposits above this many times the price need confirm_overpay
+    pub min_auction_duration: u64, // seconds
+    pub max_auction_duration: u64, // seconds, 0 for no limit
+    pub transfer_only_contracts: UnorderedSet<AccountId>, // NEP-171 without nft_transfer_payout
+    pub pending_fee_limits: Option<PendingFeeLimits>,
 }
 
 #[derive(BorshStorageKey, BorshSerialize)]
@@ -122,10 +632,37 @@ pub enum StorageKey {
     NFTContractIds,
     StorageDeposits,
     ByOwnerId,
-    ByOwnerIdInner {
-        account_id_hash: CryptoHash,
-    },
-    Offers
+    ByOwnerIdInner { account_id_hash: CryptoHash },
+    Offers,
+    ResaleCooldowns,
+    LastPurchases,
+    FTTokenDecimals,
+    CreatorRoyalties,
+    PausedContracts,
+    MarketIndex,
+    MarketIndexPositions,
+    StrictPayoutContracts,
+    RoyaltyOverrides,
+    ByNFTContractOffers,
+    ByNFTContractOffersInner { account_id_hash: CryptoHash },
+    FailedRefunds,
+    KycRequiredContracts,
+    KycAllowlist,
+    Escheated,
+    TrustedCallers,
+    SettlementPreferences,
+    LastSales,
+    AuctionEnds,
+    StorageExtra,
+    TrustedDelegates,
+    PriceHistory,
+    SettlementsInFlight,
+    ByNFTContract,
+    ByNFTContractInner { account_id_hash: CryptoHash },
+    FailedFtRefunds,
+    OffersDisabledContracts,
+    LastAuctionResults,
+    TransferOnlyContracts,
 }
 
 #[near_bindgen]
@@ -137,17 +674,7 @@ impl Contract {
         approved_ft_token_ids: Option<Vec<AccountId>>,
         approved_nft_contract_ids: Option<Vec<AccountId>>,
     ) -> Self {
-        let mut this = Self {
-            owner_id: owner_id.into(),
-            treasury_id: treasury_id.into(),            
-            market: UnorderedMap::new(StorageKey::Market),
-            approved_ft_token_ids: UnorderedSet::new(StorageKey::FTTokenIds),
-            approved_nft_contract_ids: UnorderedSet::new(StorageKey::NFTContractIds),
-            storage_deposits: LookupMap::new(StorageKey::StorageDeposits),
-            by_owner_id: LookupMap::new(StorageKey::ByOwnerId),
-            offers: UnorderedMap::new(StorageKey::Offers),
-            transaction_fee: 200
-        };
+        let mut this = Self::with_defaults(owner_id, treasury_id);
 
         this.approved_ft_token_ids.insert(&near_account());
 
@@ -159,6 +686,79 @@ impl Contract {
 
         this
     }
+
+    /// Every field at its initial value, without touching the state of the
+    /// collections, shared by new and migrate.
+    fn with_defaults(owner_id: AccountId, treasury_id: AccountId) -> Self {
+        Self {
+            owner_id,
+            treasury_id,
+            market: MarketMap::new(StorageKey::Market),
+            approved_ft_token_ids: UnorderedSet::new(StorageKey::FTTokenIds),
+            approved_nft_contract_ids: UnorderedSet::new(StorageKey::NFTContractIds),
+            storage_deposits: LookupMap::new(StorageKey::StorageDeposits),
+            by_owner_id: LookupMap::new(StorageKey::ByOwnerId),
+            offers: OfferMap::new(StorageKey::Offers),
+            transaction_fee: 200,
+            pending_fee: None,
+            fee_change_delay: DEFAULT_FEE_CHANGE_DELAY_SEC,
+            max_fee_step: DEFAULT_MAX_FEE_STEP,
+            resale_cooldowns: LookupMap::new(StorageKey::ResaleCooldowns),
+            last_purchases: LookupMap::new(StorageKey::LastPurchases),
+            max_offers_per_buyer: None,
+            treasury_accrued: 0,
+            ft_token_decimals: LookupMap::new(StorageKey::FTTokenDecimals),
+            creator_royalties: LookupMap::new(StorageKey::CreatorRoyalties),
+            paused_contracts: UnorderedSet::new(StorageKey::PausedContracts),
+            market_index: Vector::new(StorageKey::MarketIndex),
+            market_index_positions: LookupMap::new(StorageKey::MarketIndexPositions),
+            strict_payout_contracts: UnorderedSet::new(StorageKey::StrictPayoutContracts),
+            royalty_overrides: LookupMap::new(StorageKey::RoyaltyOverrides),
+            offers_paused: false,
+            by_nft_contract_offers: LookupMap::new(StorageKey::ByNFTContractOffers),
+            price_update_cooldown: 0,
+            total_volume: 0,
+            price_oracle_id: None,
+            allow_leader_cancel: false,
+            failed_refunds: LookupMap::new(StorageKey::FailedRefunds),
+            refund_gas: BASE_GAS.0,
+            next_listing_nonce: 0,
+            kyc_required_contracts: UnorderedSet::new(StorageKey::KycRequiredContracts),
+            kyc_allowlist: UnorderedSet::new(StorageKey::KycAllowlist),
+            offer_dormancy: DEFAULT_OFFER_DORMANCY_SEC,
+            recovery_id: None,
+            escheated: LookupMap::new(StorageKey::Escheated),
+            buyer_rebate_bps: 0,
+            max_schedule_horizon: DEFAULT_MAX_SCHEDULE_HORIZON_SEC,
+            trusted_callers: UnorderedSet::new(StorageKey::TrustedCallers),
+            emit_legacy_events: true,
+            wnear_id: None,
+            settlement_preferences: LookupMap::new(StorageKey::SettlementPreferences),
+            last_sales: LookupMap::new(StorageKey::LastSales),
+            dust_accrued: 0,
+            auction_ends: TreeMap::new(StorageKey::AuctionEnds),
+            storage_extra: LookupMap::new(StorageKey::StorageExtra),
+            min_bid_age: 0,
+            trusted_delegates: UnorderedSet::new(StorageKey::TrustedDelegates),
+            price_history: LookupMap::new(StorageKey::PriceHistory),
+            round_fee_up: false,
+            settlements_in_flight: UnorderedSet::new(StorageKey::SettlementsInFlight),
+            min_storage_deposit: STORAGE_ADD_MARKET_DATA,
+            by_nft_contract: LookupMap::new(StorageKey::ByNFTContract),
+            auction_extension_seconds: 0,
+            open_mode: false,
+            min_bid_increment_bps: 0,
+            failed_ft_refunds: LookupMap::new(StorageKey::FailedFtRefunds),
+            offers_disabled_contracts: UnorderedSet::new(StorageKey::OffersDisabledContracts),
+            last_auction_results: LookupMap::new(StorageKey::LastAuctionResults),
+            paused: false,
+            max_overpay_factor: 0,
+            min_auction_duration: MIN_AUCTION_DURATION_SEC,
+            max_auction_duration: 0,
+            transfer_only_contracts: UnorderedSet::new(StorageKey::TransferOnlyContracts),
+            pending_fee_limits: None,
+        }
+    }
     // Changing treasury & ownership
 
     #[payable]
@@ -172,1174 +772,5646 @@ impl Contract {
     pub fn set_transaction_fee(&mut self, next_fee: u16) {
         assert_one_yocto();
         self.assert_owner();
+        self.internal_set_transaction_fee(next_fee);
+    }
 
-        assert!(next_fee < 10_000, "Error: fee is higher than 10_000");
+    fn internal_set_transaction_fee(&mut self, next_fee: u16) {
+        require(next_fee < 10_000, ContractError::FeeTooHigh);
 
-        self.transaction_fee = next_fee;
-        return;
-        
-    }
+        // steps are measured from the fee in force, a pending change replaces the old one
+        self.internal_apply_pending_fee();
+        require(
+            next_fee.abs_diff(self.transaction_fee) <= self.max_fee_step,
+            ContractError::FeeStepTooLarge {
+                max_step: self.max_fee_step,
+            },
+        );
 
-    pub fn get_transaction_fee(&self) -> u16 {
-        self.transaction_fee
+        let effective_at = self.fee_change_effective_at();
+        self.pending_fee = Some(PendingFee {
+            fee: next_fee,
+            effective_at: effective_at.into(),
+        });
+        self.internal_apply_pending_fee();
+
+        self.log_event(
+            "set_transaction_fee",
+            json!({
+                "current_fee": self.transaction_fee,
+                "next_fee": next_fee,
+                "effective_at": U64(effective_at),
+            }),
+        );
     }
 
+    /// A longer delay applies right away, a shorter one waits out the delay
+    /// in force, see internal_set_fee_limits.
     #[payable]
-    pub fn transfer_ownership(&mut self, owner_id: AccountId) {
+    pub fn set_fee_change_delay(&mut self, fee_change_delay: u64) {
         assert_one_yocto();
         self.assert_owner();
-        self.owner_id = owner_id;
+        self.internal_set_fee_limits(Some(fee_change_delay), None);
     }
 
-    // Approved contracts
+    /// A smaller step applies right away, a larger one waits out the delay
+    /// in force, see internal_set_fee_limits.
     #[payable]
-    pub fn add_approved_nft_contract_ids(&mut self, nft_contract_ids: Vec<AccountId>) {
+    pub fn set_max_fee_step(&mut self, max_fee_step: u16) {
         assert_one_yocto();
         self.assert_owner();
-        add_accounts(Some(nft_contract_ids), &mut self.approved_nft_contract_ids);
+        self.internal_set_fee_limits(None, Some(max_fee_step));
+    }
+
+    /// Tightening the limits takes effect at once. Loosening either one is
+    /// scheduled like a fee change, after the current fee_change_delay, so a
+    /// stolen owner key can't drop the delay and jump the fee in one go.
+    /// Until then fee changes are checked against the limits in force. A new
+    /// call replaces a pending change.
+    fn internal_set_fee_limits(
+        &mut self,
+        fee_change_delay: Option<u64>,
+        max_fee_step: Option<u16>,
+    ) {
+        self.internal_apply_pending_fee();
+        let next_delay = fee_change_delay
+            .or_else(|| self.pending_fee_limits.as_ref().map(|p| p.fee_change_delay))
+            .unwrap_or(self.fee_change_delay);
+        let next_step = max_fee_step
+            .or_else(|| self.pending_fee_limits.as_ref().map(|p| p.max_fee_step))
+            .unwrap_or(self.max_fee_step);
+
+        let effective_at = if next_delay >= self.fee_change_delay && next_step <= self.max_fee_step
+        {
+            self.fee_change_delay = next_delay;
+            self.max_fee_step = next_step;
+            self.pending_fee_limits = None;
+            env::block_timestamp()
+        } else {
+            let effective_at = self.fee_change_effective_at();
+            self.pending_fee_limits = Some(PendingFeeLimits {
+                fee_change_delay: next_delay,
+                max_fee_step: next_step,
+                effective_at: effective_at.into(),
+            });
+            effective_at
+        };
+
+        self.log_event(
+            "set_fee_limits",
+            json!({
+                "fee_change_delay": U64(next_delay),
+                "max_fee_step": next_step,
+                "effective_at": U64(effective_at),
+            }),
+        );
+    }
+
+    fn fee_change_effective_at(&self) -> u64 {
+        env::block_timestamp().saturating_add(self.fee_change_delay.saturating_mul(10u64.pow(9)))
+    }
+
+    /// fee in force right now, including a pending change whose delay has passed
+    pub fn get_transaction_fee(&self) -> u16 {
+        match &self.pending_fee {
+            Some(pending) if env::block_timestamp() >= pending.effective_at.0 => pending.fee,
+            _ => self.transaction_fee,
+        }
+    }
+
+    pub fn get_pending_fee(&self) -> Option<PendingFee> {
+        self.pending_fee
+            .as_ref()
+            .filter(|pending| env::block_timestamp() < pending.effective_at.0)
+            .cloned()
+    }
+
+    /// delay in force right now, including a pending change whose delay has passed
+    pub fn get_fee_change_delay(&self) -> u64 {
+        self.internal_due_fee_limits()
+            .map_or(self.fee_change_delay, |pending| pending.fee_change_delay)
+    }
+
+    /// step in force right now, including a pending change whose delay has passed
+    pub fn get_max_fee_step(&self) -> u16 {
+        self.internal_due_fee_limits()
+            .map_or(self.max_fee_step, |pending| pending.max_fee_step)
+    }
+
+    pub fn get_pending_fee_limits(&self) -> Option<PendingFeeLimits> {
+        self.pending_fee_limits
+            .as_ref()
+            .filter(|pending| env::block_timestamp() < pending.effective_at.0)
+            .cloned()
+    }
+
+    fn internal_due_fee_limits(&self) -> Option<&PendingFeeLimits> {
+        self.pending_fee_limits
+            .as_ref()
+            .filter(|pending| env::block_timestamp() >= pending.effective_at.0)
     }
 
+    /// `None` means buyers may hold any number of offers
     #[payable]
-    pub fn remove_approved_nft_contract_ids(&mut self, nft_contract_ids: Vec<AccountId>) {
+    pub fn set_max_offers_per_buyer(&mut self, max_offers_per_buyer: Option<u64>) {
         assert_one_yocto();
         self.assert_owner();
-        remove_accounts(Some(nft_contract_ids), &mut self.approved_nft_contract_ids);
+        self.max_offers_per_buyer = max_offers_per_buyer;
+    }
+
+    pub fn get_max_offers_per_buyer(&self) -> Option<u64> {
+        self.max_offers_per_buyer
     }
 
+    /// 0 lets sellers reprice as often as they like
     #[payable]
-    pub fn add_approved_ft_token_ids(&mut self, ft_token_ids: Vec<AccountId>) {
+    pub fn set_price_update_cooldown(&mut self, price_update_cooldown: u64) {
         assert_one_yocto();
         self.assert_owner();
-        add_accounts(Some(ft_token_ids), &mut self.approved_ft_token_ids);
+        self.price_update_cooldown = price_update_cooldown;
     }
 
-    // Buy & Payment
+    pub fn get_price_update_cooldown(&self) -> u64 {
+        self.price_update_cooldown
+    }
 
+    /// How far ahead, in seconds, a listing's started_at may be scheduled.
     #[payable]
-    pub fn buy(
-        &mut self,
-        nft_contract_id: AccountId,
-        token_id: TokenId,
-        ft_token_id: Option<AccountId>,
-        price: Option<U128>,
-    ) {
-        let contract_and_token_id = format!("{}{}{}", &nft_contract_id, DELIMETER, token_id);
+    pub fn set_max_schedule_horizon(&mut self, max_schedule_horizon: u64) {
+        assert_one_yocto();
+        self.assert_owner();
+        self.max_schedule_horizon = max_schedule_horizon;
+    }
 
-        let market_data: MarketData = self.market.get(&contract_and_token_id).expect("Error: Market data does not exist");
+    pub fn get_max_schedule_horizon(&self) -> u64 {
+        self.max_schedule_horizon
+    }
 
-        let buyer_id = env::predecessor_account_id();
+    /// 0 lets a seller accept a bid the moment it lands. Auctions that have
+    /// ended settle regardless.
+    #[payable]
+    pub fn set_min_bid_age(&mut self, min_bid_age: u64) {
+        assert_one_yocto();
+        self.assert_owner();
+        self.min_bid_age = min_bid_age;
+    }
 
-        assert_ne!(
-            buyer_id, market_data.owner_id,
-            "Error: Cannot buy your own sale"
-        );
+    pub fn get_min_bid_age(&self) -> u64 {
+        self.min_bid_age
+    }
 
-        // only NEAR supported for now
-        assert_eq!(
-            market_data.ft_token_id.to_string(),
-            NEAR,
-            "Error: NEAR support only"
+    /// Testnet dry runs: with `enabled` any NFT contract can be listed, bought
+    /// and offered on without being approved. It can only be turned on for a
+    /// market deployed under `.testnet`.
+    #[payable]
+    pub fn set_open_mode(&mut self, enabled: bool) {
+        assert_one_yocto();
+        self.assert_owner();
+        require(
+            !enabled || env::current_account_id().as_str().ends_with(".testnet"),
+            ContractError::OpenModeTestnetOnly,
+        );
+        self.open_mode = enabled;
+        self.log_event(
+            "open_mode",
+            json!({
+                "enabled": enabled,
+                "warning": if enabled {
+                    "the NFT contract allowlist is not enforced"
+                } else {
+                    "the NFT contract allowlist is enforced"
+                },
+            }),
         );
+    }
 
-        if ft_token_id.is_some() {
-            assert_eq!(
-                ft_token_id.unwrap().to_string(),
-                market_data.ft_token_id.to_string()
-            )
-        }
-        if price.is_some() {
-            assert_eq!(price.unwrap().0, market_data.price);
-        }
+    pub fn is_open_mode(&self) -> bool {
+        self.open_mode
+    }
 
-        let price = market_data.price;
+    /// bps of the top bid a counter bid must add, 0 only asks for more than it
+    #[payable]
+    pub fn set_min_bid_increment_bps(&mut self, min_bid_increment_bps: u16) {
+        assert_one_yocto();
+        self.assert_owner();
+        require(min_bid_increment_bps <= 10_000, ContractError::FeeTooHigh);
+        self.min_bid_increment_bps = min_bid_increment_bps;
+    }
 
-        if let Some(auction) = market_data.is_auction {
-            assert_eq!(auction, false, "Error: the NFT is on auction");
-        }
+    pub fn get_min_bid_increment_bps(&self) -> u16 {
+        self.min_bid_increment_bps
+    }
 
-        assert!(
-            env::attached_deposit() >= price,
-            "Error: Attached deposit is less than price {}",
-            price
-        );
+    /// Anti-sniping: a bid within the last `seconds` of an auction moves its
+    /// ended_at back by `seconds`. 0 turns it off.
+    #[payable]
+    pub fn set_auction_extension(&mut self, seconds: u32) {
+        assert_one_yocto();
+        self.assert_owner();
+        self.auction_extension_seconds = seconds;
+    }
 
-        self.internal_process_purchase(nft_contract_id.into(), token_id, buyer_id, price);
+    pub fn get_auction_extension(&self) -> u32 {
+        self.auction_extension_seconds
     }
 
-    fn internal_process_purchase(
-        &mut self,
-        nft_contract_id: AccountId,
-        token_id: TokenId,
-        buyer_id: AccountId,
-        price: u128,
-    ) -> Promise {
-        let market_data = self
-            .internal_delete_market_data(&nft_contract_id, &token_id)
-            .expect("Error: Sale does not exist");
+    /// Fat-finger guard: a buy whose deposit is more than `factor` times the
+    /// price fails unless it sets confirm_overpay. 0 turns it off.
+    #[payable]
+    pub fn set_max_overpay_factor(&mut self, factor: u32) {
+        assert_one_yocto();
+        self.assert_owner();
+        self.max_overpay_factor = factor;
+    }
 
-        ext_contract::nft_transfer_payout(
-            buyer_id.clone(),
-            token_id,
-            Some(market_data.approval_id),
-            Some(price.into()),
-            Some(10u32), // max length payout
-            nft_contract_id,
-            1,
-            GAS_FOR_NFT_TRANSFER,
-        )
-        .then(ext_self::resolve_purchase(
-            buyer_id,
-            market_data,
-            price.into(),
-            env::current_account_id(),
-            NO_DEPOSIT,
-            GAS_FOR_ROYALTIES,
-        ))
+    pub fn get_max_overpay_factor(&self) -> u32 {
+        self.max_overpay_factor
     }
 
-    #[private]
-    pub fn resolve_purchase(
-        &mut self,
-        buyer_id: AccountId,
-        market_data: MarketData,
-        price: U128,
-    ) -> U128 {
-        let payout_option = promise_result_as_success().and_then(|value| {
-            let parsed_payout = near_sdk::serde_json::from_slice::<PayoutHashMap>(&value);
-            if parsed_payout.is_err() {
-                near_sdk::serde_json::from_slice::<Payout>(&value)
-                    .ok()
-                    .and_then(|payout| {
-                        let mut remainder = price.0;
-                        for &value in payout.payout.values() {
-                            remainder = remainder.checked_sub(value.0)?;
-                        }
-                        if remainder <= 100 {
-                            Some(payout.payout)
-                        } else {
-                            None
-                        }
-                    })
-            } else {
-                parsed_payout
-                    .ok()
-                    .and_then(|payout| {
-                        let mut remainder = price.0;
-                        for &value in payout.values() {
-                            remainder = remainder.checked_sub(value.0)?;
-                        }
-                        if remainder <= 100 {
-                            Some(payout)
-                        } else {
-                            None
-                        }
-                    })
-            }
-        });
-        let payout = if let Some(payout_option) = payout_option {
-            payout_option
-        } else {
-            // leave function and return all FTs in ft_resolve_transfer
-            if !is_promise_success() {
-                if market_data.ft_token_id == near_account() {
-                    Promise::new(buyer_id.clone()).transfer(u128::from(market_data.price));
-                }
-            
-                env::log_str(
-                    &json!({
-                        "event": "resolve_purchase_fail",
-                        "params": {
-                            "owner_id": market_data.owner_id,
-                            "nft_contract_id": market_data.nft_contract_id,
-                            "token_id": market_data.token_id,
-                            "ft_token_id": market_data.ft_token_id,
-                            "price": price,
-                            "buyer_id": buyer_id,
-                        }
-                    })
-                    .to_string(),
-                );
-            }  else if market_data.ft_token_id == near_account() {
-                let treasury_fee = price.0 * self.transaction_fee as u128 / 10_000u128;
-                Promise::new(market_data.owner_id.clone()).transfer(price.0 - treasury_fee);
-                if treasury_fee > 0 {
-                    Promise::new(self.treasury_id.clone()).transfer(treasury_fee);
-                }
+    /// Bounds on an auction's window, in seconds. A max of 0 means no limit.
+    #[payable]
+    pub fn set_auction_duration_bounds(&mut self, min_seconds: u64, max_seconds: u64) {
+        assert_one_yocto();
+        self.assert_owner();
+        assert_auction_duration_bounds(min_seconds, max_seconds);
+        self.min_auction_duration = min_seconds;
+        self.max_auction_duration = max_seconds;
+    }
 
-                env::log_str(
-                    &json!({
-                    "event": "resolve_purchase",
-                    "params": {
-                        "owner_id": &market_data.owner_id,
-                        "nft_contract_id": &market_data.nft_contract_id,
-                        "token_id": &market_data.token_id,
-                        "ft_token_id": market_data.ft_token_id,
-                        "price": price,
-                        "buyer_id": buyer_id,
-                    }
-                })
-                        .to_string(),
+    /// (min, max) auction window in seconds
+    pub fn get_auction_duration_bounds(&self) -> (u64, u64) {
+        (self.min_auction_duration, self.max_auction_duration)
+    }
+
+    /// The smallest storage_deposit, in listings' worth of storage. 1 keeps
+    /// the single listing minimum.
+    #[payable]
+    pub fn set_min_storage_listings(&mut self, listings: u64) {
+        assert_one_yocto();
+        self.assert_owner();
+        self.internal_set_min_storage_listings(listings);
+    }
+
+    fn internal_set_min_storage_listings(&mut self, listings: u64) {
+        require(listings > 0, ContractError::InvalidMinStorageDeposit);
+        self.min_storage_deposit = STORAGE_ADD_MARKET_DATA
+            .checked_mul(listings as u128)
+            .unwrap_or_else(|| ContractError::InvalidMinStorageDeposit.panic());
+    }
+
+    pub fn get_min_storage_deposit(&self) -> U128 {
+        U128(self.min_storage_deposit)
+    }
+
+    /// By default the treasury fee is rounded down, in the seller's favour.
+    #[payable]
+    pub fn set_round_fee_up(&mut self, round_fee_up: bool) {
+        assert_one_yocto();
+        self.assert_owner();
+        self.round_fee_up = round_fee_up;
+    }
+
+    pub fn get_round_fee_up(&self) -> bool {
+        self.round_fee_up
+    }
+
+    /// Turn off once indexers read the NEP-297 `EVENT_JSON:` logs.
+    #[payable]
+    pub fn set_emit_legacy_events(&mut self, emit_legacy_events: bool) {
+        assert_one_yocto();
+        self.assert_owner();
+        self.emit_legacy_events = emit_legacy_events;
+    }
+
+    /// Oracle answering `get_price(token) -> U128`, the amount of `token` in
+    /// its smallest unit worth one USD. `None` disables set_usd_floor.
+    #[payable]
+    pub fn set_price_oracle(&mut self, price_oracle_id: Option<AccountId>) {
+        assert_one_yocto();
+        self.assert_owner();
+        self.price_oracle_id = price_oracle_id;
+    }
+
+    pub fn get_price_oracle(&self) -> Option<AccountId> {
+        self.price_oracle_id.clone()
+    }
+
+    /// Wrapped NEAR contract used for accounts preferring it. `None` sends
+    /// everyone native NEAR again, whatever their preference.
+    #[payable]
+    pub fn set_wnear_id(&mut self, wnear_id: Option<AccountId>) {
+        assert_one_yocto();
+        self.assert_owner();
+        self.wnear_id = wnear_id;
+    }
+
+    pub fn get_wnear_id(&self) -> Option<AccountId> {
+        self.wnear_id.clone()
+    }
+
+    /// Refunds and proceeds to the caller are wrapped and sent with
+    /// ft_transfer of `ft_token_id`, only wnear_id is supported. The caller
+    /// must be registered with that contract or the transfer fails.
+    /// `None` goes back to native NEAR.
+    #[payable]
+    pub fn set_settlement_preference(&mut self, ft_token_id: Option<AccountId>) {
+        assert_one_yocto();
+        let account_id = env::predecessor_account_id();
+        match ft_token_id {
+            Some(ft_token_id) => {
+                require(
+                    self.wnear_id.as_ref() == Some(&ft_token_id),
+                    ContractError::SettlementFtNotSupported,
                 );
+                self.settlement_preferences
+                    .insert(&account_id, &ft_token_id);
             }
-            
-            return price;
-        };
+            None => {
+                self.settlement_preferences.remove(&account_id);
+            }
+        }
+    }
 
-        // Payout (transfer to royalties and seller)
-        if market_data.ft_token_id == near_account() {
-            // 5% fee for treasury
-            let treasury_fee = price.0 * self.transaction_fee as u128 / 10_000u128;
-
-            for (receiver_id, amount) in payout {
-                if receiver_id == market_data.owner_id {
-                    Promise::new(receiver_id).transfer(amount.0 - treasury_fee);
-                    if treasury_fee != 0 {
-                        Promise::new(self.treasury_id.clone()).transfer(treasury_fee);
+    pub fn get_settlement_preference(&self, account_id: AccountId) -> Option<AccountId> {
+        self.settlement_preferences.get(&account_id)
+    }
+
+    /// By default the highest bidder can't withdraw their bid, it is refunded
+    /// when outbid or when the auction is cancelled. Trailing bids stay
+    /// cancellable and the contract owner can still cancel any bid.
+    #[payable]
+    pub fn set_allow_leader_cancel(&mut self, allow_leader_cancel: bool) {
+        assert_one_yocto();
+        self.assert_owner();
+        self.allow_leader_cancel = allow_leader_cancel;
+    }
+
+    pub fn get_allow_leader_cancel(&self) -> bool {
+        self.allow_leader_cancel
+    }
+
+    // Config
+
+    /// Applies every provided field in one call. Fee limits are set before
+    /// `transaction_fee`, so tighter ones govern the change and looser ones
+    /// only after their own delay, see internal_set_fee_limits.
+    /// Use set_max_offers_per_buyer to remove the offer limit.
+    #[payable]
+    pub fn set_config(&mut self, config: ConfigUpdate) {
+        assert_one_yocto();
+        self.assert_owner();
+
+        if let Some(treasury_id) = config.treasury_id {
+            self.treasury_id = treasury_id;
+        }
+        if config.fee_change_delay.is_some() || config.max_fee_step.is_some() {
+            self.internal_set_fee_limits(config.fee_change_delay, config.max_fee_step);
+        }
+        if let Some(max_offers_per_buyer) = config.max_offers_per_buyer {
+            self.max_offers_per_buyer = Some(max_offers_per_buyer);
+        }
+        if let Some(price_update_cooldown) = config.price_update_cooldown {
+            self.price_update_cooldown = price_update_cooldown;
+        }
+        if let Some(allow_leader_cancel) = config.allow_leader_cancel {
+            self.allow_leader_cancel = allow_leader_cancel;
+        }
+        if let Some(offer_dormancy) = config.offer_dormancy {
+            self.offer_dormancy = offer_dormancy;
+        }
+        if let Some(recovery_id) = config.recovery_id {
+            self.recovery_id = Some(recovery_id);
+        }
+        if let Some(buyer_rebate_bps) = config.buyer_rebate_bps {
+            require(buyer_rebate_bps <= 10_000, ContractError::FeeTooHigh);
+            self.buyer_rebate_bps = buyer_rebate_bps;
+        }
+        if let Some(max_schedule_horizon) = config.max_schedule_horizon {
+            self.max_schedule_horizon = max_schedule_horizon;
+        }
+        if let Some(emit_legacy_events) = config.emit_legacy_events {
+            self.emit_legacy_events = emit_legacy_events;
+        }
+        if let Some(min_bid_age) = config.min_bid_age {
+            self.min_bid_age = min_bid_age;
+        }
+        if let Some(round_fee_up) = config.round_fee_up {
+            self.round_fee_up = round_fee_up;
+        }
+        if let Some(listings) = config.min_storage_listings {
+            self.internal_set_min_storage_listings(listings);
+        }
+        if let Some(auction_extension_seconds) = config.auction_extension_seconds {
+            self.auction_extension_seconds = auction_extension_seconds;
+        }
+        if let Some(min_bid_increment_bps) = config.min_bid_increment_bps {
+            require(min_bid_increment_bps <= 10_000, ContractError::FeeTooHigh);
+            self.min_bid_increment_bps = min_bid_increment_bps;
+        }
+        if let Some(max_overpay_factor) = config.max_overpay_factor {
+            self.max_overpay_factor = max_overpay_factor;
+        }
+        if config.min_auction_duration.is_some() || config.max_auction_duration.is_some() {
+            let min_seconds = config
+                .min_auction_duration
+                .unwrap_or(self.min_auction_duration);
+            let max_seconds = config
+                .max_auction_duration
+                .unwrap_or(self.max_auction_duration);
+            assert_auction_duration_bounds(min_seconds, max_seconds);
+            self.min_auction_duration = min_seconds;
+            self.max_auction_duration = max_seconds;
+        }
+        if let Some(transaction_fee) = config.transaction_fee {
+            self.internal_set_transaction_fee(transaction_fee);
+        }
+    }
+
+    pub fn get_config(&self) -> Config {
+        Config {
+            owner_id: self.owner_id.clone(),
+            treasury_id: self.treasury_id.clone(),
+            transaction_fee: self.get_transaction_fee(),
+            pending_fee: self.get_pending_fee(),
+            fee_change_delay: self.get_fee_change_delay(),
+            max_fee_step: self.get_max_fee_step(),
+            max_offers_per_buyer: self.max_offers_per_buyer,
+            offers_paused: self.offers_paused,
+            price_update_cooldown: self.price_update_cooldown,
+            allow_leader_cancel: self.allow_leader_cancel,
+            offer_dormancy: self.offer_dormancy,
+            recovery_id: self.internal_recovery_id(),
+            buyer_rebate_bps: self.buyer_rebate_bps,
+            max_schedule_horizon: self.max_schedule_horizon,
+            emit_legacy_events: self.emit_legacy_events,
+            min_bid_age: self.min_bid_age,
+            round_fee_up: self.round_fee_up,
+            min_storage_deposit: U128(self.min_storage_deposit),
+            auction_extension_seconds: self.auction_extension_seconds,
+            open_mode: self.open_mode,
+            min_bid_increment_bps: self.min_bid_increment_bps,
+            paused: self.paused,
+            max_overpay_factor: self.max_overpay_factor,
+            min_auction_duration: self.min_auction_duration,
+            max_auction_duration: self.max_auction_duration,
+            pending_fee_limits: self.get_pending_fee_limits(),
+        }
+    }
+
+    #[payable]
+    pub fn transfer_ownership(&mut self, owner_id: AccountId) {
+        assert_one_yocto();
+        self.assert_owner();
+        self.owner_id = owner_id;
+    }
+
+    // Approved contracts
+    // each returns how many ids actually changed, ids already in that state are ignored
+
+    /// With `verify` an id is only added once its nft_metadata call succeeds,
+    /// attach about 10 Tgas per id on top of the call itself.
+    #[payable]
+    pub fn add_approved_nft_contract_ids(
+        &mut self,
+        nft_contract_ids: Vec<AccountId>,
+        verify: Option<bool>,
+    ) -> PromiseOrValue<U64> {
+        assert_one_yocto();
+        self.assert_owner();
+        if verify != Some(true) || nft_contract_ids.is_empty() {
+            let (added, ignored) =
+                add_accounts(Some(nft_contract_ids), &mut self.approved_nft_contract_ids);
+            return PromiseOrValue::Value(self.log_allowlist_change(
+                "update_approved_nft_contracts",
+                &added,
+                &[],
+                &ignored,
+                self.approved_nft_contract_ids.len(),
+            ));
+        }
+
+        nft_contract_ids
+            .iter()
+            .map(|nft_contract_id| {
+                ext_contract::ext(nft_contract_id.clone())
+                    .with_static_gas(GAS_FOR_NFT_METADATA)
+                    .nft_metadata()
+            })
+            .reduce(|calls, call| calls.and(call))
+            .unwrap()
+            .then(
+                ext_self::ext(env::current_account_id())
+                    .with_attached_deposit(NO_DEPOSIT)
+                    .with_static_gas(BASE_GAS)
+                    .resolve_approve_nft_contracts(nft_contract_ids),
+            )
+            .into()
+    }
+
+    /// Adds the ids whose nft_metadata call succeeded, results arrive in the
+    /// order the calls were joined.
+    #[private]
+    pub fn resolve_approve_nft_contracts(&mut self, nft_contract_ids: Vec<AccountId>) -> U64 {
+        let (verified, unverified): (Vec<AccountId>, Vec<AccountId>) =
+            nft_contract_ids.into_iter().enumerate().fold(
+                (vec![], vec![]),
+                |(mut verified, mut unverified), (i, id)| {
+                    match env::promise_result(i as u64) {
+                        PromiseResult::Successful(_) => verified.push(id),
+                        _ => unverified.push(id),
                     }
-                } else {
-                    Promise::new(receiver_id).transfer(amount.0);
+                    (verified, unverified)
+                },
+            );
+
+        self.log_event(
+            "verify_nft_contract",
+            json!({ "verified": verified, "unverified": unverified }),
+        );
+
+        let (added, ignored) = add_accounts(Some(verified), &mut self.approved_nft_contract_ids);
+        self.log_allowlist_change(
+            "update_approved_nft_contracts",
+            &added,
+            &[],
+            &ignored,
+            self.approved_nft_contract_ids.len(),
+        )
+    }
+
+    #[payable]
+    pub fn remove_approved_nft_contract_ids(&mut self, nft_contract_ids: Vec<AccountId>) -> U64 {
+        assert_one_yocto();
+        self.assert_owner();
+        let (removed, ignored) =
+            remove_accounts(Some(nft_contract_ids), &mut self.approved_nft_contract_ids);
+        self.log_allowlist_change(
+            "update_approved_nft_contracts",
+            &[],
+            &removed,
+            &ignored,
+            self.approved_nft_contract_ids.len(),
+        )
+    }
+
+    #[payable]
+    pub fn add_approved_ft_token_ids(&mut self, ft_token_ids: Vec<AccountId>) -> U64 {
+        assert_one_yocto();
+        self.assert_owner();
+        let (added, ignored) = add_accounts(Some(ft_token_ids), &mut self.approved_ft_token_ids);
+        self.log_allowlist_change(
+            "update_approved_ft_tokens",
+            &added,
+            &[],
+            &ignored,
+            self.approved_ft_token_ids.len(),
+        )
+    }
+
+    /// "near" always stays approved
+    #[payable]
+    pub fn remove_approved_ft_token_ids(&mut self, ft_token_ids: Vec<AccountId>) -> U64 {
+        assert_one_yocto();
+        self.assert_owner();
+        let (near, ft_token_ids): (Vec<_>, Vec<_>) =
+            ft_token_ids.into_iter().partition(|id| id.as_str() == NEAR);
+        let (removed, mut ignored) =
+            remove_accounts(Some(ft_token_ids), &mut self.approved_ft_token_ids);
+        ignored.extend(near);
+        self.log_allowlist_change(
+            "update_approved_ft_tokens",
+            &[],
+            &removed,
+            &ignored,
+            self.approved_ft_token_ids.len(),
+        )
+    }
+
+    /// remove_approved_ft_token_ids, then cleans up after up to `limit`
+    /// listings in those currencies: listings priced in one are removed,
+    /// and one that also settles in it as its alt currency keeps only its
+    /// main price. Nothing is escrowed in an FT, offers are NEAR only and
+    /// auctions are only listed in NEAR without an alt currency, so no bid
+    /// or offer is refunded.
+    ///
+    /// Rather than a count of the affected listings left, which would take
+    /// a scan of the whole market, this returns a cursor: market_index is
+    /// scanned from `from_index` until `limit` affected listings were
+    /// cleaned up, and the index to resume from is returned. Call again with
+    /// the same ids and that index until it returns None.
+    #[payable]
+    pub fn remove_approved_ft_token_ids_and_cleanup(
+        &mut self,
+        ft_token_ids: Vec<AccountId>,
+        from_index: Option<U64>,
+        limit: u64,
+    ) -> Option<U64> {
+        self.remove_approved_ft_token_ids(ft_token_ids.clone());
+        let removed: HashSet<AccountId> = ft_token_ids
+            .into_iter()
+            .filter(|id| !self.approved_ft_token_ids.contains(id))
+            .collect();
+
+        let total = self.market_index.len();
+        let mut index = from_index.map_or(0, |x| x.0);
+        let mut affected: Vec<(ContractAndTokenId, MarketData)> = vec![];
+        while index < total && (affected.len() as u64) < limit {
+            let key = self.market_index.get(index).unwrap();
+            // a deleted listing's slot stays behind in market_index
+            let market_data = match self.market_index_positions.get(&key) {
+                Some(position) if position == index => self.market.get(&key),
+                _ => None,
+            };
+            index += 1;
+            if let Some(market_data) = market_data {
+                if removed.contains(&market_data.ft_token_id)
+                    || market_data
+                        .alt_ft_token_id
+                        .as_ref()
+                        .is_some_and(|id| removed.contains(id))
+                {
+                    affected.push((key, market_data));
                 }
             }
-            env::log_str(
-                &json!({
-                    "event": "resolve_purchase",
-                    "params": {
-                        "owner_id": &market_data.owner_id,
-                        "nft_contract_id": &market_data.nft_contract_id,
-                        "token_id": &market_data.token_id,
-                        "ft_token_id": market_data.ft_token_id,
-                        "price": price,
-                        "buyer_id": buyer_id,
-                    }
-                })
-                .to_string(),
+        }
+
+        for (contract_and_token_id, mut market_data) in affected {
+            if !removed.contains(&market_data.ft_token_id) {
+                market_data.alt_ft_token_id = None;
+                market_data.alt_price = None;
+                self.internal_update_market_data(&contract_and_token_id, &market_data);
+                continue;
+            }
+            self.internal_delete_market_data(&market_data.nft_contract_id, &market_data.token_id);
+            self.log_event(
+                "delete_market_data",
+                json!({
+                    "owner_id": market_data.owner_id,
+                    "nft_contract_id": market_data.nft_contract_id,
+                    "token_id": market_data.token_id,
+                    "listing_nonce": U64(market_data.listing_nonce),
+                    "ft_token_id": market_data.ft_token_id,
+                    "reason": "ft_token_removed",
+                }),
             );
+        }
+        (index < total).then(|| index.into())
+    }
 
-            return price;
+    /// Accounts besides the marketplace itself allowed to call
+    /// resolve_purchase and resolve_offer, e.g. a settlement helper contract.
+    /// Empty by default. A trusted caller decides how funds held here move,
+    /// only add contracts you control.
+    #[payable]
+    pub fn add_trusted_callers(&mut self, account_ids: Vec<AccountId>) -> U64 {
+        assert_one_yocto();
+        self.assert_owner();
+        let (added, ignored) = add_accounts(Some(account_ids), &mut self.trusted_callers);
+        self.log_allowlist_change(
+            "update_trusted_callers",
+            &added,
+            &[],
+            &ignored,
+            self.trusted_callers.len(),
+        )
+    }
+
+    #[payable]
+    pub fn remove_trusted_callers(&mut self, account_ids: Vec<AccountId>) -> U64 {
+        assert_one_yocto();
+        self.assert_owner();
+        let (removed, ignored) = remove_accounts(Some(account_ids), &mut self.trusted_callers);
+        self.log_allowlist_change(
+            "update_trusted_callers",
+            &[],
+            &removed,
+            &ignored,
+            self.trusted_callers.len(),
+        )
+    }
+
+    pub fn get_trusted_callers(&self) -> Vec<AccountId> {
+        self.trusted_callers.to_vec()
+    }
+
+    /// Accounts allowed to list for a seller with delegate_list, e.g. a
+    /// companion marketplace cross-listing tokens it was approved on. Empty
+    /// by default, only add contracts you control.
+    #[payable]
+    pub fn add_trusted_delegates(&mut self, account_ids: Vec<AccountId>) -> U64 {
+        assert_one_yocto();
+        self.assert_owner();
+        let (added, ignored) = add_accounts(Some(account_ids), &mut self.trusted_delegates);
+        self.log_allowlist_change(
+            "update_trusted_delegates",
+            &added,
+            &[],
+            &ignored,
+            self.trusted_delegates.len(),
+        )
+    }
+
+    #[payable]
+    pub fn remove_trusted_delegates(&mut self, account_ids: Vec<AccountId>) -> U64 {
+        assert_one_yocto();
+        self.assert_owner();
+        let (removed, ignored) = remove_accounts(Some(account_ids), &mut self.trusted_delegates);
+        self.log_allowlist_change(
+            "update_trusted_delegates",
+            &[],
+            &removed,
+            &ignored,
+            self.trusted_delegates.len(),
+        )
+    }
+
+    pub fn get_trusted_delegates(&self) -> Vec<AccountId> {
+        self.trusted_delegates.to_vec()
+    }
+
+    /// Lists `token_id` for `owner_id` as nft_on_approve would, `msg` takes
+    /// the same MarketArgs with market_type "sale". `approval_id` must be the
+    /// marketplace's own approval on the token. The listing belongs to
+    /// `owner_id`, who pays its storage and receives the proceeds.
+    pub fn delegate_list(
+        &mut self,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        owner_id: AccountId,
+        approval_id: u64,
+        msg: String,
+    ) {
+        let delegate_id = env::predecessor_account_id();
+        require(
+            self.trusted_delegates.contains(&delegate_id),
+            ContractError::NotTrustedDelegate,
+        );
+        require(
+            self.is_nft_contract_allowed(&nft_contract_id),
+            ContractError::NftContractNotApproved,
+        );
+
+        let args: MarketArgs = near_sdk::serde_json::from_str(&msg)
+            .unwrap_or_else(|_| ContractError::InvalidMarketArgs.panic());
+        require(args.market_type == "sale", ContractError::InvalidMarketArgs);
+
+        self.internal_list_approved_token(owner_id, approval_id, nft_contract_id, token_id, args);
+    }
+
+    /// decimals from the token's ft_metadata, used to render prices
+    #[payable]
+    pub fn set_ft_token_decimals(&mut self, ft_token_id: AccountId, decimals: u8) {
+        assert_one_yocto();
+        self.assert_owner();
+        require(
+            self.approved_ft_token_ids.contains(&ft_token_id),
+            ContractError::FtTokenNotApproved,
+        );
+        require(
+            decimals <= MAX_FT_DECIMALS,
+            ContractError::InvalidFtDecimals,
+        );
+        self.ft_token_decimals.insert(&ft_token_id, &decimals);
+    }
+
+    pub fn get_ft_token_decimals(&self, ft_token_id: AccountId) -> Option<u8> {
+        if ft_token_id.as_str() == NEAR {
+            Some(NEAR_DECIMALS)
         } else {
-            U128(0)
+            self.ft_token_decimals.get(&ft_token_id)
         }
     }
 
-    // Offer
+    /// human readable amount, e.g. 12500000 with 6 decimals gives "12.5"
+    pub fn format_price(&self, ft_token_id: AccountId, amount: U128) -> String {
+        let decimals = self
+            .get_ft_token_decimals(ft_token_id)
+            .unwrap_or_else(|| ContractError::FtDecimalsUnknown.panic());
+        format_amount(amount.0, decimals)
+    }
+
+    // Collection settings
+
+    /// An account that bought a token of this collection cannot buy the same
+    /// token again until `cooldown_sec` has passed. `None` removes the rule.
+    #[payable]
+    pub fn set_resale_cooldown(&mut self, nft_contract_id: AccountId, cooldown_sec: Option<u64>) {
+        assert_one_yocto();
+        self.assert_owner();
+        match cooldown_sec {
+            Some(cooldown_sec) => self
+                .resale_cooldowns
+                .insert(&nft_contract_id, &cooldown_sec),
+            None => self.resale_cooldowns.remove(&nft_contract_id),
+        };
+    }
+
+    pub fn get_resale_cooldown(&self, nft_contract_id: AccountId) -> Option<u64> {
+        self.resale_cooldowns.get(&nft_contract_id)
+    }
+
+    /// Royalty paid to `creator_id` when the collection's nft_transfer_payout
+    /// returns no usable payout. Compliant contracts are paid as they report.
+    #[payable]
+    pub fn add_creator_royalty(
+        &mut self,
+        nft_contract_id: AccountId,
+        creator_id: AccountId,
+        bps: u16,
+    ) {
+        assert_one_yocto();
+        self.assert_owner();
+        require(bps <= 10_000, ContractError::RoyaltyTooHigh);
+        self.creator_royalties
+            .insert(&nft_contract_id, &(creator_id, bps));
+    }
+
+    #[payable]
+    pub fn remove_creator_royalty(&mut self, nft_contract_id: AccountId) {
+        assert_one_yocto();
+        self.assert_owner();
+        self.creator_royalties.remove(&nft_contract_id);
+    }
+
+    pub fn get_creator_royalty(&self, nft_contract_id: AccountId) -> Option<(AccountId, u16)> {
+        self.creator_royalties.get(&nft_contract_id)
+    }
+
+    /// Royalty enforced for a collection whose on-chain royalties are wrong:
+    /// `bps` of every price goes to `receiver_id` out of the seller's share,
+    /// on top of whatever nft_transfer_payout returns. See the precedence
+    /// notes above internal_pay_creator_royalty.
+    #[payable]
+    pub fn set_royalty_override(
+        &mut self,
+        nft_contract_id: AccountId,
+        receiver_id: AccountId,
+        bps: u16,
+    ) {
+        assert_one_yocto();
+        self.assert_owner();
+        require(bps <= 10_000, ContractError::RoyaltyTooHigh);
+        self.royalty_overrides
+            .insert(&nft_contract_id, &(receiver_id, bps));
+    }
+
+    #[payable]
+    pub fn remove_royalty_override(&mut self, nft_contract_id: AccountId) {
+        assert_one_yocto();
+        self.assert_owner();
+        self.royalty_overrides.remove(&nft_contract_id);
+    }
+
+    pub fn get_royalty_override(&self, nft_contract_id: AccountId) -> Option<(AccountId, u16)> {
+        self.royalty_overrides.get(&nft_contract_id)
+    }
+
+    /// In strict mode a payout must add up to exactly the price, otherwise it
+    /// is treated like a missing payout and the seller is paid directly.
+    #[payable]
+    pub fn set_strict_payout(&mut self, nft_contract_id: AccountId, strict: bool) {
+        assert_one_yocto();
+        self.assert_owner();
+        if strict {
+            self.strict_payout_contracts.insert(&nft_contract_id);
+        } else {
+            self.strict_payout_contracts.remove(&nft_contract_id);
+        }
+    }
+
+    pub fn is_strict_payout(&self, nft_contract_id: AccountId) -> bool {
+        self.strict_payout_contracts.contains(&nft_contract_id)
+    }
+
+    /// For collections without nft_transfer_payout (NEP-171 only). Their
+    /// sales go through nft_transfer, which returns no payout, so the seller
+    /// is paid directly like for a missing payout.
+    #[payable]
+    pub fn set_transfer_only(&mut self, nft_contract_id: AccountId, transfer_only: bool) {
+        assert_one_yocto();
+        self.assert_owner();
+        if transfer_only {
+            self.transfer_only_contracts.insert(&nft_contract_id);
+        } else {
+            self.transfer_only_contracts.remove(&nft_contract_id);
+        }
+    }
+
+    pub fn is_transfer_only(&self, nft_contract_id: AccountId) -> bool {
+        self.transfer_only_contracts.contains(&nft_contract_id)
+    }
+
+    /// Halts buying, bidding, offering and settlement for one collection.
+    /// Cancelling bids, offers and listings keeps working.
+    #[payable]
+    pub fn pause_nft_contract(&mut self, nft_contract_id: AccountId) {
+        assert_one_yocto();
+        self.assert_owner();
+        self.paused_contracts.insert(&nft_contract_id);
+        self.log_event(
+            "pause_nft_contract",
+            json!({ "nft_contract_id": nft_contract_id }),
+        );
+    }
+
+    #[payable]
+    pub fn unpause_nft_contract(&mut self, nft_contract_id: AccountId) {
+        assert_one_yocto();
+        self.assert_owner();
+        self.paused_contracts.remove(&nft_contract_id);
+        self.log_event(
+            "unpause_nft_contract",
+            json!({ "nft_contract_id": nft_contract_id }),
+        );
+    }
+
+    pub fn is_contract_paused(&self, nft_contract_id: AccountId) -> bool {
+        self.paused_contracts.contains(&nft_contract_id)
+    }
+
+    /// Circuit breaker for every collection: no new listings, buys, bids,
+    /// offers or settlements. delete_market_data, delete_offer, cancel_bid and
+    /// storage_withdraw keep working so funds can always be taken out.
+    #[payable]
+    pub fn set_paused(&mut self, paused: bool) {
+        assert_one_yocto();
+        self.assert_owner();
+        self.paused = paused;
+        self.log_event("set_paused", json!({ "paused": paused }));
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    /// Stops new offers across every collection. Listings and auctions keep
+    /// trading, and delete_offer still refunds existing offers.
+    #[payable]
+    pub fn set_offers_paused(&mut self, paused: bool) {
+        assert_one_yocto();
+        self.assert_owner();
+        self.offers_paused = paused;
+        self.log_event("set_offers_paused", json!({ "paused": paused }));
+    }
+
+    pub fn are_offers_paused(&self) -> bool {
+        self.offers_paused
+    }
+
+    /// Offers are enabled for every collection by default. Disabling them
+    /// only stops new offers, existing ones can still be deleted or accepted.
+    #[payable]
+    pub fn set_collection_offers_enabled(&mut self, nft_contract_id: AccountId, enabled: bool) {
+        assert_one_yocto();
+        self.assert_owner();
+        if enabled {
+            self.offers_disabled_contracts.remove(&nft_contract_id);
+        } else {
+            self.offers_disabled_contracts.insert(&nft_contract_id);
+        }
+        self.log_event(
+            "set_collection_offers_enabled",
+            json!({ "nft_contract_id": nft_contract_id, "enabled": enabled }),
+        );
+    }
+
+    /// Opt-in per collection: buy, add_bid and add_offer then only accept
+    /// accounts on the KYC allowlist.
+    #[payable]
+    pub fn set_kyc_required(&mut self, nft_contract_id: AccountId, required: bool) {
+        assert_one_yocto();
+        self.assert_owner();
+        if required {
+            self.kyc_required_contracts.insert(&nft_contract_id);
+        } else {
+            self.kyc_required_contracts.remove(&nft_contract_id);
+        }
+        self.log_event(
+            "set_kyc_required",
+            json!({ "nft_contract_id": nft_contract_id, "required": required }),
+        );
+    }
+
+    pub fn is_kyc_required(&self, nft_contract_id: AccountId) -> bool {
+        self.kyc_required_contracts.contains(&nft_contract_id)
+    }
+
+    #[payable]
+    pub fn add_kyc_accounts(&mut self, account_ids: Vec<AccountId>) -> U64 {
+        assert_one_yocto();
+        self.assert_owner();
+        let (added, ignored) = add_accounts(Some(account_ids), &mut self.kyc_allowlist);
+        self.log_allowlist_change(
+            "update_kyc_allowlist",
+            &added,
+            &[],
+            &ignored,
+            self.kyc_allowlist.len(),
+        )
+    }
+
+    #[payable]
+    pub fn remove_kyc_accounts(&mut self, account_ids: Vec<AccountId>) -> U64 {
+        assert_one_yocto();
+        self.assert_owner();
+        let (removed, ignored) = remove_accounts(Some(account_ids), &mut self.kyc_allowlist);
+        self.log_allowlist_change(
+            "update_kyc_allowlist",
+            &[],
+            &removed,
+            &ignored,
+            self.kyc_allowlist.len(),
+        )
+    }
+
+    pub fn is_kyc_approved(&self, account_id: AccountId) -> bool {
+        self.kyc_allowlist.contains(&account_id)
+    }
+
+    // Buy & Payment
+
+    /// `receiver_id` gets the NFT instead of the buyer, the buyer still pays
+    /// and receives any refund. The returned promise resolves to the price
+    /// from resolve_purchase once the transfer has settled. `confirm_overpay`
+    /// allows a deposit above max_overpay_factor times the price.
+    #[payable]
+    pub fn buy(
+        &mut self,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        ft_token_id: Option<AccountId>,
+        price: Option<U128>,
+        receiver_id: Option<AccountId>,
+        confirm_overpay: Option<bool>,
+    ) -> Promise {
+        let contract_and_token_id = format!("{}{}{}", &nft_contract_id, DELIMETER, token_id);
+
+        let mut market_data: MarketData = self
+            .market
+            .get(&contract_and_token_id)
+            .unwrap_or_else(|| ContractError::ListingNotFound.panic());
+
+        let buyer_id = env::predecessor_account_id();
+        let deposit = env::attached_deposit();
+
+        let effective_price = self
+            .internal_check_buy(&mut market_data, Some(&buyer_id), &near_account(), deposit)
+            .unwrap_or_else(|e| e.panic());
+
+        if let Some(ft_token_id) = ft_token_id {
+            require(
+                ft_token_id == market_data.ft_token_id,
+                ContractError::FtTokenMismatch,
+            );
+        }
+        if let Some(price) = price {
+            // a Dutch price only goes down while the buy is in flight
+            let price_matches = if market_data.start_price.is_some() {
+                effective_price <= price.0
+            } else {
+                effective_price == price.0
+            };
+            require(price_matches, ContractError::PriceMismatch);
+        }
+        if let Some(receiver_id) = &receiver_id {
+            require(
+                *receiver_id != market_data.owner_id && *receiver_id != env::current_account_id(),
+                ContractError::InvalidRecipient,
+            );
+        }
+
+        let price = effective_price;
+        if self.max_overpay_factor > 0 && confirm_overpay != Some(true) {
+            let max = price.saturating_mul(self.max_overpay_factor as u128);
+            require(deposit <= max, ContractError::OverpayNotConfirmed { max });
+        }
+
+        if deposit > price {
+            self.internal_refund(&buyer_id, &near_account(), deposit - price);
+        }
+
+        self.internal_process_purchase(
+            nft_contract_id,
+            token_id,
+            buyer_id,
+            receiver_id,
+            near_account(),
+            price,
+            None,
+            true,
+        )
+    }
+
+    /// Checks everything `buy` needs before money moves, in the order `buy`
+    /// reports them, and returns the price to charge. `deposit` is what was
+    /// paid in `ft_token_id`, NEAR for buy and the transferred amount for
+    /// ft_on_transfer. `market_data` is switched to the currency the sale
+    /// settles in. simulate_buy runs the same checks so the two can't drift
+    /// apart.
+    fn internal_check_buy(
+        &self,
+        market_data: &mut MarketData,
+        buyer_id: Option<&AccountId>,
+        ft_token_id: &AccountId,
+        deposit: Balance,
+    ) -> Result<u128, ContractError> {
+        let nft_contract_id = &market_data.nft_contract_id;
+        if !self.is_nft_contract_allowed(nft_contract_id) {
+            return Err(ContractError::NftContractNotApproved);
+        }
+        if self.paused {
+            return Err(ContractError::MarketplacePaused);
+        }
+        if self.paused_contracts.contains(nft_contract_id) {
+            return Err(ContractError::ContractPaused);
+        }
+        if buyer_id == Some(&market_data.owner_id) {
+            return Err(ContractError::CannotBuyOwnSale);
+        }
+        if let Some(buyer_id) = buyer_id {
+            if !self.is_kyc_allowed(nft_contract_id, buyer_id) {
+                return Err(ContractError::KycRequired);
+            }
+        }
+
+        let current_time = env::block_timestamp();
+        if market_data
+            .started_at
+            .is_some_and(|started_at| current_time < started_at)
+        {
+            return Err(ContractError::SaleNotStarted);
+        }
+        if market_data
+            .ended_at
+            .is_some_and(|ended_at| current_time > ended_at)
+        {
+            return Err(ContractError::SaleEnded);
+        }
+
+        // paid in the listing's alternative currency, settle at the alternative price
+        if market_data.ft_token_id != *ft_token_id
+            && market_data.alt_ft_token_id.as_ref() == Some(ft_token_id)
+        {
+            market_data.swap_alt_currency();
+        }
+
+        let price = self.resolve_effective_price(market_data)?;
+        // a malformed listing must not give the token away
+        if price < MIN_PRICE && !market_data.free_listing {
+            return Err(ContractError::PriceTooLow { min: MIN_PRICE });
+        }
+
+        if market_data.ft_token_id != *ft_token_id {
+            return Err(ContractError::FtTokenMismatch);
+        }
+        if deposit < price {
+            return Err(ContractError::DepositLessThanPrice { price });
+        }
+        if let Some(buyer_id) = buyer_id {
+            if !self.internal_resale_cooldown_passed(
+                &market_data.nft_contract_id,
+                &market_data.token_id,
+                buyer_id,
+            ) {
+                return Err(ContractError::ResaleCooldownActive);
+            }
+        }
+
+        Ok(price)
+    }
+
+    /// The price `buy` charges for a listing, in its settlement currency. Every
+    /// listing mode computes it here; auctions settle through add_bid/accept_bid.
+    fn resolve_effective_price(&self, market_data: &MarketData) -> Result<u128, ContractError> {
+        if market_data.is_auction == Some(true) {
+            let bids = market_data.bids.as_ref().map_or(0, |bids| bids.len());
+            return Err(ContractError::ListingIsAuction { bids: bids as u64 });
+        }
+        Ok(market_data
+            .dutch_price(env::block_timestamp())
+            .unwrap_or(market_data.price))
+    }
+
+    /// What `buy` charges right now. A Dutch listing declines linearly from
+    /// start_price at started_at to price at ended_at, other listings and
+    /// auctions return their price.
+    pub fn current_price(&self, nft_contract_id: AccountId, token_id: TokenId) -> U128 {
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+        let market_data = self
+            .market
+            .get(&contract_and_token_id)
+            .unwrap_or_else(|| ContractError::ListingNotFound.panic());
+        U128(
+            market_data
+                .dutch_price(env::block_timestamp())
+                .unwrap_or(market_data.price),
+        )
+    }
+
+    /// `ft_token_id` is the currency the buyer paid in, either the listing's
+    /// own or its alternative. The payout in resolve_purchase follows it.
+    /// The NFT goes to `receiver_id` when set, refunds always go to `buyer_id`.
+    #[allow(clippy::too_many_arguments)]
+    fn internal_process_purchase(
+        &mut self,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        buyer_id: AccountId,
+        receiver_id: Option<AccountId>,
+        ft_token_id: AccountId,
+        price: u128,
+        losing_bids: Option<Bids>,
+        escrowed: bool,
+    ) -> Promise {
+        let mut market_data = self
+            .internal_delete_market_data(&nft_contract_id, &token_id)
+            .unwrap_or_else(|| ContractError::ListingNotFound.panic());
+
+        if market_data.alt_ft_token_id.as_ref() == Some(&ft_token_id) {
+            market_data.swap_alt_currency();
+        }
+        require(
+            market_data.ft_token_id == ft_token_id,
+            ContractError::FtTokenMismatch,
+        );
+
+        let transfer_only = self.transfer_only_contracts.contains(&nft_contract_id);
+        internal_transfer_payout(
+            market_data,
+            buyer_id,
+            receiver_id,
+            price,
+            losing_bids,
+            transfer_only,
+            escrowed,
+        )
+    }
+
+    /// `losing_bids` are the auction bids still escrowed while the winner's
+    /// transfer is in flight. They are refunded once the transfer succeeds;
+    /// if it fails, the NFT is offered once to the highest of them.
+    /// `escrowed` is false for an ft_on_transfer buy, whose price the FT
+    /// contract still holds, and true when the market holds it.
+    pub fn resolve_purchase(
+        &mut self,
+        buyer_id: AccountId,
+        market_data: MarketData,
+        price: U128,
+        losing_bids: Option<Bids>,
+        escrowed: bool,
+    ) -> U128 {
+        self.assert_callback_or_trusted();
+        self.internal_apply_pending_fee();
+        let fee_recipient = market_data
+            .fee_recipient
+            .clone()
+            .unwrap_or_else(|| self.treasury_id.clone());
+        let payout_tolerance = self.internal_payout_tolerance(&market_data.nft_contract_id);
+
+        let mut losing_bids = losing_bids.unwrap_or_default();
+        if is_promise_success() {
+            self.internal_refund_bids(&market_data, &losing_bids);
+            losing_bids.clear();
+            self.internal_record_purchase(&market_data, &buyer_id);
+            let market_type = if market_data.is_auction == Some(true) {
+                self.last_auction_results.insert(
+                    &format!(
+                        "{}{}{}",
+                        market_data.nft_contract_id, DELIMETER, market_data.token_id
+                    ),
+                    &AuctionResult {
+                        winner_id: buyer_id.clone(),
+                        price,
+                        settled_at: env::block_timestamp().into(),
+                    },
+                );
+                "auction"
+            } else {
+                "sale"
+            };
+            self.internal_record_last_sale(
+                &market_data.nft_contract_id,
+                &market_data.token_id,
+                market_type,
+                LastSale {
+                    seller_id: market_data.owner_id.clone(),
+                    buyer_id: buyer_id.clone(),
+                    ft_token_id: market_data.ft_token_id.clone(),
+                    price,
+                    sold_at: env::block_timestamp().into(),
+                },
+            );
+            if market_data.ft_token_id == near_account() {
+                self.total_volume = self.total_volume.saturating_add(price.0);
+            }
+        }
+
+        let payout_option = promise_result_as_success()
+            .and_then(|value| validate_payout(&value, price.0, payout_tolerance));
+        let payout = if let Some(payout_option) = payout_option {
+            payout_option
+        } else {
+            // leave function, an ft_on_transfer buy gets its FTs back in
+            // ft_resolve_transfer
+            if !is_promise_success() {
+                if escrowed {
+                    self.internal_refund(&buyer_id, &market_data.ft_token_id, price.0);
+                }
+
+                self.log_event(
+                    "resolve_purchase_fail",
+                    json!({
+                        "owner_id": market_data.owner_id,
+                        "nft_contract_id": market_data.nft_contract_id,
+                        "token_id": market_data.token_id,
+                        "listing_nonce": U64(market_data.listing_nonce),
+                        "ft_token_id": market_data.ft_token_id,
+                        "price": price,
+                        "buyer_id": buyer_id,
+                    }),
+                );
+
+                if let Some(next_bid) = losing_bids.pop() {
+                    // only one retry, release everyone below the runner-up now
+                    self.internal_refund_bids(&market_data, &losing_bids);
+
+                    self.log_event(
+                        "resolve_purchase_fallback",
+                        json!({
+                            "owner_id": market_data.owner_id,
+                            "nft_contract_id": market_data.nft_contract_id,
+                            "token_id": market_data.token_id,
+                            "listing_nonce": U64(market_data.listing_nonce),
+                            "ft_token_id": market_data.ft_token_id,
+                            "price": next_bid.price,
+                            "buyer_id": next_bid.bidder_id,
+                        }),
+                    );
+
+                    let transfer_only = self
+                        .transfer_only_contracts
+                        .contains(&market_data.nft_contract_id);
+                    internal_transfer_payout(
+                        market_data,
+                        next_bid.bidder_id,
+                        next_bid.recipient,
+                        next_bid.price.0,
+                        None,
+                        transfer_only,
+                        true,
+                    );
+                }
+            } else {
+                let rebate = self.internal_settle(
+                    None,
+                    price.0,
+                    &market_data.owner_id,
+                    &SettleContext {
+                        nft_contract_id: &market_data.nft_contract_id,
+                        token_id: &market_data.token_id,
+                        ft_token_id: &market_data.ft_token_id,
+                        buyer_id: &buyer_id,
+                        fee_recipient: &fee_recipient,
+                        rebate_bps: self.buyer_rebate_bps,
+                    },
+                );
+                self.log_event(
+                    "resolve_purchase",
+                    json!({
+                        "owner_id": &market_data.owner_id,
+                        "nft_contract_id": &market_data.nft_contract_id,
+                        "token_id": &market_data.token_id,
+                        "listing_nonce": U64(market_data.listing_nonce),
+                        "ft_token_id": market_data.ft_token_id,
+                        "price": price,
+                        "buyer_id": buyer_id,
+                        "fee_recipient": fee_recipient,
+                        "rebate": U128(rebate),
+                    }),
+                );
+                return purchase_result(&market_data, price);
+            }
+
+            return price;
+        };
+
+        // Payout (transfer to royalties and seller)
+        let rebate = self.internal_settle(
+            Some(payout),
+            price.0,
+            &market_data.owner_id,
+            &SettleContext {
+                nft_contract_id: &market_data.nft_contract_id,
+                token_id: &market_data.token_id,
+                ft_token_id: &market_data.ft_token_id,
+                buyer_id: &buyer_id,
+                fee_recipient: &fee_recipient,
+                rebate_bps: self.buyer_rebate_bps,
+            },
+        );
+        self.log_event(
+            "resolve_purchase",
+            json!({
+                "owner_id": &market_data.owner_id,
+                "nft_contract_id": &market_data.nft_contract_id,
+                "token_id": &market_data.token_id,
+                "listing_nonce": U64(market_data.listing_nonce),
+                "ft_token_id": market_data.ft_token_id,
+                "price": price,
+                "buyer_id": buyer_id,
+                "fee_recipient": fee_recipient,
+                "rebate": U128(rebate),
+            }),
+        );
+        purchase_result(&market_data, price)
+    }
+
+    // Offer
+
+    /// The attached deposit is either exactly `price`, or `price` plus the
+    /// missing storage (see `storage_cost_for(buyer, 1)`) so a first-time
+    /// buyer doesn't need a separate storage_deposit. The storage part is
+    /// credited to the buyer's storage balance, the rest is escrowed.
+    /// Replaces the buyer's previous offer on the token, refunding it. A retry
+    /// with the same `nonce`, price and currency as the standing offer changes
+    /// nothing: the new deposit is refunded and the standing offer returned.
+    /// A token on auction takes offers too, apart from its bids: the seller
+    /// may accept one instead, which cancels the auction and refunds the bids.
+    /// An offer with `expires_at` can't be accepted after it, anyone may then
+    /// refund it with prune_offer.
+    #[payable]
+    pub fn add_offer(
+        &mut self,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        ft_token_id: AccountId,
+        price: U128,
+        nonce: Option<U64>,
+        expires_at: Option<U64>,
+    ) -> OfferDataJson {
+        require(
+            self.is_nft_contract_allowed(&nft_contract_id),
+            ContractError::NftContractNotApproved,
+        );
+        self.assert_not_paused();
+        self.assert_contract_not_paused(&nft_contract_id);
+        require(!self.offers_paused, ContractError::OffersPaused);
+        require(
+            !self.offers_disabled_contracts.contains(&nft_contract_id),
+            ContractError::CollectionOffersDisabled,
+        );
+
+        let deposit = env::attached_deposit();
+        require(deposit >= price.0, ContractError::DepositNotEqualPrice);
+
+        require(ft_token_id.as_str() == NEAR, ContractError::NearOnly);
+        require(
+            expires_at.is_none_or(|expires_at| expires_at.0 > env::block_timestamp()),
+            ContractError::InvalidOfferExpiry,
+        );
+
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+        if let Some(listing) = self.market.get(&contract_and_token_id) {
+            if let Some(min_offer_price) = listing.min_offer_price {
+                if listing.ft_token_id == ft_token_id {
+                    require(
+                        price.0 >= min_offer_price,
+                        ContractError::OfferBelowMinimum {
+                            min: min_offer_price,
+                        },
+                    );
+                }
+            }
+        }
+
+        let buyer_id = env::predecessor_account_id();
+        require(
+            self.is_kyc_allowed(&nft_contract_id, &buyer_id),
+            ContractError::KycRequired,
+        );
+        if let Some(nonce) = nonce {
+            let key = make_triple(&nft_contract_id, &buyer_id, &token_id);
+            if let Some(standing) = self.offers.get(&key) {
+                if standing.nonce == Some(nonce.0)
+                    && standing.price == price.0
+                    && standing.ft_token_id == ft_token_id
+                {
+                    self.internal_refund(&buyer_id, &ft_token_id, deposit);
+                    return standing.into();
+                }
+            }
+        }
+
+        let offer_data =
+            self.internal_delete_offer(nft_contract_id.clone(), buyer_id.clone(), token_id.clone());
+
+        if let Some(offer_data) = offer_data {
+            self.internal_refund(&buyer_id, &offer_data.ft_token_id, offer_data.price);
+        }
+
+        if let Some(max_offers) = self.max_offers_per_buyer {
+            require(
+                self.internal_offer_count(&buyer_id) < max_offers,
+                ContractError::TooManyOffers { max: max_offers },
+            );
+        }
+
+        let storage_part = deposit - price.0;
+        if storage_part > 0 {
+            require(
+                storage_part == self.storage_cost_for(buyer_id.clone(), 1).0,
+                ContractError::DepositNotEqualPrice,
+            );
+            let paid_storage = self.storage_deposits.get(&buyer_id).unwrap_or(0);
+            self.storage_deposits
+                .insert(&buyer_id, &(paid_storage + storage_part));
+        }
+
+        self.assert_storage_available(&buyer_id);
+
+        let offer_data = self.internal_add_offer(
+            nft_contract_id.clone(),
+            token_id.clone(),
+            ft_token_id.clone(),
+            price,
+            buyer_id.clone(),
+            nonce.map(|x| x.0),
+            expires_at.map(|x| x.0),
+        );
+
+        self.log_event(
+            "add_offer",
+            json!({
+                "buyer_id": buyer_id,
+                "nft_contract_id": nft_contract_id,
+                "token_id": token_id,
+                "ft_token_id": ft_token_id,
+                "price": price,
+                "nonce": nonce,
+                "expires_at": expires_at,
+            }),
+        );
+
+        offer_data.into()
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn internal_add_offer(
+        &mut self,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        ft_token_id: AccountId,
+        price: U128,
+        buyer_id: AccountId,
+        nonce: Option<u64>,
+        expires_at: Option<u64>,
+    ) -> OfferData {
+        assert_valid_token_id(&token_id);
+        let contract_account_id_token_id = make_triple(&nft_contract_id, &buyer_id, &token_id);
+        let offer_data = OfferData {
+            buyer_id: buyer_id.clone(),
+            nft_contract_id: nft_contract_id.clone(),
+            token_id,
+            ft_token_id,
+            price: price.into(),
+            nonce,
+            created_at: env::block_timestamp(),
+            expires_at,
+        };
+        self.offers
+            .insert(&contract_account_id_token_id, &offer_data);
+
+        let mut token_ids = self.internal_get_by_owner_id(&buyer_id);
+        token_ids.insert(&contract_account_id_token_id);
+        self.by_owner_id.insert(&buyer_id, &token_ids);
+
+        let mut offer_keys = self
+            .by_nft_contract_offers
+            .get(&nft_contract_id)
+            .unwrap_or_else(|| {
+                UnorderedSet::new(
+                    StorageKey::ByNFTContractOffersInner {
+                        account_id_hash: hash_account_id(&nft_contract_id),
+                    }
+                    .try_to_vec()
+                    .unwrap(),
+                )
+            });
+        offer_keys.insert(&contract_account_id_token_id);
+        self.by_nft_contract_offers
+            .insert(&nft_contract_id, &offer_keys);
+
+        offer_data
+    }
+
+    fn internal_delete_offer(
+        &mut self,
+        nft_contract_id: AccountId,
+        buyer_id: AccountId,
+        token_id: TokenId,
+    ) -> Option<OfferData> {
+        let contract_account_id_token_id = make_triple(&nft_contract_id, &buyer_id, &token_id);
+        let offer_data = self.offers.remove(&contract_account_id_token_id);
+
+        if let Some(offer) = &offer_data {
+            self.internal_remove_by_owner_id(&offer.buyer_id, &contract_account_id_token_id);
+
+            if let Some(mut offer_keys) = self.by_nft_contract_offers.get(&nft_contract_id) {
+                offer_keys.remove(&contract_account_id_token_id);
+                if offer_keys.is_empty() {
+                    self.by_nft_contract_offers.remove(&nft_contract_id);
+                } else {
+                    self.by_nft_contract_offers
+                        .insert(&nft_contract_id, &offer_keys);
+                }
+            }
+        }
+        offer_data
+    }
+
+    #[payable]
+    pub fn delete_offer(&mut self, nft_contract_id: AccountId, token_id: TokenId) {
+        assert_one_yocto();
+
+        let buyer_id = env::predecessor_account_id();
+        let contract_account_id_token_id = make_triple(&nft_contract_id, &buyer_id, &token_id);
+
+        let offer_data = self
+            .offers
+            .get(&contract_account_id_token_id)
+            .unwrap_or_else(|| ContractError::OfferNotFound.panic());
+
+        require(
+            offer_data.token_id == token_id,
+            ContractError::TokenIdMismatch,
+        );
+
+        require(
+            offer_data.buyer_id == buyer_id,
+            ContractError::NotOfferBuyer,
+        );
+
+        self.internal_delete_offer(nft_contract_id.clone(), buyer_id.clone(), token_id.clone())
+            .unwrap_or_else(|| ContractError::OfferNotFound.panic());
+
+        self.internal_refund(
+            &offer_data.buyer_id,
+            &offer_data.ft_token_id,
+            offer_data.price,
+        );
+
+        self.log_event(
+            "delete_offer",
+            json!({
+                "nft_contract_id": nft_contract_id,
+                "buyer_id": buyer_id,
+                "token_id": token_id,
+                "ft_token_id": offer_data.ft_token_id,
+            }),
+        );
+    }
+
+    /// Refunds and deletes an offer past its expires_at, anyone may call it.
+    /// Logs `delete_offer` with reason "expired".
+    pub fn prune_offer(
+        &mut self,
+        nft_contract_id: AccountId,
+        buyer_id: AccountId,
+        token_id: TokenId,
+    ) {
+        let contract_account_id_token_id = make_triple(&nft_contract_id, &buyer_id, &token_id);
+        let offer_data = self
+            .offers
+            .get(&contract_account_id_token_id)
+            .unwrap_or_else(|| ContractError::OfferNotFound.panic());
+        require(
+            offer_data.is_expired(env::block_timestamp()),
+            ContractError::OfferNotExpired,
+        );
+
+        self.internal_delete_offer(nft_contract_id.clone(), buyer_id.clone(), token_id.clone());
+        self.internal_refund(
+            &offer_data.buyer_id,
+            &offer_data.ft_token_id,
+            offer_data.price,
+        );
+
+        self.log_event(
+            "delete_offer",
+            json!({
+                "nft_contract_id": nft_contract_id,
+                "buyer_id": buyer_id,
+                "token_id": token_id,
+                "ft_token_id": offer_data.ft_token_id,
+                "reason": "expired",
+            }),
+        );
+    }
+
+    /// Lets the seller of a listed token turn down an offer on it. The buyer
+    /// is refunded and `reason` ("too_low", "wrong_currency", ...) is logged so
+    /// they know what to change.
+    #[payable]
+    pub fn reject_offer(
+        &mut self,
+        nft_contract_id: AccountId,
+        buyer_id: AccountId,
+        token_id: TokenId,
+        reason: Option<String>,
+    ) {
+        assert_one_yocto();
+        if let Some(reason) = &reason {
+            require(
+                reason.chars().count() <= MAX_REJECT_REASON_LEN,
+                ContractError::RejectReasonTooLong {
+                    max: MAX_REJECT_REASON_LEN as u64,
+                },
+            );
+        }
+
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+        let market_data = self
+            .market
+            .get(&contract_and_token_id)
+            .unwrap_or_else(|| ContractError::ListingNotFound.panic());
+        let owner_id = env::predecessor_account_id();
+        require(market_data.owner_id == owner_id, ContractError::NotSeller);
+
+        let offer_data = self
+            .internal_delete_offer(nft_contract_id.clone(), buyer_id.clone(), token_id.clone())
+            .unwrap_or_else(|| ContractError::OfferNotFound.panic());
+
+        self.internal_refund(
+            &offer_data.buyer_id,
+            &offer_data.ft_token_id,
+            offer_data.price,
+        );
+
+        self.log_event(
+            "reject_offer",
+            json!({
+                "owner_id": owner_id,
+                "nft_contract_id": nft_contract_id,
+                "buyer_id": buyer_id,
+                "token_id": token_id,
+                "ft_token_id": offer_data.ft_token_id,
+                "price": U128(offer_data.price),
+                "reason": reason,
+            }),
+        );
+    }
+
+    /// Refunds and deletes up to `limit` of the caller's offers, returns how
+    /// many are left so it can be called again until it returns 0.
+    #[payable]
+    pub fn cancel_all_offers(&mut self, limit: Option<u64>) -> U64 {
+        assert_one_yocto();
+        let buyer_id = env::predecessor_account_id();
+        let limit = limit.unwrap_or(MAX_VIEW_LIMIT).min(MAX_VIEW_LIMIT);
+
+        // by_owner_id also holds listing keys
+        let offers: Vec<OfferData> = self
+            .by_owner_id
+            .get(&buyer_id)
+            .map(|keys| {
+                keys.iter()
+                    .filter_map(|key| self.offers.get(&key))
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let remaining = offers.len().saturating_sub(limit as usize) as u64;
+        for offer_data in offers.into_iter().take(limit as usize) {
+            self.internal_delete_offer(
+                offer_data.nft_contract_id.clone(),
+                buyer_id.clone(),
+                offer_data.token_id.clone(),
+            );
+            self.internal_refund(&buyer_id, &offer_data.ft_token_id, offer_data.price);
+
+            self.log_event(
+                "delete_offer",
+                json!({
+                    "nft_contract_id": offer_data.nft_contract_id,
+                    "buyer_id": buyer_id,
+                    "token_id": offer_data.token_id,
+                    "ft_token_id": offer_data.ft_token_id,
+                }),
+            );
+        }
+        remaining.into()
+    }
+
+    pub fn get_offer(
+        &self,
+        nft_contract_id: AccountId,
+        buyer_id: AccountId,
+        token_id: TokenId,
+    ) -> OfferDataJson {
+        let contract_account_id_token_id = make_triple(&nft_contract_id, &buyer_id, &token_id);
+
+        let offer_data = self
+            .offers
+            .get(&contract_account_id_token_id)
+            .unwrap_or_else(|| ContractError::OfferNotFound.panic());
+
+        require(
+            offer_data.token_id == token_id,
+            ContractError::TokenIdMismatch,
+        );
+
+        offer_data.into()
+    }
+
+    /// get_offer plus the token's current listing price, if it is listed.
+    /// Compare the two only when the currencies match.
+    pub fn get_offer_context(
+        &self,
+        nft_contract_id: AccountId,
+        buyer_id: AccountId,
+        token_id: TokenId,
+    ) -> OfferContextJson {
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+        let listing = self.market.get(&contract_and_token_id);
+        OfferContextJson {
+            offer: self.get_offer(nft_contract_id, buyer_id, token_id),
+            listing_price: listing.as_ref().map(|market_data| market_data.price.into()),
+            listing_ft_token_id: listing.map(|market_data| market_data.ft_token_id),
+        }
+    }
+
+    /// offers for one token, optionally only those in `ft_token_id`. Reads
+    /// the collection's offers only, not the whole offers map.
+    pub fn get_offers(
+        &self,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        ft_token_id: Option<AccountId>,
+        from_index: Option<U64>,
+        limit: Option<u64>,
+    ) -> Vec<OfferDataJson> {
+        let offer_keys = match self.by_nft_contract_offers.get(&nft_contract_id) {
+            Some(offer_keys) => offer_keys,
+            None => return vec![],
+        };
+        let limit = limit.unwrap_or(MAX_VIEW_LIMIT).min(MAX_VIEW_LIMIT);
+        offer_keys
+            .iter()
+            .filter_map(|key| self.offers.get(&key))
+            .filter(|offer| {
+                offer.token_id == token_id
+                    && ft_token_id
+                        .as_ref()
+                        .is_none_or(|id| *id == offer.ft_token_id)
+            })
+            .skip(from_index.map_or(0, |x| x.0) as usize)
+            .take(limit as usize)
+            .map(|offer_data| offer_data.into())
+            .collect()
+    }
+
+    /// get_offers, each flagged with whether it beats the token's listing
+    /// price. Offers in another currency than the listing, or on a token
+    /// that isn't listed, never exceed it.
+    pub fn get_offers_vs_listing(
+        &self,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        from_index: Option<U64>,
+        limit: Option<u64>,
+    ) -> Vec<OfferVsListingJson> {
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+        let listing = self.market.get(&contract_and_token_id);
+        self.get_offers(nft_contract_id, token_id, None, from_index, limit)
+            .into_iter()
+            .map(|offer| {
+                let exceeds_listing = listing.as_ref().is_some_and(|market_data| {
+                    market_data.ft_token_id == offer.ft_token_id
+                        && offer.price.0 > market_data.price
+                });
+                OfferVsListingJson {
+                    offer,
+                    exceeds_listing,
+                }
+            })
+            .collect()
+    }
+
+    /// every offer on one collection, across all its tokens
+    pub fn get_offers_by_contract(
+        &self,
+        nft_contract_id: AccountId,
+        from_index: Option<U64>,
+        limit: Option<u64>,
+    ) -> Vec<OfferDataJson> {
+        let offer_keys = match self.by_nft_contract_offers.get(&nft_contract_id) {
+            Some(offer_keys) => offer_keys,
+            None => return vec![],
+        };
+        let limit = limit.unwrap_or(MAX_VIEW_LIMIT).min(MAX_VIEW_LIMIT);
+        offer_keys
+            .iter()
+            .skip(from_index.map_or(0, |x| x.0) as usize)
+            .take(limit as usize)
+            .filter_map(|key| self.offers.get(&key))
+            .map(|offer_data| offer_data.into())
+            .collect()
+    }
+
+    /// every offer on one token, get_offers without the currency filter
+    pub fn get_offers_by_token(
+        &self,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        from_index: Option<U64>,
+        limit: Option<u64>,
+    ) -> Vec<OfferDataJson> {
+        self.get_offers(nft_contract_id, token_id, None, from_index, limit)
+    }
+
+    /// `buyer_id`'s offers. by_owner_id also holds the account's listings,
+    /// those are skipped before paging.
+    pub fn get_offers_by_buyer(
+        &self,
+        buyer_id: AccountId,
+        from_index: Option<U64>,
+        limit: Option<u64>,
+    ) -> Vec<OfferDataJson> {
+        let keys = match self.by_owner_id.get(&buyer_id) {
+            Some(keys) => keys,
+            None => return vec![],
+        };
+        let limit = limit.unwrap_or(MAX_VIEW_LIMIT).min(MAX_VIEW_LIMIT);
+        keys.iter()
+            .filter_map(|key| self.offers.get(&key))
+            .skip(from_index.map_or(0, |x| x.0) as usize)
+            .take(limit as usize)
+            .map(|offer_data| offer_data.into())
+            .collect()
+    }
+
+    pub fn get_offer_supply(&self) -> U64 {
+        self.offers.len().into()
+    }
+
+    /// current listing, offers, top bid and last sale of one token
+    pub fn get_token_activity(
+        &self,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+    ) -> TokenActivity {
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+        let listing = self.market.get(&contract_and_token_id);
+        let top_bid = listing
+            .as_ref()
+            .and_then(|market_data| market_data.bids.as_ref())
+            .and_then(|bids| bids.last().cloned());
+        let offers = self
+            .by_nft_contract_offers
+            .get(&nft_contract_id)
+            .map(|offer_keys| {
+                offer_keys
+                    .iter()
+                    .filter_map(|key| self.offers.get(&key))
+                    .filter(|offer_data| offer_data.token_id == token_id)
+                    .take(MAX_VIEW_LIMIT as usize)
+                    .map(|offer_data| offer_data.into())
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        TokenActivity {
+            listing: listing.map(|market_data| self.internal_market_data_json(market_data)),
+            offers,
+            top_bid,
+            last_sale: self.last_sales.get(&contract_and_token_id),
+        }
+    }
+
+    /// Who won the token's last settled auction and for how much, kept after
+    /// the listing is gone. Only the most recent auction is kept.
+    pub fn get_last_auction_result(
+        &self,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+    ) -> Option<AuctionResult> {
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+        self.last_auction_results.get(&contract_and_token_id)
+    }
+
+    /// (price, sold_at) of the token's last settlements, oldest first and
+    /// at most 10. Prices are in whatever currency each sale settled in.
+    pub fn get_price_history(
+        &self,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+    ) -> Vec<(U128, U64)> {
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+        self.price_history
+            .get(&contract_and_token_id)
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(price, sold_at)| (U128(price), U64(sold_at)))
+            .collect()
+    }
+
+    /// highest offer in `ft_token_id` (NEAR by default), prices in different
+    /// currencies aren't compared
+    pub fn get_best_offer(
+        &self,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        ft_token_id: Option<AccountId>,
+    ) -> Option<OfferDataJson> {
+        let ft_token_id = ft_token_id.unwrap_or_else(near_account);
+        self.internal_get_best_offer(&nft_contract_id, &token_id, &ft_token_id)
+            .map(|offer_data| offer_data.into())
+    }
+
+    fn internal_get_best_offer(
+        &self,
+        nft_contract_id: &AccountId,
+        token_id: &TokenId,
+        ft_token_id: &AccountId,
+    ) -> Option<OfferData> {
+        let now = env::block_timestamp();
+        // only the collection's offers are read, not the whole offers map
+        let offer_keys = self.by_nft_contract_offers.get(nft_contract_id)?;
+        offer_keys
+            .iter()
+            .filter_map(|key| self.offers.get(&key))
+            .filter(|offer| {
+                &offer.token_id == token_id
+                    && &offer.ft_token_id == ft_token_id
+                    && !offer.is_expired(now)
+            })
+            .fold(None, |best: Option<OfferData>, offer| match best {
+                Some(best) if best.price >= offer.price => Some(best),
+                _ => Some(offer),
+            })
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn internal_accept_offer(
+        &mut self,
+        nft_contract_id: AccountId,
+        buyer_id: AccountId,
+        token_id: TokenId,
+        seller_id: AccountId,
+        approval_id: u64,
+        price: u128,
+        fee_recipient: Option<AccountId>,
+    ) -> Promise {
+        self.assert_not_paused();
+        self.assert_contract_not_paused(&nft_contract_id);
+        let contract_account_id_token_id = make_triple(&nft_contract_id, &buyer_id, &token_id);
+        // an auction being settled by accept_bid can't be replaced by an offer
+        require(
+            !self
+                .settlements_in_flight
+                .contains(&format!("{}{}{}", nft_contract_id, DELIMETER, token_id)),
+            ContractError::SettlementInFlight,
+        );
+
+        // a listing replaced by the accepted offer keeps its fee recipient,
+        // an auction's bids are refunded
+        let fee_recipient = self
+            .internal_delete_market_data(&nft_contract_id, &token_id)
+            .and_then(|market_data| market_data.fee_recipient)
+            .or(fee_recipient);
+
+        let offer_data = self
+            .offers
+            .get(&contract_account_id_token_id)
+            .unwrap_or_else(|| ContractError::OfferNotFound.panic());
+
+        require(
+            offer_data.token_id == token_id,
+            ContractError::TokenIdMismatch,
+        );
+        require(offer_data.price == price, ContractError::OfferPriceMismatch);
+        require(
+            !offer_data.is_expired(env::block_timestamp()),
+            ContractError::OfferExpired,
+        );
+
+        let offer_data = self
+            .internal_delete_offer(nft_contract_id.clone(), buyer_id, token_id.clone())
+            .unwrap_or_else(|| ContractError::OfferNotFound.panic());
+
+        let transfer_only = self.transfer_only_contracts.contains(&nft_contract_id);
+        nft_transfer_for_sale(
+            nft_contract_id,
+            offer_data.buyer_id.clone(),
+            token_id.clone(),
+            approval_id,
+            offer_data.price,
+            transfer_only,
+        )
+        .then(
+            ext_self::ext(env::current_account_id())
+                .with_attached_deposit(NO_DEPOSIT)
+                .with_static_gas(GAS_FOR_ROYALTIES)
+                .resolve_offer(seller_id, offer_data, token_id, fee_recipient),
+        )
+    }
+
+    pub fn resolve_offer(
+        &mut self,
+        seller_id: AccountId,
+        offer_data: OfferData,
+        token_id: TokenId,
+        fee_recipient: Option<AccountId>,
+    ) -> U128 {
+        self.assert_callback_or_trusted();
+        self.internal_apply_pending_fee();
+        let fee_recipient = fee_recipient.unwrap_or_else(|| self.treasury_id.clone());
+        let payout_tolerance = self.internal_payout_tolerance(&offer_data.nft_contract_id);
+        if is_promise_success() {
+            if offer_data.ft_token_id == near_account() {
+                self.total_volume = self.total_volume.saturating_add(offer_data.price);
+            }
+            self.internal_record_last_sale(
+                &offer_data.nft_contract_id,
+                &token_id,
+                "accept_offer",
+                LastSale {
+                    seller_id: seller_id.clone(),
+                    buyer_id: offer_data.buyer_id.clone(),
+                    ft_token_id: offer_data.ft_token_id.clone(),
+                    price: offer_data.price.into(),
+                    sold_at: env::block_timestamp().into(),
+                },
+            );
+        }
+
+        // None means a bad payout from bad NFT contract
+        let payout_option = promise_result_as_success()
+            .and_then(|value| validate_payout(&value, offer_data.price, payout_tolerance));
+
+        let payout = if let Some(payout_option) = payout_option {
+            payout_option
+        } else {
+            if !is_promise_success() {
+                if offer_data.ft_token_id == near_account() {
+                    self.internal_refund(
+                        &offer_data.buyer_id,
+                        &offer_data.ft_token_id,
+                        offer_data.price,
+                    );
+                }
+                // leave function and return all FTs in ft_resolve_transfer
+                self.log_event(
+                    "resolve_purchase_fail",
+                    json!({
+                        "owner_id": seller_id,
+                        "nft_contract_id": offer_data.nft_contract_id,
+                        "token_id": token_id,
+                        "ft_token_id": offer_data.ft_token_id,
+                        "price": offer_data.price.to_string(),
+                        "buyer_id": offer_data.buyer_id,
+                        "is_offer": true,
+                    }),
+                );
+            } else {
+                self.internal_settle(
+                    None,
+                    offer_data.price,
+                    &seller_id,
+                    &SettleContext {
+                        nft_contract_id: &offer_data.nft_contract_id,
+                        token_id: &token_id,
+                        ft_token_id: &offer_data.ft_token_id,
+                        buyer_id: &offer_data.buyer_id,
+                        fee_recipient: &fee_recipient,
+                        rebate_bps: 0,
+                    },
+                );
+                self.log_event(
+                    "resolve_purchase",
+                    json!({
+                        "owner_id": seller_id,
+                        "nft_contract_id": &offer_data.nft_contract_id,
+                        "token_id": &token_id,
+                        "ft_token_id": offer_data.ft_token_id,
+                        "price": offer_data.price.to_string(),
+                        "buyer_id": offer_data.buyer_id,
+                        "fee_recipient": fee_recipient,
+                        "is_offer": true,
+                    }),
+                );
+            }
+
+            return offer_data.price.into();
+        };
+
+        // Payout (transfer to royalties and seller)
+        self.internal_settle(
+            Some(payout),
+            offer_data.price,
+            &seller_id,
+            &SettleContext {
+                nft_contract_id: &offer_data.nft_contract_id,
+                token_id: &token_id,
+                ft_token_id: &offer_data.ft_token_id,
+                buyer_id: &offer_data.buyer_id,
+                fee_recipient: &fee_recipient,
+                rebate_bps: 0,
+            },
+        );
+        self.log_event(
+            "resolve_purchase",
+            json!({
+                "owner_id": seller_id,
+                "nft_contract_id": &offer_data.nft_contract_id,
+                "token_id": &token_id,
+                "ft_token_id": offer_data.ft_token_id,
+                "price": offer_data.price.to_string(),
+                "buyer_id": offer_data.buyer_id,
+                "fee_recipient": fee_recipient,
+                "is_offer": true,
+            }),
+        );
+        offer_data.price.into()
+    }
+
+    // Auction bids
+
+    /// With the default `hold_until_settlement` escrow model every bid stays
+    /// escrowed until the auction settles or is cancelled, outbid ones
+    /// included: resolve_purchase falls back to the runner-up if the winner's
+    /// transfer fails, and min_participants counts all bidders. An outbid
+    /// bidder can withdraw at any time with cancel_bid. With
+    /// `refund_on_outbid` the previous leader is refunded here instead.
+    /// Raising your own bid refunds the previous one right away.
+    ///
+    /// With `expected_current_price` the bid fails, and the deposit goes back,
+    /// unless the top bid (the starting price before any bid) still equals it.
+    #[payable]
+    pub fn add_bid(
+        &mut self,
+        nft_contract_id: AccountId,
+        ft_token_id: AccountId,
+        token_id: TokenId,
+        amount: U128,
+        recipient: Option<AccountId>,
+        expected_current_price: Option<U128>,
+    ) {
+        let contract_and_token_id = format!("{}{}{}", &nft_contract_id, DELIMETER, token_id);
+        let mut market_data = self
+            .market
+            .get(&contract_and_token_id)
+            .unwrap_or_else(|| ContractError::ListingNotFound.panic());
+
+        let bidder_id = env::predecessor_account_id();
+        if let Err(error) = self.internal_check_bid(
+            &market_data,
+            &bidder_id,
+            &ft_token_id,
+            amount.0,
+            env::attached_deposit(),
+            recipient.as_ref(),
+            expected_current_price,
+        ) {
+            error.panic()
+        }
+
+        let new_bid = Bid {
+            bidder_id: bidder_id.clone(),
+            price: amount,
+            placed_at: env::block_timestamp().into(),
+            recipient: recipient.clone(),
+        };
+
+        let mut bids = market_data.bids.unwrap_or_default();
+
+        let mut outbid: Option<(AccountId, U128)> = None;
+        if let Some(current_bid) = bids.last() {
+            if current_bid.bidder_id != bidder_id {
+                outbid = Some((current_bid.bidder_id.clone(), current_bid.price));
+            }
+
+            // one bid per bidder, refund the bidder's previous one
+            let bid_ft_token_id = &market_data.ft_token_id;
+            bids.retain(|bid| {
+                if bid.bidder_id == bidder_id {
+                    self.internal_refund(&bid.bidder_id, bid_ft_token_id, bid.price.0);
+                }
+
+                bid.bidder_id != bidder_id
+            });
+
+            if market_data.escrow_model == EscrowModel::RefundOnOutbid {
+                for bid in bids.drain(..) {
+                    self.internal_refund(&bid.bidder_id, &market_data.ft_token_id, bid.price.0);
+                }
+            }
+        }
+
+        bids.push(new_bid);
+        market_data.bids = Some(bids);
+        self.internal_mark_auction_started(&mut market_data);
+        self.internal_extend_auction(&mut market_data);
+        self.internal_update_market_data(&contract_and_token_id, &market_data);
+
+        self.log_event(
+            "add_bid",
+            json!({
+                "bidder_id": bidder_id,
+                "nft_contract_id": nft_contract_id,
+                "token_id": token_id,
+                "listing_nonce": U64(market_data.listing_nonce),
+                "ft_token_id": ft_token_id,
+                "amount": amount,
+                "recipient": recipient,
+                "ended_at": market_data.ended_at.map(U64),
+            }),
+        );
+
+        if let Some((outbid_id, outbid_amount)) = outbid {
+            self.log_event(
+                "outbid",
+                json!({
+                    "bidder_id": outbid_id,
+                    "amount": outbid_amount,
+                    "new_bidder_id": bidder_id,
+                    "nft_contract_id": nft_contract_id,
+                    "token_id": token_id,
+                    "ft_token_id": ft_token_id,
+                    "listing_nonce": U64(market_data.listing_nonce),
+                }),
+            );
+        }
+    }
+
+    // a bid always lands before ended_at, so the new end stays after started_at
+    fn internal_extend_auction(&self, market_data: &mut MarketData) {
+        let extension = self.auction_extension_seconds as u64 * 10u64.pow(9);
+        if extension == 0 || market_data.is_auction != Some(true) {
+            return;
+        }
+        if let Some(ended_at) = market_data.ended_at {
+            if env::block_timestamp() >= ended_at.saturating_sub(extension) {
+                market_data.ended_at = Some(ended_at.saturating_add(extension));
+            }
+        }
+    }
+
+    /// Every check add_bid makes before it touches state, in the same order,
+    /// so can_bid reports the error add_bid would panic with.
+    #[allow(clippy::too_many_arguments)]
+    fn internal_check_bid(
+        &self,
+        market_data: &MarketData,
+        bidder_id: &AccountId,
+        ft_token_id: &AccountId,
+        amount: Balance,
+        deposit: Balance,
+        recipient: Option<&AccountId>,
+        expected_current_price: Option<U128>,
+    ) -> Result<(), ContractError> {
+        if market_data.is_auction != Some(true) {
+            return Err(ContractError::ListingNotAuction);
+        }
+        if self.paused {
+            return Err(ContractError::MarketplacePaused);
+        }
+        if self.paused_contracts.contains(&market_data.nft_contract_id) {
+            return Err(ContractError::ContractPaused);
+        }
+        if !self.is_kyc_allowed(&market_data.nft_contract_id, bidder_id) {
+            return Err(ContractError::KycRequired);
+        }
+
+        let current_time = env::block_timestamp();
+        if market_data
+            .started_at
+            .is_some_and(|started_at| current_time < started_at)
+        {
+            return Err(ContractError::SaleNotStarted);
+        }
+        if market_data
+            .ended_at
+            .is_some_and(|ended_at| current_time > ended_at)
+        {
+            return Err(ContractError::SaleEnded);
+        }
+
+        if market_data.owner_id == *bidder_id {
+            return Err(ContractError::CannotBidOwnListing);
+        }
+        if recipient.is_some_and(|recipient| {
+            *recipient == market_data.owner_id || *recipient == env::current_account_id()
+        }) {
+            return Err(ContractError::InvalidRecipient);
+        }
+        if deposit < amount {
+            return Err(ContractError::DepositLessThanAmount);
+        }
+        if ft_token_id.as_str() != NEAR {
+            return Err(ContractError::NearOnly);
+        }
+        // the bid is escrowed in NEAR and refunded in the listing's currency
+        if market_data.ft_token_id.as_str() != NEAR {
+            return Err(ContractError::AuctionNearOnly);
+        }
+        self.internal_check_storage_available(bidder_id)?;
+
+        let bids = market_data.bids.as_deref().unwrap_or_default();
+        // one bid per bidder per block
+        if bids
+            .iter()
+            .any(|bid| bid.bidder_id == *bidder_id && bid.placed_at.0 == current_time)
+        {
+            return Err(ContractError::BidTooSoon);
+        }
+        if let Some(expected_current_price) = expected_current_price {
+            let current = bids.last().map_or(market_data.price, |bid| bid.price.0);
+            if current != expected_current_price.0 {
+                return Err(ContractError::BidPriceMoved { current });
+            }
+        }
+        if let Some(current_bid) = bids.last() {
+            if amount <= current_bid.price.0 {
+                return Err(ContractError::BidNotHigherThanCurrent {
+                    current: current_bid.price.0,
+                });
+            }
+            let min = market_data
+                .min_next_bid(self.min_bid_increment_bps)
+                .unwrap_or_default();
+            if amount < min {
+                return Err(ContractError::BidBelowMinIncrement { min });
+            }
+        }
+        if amount < market_data.price {
+            return Err(ContractError::BidBelowStartingPrice {
+                price: market_data.price,
+            });
+        }
+        Ok(())
+    }
+
+    /// With `check_approval` the NFT contract is first asked whether the
+    /// marketplace's approval still stands, and the auction only settles if
+    /// it does. A revoked approval then fails before any bid or NFT moves.
+    /// This costs about 10 Tgas on top of a plain accept_bid. Until
+    /// resolve_accept_bid runs the auction can't be settled again.
+    #[payable]
+    pub fn accept_bid(
+        &mut self,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        check_approval: Option<bool>,
+    ) {
+        assert_one_yocto();
+        self.dust_accrued = self.dust_accrued.saturating_add(env::attached_deposit());
+        let seller_id = env::predecessor_account_id();
+        if check_approval != Some(true) {
+            self.internal_accept_bid(&seller_id, &nft_contract_id, &token_id);
+            return;
+        }
+
+        // everything internal_accept_bid checks fails here, before the flag is
+        // set, so a settlement can't be left marked in flight by a known error
+        let market_data = self
+            .internal_check_accept_bid(&seller_id, &nft_contract_id, &token_id)
+            .unwrap_or_else(|error| error.panic());
+        let contract_and_token_id = format!("{}{}{}", &nft_contract_id, DELIMETER, token_id);
+        self.settlements_in_flight.insert(&contract_and_token_id);
+
+        ext_contract::ext(nft_contract_id.clone())
+            .with_static_gas(GAS_FOR_NFT_IS_APPROVED)
+            .nft_is_approved(
+                token_id.clone(),
+                env::current_account_id(),
+                Some(market_data.approval_id),
+            )
+            .then(
+                ext_self::ext(env::current_account_id())
+                    .with_attached_deposit(NO_DEPOSIT)
+                    .with_static_gas(GAS_FOR_AUCTION_SETTLEMENT)
+                    .resolve_accept_bid(
+                        seller_id,
+                        nft_contract_id,
+                        token_id,
+                        U64(market_data.listing_nonce),
+                    ),
+            );
+    }
+
+    /// Settles accept_bid once nft_is_approved confirmed the approval. The
+    /// listing is checked again, it may have changed while the view ran; a
+    /// check that fails now is logged instead of panicking, which would keep
+    /// the settlement marked in flight. A listing deleted in the meantime has
+    /// cleared the flag, and one listed again since has a new listing_nonce
+    /// whose flag belongs to its own accept_bid.
+    #[private]
+    pub fn resolve_accept_bid(
+        &mut self,
+        seller_id: AccountId,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        listing_nonce: U64,
+    ) -> bool {
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+        let same_listing = self
+            .market
+            .get(&contract_and_token_id)
+            .is_some_and(|market_data| market_data.listing_nonce == listing_nonce.0);
+        if !same_listing {
+            self.log_accept_bid_failed(
+                &seller_id,
+                &nft_contract_id,
+                &token_id,
+                ContractError::ListingNotFound,
+            );
+            return false;
+        }
+        self.settlements_in_flight.remove(&contract_and_token_id);
+        let approved = promise_result_as_success()
+            .and_then(|value| near_sdk::serde_json::from_slice::<bool>(&value).ok())
+            .unwrap_or(false);
+        if !approved {
+            let ft_token_id = self
+                .market
+                .get(&contract_and_token_id)
+                .map(|market_data| market_data.ft_token_id);
+            self.log_event(
+                "accept_bid_not_approved",
+                json!({
+                    "owner_id": seller_id,
+                    "nft_contract_id": nft_contract_id,
+                    "token_id": token_id,
+                    "ft_token_id": ft_token_id,
+                }),
+            );
+            return false;
+        }
+        match self.internal_check_accept_bid(&seller_id, &nft_contract_id, &token_id) {
+            Ok(market_data) => {
+                self.internal_accept_checked_bid(&contract_and_token_id, market_data);
+                true
+            }
+            Err(error) => {
+                self.log_accept_bid_failed(&seller_id, &nft_contract_id, &token_id, error);
+                false
+            }
+        }
+    }
+
+    fn log_accept_bid_failed(
+        &self,
+        seller_id: &AccountId,
+        nft_contract_id: &AccountId,
+        token_id: &TokenId,
+        error: ContractError,
+    ) {
+        self.log_event(
+            "accept_bid_failed",
+            json!({
+                "owner_id": seller_id,
+                "nft_contract_id": nft_contract_id,
+                "token_id": token_id,
+                "error": error.to_string(),
+            }),
+        );
+    }
+
+    /// The top bidder can settle an auction that has ended instead of waiting
+    /// for the seller or a keeper. Settles exactly as accept_bid would.
+    #[payable]
+    pub fn claim_won_auction(&mut self, nft_contract_id: AccountId, token_id: TokenId) {
+        assert_one_yocto();
+        let contract_and_token_id = format!("{}{}{}", &nft_contract_id, DELIMETER, token_id);
+        let market_data = self
+            .market
+            .get(&contract_and_token_id)
+            .unwrap_or_else(|| ContractError::ListingNotFound.panic());
+        require(
+            market_data.is_auction == Some(true),
+            ContractError::ListingNotAuction,
+        );
+        require(
+            market_data
+                .ended_at
+                .is_some_and(|ended_at| env::block_timestamp() > ended_at),
+            ContractError::AuctionNotEnded,
+        );
+        let top_bid = market_data
+            .bids
+            .as_ref()
+            .and_then(|bids| bids.last())
+            .unwrap_or_else(|| ContractError::NoBids.panic());
+        require(
+            top_bid.bidder_id == env::predecessor_account_id(),
+            ContractError::NotTopBidder,
+        );
+
+        self.internal_accept_bid(&market_data.owner_id, &nft_contract_id, &token_id);
+    }
+
+    /// Ends an auction with a buyout_price right away by paying it. Every
+    /// bid is refunded, unless the top bid already reaches the buyout, then
+    /// that bidder wins through accept_bid instead.
+    #[payable]
+    pub fn buy_now(&mut self, nft_contract_id: AccountId, token_id: TokenId) -> Promise {
+        let contract_and_token_id = format!("{}{}{}", &nft_contract_id, DELIMETER, token_id);
+        let market_data = self
+            .market
+            .get(&contract_and_token_id)
+            .unwrap_or_else(|| ContractError::ListingNotFound.panic());
+        require(
+            market_data.is_auction == Some(true),
+            ContractError::ListingNotAuction,
+        );
+        // the buyout is paid with the attached deposit
+        require(
+            market_data.ft_token_id.as_str() == NEAR,
+            ContractError::AuctionNearOnly,
+        );
+        let buyout_price = market_data
+            .buyout_price
+            .unwrap_or_else(|| ContractError::NoBuyoutPrice.panic());
+        require(
+            self.is_nft_contract_allowed(&nft_contract_id),
+            ContractError::NftContractNotApproved,
+        );
+        self.assert_not_paused();
+        self.assert_contract_not_paused(&nft_contract_id);
+        require(
+            !self.settlements_in_flight.contains(&contract_and_token_id),
+            ContractError::SettlementInFlight,
+        );
+
+        let buyer_id = env::predecessor_account_id();
+        require(
+            buyer_id != market_data.owner_id,
+            ContractError::CannotBuyOwnSale,
+        );
+        require(
+            self.is_kyc_allowed(&nft_contract_id, &buyer_id),
+            ContractError::KycRequired,
+        );
+        let now = env::block_timestamp();
+        require(
+            market_data
+                .started_at
+                .is_none_or(|started_at| now >= started_at),
+            ContractError::SaleNotStarted,
+        );
+        require(
+            market_data.ended_at.is_none_or(|ended_at| now <= ended_at),
+            ContractError::SaleEnded,
+        );
+        if let Some(top_bid) = market_data.bids.as_ref().and_then(|bids| bids.last()) {
+            require(
+                top_bid.price.0 < buyout_price,
+                ContractError::BuyoutBidExists {
+                    bid: top_bid.price.0,
+                },
+            );
+        }
+        self.assert_resale_cooldown_passed(&nft_contract_id, &token_id, &buyer_id);
+
+        let deposit = env::attached_deposit();
+        require(
+            deposit >= buyout_price,
+            ContractError::DepositLessThanPrice {
+                price: buyout_price,
+            },
+        );
+        if deposit > buyout_price {
+            self.internal_refund(&buyer_id, &near_account(), deposit - buyout_price);
+        }
+
+        self.log_event(
+            "buy_now",
+            json!({
+                "owner_id": market_data.owner_id,
+                "nft_contract_id": nft_contract_id,
+                "token_id": token_id,
+                "listing_nonce": U64(market_data.listing_nonce),
+                "ft_token_id": market_data.ft_token_id,
+                "buyer_id": buyer_id,
+                "price": U128(buyout_price),
+                "refunded_bids": market_data.bids.as_ref().map_or(0, |bids| bids.len()),
+            }),
+        );
+
+        // deleting the listing refunds its bids
+        self.internal_process_purchase(
+            nft_contract_id,
+            token_id,
+            buyer_id,
+            None,
+            near_account(),
+            buyout_price,
+            None,
+            true,
+        )
+    }
+
+    fn internal_accept_bid(
+        &mut self,
+        seller_id: &AccountId,
+        nft_contract_id: &AccountId,
+        token_id: &TokenId,
+    ) {
+        let market_data = self
+            .internal_check_accept_bid(seller_id, nft_contract_id, token_id)
+            .unwrap_or_else(|error| error.panic());
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+        self.internal_accept_checked_bid(&contract_and_token_id, market_data);
+    }
+
+    /// Every reason internal_accept_bid can refuse a settlement, returns the
+    /// listing to settle. An auction short of min_participants passes, it is
+    /// cancelled instead of settled.
+    fn internal_check_accept_bid(
+        &self,
+        seller_id: &AccountId,
+        nft_contract_id: &AccountId,
+        token_id: &TokenId,
+    ) -> Result<MarketData, ContractError> {
+        if self.paused {
+            return Err(ContractError::MarketplacePaused);
+        }
+        if self.paused_contracts.contains(nft_contract_id) {
+            return Err(ContractError::ContractPaused);
+        }
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+        if self.settlements_in_flight.contains(&contract_and_token_id) {
+            return Err(ContractError::SettlementInFlight);
+        }
+        let market_data = self
+            .market
+            .get(&contract_and_token_id)
+            .ok_or(ContractError::ListingNotFound)?;
+        if market_data.owner_id != *seller_id {
+            return Err(ContractError::NotSeller);
+        }
+
+        let selected_bid = market_data
+            .bids
+            .as_ref()
+            .and_then(|bids| bids.last())
+            .ok_or(ContractError::NoBids)?;
+
+        if !market_data.has_min_participants() {
+            return Ok(market_data);
+        }
+        if !market_data.reserve_met() {
+            return Err(ContractError::ReserveNotMet);
+        }
+
+        // the starting price may have been raised since the bid was placed
+        if selected_bid.price.0 < market_data.price {
+            return Err(ContractError::BidBelowStartingPrice {
+                price: market_data.price,
+            });
+        }
+
+        // settling early needs a top bid that has stood for min_bid_age
+        let now = env::block_timestamp();
+        if market_data.ended_at.is_none_or(|ended_at| now <= ended_at)
+            && now
+                < selected_bid
+                    .placed_at
+                    .0
+                    .saturating_add(self.min_bid_age.saturating_mul(10u64.pow(9)))
+        {
+            return Err(ContractError::BidTooRecent {
+                min_age_sec: self.min_bid_age,
+            });
+        }
+
+        if !self.internal_resale_cooldown_passed(nft_contract_id, token_id, &selected_bid.bidder_id)
+        {
+            return Err(ContractError::ResaleCooldownActive);
+        }
+        Ok(market_data)
+    }
+
+    /// Settles a listing internal_check_accept_bid passed
+    fn internal_accept_checked_bid(
+        &mut self,
+        contract_and_token_id: &ContractAndTokenId,
+        mut market_data: MarketData,
+    ) {
+        if !market_data.has_min_participants() {
+            self.internal_cancel_auction(&market_data, "min_participants");
+            return;
+        }
+        self.internal_settle_auction(contract_and_token_id, &mut market_data);
+    }
+
+    /// Settles an auction to its highest bid. The other bids stay escrowed
+    /// until resolve_purchase knows whether the winner's transfer went through.
+    fn internal_settle_auction(
+        &mut self,
+        contract_and_token_id: &ContractAndTokenId,
+        market_data: &mut MarketData,
+    ) -> Promise {
+        let mut bids = market_data.bids.take().unwrap_or_default();
+        let selected_bid = bids.pop().unwrap_or_else(|| ContractError::NoBids.panic());
+
+        market_data.bids = Some(Vec::new());
+        self.internal_update_market_data(contract_and_token_id, market_data);
+
+        self.log_event(
+            "settle_auction",
+            json!({
+                "owner_id": market_data.owner_id,
+                "nft_contract_id": market_data.nft_contract_id,
+                "token_id": market_data.token_id,
+                "listing_nonce": U64(market_data.listing_nonce),
+                "ft_token_id": market_data.ft_token_id,
+                "bidder_id": selected_bid.bidder_id,
+                "recipient": selected_bid.recipient.as_ref().unwrap_or(&selected_bid.bidder_id),
+                "price": selected_bid.price,
+            }),
+        );
+
+        // bids are escrowed in the listing's own currency
+        self.internal_process_purchase(
+            market_data.nft_contract_id.clone(),
+            market_data.token_id.clone(),
+            selected_bid.bidder_id,
+            selected_bid.recipient,
+            market_data.ft_token_id.clone(),
+            selected_bid.price.0,
+            Some(bids),
+            true,
+        )
+    }
+
+    /// Keeper entry point: for up to `limit` auctions past `ended_at`, settle
+    /// to the top bid or delete the listing when nobody bid. Returns how many
+    /// were processed so keepers can call again until it returns 0.
+    ///
+    /// Ended auctions are read from auction_ends. A deletion costs a few
+    /// Tgas; a settlement reserves about 150 Tgas for the NFT transfer and its
+    /// callback, so a 300 Tgas call settles at most one auction. The loop
+    /// stops early when the remaining gas cannot cover another settlement.
+    /// Auctions whose winner is blocked by a resale cooldown, or whose top bid
+    /// is below the current starting price, are skipped before `limit` is
+    /// applied so they can't hold back the auctions ending after them.
+    pub fn process_ended_auctions(&mut self, limit: u64) -> u64 {
+        if self.paused {
+            return 0;
+        }
+        let ended: Vec<(ContractAndTokenId, MarketData)> = self
+            .internal_ended_auctions(env::block_timestamp())
+            .filter(|(contract_and_token_id, market_data)| {
+                !self.paused_contracts.contains(&market_data.nft_contract_id)
+                    && !self.settlements_in_flight.contains(contract_and_token_id)
+                    && !self.is_settlement_blocked(market_data)
+            })
+            .take(limit as usize)
+            .collect();
+
+        let mut processed = 0;
+        for (contract_and_token_id, mut market_data) in ended {
+            match market_data.bids.as_ref().and_then(|bids| bids.last()) {
+                Some(_) if market_data.cancel_reason().is_some() => {
+                    let reason = market_data.cancel_reason().unwrap_or_default();
+                    self.internal_cancel_auction(&market_data, reason);
+                }
+                Some(_) => {
+                    let remaining_gas = env::prepaid_gas().0 - env::used_gas().0;
+                    if remaining_gas < GAS_FOR_AUCTION_SETTLEMENT.0 {
+                        break;
+                    }
+                    self.internal_settle_auction(&contract_and_token_id, &mut market_data);
+                }
+                None => {
+                    self.internal_delete_market_data(
+                        &market_data.nft_contract_id,
+                        &market_data.token_id,
+                    );
+
+                    self.log_event(
+                        "delete_market_data",
+                        json!({
+                            "owner_id": market_data.owner_id,
+                            "nft_contract_id": market_data.nft_contract_id,
+                            "token_id": market_data.token_id,
+                            "listing_nonce": U64(market_data.listing_nonce),
+                            "ft_token_id": market_data.ft_token_id,
+                        }),
+                    );
+                }
+            }
+            processed += 1;
+        }
+        processed
+    }
+
+    /// Keeper cleanup for one collection: deletes up to `limit` of its
+    /// listings past `ended_at` and returns how many went. Auctions that
+    /// missed min_participants are cancelled with their bids refunded; an
+    /// auction that can still settle to its top bid is left to
+    /// process_ended_auctions.
+    pub fn prune_expired_by_contract(&mut self, nft_contract_id: AccountId, limit: u64) -> u64 {
+        let listing_keys = match self.by_nft_contract.get(&nft_contract_id) {
+            Some(listing_keys) => listing_keys,
+            None => return 0,
+        };
+        let now = env::block_timestamp();
+        let expired: Vec<MarketData> = listing_keys
+            .iter()
+            .filter(|contract_and_token_id| {
+                !self.settlements_in_flight.contains(contract_and_token_id)
+            })
+            .filter_map(|contract_and_token_id| self.market.get(&contract_and_token_id))
+            .filter(|market_data| {
+                market_data.ended_at.is_some_and(|ended_at| now > ended_at)
+                    && (market_data.bids.as_ref().is_none_or(|bids| bids.is_empty())
+                        || market_data.cancel_reason().is_some())
+            })
+            .take(limit as usize)
+            .collect();
+
+        for market_data in &expired {
+            if market_data
+                .bids
+                .as_ref()
+                .is_some_and(|bids| !bids.is_empty())
+            {
+                let reason = market_data.cancel_reason().unwrap_or_default();
+                self.internal_cancel_auction(market_data, reason);
+                continue;
+            }
+            self.internal_delete_market_data(&market_data.nft_contract_id, &market_data.token_id);
+            self.log_event(
+                "delete_market_data",
+                json!({
+                    "owner_id": market_data.owner_id,
+                    "nft_contract_id": market_data.nft_contract_id,
+                    "token_id": market_data.token_id,
+                    "listing_nonce": U64(market_data.listing_nonce),
+                    "ft_token_id": market_data.ft_token_id,
+                    "reason": "expired",
+                }),
+            );
+        }
+        expired.len() as u64
+    }
+
+    /// Auctions past ended_at that are still listed, earliest first, so a
+    /// keeper can tell whether process_ended_auctions has work. `now_override`
+    /// replaces the block timestamp. Paused collections are included.
+    pub fn get_settleable_auctions(
+        &self,
+        now_override: Option<U64>,
+        limit: Option<u64>,
+    ) -> Vec<(AccountId, TokenId)> {
+        let now = now_override.map_or_else(env::block_timestamp, |now| now.0);
+        let limit = limit.unwrap_or(MAX_VIEW_LIMIT).min(MAX_VIEW_LIMIT);
+        self.internal_ended_auctions(now)
+            .take(limit as usize)
+            .map(|(_, market_data)| (market_data.nft_contract_id, market_data.token_id))
+            .collect()
+    }
+
+    fn internal_ended_auctions(
+        &self,
+        now: u64,
+    ) -> impl Iterator<Item = (ContractAndTokenId, MarketData)> + '_ {
+        self.auction_ends
+            .iter()
+            .take_while(move |((ended_at, _), _)| *ended_at < now)
+            .filter_map(move |((_, contract_and_token_id), _)| {
+                self.market
+                    .get(&contract_and_token_id)
+                    .map(|market_data| (contract_and_token_id, market_data))
+            })
+    }
+
+    /// Logs `auction_started` once the auction's started_at has passed, so
+    /// indexers get an open signal. Anyone may call it and add_bid does it
+    /// for the first bidder. Returns false if the event was already logged.
+    pub fn mark_auction_started(&mut self, nft_contract_id: AccountId, token_id: TokenId) -> bool {
+        let contract_and_token_id = format!("{}{}{}", &nft_contract_id, DELIMETER, token_id);
+        let mut market_data = self
+            .market
+            .get(&contract_and_token_id)
+            .unwrap_or_else(|| ContractError::ListingNotFound.panic());
+
+        require(
+            market_data.is_auction == Some(true),
+            ContractError::ListingNotAuction,
+        );
+        if !self.internal_mark_auction_started(&mut market_data) {
+            return false;
+        }
+        self.internal_update_market_data(&contract_and_token_id, &market_data);
+        true
+    }
+
+    fn internal_mark_auction_started(&self, market_data: &mut MarketData) -> bool {
+        if market_data.started_emitted {
+            return false;
+        }
+        let started_at = market_data.started_at.unwrap_or_default();
+        require(
+            env::block_timestamp() >= started_at,
+            ContractError::SaleNotStarted,
+        );
+
+        market_data.started_emitted = true;
+        self.log_event(
+            "auction_started",
+            json!({
+                "owner_id": market_data.owner_id,
+                "nft_contract_id": market_data.nft_contract_id,
+                "token_id": market_data.token_id,
+                "listing_nonce": U64(market_data.listing_nonce),
+                "ft_token_id": market_data.ft_token_id,
+                "started_at": U64(started_at),
+                "ended_at": market_data.ended_at.map(U64),
+                "price": U128(market_data.price),
+            }),
+        );
+        true
+    }
+
+    /// Seller or owner: cancels an auction that ended below its reserve_price,
+    /// every bid is refunded and the listing removed.
+    #[payable]
+    pub fn cancel_unmet_reserve(&mut self, nft_contract_id: AccountId, token_id: TokenId) {
+        assert_one_yocto();
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+        let market_data = self
+            .market
+            .get(&contract_and_token_id)
+            .unwrap_or_else(|| ContractError::ListingNotFound.panic());
+        require(
+            [market_data.owner_id.clone(), self.owner_id.clone()]
+                .contains(&env::predecessor_account_id()),
+            ContractError::NotSellerOrOwner,
+        );
+        require(
+            market_data
+                .ended_at
+                .is_some_and(|ended_at| env::block_timestamp() > ended_at),
+            ContractError::AuctionNotEnded,
+        );
+        require(!market_data.reserve_met(), ContractError::ReserveMet);
+        require(
+            !self.settlements_in_flight.contains(&contract_and_token_id),
+            ContractError::SettlementInFlight,
+        );
+
+        self.internal_cancel_auction(&market_data, "reserve_not_met");
+    }
+
+    /// Too few distinct bidders or a top bid below the reserve: every bid is
+    /// refunded and the listing removed. `reason` is logged.
+    fn internal_cancel_auction(&mut self, market_data: &MarketData, reason: &str) {
+        self.internal_delete_market_data(&market_data.nft_contract_id, &market_data.token_id);
+
+        self.log_event(
+            "cancel_auction",
+            json!({
+                "owner_id": market_data.owner_id,
+                "nft_contract_id": market_data.nft_contract_id,
+                "token_id": market_data.token_id,
+                "listing_nonce": U64(market_data.listing_nonce),
+                "ft_token_id": market_data.ft_token_id,
+                "min_participants": market_data.min_participants,
+                "reason": reason,
+            }),
+        );
+    }
+
+    fn internal_cancel_bid(
+        &mut self,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        account_id: AccountId,
+    ) {
+        let contract_and_token_id = format!("{}{}{}", &nft_contract_id, DELIMETER, token_id);
+        let mut market_data = self
+            .market
+            .get(&contract_and_token_id)
+            .unwrap_or_else(|| ContractError::ListingNotFound.panic());
+
+        let mut bids = market_data.bids.unwrap_or_default();
+
+        require(!bids.is_empty(), ContractError::NoBids);
+
+        // Retain all elements except account_id
+        let bid_ft_token_id = &market_data.ft_token_id;
+        bids.retain(|bid| {
+            if bid.bidder_id == account_id {
+                self.internal_refund(&bid.bidder_id, bid_ft_token_id, bid.price.0);
+            }
+
+            bid.bidder_id != account_id
+        });
+
+        market_data.bids = Some(bids);
+        self.internal_update_market_data(&contract_and_token_id, &market_data);
+
+        self.log_event(
+            "cancel_bid",
+            json!({
+                "bidder_id": account_id,
+                "nft_contract_id": nft_contract_id,
+                "token_id": token_id,
+                "listing_nonce": U64(market_data.listing_nonce),
+                "ft_token_id": market_data.ft_token_id,
+            }),
+        );
+    }
+
+    #[payable]
+    pub fn cancel_bid(
+        &mut self,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        account_id: AccountId,
+    ) {
+        assert_one_yocto();
+        let contract_and_token_id = format!("{}{}{}", &nft_contract_id, DELIMETER, token_id);
+        let market_data = self
+            .market
+            .get(&contract_and_token_id)
+            .unwrap_or_else(|| ContractError::ListingNotFound.panic());
+
+        let bids = market_data.bids.unwrap_or_default();
+
+        require(!bids.is_empty(), ContractError::NoBids);
+
+        let predecessor_id = env::predecessor_account_id();
+        for bid in &bids {
+            if bid.bidder_id == account_id {
+                require(
+                    [bid.bidder_id.clone(), self.owner_id.clone()].contains(&predecessor_id),
+                    ContractError::NotBidderOrOwner,
+                );
+            }
+        }
+
+        // the leading bid is locked for its own bidder
+        if predecessor_id != self.owner_id && !self.allow_leader_cancel {
+            require(
+                bids.last().is_none_or(|bid| bid.bidder_id != account_id),
+                ContractError::LeaderBidLocked,
+            );
+        }
+
+        self.internal_cancel_bid(nft_contract_id, token_id, account_id);
+    }
+
+    // Market Data functions
+
+    /// `started_at`, `ended_at` and `is_auction` are kept when None, see
+    /// internal_reschedule for how they are checked
+    #[payable]
+    #[allow(clippy::too_many_arguments)]
+    pub fn update_market_data(
+        &mut self,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        ft_token_id: AccountId,
+        price: U128,
+        seller_note: Option<String>,
+        started_at: Option<U64>,
+        ended_at: Option<U64>,
+        is_auction: Option<bool>,
+    ) {
+        assert_one_yocto();
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+        let mut market_data = self
+            .market
+            .get(&contract_and_token_id)
+            .unwrap_or_else(|| ContractError::ListingNotFound.panic());
+
+        require(
+            market_data.owner_id == env::predecessor_account_id(),
+            ContractError::NotSeller,
+        );
+
+        require(
+            ft_token_id == market_data.ft_token_id,
+            ContractError::FtTokenMismatch,
+        ); // sanity check
+        self.assert_listing_mutable(&market_data, &ft_token_id);
+
+        require(
+            price.0 < MAX_PRICE,
+            ContractError::PriceTooHigh { max: MAX_PRICE },
+        );
+        assert_min_price(price.0, market_data.free_listing);
+        if let Some(start_price) = market_data.start_price {
+            require(price.0 < start_price, ContractError::InvalidDutchAuction);
+        }
+        // the order internal_add_market_data checks them in
+        if let Some(buyout_price) = market_data.buyout_price {
+            require(price.0 <= buyout_price, ContractError::InvalidBuyoutPrice);
+        }
+        if let Some(reserve_price) = market_data.reserve_price {
+            require(price.0 <= reserve_price, ContractError::InvalidReservePrice);
+        }
+
+        let current_time = env::block_timestamp();
+        if let Some(last_updated_at) = market_data.last_updated_at {
+            require(
+                current_time
+                    >= last_updated_at
+                        .saturating_add(self.price_update_cooldown.saturating_mul(10u64.pow(9))),
+                ContractError::PriceUpdateTooSoon {
+                    cooldown_sec: self.price_update_cooldown,
+                },
+            );
+        }
+
+        if started_at.is_some() || ended_at.is_some() || is_auction.is_some() {
+            self.internal_reschedule(&mut market_data, started_at, ended_at, is_auction);
+        }
+        market_data.price = price.into();
+        market_data.last_updated_at = Some(current_time);
+        if let Some(seller_note) = seller_note {
+            assert_valid_seller_note(Some(&seller_note));
+            // an empty note removes it
+            market_data.seller_note = Some(seller_note).filter(|note| !note.is_empty());
+        }
+        self.internal_update_market_data(&contract_and_token_id, &market_data);
+
+        self.log_event(
+            "update_market_data",
+            json!({
+                "owner_id": market_data.owner_id,
+                "nft_contract_id": nft_contract_id,
+                "token_id": token_id,
+                "listing_nonce": U64(market_data.listing_nonce),
+                "ft_token_id": ft_token_id,
+                "price": price,
+                "last_updated_at": U64(current_time),
+                "seller_note": market_data.seller_note,
+                "started_at": market_data.started_at.map(U64),
+                "ended_at": market_data.ended_at.map(U64),
+                "is_auction": market_data.is_auction,
+            }),
+        );
+    }
+
+    /// Re-denominates a listing, e.g. out of an FT that is no longer approved.
+    /// An alternative currency equal to the new one is dropped. See
+    /// assert_listing_mutable for when this is allowed; a listing with a
+    /// start_price, buyout_price or reserve_price has to be relisted.
+    #[payable]
+    pub fn change_listing_currency(
+        &mut self,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        new_ft_token_id: AccountId,
+        new_price: U128,
+    ) {
+        assert_one_yocto();
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+        let mut market_data = self
+            .market
+            .get(&contract_and_token_id)
+            .unwrap_or_else(|| ContractError::ListingNotFound.panic());
+
+        require(
+            market_data.owner_id == env::predecessor_account_id(),
+            ContractError::NotSeller,
+        );
+        require(
+            self.approved_ft_token_ids.contains(&new_ft_token_id),
+            ContractError::FtTokenNotApproved,
+        );
+        self.assert_listing_mutable(&market_data, &new_ft_token_id);
+        require(
+            market_data.is_auction != Some(true) || new_ft_token_id.as_str() == NEAR,
+            ContractError::AuctionNearOnly,
+        );
+        // they would keep the old currency's units
+        require(
+            market_data.start_price.is_none()
+                && market_data.buyout_price.is_none()
+                && market_data.reserve_price.is_none(),
+            ContractError::PricesInListingCurrency,
+        );
+        require(
+            new_price.0 < MAX_PRICE,
+            ContractError::PriceTooHigh { max: MAX_PRICE },
+        );
+        assert_min_price(new_price.0, market_data.free_listing);
+
+        if market_data.alt_ft_token_id.as_ref() == Some(&new_ft_token_id) {
+            market_data.alt_ft_token_id = None;
+            market_data.alt_price = None;
+        }
+        market_data.ft_token_id = new_ft_token_id.clone();
+        market_data.price = new_price.0;
+        // the floor was converted into the old currency
+        market_data.min_offer_price = None;
+        self.internal_update_market_data(&contract_and_token_id, &market_data);
+
+        self.log_event(
+            "update_market_data",
+            json!({
+                "owner_id": market_data.owner_id,
+                "nft_contract_id": nft_contract_id,
+                "token_id": token_id,
+                "listing_nonce": U64(market_data.listing_nonce),
+                "ft_token_id": new_ft_token_id,
+                "price": new_price,
+            }),
+        );
+    }
+
+    /// Sets a minimum offer for the listing from a USD amount in cents,
+    /// converted into the listing's currency with the price oracle.
+    ///
+    /// The conversion happens once, when resolve_usd_floor runs; the stored
+    /// floor is in token units and doesn't follow the rate afterwards. Call
+    /// again to refresh it. If the oracle call fails the floor is unchanged.
+    #[payable]
+    pub fn set_usd_floor(
+        &mut self,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        floor_usd_cents: U128,
+    ) -> Promise {
+        assert_one_yocto();
+        let price_oracle_id = self
+            .price_oracle_id
+            .clone()
+            .unwrap_or_else(|| ContractError::OracleNotSet.panic());
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+        let market_data = self
+            .market
+            .get(&contract_and_token_id)
+            .unwrap_or_else(|| ContractError::ListingNotFound.panic());
+        require(
+            market_data.owner_id == env::predecessor_account_id(),
+            ContractError::NotSeller,
+        );
+
+        ext_oracle::ext(price_oracle_id)
+            .with_static_gas(GAS_FOR_ORACLE_PRICE)
+            .get_price(market_data.ft_token_id.clone())
+            .then(
+                ext_self::ext(env::current_account_id())
+                    .with_attached_deposit(NO_DEPOSIT)
+                    .with_static_gas(BASE_GAS)
+                    .resolve_usd_floor(
+                        nft_contract_id,
+                        token_id,
+                        market_data.ft_token_id,
+                        floor_usd_cents,
+                    ),
+            )
+    }
+
+    /// Stores the converted floor unless the listing changed currency or
+    /// was removed while the oracle answered. Returns the floor that was set.
+    #[private]
+    pub fn resolve_usd_floor(
+        &mut self,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        ft_token_id: AccountId,
+        floor_usd_cents: U128,
+    ) -> Option<U128> {
+        let units_per_usd = promise_result_as_success()
+            .and_then(|value| near_sdk::serde_json::from_slice::<U128>(&value).ok())?;
+        let min_offer_price = floor_usd_cents
+            .0
+            .checked_mul(units_per_usd.0)
+            .map(|x| x / 100)?;
+
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+        let mut market_data = self.market.get(&contract_and_token_id)?;
+        if market_data.ft_token_id != ft_token_id {
+            return None;
+        }
+        market_data.min_offer_price = Some(min_offer_price);
+        self.internal_update_market_data(&contract_and_token_id, &market_data);
+
+        self.log_event(
+            "set_usd_floor",
+            json!({
+                "owner_id": market_data.owner_id,
+                "nft_contract_id": nft_contract_id,
+                "token_id": token_id,
+                "listing_nonce": U64(market_data.listing_nonce),
+                "ft_token_id": ft_token_id,
+                "floor_usd_cents": floor_usd_cents,
+                "min_offer_price": U128(min_offer_price),
+            }),
+        );
+        Some(min_offer_price.into())
+    }
+
+    /// Moves an auction that hasn't started and has no bids to a new window.
+    #[payable]
+    pub fn reschedule_auction(
+        &mut self,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        started_at: U64,
+        ended_at: Option<U64>,
+    ) {
+        assert_one_yocto();
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+        let mut market_data = self
+            .market
+            .get(&contract_and_token_id)
+            .unwrap_or_else(|| ContractError::ListingNotFound.panic());
+
+        require(
+            market_data.owner_id == env::predecessor_account_id(),
+            ContractError::NotSeller,
+        );
+        require(
+            market_data.is_auction == Some(true),
+            ContractError::ListingNotAuction,
+        );
+        require(
+            market_data
+                .started_at
+                .is_some_and(|started_at| started_at > env::block_timestamp())
+                && market_data.bids.as_ref().is_none_or(|bids| bids.is_empty()),
+            ContractError::AuctionAlreadyStarted,
+        );
+
+        assert_valid_time_window(Some(started_at), ended_at);
+        self.assert_within_schedule_horizon(Some(started_at));
+        self.assert_auction_duration(Some(started_at), ended_at);
+
+        market_data.started_at = Some(started_at.0);
+        market_data.ended_at = ended_at.map(|x| x.0);
+        self.internal_update_market_data(&contract_and_token_id, &market_data);
+
+        self.log_event(
+            "reschedule_auction",
+            json!({
+                "owner_id": market_data.owner_id,
+                "nft_contract_id": nft_contract_id,
+                "token_id": token_id,
+                "listing_nonce": U64(market_data.listing_nonce),
+                "ft_token_id": market_data.ft_token_id,
+                "started_at": started_at,
+                "ended_at": ended_at,
+            }),
+        );
+    }
+
+    /// Replaces the caller's listing with a new one in a single call: the
+    /// listing is recreated with the same approval and storage slot. The
+    /// alternative currency is kept for fixed-price listings,
+    /// min_participants for auctions. A Dutch listing relists at a fixed
+    /// price. Logs `relist` instead of `add_market_data`.
+    #[allow(clippy::too_many_arguments)]
+    #[payable]
+    pub fn relist(
+        &mut self,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        new_price: U128,
+        new_ft_token_id: Option<AccountId>,
+        started_at: Option<U64>,
+        ended_at: Option<U64>,
+        is_auction: Option<bool>,
+    ) {
+        assert_one_yocto();
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+        let market_data = self
+            .market
+            .get(&contract_and_token_id)
+            .unwrap_or_else(|| ContractError::ListingNotFound.panic());
+        require(
+            market_data.owner_id == env::predecessor_account_id(),
+            ContractError::NotSeller,
+        );
+
+        let ft_token_id = new_ft_token_id.unwrap_or_else(|| market_data.ft_token_id.clone());
+        require(
+            self.approved_ft_token_ids.contains(&ft_token_id),
+            ContractError::FtTokenNotApproved,
+        );
+        self.assert_listing_mutable(&market_data, &ft_token_id);
+        let auction = is_auction == Some(true);
+        let alt_currency = match (market_data.alt_ft_token_id, market_data.alt_price) {
+            (Some(alt_ft_token_id), Some(alt_price))
+                if !auction && alt_ft_token_id != ft_token_id =>
+            {
+                Some((alt_ft_token_id, U128(alt_price)))
+            }
+            _ => None,
+        };
+        let min_participants = market_data.min_participants.filter(|_| auction);
+        let buyout_price = market_data.buyout_price.filter(|_| auction).map(U128);
+        let reserve_price = market_data.reserve_price.filter(|_| auction).map(U128);
+
+        self.internal_delete_market_data(&nft_contract_id, &token_id);
+        self.internal_add_market_data(
+            market_data.owner_id,
+            market_data.approval_id,
+            nft_contract_id,
+            token_id,
+            ft_token_id,
+            new_price,
+            started_at,
+            ended_at,
+            is_auction,
+            alt_currency,
+            min_participants,
+            market_data.fee_recipient,
+            market_data.seller_note,
+            market_data.free_listing,
+            market_data.escrow_model,
+            buyout_price,
+            None,
+            reserve_price,
+            "relist",
+        );
+    }
+
+    /// `event` is `add_market_data`, or `relist` when called from relist.
+    /// Callers delete the token's listing first, a token has one MarketData
+    /// and its alternative currency lives on it.
+    #[allow(clippy::too_many_arguments)]
+    fn internal_add_market_data(
+        &mut self,
+        owner_id: AccountId,
+        approval_id: u64,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        ft_token_id: AccountId,
+        price: U128,
+        started_at: Option<U64>,
+        ended_at: Option<U64>,
+        is_auction: Option<bool>,
+        alt_currency: Option<(AccountId, U128)>,
+        min_participants: Option<u32>,
+        fee_recipient: Option<AccountId>,
+        seller_note: Option<String>,
+        free_listing: bool,
+        escrow_model: EscrowModel,
+        buyout_price: Option<U128>,
+        start_price: Option<U128>,
+        reserve_price: Option<U128>,
+        event: &str,
+    ) {
+        self.assert_not_paused();
+        assert_valid_token_id(&token_id);
+        assert_valid_seller_note(seller_note.as_deref());
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+
+        let bids: Option<Bids> = match is_auction {
+            Some(true) => Some(Vec::new()),
+            _ => None,
+        };
+        // an auction without started_at starts now, so its window is always stored
+        let started_at = match is_auction {
+            Some(true) => started_at.or_else(|| Some(env::block_timestamp().into())),
+            _ => started_at,
+        };
+
+        assert_valid_time_window(started_at, ended_at);
+        self.assert_within_schedule_horizon(started_at);
+        if is_auction == Some(true) {
+            self.assert_auction_duration(started_at, ended_at);
+            // bids are escrowed from the attached deposit
+            require(ft_token_id.as_str() == NEAR, ContractError::AuctionNearOnly);
+        }
+
+        require(
+            price.0 < MAX_PRICE,
+            ContractError::PriceTooHigh { max: MAX_PRICE },
+        );
+        assert_min_price(price.0, free_listing);
+
+        if let Some((alt_ft_token_id, alt_price)) = &alt_currency {
+            require(
+                *alt_ft_token_id != ft_token_id && is_auction != Some(true),
+                ContractError::InvalidAltCurrency,
+            );
+            require(
+                alt_price.0 < MAX_PRICE,
+                ContractError::PriceTooHigh { max: MAX_PRICE },
+            );
+        }
+        let (alt_ft_token_id, alt_price) = alt_currency.unzip();
+
+        if min_participants.is_some() {
+            require(is_auction == Some(true), ContractError::InvalidMarketArgs);
+            require(
+                escrow_model == EscrowModel::HoldUntilSettlement,
+                ContractError::MinParticipantsNeedHeldBids,
+            );
+        }
+        if let Some(buyout_price) = buyout_price {
+            require(
+                is_auction == Some(true) && buyout_price.0 >= price.0,
+                ContractError::InvalidBuyoutPrice,
+            );
+            require(
+                buyout_price.0 < MAX_PRICE,
+                ContractError::PriceTooHigh { max: MAX_PRICE },
+            );
+        }
+        if let Some(reserve_price) = reserve_price {
+            require(
+                is_auction == Some(true)
+                    && reserve_price.0 >= price.0
+                    && buyout_price.is_none_or(|buyout_price| reserve_price.0 <= buyout_price.0),
+                ContractError::InvalidReservePrice,
+            );
+        }
+        if let Some(start_price) = start_price {
+            require(
+                is_auction != Some(true)
+                    && alt_ft_token_id.is_none()
+                    && started_at.is_some()
+                    && ended_at.is_some()
+                    && start_price.0 > price.0,
+                ContractError::InvalidDutchAuction,
+            );
+            require(
+                start_price.0 < MAX_PRICE,
+                ContractError::PriceTooHigh { max: MAX_PRICE },
+            );
+        }
+
+        // the collection index is kept at the market's cost, a listing is
+        // charged the same whatever else its collection has listed
+        let mut listing_keys = self
+            .by_nft_contract
+            .get(&nft_contract_id)
+            .unwrap_or_else(|| {
+                UnorderedSet::new(
+                    StorageKey::ByNFTContractInner {
+                        account_id_hash: hash_account_id(&nft_contract_id),
+                    }
+                    .try_to_vec()
+                    .unwrap(),
+                )
+            });
+        listing_keys.insert(&contract_and_token_id);
+        self.by_nft_contract.insert(&nft_contract_id, &listing_keys);
+        let initial_storage_usage = env::storage_usage();
+        let listing_nonce = self.next_listing_nonce;
+        self.next_listing_nonce += 1;
+
+        self.market.insert(
+            &contract_and_token_id,
+            &MarketData {
+                owner_id: owner_id.clone(),
+                approval_id,
+                nft_contract_id: nft_contract_id.clone(),
+                token_id: token_id.clone(),
+                ft_token_id: ft_token_id.clone(),
+                price: price.into(),
+                bids,
+                started_at: started_at.map(|x| x.0),
+                ended_at: ended_at.map(|x| x.0),
+                is_auction,
+                alt_ft_token_id: alt_ft_token_id.clone(),
+                alt_price: alt_price.map(|x| x.0),
+                min_participants,
+                fee_recipient: fee_recipient.clone(),
+                last_updated_at: None,
+                started_emitted: false,
+                seller_note: seller_note.clone(),
+                min_offer_price: None,
+                listing_nonce,
+                free_listing,
+                storage_extra: 0,
+                escrow_model,
+                buyout_price: buyout_price.map(|x| x.0),
+                start_price: start_price.map(|x| x.0),
+                reserve_price: reserve_price.map(|x| x.0),
+            },
+        );
+
+        if let (Some(true), Some(ended_at)) = (is_auction, ended_at) {
+            self.auction_ends
+                .insert(&(ended_at.0, contract_and_token_id.clone()), &());
+        }
+        self.market_index_positions
+            .insert(&contract_and_token_id, &self.market_index.len());
+        self.market_index.push(&contract_and_token_id);
+
+        let mut token_ids = self.internal_get_by_owner_id(&owner_id);
+
+        token_ids.insert(&contract_and_token_id);
+
+        self.by_owner_id.insert(&owner_id, &token_ids);
+        self.internal_charge_listing_storage(
+            &owner_id,
+            &contract_and_token_id,
+            initial_storage_usage,
+        );
+
+        self.log_event(
+            event,
+            json!({
+                "owner_id": owner_id,
+                "approval_id": approval_id,
+                "nft_contract_id": nft_contract_id,
+                "token_id": token_id,
+                "ft_token_id": ft_token_id,
+                "price": price,
+                "started_at": started_at,
+                "ended_at": ended_at,
+                "is_auction": is_auction,
+                "alt_ft_token_id": alt_ft_token_id,
+                "alt_price": alt_price,
+                "min_participants": min_participants,
+                "fee_recipient": fee_recipient,
+                "seller_note": seller_note,
+                "listing_nonce": U64(listing_nonce),
+                "free_listing": free_listing,
+                "escrow_model": escrow_model,
+                "buyout_price": buyout_price,
+                "start_price": start_price,
+                "reserve_price": reserve_price,
+            }),
+        );
+    }
+
+    /// Checks shared by update_market_data, change_listing_currency and
+    /// relist before a listing's price or currency changes: no bids may be
+    /// escrowed on it, and moving to `ft_token_id` must not leave offers on
+    /// the token in a currency the listing no longer uses.
+    fn assert_listing_mutable(&self, market_data: &MarketData, ft_token_id: &AccountId) {
+        require(
+            market_data.bids.as_ref().is_none_or(|bids| bids.is_empty()),
+            ContractError::AuctionAlreadyStarted,
+        );
+        if *ft_token_id == market_data.ft_token_id {
+            return;
+        }
+        let offers_in_other_currency = self
+            .by_nft_contract_offers
+            .get(&market_data.nft_contract_id)
+            .is_some_and(|offer_keys| {
+                offer_keys
+                    .iter()
+                    .filter_map(|key| self.offers.get(&key))
+                    .any(|offer_data| {
+                        offer_data.token_id == market_data.token_id
+                            && offer_data.ft_token_id != *ft_token_id
+                    })
+            });
+        require(
+            !offers_in_other_currency,
+            ContractError::OffersInOtherCurrency,
+        );
+    }
+
+    /// Moves a listing's window or turns it into an auction and back with the
+    /// checks internal_add_market_data makes on a new listing. Only listings
+    /// without bids get here, assert_listing_mutable rejects the rest.
+    fn internal_reschedule(
+        &self,
+        market_data: &mut MarketData,
+        started_at: Option<U64>,
+        ended_at: Option<U64>,
+        is_auction: Option<bool>,
+    ) {
+        assert_valid_time_window(started_at, ended_at);
+        self.assert_within_schedule_horizon(started_at);
+
+        let auction = is_auction.unwrap_or(market_data.is_auction == Some(true));
+        let ended_at = ended_at.map(|x| x.0).or(market_data.ended_at);
+        let started_at = match (started_at, auction) {
+            (Some(started_at), _) => {
+                // the new start gets its own auction_started
+                market_data.started_emitted = false;
+                Some(started_at.0)
+            }
+            // an auction without started_at starts now, as in internal_add_market_data
+            (None, true) => market_data.started_at.or(Some(env::block_timestamp())),
+            (None, false) => market_data.started_at,
+        };
+        if let (Some(started_at), Some(ended_at)) = (started_at, ended_at) {
+            require(started_at < ended_at, ContractError::InvalidTimeRange);
+        }
+
+        if auction {
+            self.assert_auction_duration(started_at.map(U64), ended_at.map(U64));
+            require(
+                market_data.ft_token_id.as_str() == NEAR,
+                ContractError::AuctionNearOnly,
+            );
+            require(
+                market_data.alt_ft_token_id.is_none(),
+                ContractError::InvalidAltCurrency,
+            );
+            require(
+                market_data.start_price.is_none(),
+                ContractError::InvalidDutchAuction,
+            );
+        } else {
+            require(
+                market_data.min_participants.is_none(),
+                ContractError::InvalidMarketArgs,
+            );
+            require(
+                market_data.buyout_price.is_none(),
+                ContractError::InvalidBuyoutPrice,
+            );
+            require(
+                market_data.reserve_price.is_none(),
+                ContractError::InvalidReservePrice,
+            );
+        }
+
+        market_data.started_at = started_at;
+        market_data.ended_at = ended_at;
+        if is_auction.is_some() {
+            market_data.is_auction = Some(auction);
+            market_data.bids = if auction { Some(Vec::new()) } else { None };
+        }
+    }
+
+    /// Writes back an existing listing. Every in-place update goes through
+    /// here so `is_auction` can't flip once bids are escrowed.
+    fn internal_update_market_data(
+        &mut self,
+        contract_and_token_id: &ContractAndTokenId,
+        market_data: &MarketData,
+    ) {
+        if let Some(current) = self.market.get(contract_and_token_id) {
+            assert_auction_transition(&current, market_data);
+            if let Some(end_key) = auction_end_key(&current, contract_and_token_id) {
+                self.auction_ends.remove(&end_key);
+            }
+        }
+        if let Some(end_key) = auction_end_key(market_data, contract_and_token_id) {
+            self.auction_ends.insert(&end_key, &());
+        }
+        // a V0 listing saved before migrate_listings reached it
+        if self.market.insert(contract_and_token_id, market_data) {
+            self.internal_index_listing(contract_and_token_id, market_data);
+        }
+    }
+
+    fn internal_delete_market_data(
+        &mut self,
+        nft_contract_id: &AccountId,
+        token_id: &TokenId,
+    ) -> Option<MarketData> {
+        let contract_and_token_id = format!("{}{}{}", &nft_contract_id, DELIMETER, token_id);
+        let market_data: Option<MarketData> =
+            if let Some(market_data) = self.market.get(&contract_and_token_id) {
+                self.market.remove(&contract_and_token_id);
+                // an accept_bid waiting on nft_is_approved gives up on this listing
+                self.settlements_in_flight.remove(&contract_and_token_id);
+
+                if let Some(ref bids) = market_data.bids {
+                    self.internal_refund_bids(&market_data, bids);
+                };
+
+                Some(market_data)
+            } else {
+                None
+            };
+
+        if let Some(market_data) = &market_data {
+            if let Some(end_key) = auction_end_key(market_data, &contract_and_token_id) {
+                self.auction_ends.remove(&end_key);
+            }
+            self.market_index_positions.remove(&contract_and_token_id);
+            self.internal_remove_by_owner_id(&market_data.owner_id, &contract_and_token_id);
+            if let Some(mut listing_keys) = self.by_nft_contract.get(nft_contract_id) {
+                listing_keys.remove(&contract_and_token_id);
+                if listing_keys.is_empty() {
+                    self.by_nft_contract.remove(nft_contract_id);
+                } else {
+                    self.by_nft_contract.insert(nft_contract_id, &listing_keys);
+                }
+            }
+            if market_data.storage_extra > 0 {
+                let storage_extra = self.storage_extra.get(&market_data.owner_id).unwrap_or(0);
+                match storage_extra.saturating_sub(market_data.storage_extra) {
+                    0 => self.storage_extra.remove(&market_data.owner_id),
+                    remaining => self.storage_extra.insert(&market_data.owner_id, &remaining),
+                };
+            }
+        }
+        market_data
+    }
+
+    // by_owner_id also holds listing keys, keep only the ones that are offers
+    fn internal_offer_count(&self, buyer_id: &AccountId) -> u64 {
+        self.by_owner_id.get(buyer_id).map_or(0, |keys| {
+            keys.iter()
+                .filter(|key| self.offers.get(key).is_some())
+                .count() as u64
+        })
+    }
+
+    fn internal_get_by_owner_id(&self, account_id: &AccountId) -> UnorderedSet<String> {
+        self.by_owner_id.get(account_id).unwrap_or_else(|| {
+            UnorderedSet::new(
+                StorageKey::ByOwnerIdInner {
+                    account_id_hash: hash_account_id(account_id),
+                }
+                .try_to_vec()
+                .unwrap(),
+            )
+        })
+    }
+
+    fn internal_owner_index_contains(&self, account_id: &AccountId, key: &String) -> bool {
+        self.by_owner_id
+            .get(account_id)
+            .is_some_and(|token_ids| token_ids.contains(key))
+    }
+
+    fn internal_remove_by_owner_id(&mut self, account_id: &AccountId, key: &String) {
+        if let Some(mut by_owner_id) = self.by_owner_id.get(account_id) {
+            by_owner_id.remove(key);
+            if by_owner_id.is_empty() {
+                self.by_owner_id.remove(account_id);
+            } else {
+                self.by_owner_id.insert(account_id, &by_owner_id);
+            }
+        }
+    }
+
+    #[payable]
+    pub fn delete_market_data(&mut self, nft_contract_id: AccountId, token_id: TokenId) {
+        assert_one_yocto();
+        self.internal_delete_market_data_as_caller(nft_contract_id, token_id);
+    }
+
+    /// delete_market_data for up to 50 `(nft_contract_id, token_id)` pairs,
+    /// with the same seller-or-owner check for each. One failing item
+    /// reverts the whole batch.
+    #[payable]
+    pub fn delete_market_data_batch(&mut self, items: Vec<(AccountId, TokenId)>) {
+        assert_one_yocto();
+        require(
+            items.len() <= MAX_DELETE_BATCH_LEN,
+            ContractError::BatchTooLarge {
+                max: MAX_DELETE_BATCH_LEN as u64,
+            },
+        );
+        for (nft_contract_id, token_id) in items {
+            self.internal_delete_market_data_as_caller(nft_contract_id, token_id);
+        }
+    }
+
+    fn internal_delete_market_data_as_caller(
+        &mut self,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+    ) {
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+
+        let market_data: MarketData = self
+            .market
+            .get(&contract_and_token_id)
+            .unwrap_or_else(|| ContractError::ListingNotFound.panic());
+
+        require(
+            [market_data.owner_id.clone(), self.owner_id.clone()]
+                .contains(&env::predecessor_account_id()),
+            ContractError::NotSellerOrOwner,
+        );
+
+        self.internal_delete_market_data(&nft_contract_id, &token_id);
+
+        self.log_event(
+            "delete_market_data",
+            json!({
+                "owner_id": market_data.owner_id,
+                "nft_contract_id": nft_contract_id,
+                "token_id": token_id,
+                "listing_nonce": U64(market_data.listing_nonce),
+                "ft_token_id": market_data.ft_token_id,
+            }),
+        );
+    }
+
+    /// Asks `nft_contract_id` for each listed token in `token_ids` with
+    /// nft_token and returns the ones whose listing seller no longer owns
+    /// them, burned tokens included. Unlisted ids are skipped without a
+    /// call. Attach about 10 Tgas per listed token plus 5 Tgas for the
+    /// callback, so a 20 token batch needs roughly 205 Tgas.
+    pub fn find_stale_listings(
+        &self,
+        nft_contract_id: AccountId,
+        token_ids: Vec<TokenId>,
+    ) -> PromiseOrValue<Vec<TokenId>> {
+        let listed: Vec<TokenId> = token_ids
+            .into_iter()
+            .filter(|token_id| {
+                let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+                self.market.get(&contract_and_token_id).is_some()
+            })
+            .collect();
+        if listed.is_empty() {
+            return PromiseOrValue::Value(vec![]);
+        }
+
+        listed
+            .iter()
+            .map(|token_id| {
+                ext_contract::ext(nft_contract_id.clone())
+                    .with_static_gas(GAS_FOR_NFT_TOKEN)
+                    .nft_token(token_id.clone())
+            })
+            .reduce(|calls, call| calls.and(call))
+            .unwrap()
+            .then(
+                ext_self::ext(env::current_account_id())
+                    .with_attached_deposit(NO_DEPOSIT)
+                    .with_static_gas(BASE_GAS)
+                    .resolve_find_stale_listings(nft_contract_id, listed),
+            )
+            .into()
+    }
+
+    /// Results arrive in the order the nft_token calls were joined. A failed
+    /// call or a listing removed in the meantime isn't reported as stale.
+    #[private]
+    pub fn resolve_find_stale_listings(
+        &self,
+        nft_contract_id: AccountId,
+        token_ids: Vec<TokenId>,
+    ) -> Vec<TokenId> {
+        token_ids
+            .into_iter()
+            .enumerate()
+            .filter(|(i, token_id)| {
+                let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+                let market_data = match self.market.get(&contract_and_token_id) {
+                    Some(market_data) => market_data,
+                    None => return false,
+                };
+                match env::promise_result(*i as u64) {
+                    PromiseResult::Successful(value) => {
+                        let token: Result<Option<NftTokenOwner>, _> =
+                            near_sdk::serde_json::from_slice(&value);
+                        token.is_ok_and(|token| {
+                            token.is_none_or(|token| token.owner_id != market_data.owner_id)
+                        })
+                    }
+                    _ => false,
+                }
+            })
+            .map(|(_, token_id)| token_id)
+            .collect()
+    }
+
+    // Storage
+
+    /// A deposit needs at least min_storage_deposit. With `registration_only`
+    /// one listing's worth is enough and anything above what tops the
+    /// account up to it is refunded.
+    #[payable]
+    pub fn storage_deposit(
+        &mut self,
+        account_id: Option<AccountId>,
+        registration_only: Option<bool>,
+    ) {
+        let storage_account_id = account_id.unwrap_or_else(env::predecessor_account_id);
+        let deposit = env::attached_deposit();
+        let registration_only = registration_only == Some(true);
+        let minimum = if registration_only {
+            STORAGE_ADD_MARKET_DATA
+        } else {
+            self.min_storage_deposit
+        };
+        require(
+            deposit >= minimum,
+            ContractError::DepositBelowMinimum { minimum },
+        );
+
+        let balance: u128 = self.storage_deposits.get(&storage_account_id).unwrap_or(0);
+        let credited = if registration_only {
+            STORAGE_ADD_MARKET_DATA.saturating_sub(balance)
+        } else {
+            deposit
+        };
+        self.storage_deposits
+            .insert(&storage_account_id, &balance.saturating_add(credited));
+
+        if deposit > credited {
+            Promise::new(env::predecessor_account_id()).transfer(deposit - credited);
+        }
+    }
 
+    /// Registers several accounts in one call. The deposit is split evenly
+    /// unless `amounts` gives each account's share; whatever is left over
+    /// goes back to the caller.
     #[payable]
-    pub fn add_offer(
+    pub fn storage_deposit_batch(
         &mut self,
-        nft_contract_id: AccountId,
-        token_id: TokenId,
-        ft_token_id: AccountId,
-        price: U128,
+        account_ids: Vec<AccountId>,
+        amounts: Option<Vec<U128>>,
     ) {
+        let deposit = env::attached_deposit();
+        require(!account_ids.is_empty(), ContractError::InvalidStorageBatch);
 
-        assert!(
-            self.approved_nft_contract_ids.contains(&nft_contract_id),
-            "Error: offer series for Astro NFT only"
-        );
-
-        assert_eq!(
-            env::attached_deposit(),
-            price.0,
-            "Error: Attached deposit != price"
-        );
-
-        assert_eq!(
-            ft_token_id.to_string(),
-            "near",
-            "Error: Only NEAR is supported"
-        );
-
-        let buyer_id = env::predecessor_account_id();
-        let offer_data = self.internal_delete_offer(
-            nft_contract_id.clone().into(),
-            buyer_id.clone(),
-            token_id.clone(),
-        );
+        let amounts: Vec<Balance> = match amounts {
+            Some(amounts) => {
+                require(
+                    amounts.len() == account_ids.len(),
+                    ContractError::InvalidStorageBatch,
+                );
+                amounts.into_iter().map(|amount| amount.0).collect()
+            }
+            None => vec![deposit / account_ids.len() as u128; account_ids.len()],
+        };
 
-        if offer_data.is_some() {
-            Promise::new(buyer_id.clone()).transfer(offer_data.unwrap().price);
+        let total = amounts
+            .iter()
+            .try_fold(0u128, |total, amount| total.checked_add(*amount))
+            .unwrap_or_else(|| ContractError::DepositLessThanAmount.panic());
+        require(total <= deposit, ContractError::DepositLessThanAmount);
+
+        for (account_id, amount) in account_ids.iter().zip(amounts) {
+            require(
+                amount >= STORAGE_ADD_MARKET_DATA,
+                ContractError::DepositBelowMinimum {
+                    minimum: STORAGE_ADD_MARKET_DATA,
+                },
+            );
+            let balance = self.storage_deposits.get(account_id).unwrap_or(0);
+            self.storage_deposits
+                .insert(account_id, &balance.saturating_add(amount));
         }
 
-        let storage_amount = self.storage_minimum_balance().0;
-        let owner_paid_storage = self.storage_deposits.get(&buyer_id).unwrap_or(0);
-        let signer_storage_required =
-            (self.get_supply_by_owner_id(buyer_id.clone()).0 + 1) as u128 * storage_amount;
+        if deposit > total {
+            Promise::new(env::predecessor_account_id()).transfer(deposit - total);
+        }
+    }
 
-        assert!(
-            owner_paid_storage >= signer_storage_required,
-            "Insufficient storage paid: {}, for {} offer at {} rate of per offer",
-            owner_paid_storage,
-            signer_storage_required / storage_amount,
-            storage_amount,
-        );
+    /// withdraws `amount`, by default everything not backing current
+    /// listings/offers, returns the amount sent
+    #[payable]
+    pub fn storage_withdraw(&mut self, amount: Option<U128>) -> U128 {
+        assert_one_yocto();
+        let owner_id = env::predecessor_account_id();
+        let balance = self.storage_deposits.get(&owner_id).unwrap_or(0);
+        let market_data_owner = self.by_owner_id.get(&owner_id);
+        let len = market_data_owner.map(|s| s.len()).unwrap_or_default();
+        let diff = u128::from(len)
+            .saturating_mul(STORAGE_ADD_MARKET_DATA)
+            .saturating_add(self.storage_extra.get(&owner_id).unwrap_or(0));
+        // drifted accounting leaves nothing free rather than underflowing
+        let available = balance.saturating_sub(diff);
+        let amount = match amount {
+            Some(amount) => {
+                require(
+                    amount.0 <= available,
+                    ContractError::WithdrawMoreThanFree { available },
+                );
+                amount.0
+            }
+            None => available,
+        };
+        let remaining = balance - amount;
+        self.storage_deposits.remove(&owner_id);
+        if amount > 0 {
+            Promise::new(owner_id.clone()).transfer(amount);
+        }
+        if remaining > 0 {
+            self.storage_deposits.insert(&owner_id, &remaining);
+        }
 
-        self.internal_add_offer(
-            nft_contract_id.clone().into(),
-            token_id.clone(),
-            ft_token_id.clone(),
-            price,
-            buyer_id.clone(),
+        self.log_event(
+            "storage_withdraw",
+            json!({
+                "account_id": owner_id,
+                "amount": U128(amount),
+                "remaining": U128(remaining),
+            }),
         );
 
-        env::log_str(
-            &json!({
-                "event": "add_offer",
-                "params": {
-                    "buyer_id": buyer_id,
-                    "nft_contract_id": nft_contract_id,
-                    "token_id": token_id,
-                    "ft_token_id": ft_token_id,
-                    "price": price,
-                }
-            })
-            .to_string(),
-        );
+        amount.into()
     }
 
-    fn internal_add_offer(
-        &mut self,
-        nft_contract_id: AccountId,
-        token_id: TokenId,
-        ft_token_id: AccountId,
-        price: U128,
-        buyer_id: AccountId,
-    ) {
+    pub fn storage_minimum_balance(&self) -> U128 {
+        U128(STORAGE_ADD_MARKET_DATA)
+    }
 
-        let contract_account_id_token_id = make_triple(&nft_contract_id, &buyer_id, &token_id);
-        self.offers.insert(
-            &contract_account_id_token_id,
-            &OfferData {
-                buyer_id: buyer_id.clone().into(),
-                nft_contract_id: nft_contract_id.into(),
-                token_id: token_id.clone(),
-                ft_token_id: ft_token_id.into(),
-                price: price.into(),
-            },
-        );
+    /// `min` is what storage_deposit asks for without registration_only.
+    pub fn storage_balance_bounds(&self) -> StorageBalanceBounds {
+        StorageBalanceBounds {
+            min: U128(self.min_storage_deposit),
+            max: None,
+        }
+    }
 
-        let mut token_ids = self.by_owner_id.get(&buyer_id).unwrap_or_else(|| {
-            UnorderedSet::new(
-                StorageKey::ByOwnerIdInner {
-                    account_id_hash: hash_account_id(&buyer_id),
-                }
-                .try_to_vec()
-                .unwrap(),
-            )
-        });
-        token_ids.insert(&contract_account_id_token_id);
-        self.by_owner_id.insert(&buyer_id, &token_ids);
+    pub fn storage_balance_of(&self, account_id: AccountId) -> U128 {
+        self.storage_deposits.get(&account_id).unwrap_or(0).into()
     }
 
-    fn internal_delete_offer(
-        &mut self,
+    /// storage a listing was charged, see internal_charge_listing_storage
+    pub fn get_listing_storage_cost(
+        &self,
         nft_contract_id: AccountId,
-        buyer_id: AccountId,
         token_id: TokenId,
-    ) -> Option<OfferData> {
-        let contract_account_id_token_id = make_triple(&nft_contract_id, &buyer_id, &token_id);
-        let offer_data = self.offers.remove(&contract_account_id_token_id);
+    ) -> Option<U128> {
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+        self.market
+            .get(&contract_and_token_id)
+            .map(|market_data| U128(STORAGE_ADD_MARKET_DATA + market_data.storage_extra))
+    }
 
-        match offer_data {
-            Some(offer) => {
-                let by_owner_id = self
-                    .by_owner_id
-                    .get(&offer.buyer_id);
-                if let Some(mut by_owner_id) = by_owner_id {
-                    by_owner_id.remove(&contract_account_id_token_id);
-                    if by_owner_id.is_empty() {
-                        self.by_owner_id.remove(&offer.buyer_id);
-                    } else {
-                        self.by_owner_id.insert(&offer.buyer_id, &by_owner_id);
-                    }
-                }
-                return Some(offer);
-            }
-            None => return None,
-        };
+    /// extra deposit needed before `account_id` can hold `additional` more listings/offers/bids
+    pub fn storage_cost_for(&self, account_id: AccountId, additional: u64) -> U128 {
+        let paid_storage = self.storage_deposits.get(&account_id).unwrap_or(0);
+        self.internal_storage_required(&account_id, additional)
+            .saturating_sub(paid_storage)
+            .into()
     }
 
+    // Refunds
+
+    /// Bid refunds that bounced, e.g. because the bidder's account had been
+    /// deleted, are kept until claimed. `to` defaults to the caller.
     #[payable]
-    pub fn delete_offer(
-        &mut self,
-        nft_contract_id: AccountId,
-        token_id: TokenId,
-    ) {
+    pub fn claim_failed_refund(&mut self, to: Option<AccountId>) -> U128 {
         assert_one_yocto();
+        let account_id = env::predecessor_account_id();
+        // cleared before the transfer is scheduled
+        let amount = self
+            .failed_refunds
+            .remove(&account_id)
+            .unwrap_or_else(|| ContractError::NoFailedRefund.panic());
+        let receiver_id = to.unwrap_or_else(|| account_id.clone());
+
+        // a second failure is owed to the claimer again
+        Promise::new(receiver_id.clone()).transfer(amount).then(
+            ext_self::ext(env::current_account_id())
+                .with_attached_deposit(NO_DEPOSIT)
+                .with_static_gas(BASE_GAS)
+                .resolve_refund(account_id.clone(), U128(amount), None),
+        );
 
-        let buyer_id = env::predecessor_account_id();
-        let contract_account_id_token_id = make_triple(&nft_contract_id, &buyer_id, &token_id);
-
-        let offer_data = self
-            .offers
-            .get(&contract_account_id_token_id)
-            .expect("Error: Offer does not exist");
+        self.log_event(
+            "claim_failed_refund",
+            json!({
+                "account_id": account_id,
+                "receiver_id": receiver_id,
+                "amount": U128(amount),
+            }),
+        );
+        amount.into()
+    }
 
-        assert_eq!(offer_data.token_id.clone(), token_id);
+    /// FT refunds that bounced, e.g. because the account isn't registered
+    /// with the token, claimed once the account has registered.
+    #[payable]
+    pub fn claim_failed_ft_refund(
+        &mut self,
+        ft_token_id: AccountId,
+        to: Option<AccountId>,
+    ) -> U128 {
+        assert_one_yocto();
+        let account_id = env::predecessor_account_id();
+        let key = format!("{}{}{}", account_id, DELIMETER, ft_token_id);
+        let amount = self
+            .failed_ft_refunds
+            .remove(&key)
+            .unwrap_or_else(|| ContractError::NoFailedRefund.panic());
+        let receiver_id = to.unwrap_or_else(|| account_id.clone());
+
+        self.internal_transfer(&ft_token_id, &receiver_id, amount)
+            .then(
+                ext_self::ext(env::current_account_id())
+                    .with_attached_deposit(NO_DEPOSIT)
+                    .with_static_gas(BASE_GAS)
+                    .resolve_refund(account_id.clone(), U128(amount), Some(ft_token_id.clone())),
+            );
 
-        assert_eq!(
-            offer_data.buyer_id, buyer_id,
-            "Error: Caller not offer's buyer"
+        self.log_event(
+            "claim_failed_refund",
+            json!({
+                "account_id": account_id,
+                "receiver_id": receiver_id,
+                "ft_token_id": ft_token_id,
+                "amount": U128(amount),
+            }),
         );
+        amount.into()
+    }
 
-        self.internal_delete_offer(
-            nft_contract_id.clone().into(),
-            buyer_id.clone(),
-            token_id.clone(),
-        )
-        .expect("Error: Offer not found");
-
-        Promise::new(offer_data.buyer_id).transfer(offer_data.price);
+    /// `ft_token_id` is None for NEAR refunds
+    #[private]
+    pub fn resolve_refund(
+        &mut self,
+        account_id: AccountId,
+        amount: U128,
+        ft_token_id: Option<AccountId>,
+    ) -> bool {
+        if is_promise_success() {
+            return true;
+        }
+        match &ft_token_id {
+            Some(ft_token_id) if ft_token_id.as_str() != NEAR => {
+                let key = format!("{}{}{}", account_id, DELIMETER, ft_token_id);
+                let owed = self.failed_ft_refunds.get(&key).unwrap_or(0);
+                self.failed_ft_refunds
+                    .insert(&key, &owed.saturating_add(amount.0));
+            }
+            _ => {
+                let owed = self.failed_refunds.get(&account_id).unwrap_or(0);
+                self.failed_refunds
+                    .insert(&account_id, &owed.saturating_add(amount.0));
+            }
+        }
 
-        env::log_str(
-            &json!({
-                "event": "delete_offer",
-                "params": {
-                    "nft_contract_id": nft_contract_id,
-                    "buyer_id": buyer_id,
-                    "token_id": token_id,
-                }
-            })
-            .to_string(),
+        self.log_event(
+            "refund_failed",
+            json!({
+                "account_id": account_id,
+                "ft_token_id": ft_token_id.unwrap_or_else(near_account),
+                "amount": amount,
+            }),
         );
+        false
     }
 
-    pub fn get_offer(
-        &self,
+    pub fn get_failed_refund(&self, account_id: AccountId) -> U128 {
+        self.failed_refunds.get(&account_id).unwrap_or(0).into()
+    }
+
+    pub fn get_failed_ft_refund(&self, account_id: AccountId, ft_token_id: AccountId) -> U128 {
+        let key = format!("{}{}{}", account_id, DELIMETER, ft_token_id);
+        self.failed_ft_refunds.get(&key).unwrap_or(0).into()
+    }
+
+    /// Last resort for an offer whose buyer is gone. Only once the offer is
+    /// `offer_dormancy` seconds old (a year by default, see set_config): the
+    /// escrow is refunded to the buyer first, and only if that transfer
+    /// fails is it sent to `recovery_id` and recorded under the buyer.
+    #[payable]
+    pub fn escheat_offer(
+        &mut self,
         nft_contract_id: AccountId,
         buyer_id: AccountId,
         token_id: TokenId,
-    ) -> OfferDataJson {
-
+    ) -> Promise {
+        assert_one_yocto();
+        self.assert_owner();
         let contract_account_id_token_id = make_triple(&nft_contract_id, &buyer_id, &token_id);
-
         let offer_data = self
             .offers
             .get(&contract_account_id_token_id)
-            .expect("Error: Offer does not exist");
-
-        assert_eq!(offer_data.token_id.clone(), token_id);
+            .unwrap_or_else(|| ContractError::OfferNotFound.panic());
+        require(
+            env::block_timestamp()
+                >= offer_data
+                    .created_at
+                    .saturating_add(self.offer_dormancy.saturating_mul(10u64.pow(9))),
+            ContractError::OfferNotDormant {
+                dormancy_sec: self.offer_dormancy,
+            },
+        );
 
-        OfferDataJson {
-            buyer_id: offer_data.buyer_id,
-            nft_contract_id: offer_data.nft_contract_id,
-            token_id: offer_data.token_id,
-            ft_token_id: offer_data.ft_token_id,
-            price: U128(offer_data.price),
-        }
+        self.internal_delete_offer(nft_contract_id.clone(), buyer_id.clone(), token_id.clone());
+
+        Promise::new(buyer_id.clone())
+            .transfer(offer_data.price)
+            .then(
+                ext_self::ext(env::current_account_id())
+                    .with_attached_deposit(NO_DEPOSIT)
+                    .with_static_gas(BASE_GAS)
+                    .resolve_escheat_offer(
+                        nft_contract_id,
+                        buyer_id,
+                        token_id,
+                        U128(offer_data.price),
+                    ),
+            )
     }
 
-    fn internal_accept_offer(
+    /// true if the buyer got the refund, false if it went to recovery
+    #[private]
+    pub fn resolve_escheat_offer(
         &mut self,
         nft_contract_id: AccountId,
         buyer_id: AccountId,
         token_id: TokenId,
-        seller_id: AccountId,
-        approval_id: u64,
-        price: u128,
-    ) -> Promise {
-        let contract_account_id_token_id = make_triple(&nft_contract_id, &buyer_id, &token_id);
-
-        self.internal_delete_market_data(&nft_contract_id, &token_id);
+        amount: U128,
+    ) -> bool {
+        let refunded = is_promise_success();
+        let recovery_id = self.internal_recovery_id();
+        if !refunded {
+            Promise::new(recovery_id.clone()).transfer(amount.0);
+            let escheated = self.escheated.get(&buyer_id).unwrap_or(0);
+            self.escheated
+                .insert(&buyer_id, &escheated.saturating_add(amount.0));
+        }
 
-        let offer_data = self
-            .offers
-            .get(&contract_account_id_token_id)
-            .expect("Error: Offer does not exist");
+        self.log_event(
+            "escheat_offer",
+            json!({
+                "nft_contract_id": nft_contract_id,
+                "buyer_id": buyer_id,
+                "token_id": token_id,
+                "ft_token_id": NEAR,
+                "amount": amount,
+                "refunded": refunded,
+                "recovery_id": if refunded { None } else { Some(recovery_id) },
+            }),
+        );
+        refunded
+    }
 
-        assert_eq!(offer_data.token_id.clone(), token_id);
-        assert_eq!(offer_data.price, price);
+    /// total sent to recovery from `account_id`'s abandoned offers
+    pub fn get_escheated(&self, account_id: AccountId) -> U128 {
+        self.escheated.get(&account_id).unwrap_or(0).into()
+    }
 
-        let offer_data = self
-            .internal_delete_offer(
-                nft_contract_id.clone().into(),
-                buyer_id.clone(),
-                token_id.clone(),
-            )
-            .expect("Error: Offer does not exist");
+    /// Gas reserved per bid refund for the callback that records a failed
+    /// refund. Every refund in a loop reserves it, so settling an auction
+    /// with many bids needs more gas. 0 sends plain transfers without the
+    /// callback.
+    #[payable]
+    pub fn set_refund_gas(&mut self, refund_gas: U64) {
+        assert_one_yocto();
+        self.assert_owner();
+        self.refund_gas = refund_gas.0;
+    }
 
-        ext_contract::nft_transfer_payout(
-            offer_data.buyer_id.clone(),
-            token_id.clone(),
-            Some(approval_id),
-            Some(U128::from(offer_data.price)),
-            Some(10u32), // max length payout
-            nft_contract_id,
-            1,
-            GAS_FOR_NFT_TRANSFER,
-        )
-        .then(ext_self::resolve_offer(
-            seller_id,
-            offer_data,
-            token_id,
-            env::current_account_id(),
-            NO_DEPOSIT,
-            GAS_FOR_ROYALTIES,
-        ))
+    pub fn get_refund_gas(&self) -> U64 {
+        self.refund_gas.into()
     }
 
-    #[private]
-    pub fn resolve_offer(
-        &mut self,
-        seller_id: AccountId,
-        offer_data: OfferData,
-        token_id: TokenId,
-    ) -> U128 {
-        let payout_option = promise_result_as_success().and_then(|value| {
-            // None means a bad payout from bad NFT contract
-            let parsed_payout = near_sdk::serde_json::from_slice::<PayoutHashMap>(&value);
-            if parsed_payout.is_err() {
-                near_sdk::serde_json::from_slice::<Payout>(&value)
-                    .ok()
-                    .and_then(|payout| {
-                        let mut remainder = offer_data.price;
-                        for &value in payout.payout.values() {
-                            remainder = remainder.checked_sub(value.0)?;
-                        }
-                        if remainder <= 100 {
-                            Some(payout.payout)
-                        } else {
-                            None
-                        }
-                    })
-            } else {
-                parsed_payout.ok().and_then(|payout| {
-                    let mut remainder = offer_data.price;
-                    for &value in payout.values() {
-                        remainder = remainder.checked_sub(value.0)?;
-                    }
-                    if remainder <= 100 {
-                        Some(payout)
-                    } else {
-                        None
-                    }
-                })
-            }
-        });
+    // Maintenance
 
-        let payout = if let Some(payout_option) = payout_option {
-            payout_option
-        } else {
-            if !is_promise_success() {
-                if offer_data.ft_token_id == near_account() {
-                    Promise::new(offer_data.buyer_id.clone()).transfer(u128::from(offer_data.price));
-                }
-                // leave function and return all FTs in ft_resolve_transfer
-                env::log_str(
-                    &json!({
-                        "event": "resolve_purchase_fail",
-                        "params": {
-                            "owner_id": seller_id,
-                            "nft_contract_id": offer_data.nft_contract_id,
-                            "token_id": token_id,
-                            "ft_token_id": offer_data.ft_token_id,
-                            "price": offer_data.price.to_string(),
-                            "buyer_id": offer_data.buyer_id,
-                            "is_offer": true,
-                        }
-                    })
-                    .to_string(),
-                );
-            } else if offer_data.ft_token_id == near_account() {
-                let treasury_fee =
-                    offer_data.price as u128 * self.transaction_fee as u128 / 10_000u128;
-					Promise::new(seller_id.clone()).transfer(offer_data.price - treasury_fee);
-                if treasury_fee > 0 {
-                    Promise::new(self.treasury_id.clone()).transfer(treasury_fee);
-                }
+    /// Rebuilds `by_owner_id` for an account from the listings and offers
+    /// that actually exist, dropping orphaned keys. Scans both maps.
+    #[payable]
+    pub fn reconcile_owner(&mut self, account_id: AccountId) -> U64 {
+        assert_one_yocto();
+        self.assert_owner();
 
-                env::log_str(
-                    &json!({
-                        "event": "resolve_purchase",
-                        "params": {
-                            "owner_id": seller_id,
-                            "nft_contract_id": &offer_data.nft_contract_id,
-                            "token_id": &token_id,
-                            "ft_token_id": offer_data.ft_token_id,
-                            "price": offer_data.price.to_string(),
-                            "buyer_id": offer_data.buyer_id,
-                            "is_offer": true,
-                        }
-                    })
-                    .to_string(),
-                );
-            }
-            
-            return offer_data.price.into();
-        };
+        let mut token_ids = self.internal_get_by_owner_id(&account_id);
+        let previous_len = token_ids.len();
+        token_ids.clear();
 
-        // Payout (transfer to royalties and seller)
-        if offer_data.ft_token_id == near_account() {
-            // 5% fee for treasury
-            let treasury_fee =
-                offer_data.price as u128 * self.transaction_fee as u128 / 10_000u128;
-
-            for (receiver_id, amount) in payout {
-                if receiver_id == seller_id {
-                    Promise::new(receiver_id).transfer(amount.0 - treasury_fee);
-                    if treasury_fee != 0 {
-                        Promise::new(self.treasury_id.clone()).transfer(treasury_fee);
-                    }
-                } else {
-                    Promise::new(receiver_id).transfer(amount.0);
-                }
+        for (contract_and_token_id, market_data) in self.market.iter() {
+            if market_data.owner_id == account_id {
+                token_ids.insert(&contract_and_token_id);
             }
+        }
+        for (contract_account_id_token_id, offer_data) in self.offers.iter() {
+            if offer_data.buyer_id == account_id {
+                token_ids.insert(&contract_account_id_token_id);
+            }
+        }
 
-            env::log_str(
-                &json!({
-                    "event": "resolve_purchase",
-                    "params": {
-                        "owner_id": seller_id,
-                        "nft_contract_id": &offer_data.nft_contract_id,
-                        "token_id": &token_id,
-                        "ft_token_id": offer_data.ft_token_id,
-                        "price": offer_data.price.to_string(),
-                        "buyer_id": offer_data.buyer_id,
-                        "is_offer": true,
-                    }
-                })
-                .to_string(),
-            );
-
-            return offer_data.price.into();
+        let len = token_ids.len();
+        if token_ids.is_empty() {
+            self.by_owner_id.remove(&account_id);
         } else {
-            U128(0)
+            self.by_owner_id.insert(&account_id, &token_ids);
         }
-    }
 
-    // Auction bids
-    #[payable]
-    pub fn add_bid(
-        &mut self,
-        nft_contract_id: AccountId,
-        ft_token_id: AccountId,
-        token_id: TokenId,
-        amount: U128,
-    ) {
-        let contract_and_token_id = format!("{}{}{}", &nft_contract_id, DELIMETER, token_id);
-        let mut market_data = self
-            .market
-            .get(&contract_and_token_id)
-            .expect("Error: Token id does not exist");
+        self.log_event(
+            "reconcile_owner",
+            json!({
+                "account_id": account_id,
+                "previous_len": previous_len,
+                "len": len,
+            }),
+        );
 
-        let bidder_id = env::predecessor_account_id();
+        len.into()
+    }
 
-        let current_time = env::block_timestamp();
-		if market_data.started_at.is_some() {
-            assert!(
-                current_time >= market_data.started_at.unwrap(),
-                "Error: Sale has not started yet"
-            );
+    /// Drops an account's empty by_owner_id entry and returns its storage
+    /// balance once nothing is left to back. Anyone may prune an account
+    /// without balance, a balance is only refunded at the owner's request.
+    #[payable]
+    pub fn prune_account(&mut self, account_id: AccountId) -> bool {
+        assert_one_yocto();
+        if self.get_supply_by_owner_id(account_id.clone()).0 > 0 {
+            return false;
         }
 
-        if market_data.ended_at.is_some() {
-            assert!(
-                current_time <= market_data.ended_at.unwrap(),
-                "Error: Sale has ended"
-            );
+        let balance = self.storage_deposits.get(&account_id).unwrap_or(0);
+        if balance > 0 {
+            self.assert_owner();
         }
-		
-		assert_ne!(market_data.owner_id, bidder_id, "Error: Owner cannot bid their own token");
-
-        assert!(
-            env::attached_deposit() >= amount.into(),
-            "Error: attached deposit is less than amount"
-        );
 
-        assert_eq!(ft_token_id.to_string(), "near", "Error: Only support NEAR");
-		
-		let storage_amount = self.storage_minimum_balance().0;
-        let owner_paid_storage = self.storage_deposits.get(&bidder_id).unwrap_or(0);
-        let signer_storage_required =
-            (self.get_supply_by_owner_id(bidder_id.clone()).0 + 1) as u128 * storage_amount;
+        let had_index = self.by_owner_id.remove(&account_id).is_some();
+        if balance > 0 {
+            self.storage_deposits.remove(&account_id);
+            Promise::new(account_id.clone()).transfer(balance);
+        }
 
-        assert!(
-            owner_paid_storage >= signer_storage_required,
-            "Insufficient storage paid: {}, for {} bid at {} rate of per bid",
-            owner_paid_storage,
-            signer_storage_required / storage_amount,
-            storage_amount,
+        self.log_event(
+            "prune_account",
+            json!({
+                "account_id": account_id,
+                "refunded": U128(balance),
+            }),
         );
 
-        let new_bid = Bid {
-            bidder_id: bidder_id.clone(),
-            price: amount.into(),
-        };
+        had_index || balance > 0
+    }
 
-        let mut bids = market_data.bids.unwrap_or(Vec::new());
+    /// Scans listings then offers as one index range starting at `from_index`,
+    /// stopping after `limit` entries. Keep `limit` small, each entry is a read.
+    /// `escrow_covered` only speaks for the whole state once `next_index` is None.
+    pub fn health_check(&self, from_index: Option<U64>, limit: Option<u64>) -> HealthReport {
+        let market_len = self.market.len();
+        let total = market_len + self.offers.len();
+        let start = from_index.map_or(0, |x| x.0).min(total);
+        let end = start.saturating_add(limit.unwrap_or(50)).min(total);
+
+        let mut listings_scanned = 0;
+        let mut offers_scanned = 0;
+        let mut bid_escrow: Balance = 0;
+        let mut offer_escrow: Balance = 0;
+        let mut index_mismatches = 0;
+
+        if start < market_len {
+            let take = (end.min(market_len) - start) as usize;
+            for (key, market_data) in self.market.iter().skip(start as usize).take(take) {
+                listings_scanned += 1;
+                if let Some(bids) = &market_data.bids {
+                    bid_escrow += bids.iter().map(|bid| bid.price.0).sum::<Balance>();
+                }
+                if !self.internal_owner_index_contains(&market_data.owner_id, &key) {
+                    index_mismatches += 1;
+                }
+            }
+        }
 
-        if !bids.is_empty() {
-            let current_bid = &bids[bids.len() - 1];
+        if end > market_len {
+            let skip = start.saturating_sub(market_len) as usize;
+            let take = (end - market_len.max(start)) as usize;
+            for (key, offer_data) in self.offers.iter().skip(skip).take(take) {
+                offers_scanned += 1;
+                offer_escrow += offer_data.price;
+                if !self.internal_owner_index_contains(&offer_data.buyer_id, &key) {
+                    index_mismatches += 1;
+                }
+            }
+        }
 
-            assert!(
-                amount.0 > current_bid.price.0,
-                "Error: Can't pay less than or equal to current bid price: {:?}",
-                current_bid.price
-            );
+        let storage_staked = Balance::from(env::storage_usage()) * env::storage_byte_cost();
+        let free_balance = env::account_balance().saturating_sub(storage_staked);
+
+        HealthReport {
+            listings_scanned,
+            offers_scanned,
+            bid_escrow: bid_escrow.into(),
+            offer_escrow: offer_escrow.into(),
+            index_mismatches,
+            free_balance: free_balance.into(),
+            escrow_covered: free_balance >= bid_escrow + offer_escrow,
+            next_index: (end < total).then(|| end.into()),
+        }
+    }
 
-            assert!(
-                amount.0 >= market_data.price,
-                "Error: Can't pay less than starting price: {:?}",
-                U128(market_data.price)
-            );
+    // View
 
-            // Retain all elements except account_id
-            bids.retain(|bid| {
-              if bid.bidder_id == bidder_id {
-                // refund
-                Promise::new(bid.bidder_id.clone()).transfer(bid.price.0);
-              }
+    /// None if the token isn't listed
+    pub fn get_market_data(
+        &self,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+    ) -> Option<MarketDataJson> {
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+        self.market
+            .get(&contract_and_token_id)
+            .map(|market_data| self.internal_market_data_json(market_data))
+    }
 
-              bid.bidder_id != bidder_id
-            });
-        } else {
-            assert!(
-                amount.0 >= market_data.price,
-                "Error: Can't pay less than starting price: {}",
-                market_data.price
-            );
-        }
+    /// get_market_data for up to 100 `(nft_contract_id, token_id)` pairs, in
+    /// order, None for the ones that aren't listed
+    pub fn get_market_datas_batch(
+        &self,
+        keys: Vec<(AccountId, TokenId)>,
+    ) -> Vec<Option<MarketDataJson>> {
+        require(
+            keys.len() as u64 <= MAX_VIEW_LIMIT,
+            ContractError::BatchTooLarge {
+                max: MAX_VIEW_LIMIT,
+            },
+        );
+        keys.into_iter()
+            .map(|(nft_contract_id, token_id)| self.get_market_data(nft_contract_id, token_id))
+            .collect()
+    }
 
-        bids.push(new_bid);
-        market_data.bids = Some(bids);
-        self.market.insert(&contract_and_token_id, &market_data);
+    /// Debugging aid: the stored MarketData as is, amounts as plain numbers
+    /// rather than strings. Frontends should use get_market_data.
+    pub fn get_market_data_raw(
+        &self,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+    ) -> Option<MarketData> {
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+        self.market.get(&contract_and_token_id)
+    }
 
-        env::log_str(
-            &json!({
-                "event": "add_bid",
-                "params": {
-                    "bidder_id": bidder_id,
-                    "nft_contract_id": nft_contract_id,
-                    "token_id": token_id,
-                    "ft_token_id": ft_token_id,
-                    "amount": amount,
-                }
+    /// total escrowed in this auction's bids, 0 for sales or missing listings
+    pub fn get_auction_escrow(&self, nft_contract_id: AccountId, token_id: TokenId) -> U128 {
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+        self.market
+            .get(&contract_and_token_id)
+            .and_then(|market_data| market_data.bids)
+            .map_or(0, |bids| {
+                bids.iter().map(|bid| bid.price.0).sum::<Balance>()
             })
-            .to_string(),
-        );
+            .into()
     }
 
-    #[payable]
-    pub fn accept_bid(&mut self, nft_contract_id: AccountId, token_id: TokenId) {
-        assert_one_yocto();
-        let contract_and_token_id = format!("{}{}{}", &nft_contract_id, DELIMETER, token_id);
-        let mut market_data = self
-            .market
+    pub fn get_highest_bidder(
+        &self,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+    ) -> Option<AccountId> {
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+        self.market
             .get(&contract_and_token_id)
-            .expect("Error: Token id does not exist");
+            .and_then(|market_data| market_data.bids)
+            .and_then(|mut bids| bids.pop())
+            .map(|bid| bid.bidder_id)
+    }
 
-        assert_eq!(
-            market_data.owner_id,
-            env::predecessor_account_id(),
-            "Error: Only seller can call accept_bid"
-        );
+    /// the bid `bidder_id` has escrowed on this listing, `None` without an active bid
+    pub fn get_bid(
+        &self,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        bidder_id: AccountId,
+    ) -> Option<Bid> {
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+        self.market
+            .get(&contract_and_token_id)
+            .and_then(|market_data| market_data.bids)
+            .and_then(|bids| bids.into_iter().find(|bid| bid.bidder_id == bidder_id))
+    }
 
-        let mut bids = market_data.bids.unwrap();
-		
-		assert!(!bids.is_empty(), "Astro: Cannot accept bid with empty bid");
-		
-        let selected_bid = bids.remove(bids.len() - 1);
-		
-		// refund all except selected bids
-        for bid in &bids {
-          // refund
-          Promise::new(bid.bidder_id.clone()).transfer(bid.price.0);
+    /// Dry run of `buy` for a frontend: reports the first check that would
+    /// fail for this deposit, or the price that would be charged. Pass
+    /// `buyer_id` to include the own-sale and resale cooldown checks.
+    pub fn simulate_buy(
+        &self,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        deposit: U128,
+        buyer_id: Option<AccountId>,
+    ) -> BuyCheck {
+        let contract_and_token_id = format!("{}{}{}", &nft_contract_id, DELIMETER, token_id);
+        let result = match self.market.get(&contract_and_token_id) {
+            Some(mut market_data) => self.internal_check_buy(
+                &mut market_data,
+                buyer_id.as_ref(),
+                &near_account(),
+                deposit.0,
+            ),
+            None => Err(ContractError::ListingNotFound),
+        };
+        match result {
+            Ok(price) => BuyCheck::Ok {
+                price: price.into(),
+            },
+            Err(error) => BuyCheck::Failed {
+                error: error.to_string(),
+            },
         }
-        bids.clear();
-		
-        market_data.bids = Some(bids);
-        self.market.insert(&contract_and_token_id, &market_data);
+    }
 
-        self.internal_process_purchase(
-            market_data.nft_contract_id,
-            token_id,
-            selected_bid.bidder_id.clone(),
-            selected_bid.price.clone().0,
-        );
+    /// Dry run of `add_bid` for `bidder_id` bidding `amount` NEAR, with the
+    /// deposit equal to the amount. Reports the first check that would fail.
+    pub fn can_bid(
+        &self,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        amount: U128,
+        bidder_id: AccountId,
+    ) -> BidCheck {
+        let contract_and_token_id = format!("{}{}{}", &nft_contract_id, DELIMETER, token_id);
+        let result = match self.market.get(&contract_and_token_id) {
+            Some(market_data) => self.internal_check_bid(
+                &market_data,
+                &bidder_id,
+                &near_account(),
+                amount.0,
+                amount.0,
+                None,
+                None,
+            ),
+            None => Err(ContractError::ListingNotFound),
+        };
+        match result {
+            Ok(()) => BidCheck::Ok,
+            Err(error) => BidCheck::Failed {
+                error: error.to_string(),
+            },
+        }
     }
-	
-	
-	fn internal_cancel_bid(&mut self, nft_contract_id: AccountId, token_id: TokenId, account_id: AccountId) {
-      let contract_and_token_id = format!("{}{}{}", &nft_contract_id, DELIMETER, token_id);
-      let mut market_data = self
-        .market
-        .get(&contract_and_token_id)
-        .expect("Error: Token id does not exist");
 
-      let mut bids = market_data.bids.unwrap();
+    pub fn get_minimum_next_bid(
+        &self,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+    ) -> Option<U128> {
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+        self.market
+            .get(&contract_and_token_id)
+            .and_then(|market_data| market_data.min_next_bid(self.min_bid_increment_bps))
+            .map(U128)
+    }
 
-      assert!(
-        !bids.is_empty(),
-        "Error: Bids data does not exist"
-      );
+    /// bids from highest to lowest, empty for sales and missing listings
+    pub fn get_ranked_bids(
+        &self,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        from_index: Option<U64>,
+        limit: Option<u64>,
+    ) -> Vec<RankedBid> {
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+        let market_data = match self.market.get(&contract_and_token_id) {
+            Some(market_data) => market_data,
+            None => return vec![],
+        };
+        let starting_price = market_data.price;
+        let mut bids = market_data.bids.unwrap_or_default();
+        // appended in placement order, a later bid wins a tie
+        bids.reverse();
+        bids.sort_by_key(|bid| std::cmp::Reverse(bid.price.0));
+
+        let limit = limit.unwrap_or(MAX_VIEW_LIMIT).min(MAX_VIEW_LIMIT);
+        bids.into_iter()
+            .enumerate()
+            .skip(from_index.map_or(0, |x| x.0) as usize)
+            .take(limit as usize)
+            .map(|(index, bid)| RankedBid {
+                is_winning: index == 0 && bid.price.0 >= starting_price,
+                rank: index as u64 + 1,
+                bid,
+            })
+            .collect()
+    }
 
-      // Retain all elements except account_id
-      bids.retain(|bid| {
-        if bid.bidder_id == account_id {
-          // refund
-          Promise::new(bid.bidder_id.clone()).transfer(bid.price.0);
+    /// get_market_data plus what a detail page needs. The proceeds are an
+    /// estimate at the current price or highest bid, after the treasury fee
+    /// and any royalty override or registered creator royalty, not the NFT
+    /// contract's payout.
+    pub fn get_market_data_detail(
+        &self,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+    ) -> MarketDataDetailJson {
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+        let market_data: MarketData = self
+            .market
+            .get(&contract_and_token_id)
+            .unwrap_or_else(|| ContractError::ListingNotFound.panic());
+
+        let highest_bid = market_data
+            .bids
+            .as_ref()
+            .and_then(|bids| bids.last())
+            .map(|bid| bid.price.0);
+        let min_next_bid = market_data.min_next_bid(self.min_bid_increment_bps);
+        let time_remaining = market_data
+            .ended_at
+            .map(|ended_at| ended_at.saturating_sub(env::block_timestamp()));
+
+        let price = highest_bid.unwrap_or(market_data.price);
+        let treasury_fee = treasury_fee_of(price, self.get_transaction_fee(), self.round_fee_up);
+        let royalty = self
+            .internal_creator_royalty(&market_data.nft_contract_id, price, treasury_fee)
+            .map_or(0, |(_, royalty)| royalty);
+
+        MarketDataDetailJson {
+            market_data: self.internal_market_data_json(market_data),
+            min_next_bid: min_next_bid.map(|x| x.into()),
+            time_remaining: time_remaining.map(|x| x.into()),
+            estimated_seller_proceeds: price
+                .saturating_sub(treasury_fee)
+                .saturating_sub(royalty)
+                .into(),
         }
+    }
+
+    /// Listings whose ended_at falls within the next `within_seconds`, optionally
+    /// for one collection. Walks the whole market from `from_index`, so this
+    /// is O(n) in listings; `limit` is capped at 100 results.
+    pub fn get_ending_soon(
+        &self,
+        within_seconds: u64,
+        nft_contract_id: Option<AccountId>,
+        from_index: Option<U64>,
+        limit: Option<u64>,
+    ) -> Vec<MarketDataJson> {
+        let now = env::block_timestamp();
+        let until = now.saturating_add(within_seconds.saturating_mul(10u64.pow(9)));
+        let limit = limit.unwrap_or(MAX_VIEW_LIMIT).min(MAX_VIEW_LIMIT);
+
+        self.market
+            .values()
+            .skip(from_index.map_or(0, |x| x.0) as usize)
+            .filter(|market_data| {
+                nft_contract_id
+                    .as_ref()
+                    .is_none_or(|id| *id == market_data.nft_contract_id)
+                    && market_data
+                        .ended_at
+                        .is_some_and(|ended_at| ended_at >= now && ended_at <= until)
+            })
+            .take(limit as usize)
+            .map(|market_data| self.internal_market_data_json(market_data))
+            .collect()
+    }
 
-        bid.bidder_id != account_id
-      });
+    /// Listings in the order they were created. `from_index`/`limit` select a
+    /// window of the append-only market_index, so deleting a listing never
+    /// shifts later pages; a page holds fewer results when its window covers
+    /// deleted slots, and the next page starts at `from_index + limit`.
+    /// The index keeps one key per listing ever created, that storage is
+    /// not reclaimed.
+    pub fn get_markets(&self, from_index: Option<U64>, limit: Option<u64>) -> Vec<MarketDataJson> {
+        let start = from_index.map_or(0, |x| x.0);
+        let end = start
+            .saturating_add(limit.unwrap_or(MAX_VIEW_LIMIT).min(MAX_VIEW_LIMIT))
+            .min(self.market_index.len());
+
+        (start..end)
+            .filter_map(|index| {
+                let key = self.market_index.get(index)?;
+                if self.market_index_positions.get(&key) != Some(index) {
+                    return None;
+                }
+                self.market
+                    .get(&key)
+                    .map(|market_data| self.internal_market_data_json(market_data))
+            })
+            .collect()
+    }
 
-      market_data.bids = Some(bids);
-      self.market.insert(&contract_and_token_id, &market_data);
+    /// Active listings in market order. Deleting a listing moves the last one
+    /// into its place, so pages can shift between calls; get_markets pages
+    /// by creation order instead.
+    pub fn get_market_datas(
+        &self,
+        from_index: Option<U64>,
+        limit: Option<u64>,
+    ) -> Vec<MarketDataJson> {
+        let limit = limit.unwrap_or(MAX_VIEW_LIMIT).min(MAX_VIEW_LIMIT);
+        self.market
+            .values()
+            .skip(from_index.map_or(0, |x| x.0) as usize)
+            .take(limit as usize)
+            .map(|market_data| self.internal_market_data_json(market_data))
+            .collect()
+    }
 
-      env::log_str(
-        &json!({
-          "type": "cancel_bid",
-          "params": {
-            "bidder_id": account_id, "nft_contract_id": nft_contract_id, "token_id": token_id
-          }
-        })
-        .to_string(),
-      );
+    pub fn get_supply_market_datas(&self) -> U64 {
+        self.market.len().into()
     }
 
-    #[payable]
-    pub fn cancel_bid(&mut self, nft_contract_id: AccountId, token_id: TokenId, account_id: AccountId) {
-      assert_one_yocto();
-      let contract_and_token_id = format!("{}{}{}", &nft_contract_id, DELIMETER, token_id);
-      let market_data = self
-        .market
-        .get(&contract_and_token_id)
-        .expect("Error: Token id does not exist");
+    /// `account_id`'s listings. by_owner_id also holds the account's offers,
+    /// those are skipped before paging.
+    pub fn get_market_data_by_owner_id(
+        &self,
+        account_id: AccountId,
+        from_index: Option<U64>,
+        limit: Option<u64>,
+    ) -> Vec<MarketDataJson> {
+        let keys = match self.by_owner_id.get(&account_id) {
+            Some(keys) => keys,
+            None => return Vec::new(),
+        };
+        let limit = limit.unwrap_or(MAX_VIEW_LIMIT).min(MAX_VIEW_LIMIT);
+
+        keys.iter()
+            .filter_map(|key| self.market.get(&key))
+            .skip(from_index.map_or(0, |x| x.0) as usize)
+            .take(limit as usize)
+            .map(|market_data| self.internal_market_data_json(market_data))
+            .collect()
+    }
 
-      let bids = market_data.bids.unwrap();
+    pub fn approved_ft_token_ids(&self) -> Vec<AccountId> {
+        self.approved_ft_token_ids.to_vec()
+    }
 
-      assert!(
-        !bids.is_empty(),
-        "Error: Bids data does not exist"
-      );
+    pub fn approved_nft_contract_ids(&self) -> Vec<AccountId> {
+        self.approved_nft_contract_ids.to_vec()
+    }
 
-      for x in 0..bids.len() {
-        if bids[x].bidder_id == account_id {
-          assert!(
-            [bids[x].bidder_id.clone(), self.owner_id.clone()]
-              .contains(&env::predecessor_account_id()),
-              "Error: Bidder or owner only"
-          );
-        }
-      }
+    pub fn get_owner(&self) -> AccountId {
+        self.owner_id.clone()
+    }
 
-      self.internal_cancel_bid(nft_contract_id, token_id, account_id,);
+    pub fn get_treasury(&self) -> AccountId {
+        self.treasury_id.clone()
     }
 
+    /// lifetime fees sent to the treasury, the funds themselves leave the contract at settlement
+    pub fn get_treasury_accrued(&self) -> U128 {
+        self.treasury_accrued.into()
+    }
 
-    // Market Data functions
+    pub fn get_dust_accrued(&self) -> U128 {
+        self.dust_accrued.into()
+    }
 
+    /// Sends the accrued dust to the treasury.
     #[payable]
-    pub fn update_market_data(
-        &mut self,
-        nft_contract_id: AccountId,
-        token_id: TokenId,
-        ft_token_id: AccountId,
-        price: U128,
-    ) {
+    pub fn sweep_dust(&mut self) -> U128 {
         assert_one_yocto();
-        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
-        let mut market_data = self
-            .market
-            .get(&contract_and_token_id)
-            .expect("Error: Token id does not exist ");
+        self.assert_owner();
+        let amount = std::mem::take(&mut self.dust_accrued);
+        if amount > 0 {
+            Promise::new(self.treasury_id.clone()).transfer(amount);
+        }
 
-        assert_eq!(
-            market_data.owner_id,
-            env::predecessor_account_id(),
-            "Error: Seller only"
+        self.log_event(
+            "sweep_dust",
+            json!({
+                "treasury_id": self.treasury_id,
+                "amount": U128(amount),
+            }),
         );
+        amount.into()
+    }
 
-        assert_eq!(
-            ft_token_id, market_data.ft_token_id,
-            "Error: ft_token_id differs"
-        ); // sanity check
+    pub fn get_stats(&self) -> MarketStats {
+        MarketStats {
+            listings: self.market.len().into(),
+            offers: self.offers.len().into(),
+            total_volume: self.total_volume.into(),
+            treasury_accrued: self.treasury_accrued.into(),
+            approved_nft_contracts: self.approved_nft_contract_ids.len().into(),
+        }
+    }
 
-        assert!(
-            price.0 < MAX_PRICE,
-            "Error: price higher than {}",
-            MAX_PRICE
-        );
+    /// every per-collection setting, whether or not the collection is approved
+    pub fn get_collection_config(&self, nft_contract_id: AccountId) -> CollectionConfig {
+        CollectionConfig {
+            approved: self.approved_nft_contract_ids.contains(&nft_contract_id),
+            paused: self.paused_contracts.contains(&nft_contract_id),
+            kyc_required: self.kyc_required_contracts.contains(&nft_contract_id),
+            strict_payout: self.strict_payout_contracts.contains(&nft_contract_id),
+            transfer_only: self.transfer_only_contracts.contains(&nft_contract_id),
+            offers_enabled: !self.offers_disabled_contracts.contains(&nft_contract_id),
+            resale_cooldown: self.resale_cooldowns.get(&nft_contract_id),
+            creator_royalty: self.creator_royalties.get(&nft_contract_id),
+            royalty_override: self.royalty_overrides.get(&nft_contract_id),
+            nft_contract_id,
+        }
+    }
 
-        market_data.price = price.into();
-        self.market.insert(&contract_and_token_id, &market_data);
+    /// get_collection_config for each approved collection
+    pub fn get_all_collection_configs(
+        &self,
+        from_index: Option<U64>,
+        limit: Option<u64>,
+    ) -> Vec<CollectionConfig> {
+        let limit = limit.unwrap_or(MAX_VIEW_LIMIT).min(MAX_VIEW_LIMIT);
+        self.approved_nft_contract_ids
+            .iter()
+            .skip(from_index.map_or(0, |x| x.0) as usize)
+            .take(limit as usize)
+            .map(|nft_contract_id| self.get_collection_config(nft_contract_id))
+            .collect()
+    }
 
-        env::log_str(
-            &json!({
-                "event": "update_market_data",
-                "params": {
-                    "owner_id": market_data.owner_id,
-                    "nft_contract_id": nft_contract_id,
-                    "token_id": token_id,
-                    "ft_token_id": ft_token_id,
-                    "price": price,
-                }
+    /// Listings and offers held by `account_id`, in by_owner_id order. Listing
+    /// keys are `nft_contract_id||token_id` and offer keys are
+    /// `nft_contract_id||buyer_id||token_id`. New entries can't have `||` in
+    /// their token_id, but keys written before that check may, so each key is
+    /// looked up in the market first and in offers second rather than split;
+    /// keys found in neither are skipped.
+    pub fn get_account_activity(
+        &self,
+        account_id: AccountId,
+        from_index: Option<U64>,
+        limit: Option<u64>,
+    ) -> Vec<AccountActivity> {
+        let keys = match self.by_owner_id.get(&account_id) {
+            Some(keys) => keys,
+            None => return Vec::new(),
+        };
+        let limit = limit.unwrap_or(MAX_VIEW_LIMIT).min(MAX_VIEW_LIMIT);
+
+        keys.iter()
+            .skip(from_index.map_or(0, |x| x.0) as usize)
+            .take(limit as usize)
+            .filter_map(|key| match self.market.get(&key) {
+                Some(market_data) => Some(AccountActivity::Listing(Box::new(
+                    self.internal_market_data_json(market_data),
+                ))),
+                None => self
+                    .offers
+                    .get(&key)
+                    .map(|offer_data| AccountActivity::Offer(offer_data.into())),
             })
-            .to_string(),
-        );
+            .collect()
     }
 
-    fn internal_add_market_data(
-        &mut self,
-        owner_id: AccountId,
-        approval_id: u64,
-        nft_contract_id: AccountId,
-        token_id: TokenId,
-        ft_token_id: AccountId,
-        price: U128,
-        started_at: Option<U64>,
-        ended_at: Option<U64>,
-        is_auction: Option<bool>,
-    ) {
-        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+    /// NEAR held for `account_id`: its offers plus every bid it has in an
+    /// auction, outbid ones included. Escrow is NEAR only, add_offer and
+    /// add_bid take the attached deposit and reject any other currency, so
+    /// nothing is held in an FT. There's no index of bids by bidder, so
+    /// this walks the whole market and is O(n) in listings.
+    pub fn get_escrow_for_account(&self, account_id: AccountId) -> U128 {
+        let offers: Balance = self.by_owner_id.get(&account_id).map_or(0, |keys| {
+            keys.iter()
+                .filter_map(|key| self.offers.get(&key))
+                .map(|offer_data| offer_data.price)
+                .sum()
+        });
+        let bids: Balance = self
+            .market
+            .values()
+            .flat_map(|market_data| market_data.bids.unwrap_or_default())
+            .filter(|bid| bid.bidder_id == account_id)
+            .map(|bid| bid.price.0)
+            .sum();
+        (offers + bids).into()
+    }
 
-        let bids: Option<Bids> = match is_auction {
-            Some(u) => {
-                if u {
-                    Some(Vec::new())
-                } else {
-                    None
-                }
-            }
-            None => None,
-        };
+    pub fn get_supply_by_owner_id(&self, account_id: AccountId) -> U64 {
+        self.by_owner_id
+            .get(&account_id)
+            .map_or(0, |by_owner_id| by_owner_id.len())
+            .into()
+    }
+
+    // private fn
 
-        let current_time: u64 = env::block_timestamp();
+    fn assert_owner(&self) {
+        require(
+            env::predecessor_account_id() == self.owner_id,
+            ContractError::NotOwner,
+        )
+    }
 
-        if started_at.is_some() {
-            assert!(started_at.unwrap().0 >= current_time);
+    /// `#[private]` for resolve_purchase and resolve_offer, unless the owner
+    /// added the predecessor to trusted_callers.
+    fn assert_callback_or_trusted(&self) {
+        let predecessor_id = env::predecessor_account_id();
+        require(
+            predecessor_id == env::current_account_id()
+                || self.trusted_callers.contains(&predecessor_id),
+            ContractError::NotTrustedCaller,
+        )
+    }
 
-            if ended_at.is_some() {
-                assert!(started_at.unwrap().0 < ended_at.unwrap().0);
-            }
+    /// auctions must end, and run between min_auction_duration and
+    /// max_auction_duration from their start (or now)
+    fn assert_auction_duration(&self, started_at: Option<U64>, ended_at: Option<U64>) {
+        let ended_at = ended_at.unwrap_or_else(|| ContractError::AuctionEndRequired.panic());
+        let starts = started_at.map_or(env::block_timestamp(), |x| x.0);
+        let duration = ended_at.0.saturating_sub(starts);
+        require(
+            duration >= self.min_auction_duration.saturating_mul(10u64.pow(9)),
+            ContractError::AuctionTooShort {
+                min_sec: self.min_auction_duration,
+            },
+        );
+        require(
+            self.max_auction_duration == 0
+                || duration <= self.max_auction_duration.saturating_mul(10u64.pow(9)),
+            ContractError::AuctionTooLong {
+                max_sec: self.max_auction_duration,
+            },
+        );
+    }
+
+    fn assert_within_schedule_horizon(&self, started_at: Option<U64>) {
+        if let Some(started_at) = started_at {
+            let horizon = env::block_timestamp()
+                .saturating_add(self.max_schedule_horizon.saturating_mul(10u64.pow(9)));
+            require(
+                started_at.0 <= horizon,
+                ContractError::StartedAtTooFar {
+                    max_sec: self.max_schedule_horizon,
+                },
+            );
         }
+    }
+
+    fn internal_recovery_id(&self) -> AccountId {
+        self.recovery_id
+            .clone()
+            .unwrap_or_else(|| self.treasury_id.clone())
+    }
+
+    fn is_kyc_allowed(&self, nft_contract_id: &AccountId, account_id: &AccountId) -> bool {
+        !self.kyc_required_contracts.contains(nft_contract_id)
+            || self.kyc_allowlist.contains(account_id)
+    }
 
-        if ended_at.is_some() {
-            assert!(ended_at.unwrap().0 >= current_time);
+    /// MarketDataJson with min_next_bid including min_bid_increment_bps
+    fn internal_market_data_json(&self, market_data: MarketData) -> MarketDataJson {
+        let min_next_bid = market_data.min_next_bid(self.min_bid_increment_bps);
+        MarketDataJson {
+            min_next_bid: min_next_bid.map(U128),
+            ..market_data.into()
         }
+    }
 
-        assert!(
-            price.0 < MAX_PRICE,
-            "Error: price higher than {}",
-            MAX_PRICE
-        );
+    /// An ended auction process_ended_auctions leaves alone: its top bid is
+    /// below the current starting price or its winner is still under the
+    /// resale cooldown. Auctions to cancel or delete are never blocked.
+    fn is_settlement_blocked(&self, market_data: &MarketData) -> bool {
+        if market_data.cancel_reason().is_some() {
+            return false;
+        }
+        match market_data.bids.as_ref().and_then(|bids| bids.last()) {
+            Some(winning_bid) => {
+                winning_bid.price.0 < market_data.price
+                    || !self.internal_resale_cooldown_passed(
+                        &market_data.nft_contract_id,
+                        &market_data.token_id,
+                        &winning_bid.bidder_id,
+                    )
+            }
+            None => false,
+        }
+    }
 
-        self.market.insert(
-            &contract_and_token_id,
-            &MarketData {
-                owner_id: owner_id.clone().into(),
-                approval_id,
-                nft_contract_id: nft_contract_id.clone().into(),
-                token_id: token_id.clone(),
-                ft_token_id: ft_token_id.clone(),
-                price: price.into(),
-                bids: bids,
-                started_at: match started_at {
-                    Some(x) => Some(x.0),
-                    None => None,
-                },
-                ended_at: match ended_at {
-                    Some(x) => Some(x.0),
-                    None => None,
-                },
-                is_auction: is_auction,
-            },
-        );
+    fn is_nft_contract_allowed(&self, nft_contract_id: &AccountId) -> bool {
+        self.open_mode || self.approved_nft_contract_ids.contains(nft_contract_id)
+    }
 
-        let mut token_ids = self.by_owner_id.get(&owner_id).unwrap_or_else(|| {
-            UnorderedSet::new(
-                StorageKey::ByOwnerIdInner {
-                    account_id_hash: hash_account_id(&owner_id),
-                }
-                .try_to_vec()
-                .unwrap(),
-            )
-        });
+    fn assert_not_paused(&self) {
+        require(!self.paused, ContractError::MarketplacePaused)
+    }
 
-        token_ids.insert(&contract_and_token_id);
+    fn assert_contract_not_paused(&self, nft_contract_id: &AccountId) {
+        require(
+            !self.paused_contracts.contains(nft_contract_id),
+            ContractError::ContractPaused,
+        )
+    }
 
-        self.by_owner_id.insert(&owner_id, &token_ids);
+    fn internal_apply_pending_fee(&mut self) {
+        if let Some(pending) = self.internal_due_fee_limits().cloned() {
+            self.fee_change_delay = pending.fee_change_delay;
+            self.max_fee_step = pending.max_fee_step;
+            self.pending_fee_limits = None;
+        }
+        if let Some(pending) = &self.pending_fee {
+            if env::block_timestamp() >= pending.effective_at.0 {
+                self.transaction_fee = pending.fee;
+                self.pending_fee = None;
+            }
+        }
+    }
 
-        env::log_str(
-            &json!({
-                "event": "add_market_data",
-                "params": {
-                    "owner_id": owner_id,
-                    "approval_id": approval_id,
-                    "nft_contract_id": nft_contract_id,
-                    "token_id": token_id,
-                    "ft_token_id": ft_token_id,
-                    "price": price,
-                    "started_at": started_at,
-                    "ended_at": ended_at,
-                    "is_auction": is_auction
-                }
-            })
-            .to_string(),
+    fn assert_resale_cooldown_passed(
+        &self,
+        nft_contract_id: &AccountId,
+        token_id: &TokenId,
+        buyer_id: &AccountId,
+    ) {
+        require(
+            self.internal_resale_cooldown_passed(nft_contract_id, token_id, buyer_id),
+            ContractError::ResaleCooldownActive,
         );
     }
 
-    fn internal_delete_market_data(
-        &mut self,
+    fn internal_resale_cooldown_passed(
+        &self,
         nft_contract_id: &AccountId,
         token_id: &TokenId,
-    ) -> Option<MarketData> {
-        let contract_and_token_id = format!("{}{}{}", &nft_contract_id, DELIMETER, token_id);
-        let market_data: Option<MarketData> =
-            if let Some(market_data) = self.market.get(&contract_and_token_id) {
-                self.market.remove(&contract_and_token_id);
-
-                if let Some(ref bids) = market_data.bids {
-                    for bid in bids {
-                        Promise::new(bid.bidder_id.clone()).transfer(bid.price.0);
-                    }
-                };
-
-                Some(market_data)
-            } else {
-                None
-            };
-
-        market_data.map(|market_data| {
-            let by_owner_id = self
-                .by_owner_id
-                .get(&market_data.owner_id);
-            if let Some(mut by_owner_id) = by_owner_id {
-                by_owner_id.remove(&contract_and_token_id);
-                if by_owner_id.is_empty() {
-                self.by_owner_id.remove(&market_data.owner_id);
-                } else {
-                self.by_owner_id.insert(&market_data.owner_id, &by_owner_id);
-                }
-            }
-            market_data
+        buyer_id: &AccountId,
+    ) -> bool {
+        let cooldown_sec = match self.resale_cooldowns.get(nft_contract_id) {
+            Some(cooldown_sec) => cooldown_sec,
+            None => return true,
+        };
+        let key = make_triple(nft_contract_id, buyer_id, token_id);
+        self.last_purchases.get(&key).is_none_or(|bought_at| {
+            env::block_timestamp()
+                >= bought_at.saturating_add(cooldown_sec.saturating_mul(10u64.pow(9)))
         })
     }
 
-    #[payable]
-    pub fn delete_market_data(&mut self, nft_contract_id: AccountId, token_id: TokenId) {
-        assert_one_yocto();
-        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+    fn internal_payout_tolerance(&self, nft_contract_id: &AccountId) -> u128 {
+        if self.strict_payout_contracts.contains(nft_contract_id) {
+            0
+        } else {
+            PAYOUT_TOLERANCE
+        }
+    }
 
-        let market_data: MarketData = self.market.get(&contract_and_token_id).expect("Error: Market data does not exist");
+    /// Refunds a bid, offer or overpayment in `ft_token_id`, NEAR through
+    /// internal_send_near and FTs through ft_transfer. A refund that fails is
+    /// recorded in failed_refunds (failed_ft_refunds for FTs) by resolve_refund
+    /// so the account can claim it later. When the call is running out of gas
+    /// the remaining refunds go out as plain transfers rather than failing the
+    /// whole call.
+    fn internal_refund(&self, account_id: &AccountId, ft_token_id: &AccountId, amount: Balance) {
+        let refund = if ft_token_id.as_str() == NEAR {
+            self.internal_send_near(account_id, amount)
+        } else {
+            self.internal_transfer(ft_token_id, account_id, amount)
+        };
+        let remaining_gas = env::prepaid_gas().0 - env::used_gas().0;
+        if self.refund_gas > 0 && remaining_gas > self.refund_gas + BASE_GAS.0 {
+            refund.then(
+                ext_self::ext(env::current_account_id())
+                    .with_attached_deposit(NO_DEPOSIT)
+                    .with_static_gas(Gas(self.refund_gas))
+                    .resolve_refund(account_id.clone(), U128(amount), Some(ft_token_id.clone())),
+            );
+        }
+    }
 
-        assert!(
-            [market_data.owner_id.clone(), self.owner_id.clone()]
-                .contains(&env::predecessor_account_id()),
-            "Error: Seller or owner only"
+    /// Refunds every bid in `bids` and logs them as one bids_refunded event,
+    /// so a crowded auction doesn't log once per bidder.
+    fn internal_refund_bids(&self, market_data: &MarketData, bids: &[Bid]) {
+        if bids.is_empty() {
+            return;
+        }
+        for bid in bids {
+            self.internal_refund(&bid.bidder_id, &market_data.ft_token_id, bid.price.0);
+        }
+        let refunds: Vec<_> = bids
+            .iter()
+            .map(|bid| json!({ "bidder_id": bid.bidder_id, "amount": bid.price }))
+            .collect();
+        self.log_event(
+            "bids_refunded",
+            json!({
+                "nft_contract_id": market_data.nft_contract_id,
+                "token_id": market_data.token_id,
+                "listing_nonce": U64(market_data.listing_nonce),
+                "ft_token_id": market_data.ft_token_id,
+                "refunds": refunds,
+            }),
         );
+    }
 
-        self.internal_delete_market_data(&nft_contract_id, &token_id);
-
-        env::log_str(
-            &json!({
-                "event": "delete_market_data",
-                "params": {
-                    "owner_id": market_data.owner_id,
-                    "nft_contract_id": nft_contract_id,
-                    "token_id": token_id,
-                }
-            })
-            .to_string(),
+    /// One share of an nft_transfer_payout split. Transfers to deleted accounts
+    /// fail after this receipt and refund the contract, so every share is logged
+    /// to let operators reconcile and pay the receiver by hand.
+    fn payout_transfer(&self, context: &SettleContext, receiver_id: AccountId, amount: Balance) {
+        self.log_event(
+            "payout_transfer",
+            json!({
+                "nft_contract_id": context.nft_contract_id,
+                "token_id": context.token_id,
+                "ft_token_id": context.ft_token_id,
+                "receiver_id": receiver_id,
+                "amount": U128(amount),
+            }),
         );
+        self.internal_transfer(context.ft_token_id, &receiver_id, amount);
     }
 
-    // Storage
+    /// Plain NEAR transfer, or ft_transfer of `ft_token_id` with the yocto
+    /// paid by the market. An FT receiver that isn't registered with the
+    /// token fails the transfer and the tokens stay with the market, logged
+    /// by the caller.
+    fn internal_transfer(
+        &self,
+        ft_token_id: &AccountId,
+        receiver_id: &AccountId,
+        amount: Balance,
+    ) -> Promise {
+        if ft_token_id.as_str() == NEAR {
+            return Promise::new(receiver_id.clone()).transfer(amount);
+        }
+        ext_ft::ext(ft_token_id.clone())
+            .with_attached_deposit(ONE_YOCTO)
+            .with_static_gas(GAS_FOR_FT_TRANSFER)
+            .ft_transfer(receiver_id.clone(), U128(amount), None)
+    }
 
-    #[payable]
-    pub fn storage_deposit(&mut self, account_id: Option<AccountId>) {
-        let storage_account_id = account_id
-            .map(|a| a.into())
-            .unwrap_or_else(env::predecessor_account_id);
-        let deposit = env::attached_deposit();
-        assert!(
-            deposit >= STORAGE_ADD_MARKET_DATA,
-            "Requires minimum deposit of {}",
-            STORAGE_ADD_MARKET_DATA
-        );
+    /// Native transfer, or for an account preferring wnear_id a near_deposit
+    /// followed by ft_transfer of the wrapped amount. The market pays the
+    /// yocto ft_transfer requires.
+    fn internal_send_near(&self, account_id: &AccountId, amount: Balance) -> Promise {
+        match (&self.wnear_id, self.settlement_preferences.get(account_id)) {
+            (Some(wnear_id), Some(preferred)) if *wnear_id == preferred => {
+                ext_wnear::ext(wnear_id.clone())
+                    .with_attached_deposit(amount)
+                    .with_static_gas(BASE_GAS)
+                    .near_deposit()
+                    .then(
+                        ext_wnear::ext(wnear_id.clone())
+                            .with_attached_deposit(ONE_YOCTO)
+                            .with_static_gas(GAS_FOR_FT_TRANSFER)
+                            .ft_transfer(account_id.clone(), U128(amount), None),
+                    )
+            }
+            _ => Promise::new(account_id.clone()).transfer(amount),
+        }
+    }
 
-        let mut balance: u128 = self.storage_deposits.get(&storage_account_id).unwrap_or(0);
-        balance += deposit;
-        self.storage_deposits.insert(&storage_account_id, &balance);
+    /// one event per call, `size` is the allowlist length afterwards
+    fn log_allowlist_change(
+        &self,
+        event: &str,
+        added: &[AccountId],
+        removed: &[AccountId],
+        ignored: &[AccountId],
+        size: u64,
+    ) -> U64 {
+        self.log_event(
+            event,
+            json!({
+                "added": added,
+                "removed": removed,
+                "ignored": ignored,
+                "size": U64(size),
+            }),
+        );
+        ((added.len() + removed.len()) as u64).into()
     }
 
-    #[payable]
-    pub fn storage_withdraw(&mut self) {
-        assert_one_yocto();
-        let owner_id = env::predecessor_account_id();
-        let mut amount = self.storage_deposits.remove(&owner_id).unwrap_or(0);
-        let market_data_owner = self.by_owner_id.get(&owner_id);
-        let len = market_data_owner.map(|s| s.len()).unwrap_or_default();
-        let diff = u128::from(len) * STORAGE_ADD_MARKET_DATA;
-        amount -= diff;
-        if amount > 0 {
-            Promise::new(owner_id.clone()).transfer(amount);
-        }
-        if diff > 0 {
-            self.storage_deposits.insert(&owner_id, &diff);
+    /// Pays a sale out once the NFT is transferred, in NEAR or the FT it was
+    /// bought with. Sales, auctions and offers all settle here so they split
+    /// the price the same way, see the royalty precedence below. Returns the
+    /// buyer rebate.
+    fn internal_settle(
+        &mut self,
+        payout: Option<PayoutHashMap>,
+        price: Balance,
+        seller_id: &AccountId,
+        context: &SettleContext,
+    ) -> Balance {
+        let treasury_fee = treasury_fee_of(price, self.transaction_fee, self.round_fee_up);
+
+        let payout = match payout {
+            Some(payout) => payout,
+            None => {
+                let royalty = self.internal_pay_creator_royalty(context, price, treasury_fee);
+                let proceeds = price.saturating_sub(treasury_fee).saturating_sub(royalty);
+                if *context.ft_token_id == near_account() {
+                    self.internal_send_near(seller_id, proceeds);
+                } else {
+                    self.internal_transfer(context.ft_token_id, seller_id, proceeds);
+                }
+                return self.internal_pay_fee_and_rebate(context, treasury_fee);
+            }
+        };
+
+        // the fee comes out of the seller's share, whatever that can't cover
+        // (a payout can leave the seller less than the fee or out entirely) is
+        // taken at the fee rate from the other shares
+        let seller_fee = payout
+            .get(seller_id)
+            .map_or(0, |amount| treasury_fee.min(amount.0));
+        let mut shortfall = treasury_fee - seller_fee;
+        for (receiver_id, amount) in payout {
+            if receiver_id == *seller_id {
+                let seller_amount = amount.0 - seller_fee;
+                let royalty = self.internal_pay_royalty_override(context, price, seller_amount);
+                self.payout_transfer(context, receiver_id, seller_amount.saturating_sub(royalty));
+            } else {
+                let fee = treasury_fee_of(amount.0, self.transaction_fee, self.round_fee_up)
+                    .min(shortfall);
+                shortfall -= fee;
+                self.payout_transfer(context, receiver_id, amount.0 - fee);
+            }
         }
+        self.internal_pay_fee_and_rebate(context, treasury_fee - shortfall)
     }
 
-    pub fn storage_minimum_balance(&self) -> U128 {
-        U128(STORAGE_ADD_MARKET_DATA)
+    fn internal_pay_fee_and_rebate(
+        &mut self,
+        context: &SettleContext,
+        treasury_fee: Balance,
+    ) -> Balance {
+        let rebate = bps_of(treasury_fee, context.rebate_bps);
+        if rebate > 0 {
+            self.internal_transfer(context.ft_token_id, context.buyer_id, rebate);
+        }
+        self.internal_pay_fee(context, treasury_fee.saturating_sub(rebate));
+        rebate
     }
 
-    pub fn storage_balance_of(&self, account_id: AccountId) -> U128 {
-        self.storage_deposits.get(&account_id).unwrap_or(0).into()
+    fn internal_pay_fee(&mut self, context: &SettleContext, treasury_fee: Balance) {
+        if treasury_fee == 0 {
+            return;
+        }
+        let fee_recipient = context.fee_recipient;
+        self.internal_transfer(context.ft_token_id, fee_recipient, treasury_fee);
+        // fees redirected by the listing aren't treasury income, treasury_accrued counts NEAR
+        if *fee_recipient == self.treasury_id && *context.ft_token_id == near_account() {
+            self.treasury_accrued = self.treasury_accrued.saturating_add(treasury_fee);
+        }
     }
 
-    // View
+    // Royalty precedence, all shares come out of the price:
+    // 1. a usable nft_transfer_payout is split as the NFT contract returned it
+    // 2. a royalty override then moves its bps of the price from the seller's share
+    // 3. without a usable payout the override applies, else the creator registry
+    // 4. the treasury fee is taken from the seller's share before 2 and 3, and
+    //    from the other payout shares when the seller's doesn't cover it
 
-    pub fn get_market_data(self, nft_contract_id: AccountId, token_id: TokenId) -> MarketDataJson {
-        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
-        let market_data: MarketData = self.market.get(&contract_and_token_id).expect("Error: Market data does not exist");
-            
-        let price = market_data.price;
+    /// Without a usable payout: pays the override, or else the registered
+    /// creator, out of what's left after the treasury fee, returns the amount.
+    fn internal_pay_creator_royalty(
+        &self,
+        context: &SettleContext,
+        price: Balance,
+        treasury_fee: Balance,
+    ) -> Balance {
+        let nft_contract_id = context.nft_contract_id;
+        if self.royalty_overrides.contains_key(nft_contract_id) {
+            return self.internal_pay_royalty_override(
+                context,
+                price,
+                price.saturating_sub(treasury_fee),
+            );
+        }
+        let (creator_id, royalty) =
+            match self.internal_creator_royalty(nft_contract_id, price, treasury_fee) {
+                Some(royalty) => royalty,
+                None => return 0,
+            };
+        if royalty > 0 {
+            self.internal_transfer(context.ft_token_id, &creator_id, royalty);
+            self.log_event(
+                "creator_royalty",
+                json!({
+                    "nft_contract_id": nft_contract_id,
+                    "ft_token_id": context.ft_token_id,
+                    "creator_id": creator_id,
+                    "amount": U128(royalty),
+                }),
+            );
+        }
+        royalty
+    }
 
-        MarketDataJson {
-            owner_id: market_data.owner_id,
-            approval_id: market_data.approval_id.into(),
-            nft_contract_id: market_data.nft_contract_id,
-            token_id: market_data.token_id,
-            ft_token_id: market_data.ft_token_id, // "near" for NEAR token
-            price: price.into(),
-            bids: market_data.bids,
-            started_at: market_data.started_at.map(|x| x.into()),
-            ended_at: market_data.ended_at.map(|x| x.into()),
-            is_auction: market_data.is_auction,
+    /// pays the collection's override out of `available`, returns the amount sent
+    fn internal_pay_royalty_override(
+        &self,
+        context: &SettleContext,
+        price: Balance,
+        available: Balance,
+    ) -> Balance {
+        let nft_contract_id = context.nft_contract_id;
+        let (receiver_id, bps) = match self.royalty_overrides.get(nft_contract_id) {
+            Some(royalty_override) => royalty_override,
+            None => return 0,
+        };
+        let royalty = bps_of(price, bps).min(available);
+        if royalty > 0 {
+            self.internal_transfer(context.ft_token_id, &receiver_id, royalty);
+            self.log_event(
+                "royalty_override",
+                json!({
+                    "nft_contract_id": nft_contract_id,
+                    "ft_token_id": context.ft_token_id,
+                    "receiver_id": receiver_id,
+                    "amount": U128(royalty),
+                }),
+            );
         }
+        royalty
     }
 
-    pub fn approved_ft_token_ids(&self) -> Vec<AccountId> {
-        self.approved_ft_token_ids.to_vec()
+    /// the royalty internal_pay_creator_royalty would send, without sending it
+    fn internal_creator_royalty(
+        &self,
+        nft_contract_id: &AccountId,
+        price: Balance,
+        treasury_fee: Balance,
+    ) -> Option<(AccountId, Balance)> {
+        self.royalty_overrides
+            .get(nft_contract_id)
+            .or_else(|| self.creator_royalties.get(nft_contract_id))
+            .map(|(receiver_id, bps)| {
+                let royalty = bps_of(price, bps).min(price.saturating_sub(treasury_fee));
+                (receiver_id, royalty)
+            })
     }
 
-    pub fn approved_nft_contract_ids(&self) -> Vec<AccountId> {
-        self.approved_nft_contract_ids.to_vec()
+    /// Records a settled sale and logs it as `nft_sale`, the flat shape NFT
+    /// analytics indexers read: market_type ("sale", "auction" or
+    /// "accept_offer"), nft_contract_id, token_id, owner_id (the seller),
+    /// buyer_id, price and ft_token_id.
+    fn internal_record_last_sale(
+        &mut self,
+        nft_contract_id: &AccountId,
+        token_id: &TokenId,
+        market_type: &str,
+        last_sale: LastSale,
+    ) {
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+        let mut history = self
+            .price_history
+            .get(&contract_and_token_id)
+            .unwrap_or_default();
+        if history.len() == PRICE_HISTORY_LEN {
+            history.remove(0);
+        }
+        history.push((last_sale.price.0, last_sale.sold_at.0));
+        self.price_history.insert(&contract_and_token_id, &history);
+
+        self.log_event(
+            "nft_sale",
+            json!({
+                "market_type": market_type,
+                "nft_contract_id": nft_contract_id,
+                "token_id": token_id,
+                "owner_id": last_sale.seller_id,
+                "buyer_id": last_sale.buyer_id,
+                "price": last_sale.price,
+                "ft_token_id": last_sale.ft_token_id,
+            }),
+        );
+        self.last_sales.insert(&contract_and_token_id, &last_sale);
     }
 
-    pub fn get_owner(&self) -> AccountId {
-        self.owner_id.clone()
+    // only collections with a cooldown need the history
+    fn internal_record_purchase(&mut self, market_data: &MarketData, buyer_id: &AccountId) {
+        if self
+            .resale_cooldowns
+            .contains_key(&market_data.nft_contract_id)
+        {
+            let key = make_triple(
+                &market_data.nft_contract_id,
+                buyer_id,
+                &market_data.token_id,
+            );
+            self.last_purchases.insert(&key, &env::block_timestamp());
+        }
     }
 
-    pub fn get_treasury(&self) -> AccountId {
-        self.treasury_id.clone()
+    fn internal_storage_required(&self, account_id: &AccountId, additional: u64) -> Balance {
+        (self.get_supply_by_owner_id(account_id.clone()).0 + additional) as u128
+            * self.storage_minimum_balance().0
+            + self.storage_extra.get(account_id).unwrap_or(0)
     }
 
-    pub fn get_supply_by_owner_id(&self, account_id: AccountId) -> U64 {
-        self.by_owner_id
-            .get(&account_id)
-            .map_or(0, |by_owner_id| by_owner_id.len())
-            .into()
+    /// A listing is charged the storage it took, measured around its insert,
+    /// and never less than STORAGE_ADD_MARKET_DATA. Auctions, notes and a
+    /// second currency take more; the difference stays on the listing so it
+    /// is released with it.
+    fn internal_charge_listing_storage(
+        &mut self,
+        owner_id: &AccountId,
+        contract_and_token_id: &ContractAndTokenId,
+        initial_storage_usage: StorageUsage,
+    ) {
+        let used = env::storage_usage().saturating_sub(initial_storage_usage);
+        let extra =
+            (used as Balance * env::storage_byte_cost()).saturating_sub(STORAGE_ADD_MARKET_DATA);
+        if extra == 0 {
+            return;
+        }
+
+        if let Some(mut market_data) = self.market.get(contract_and_token_id) {
+            market_data.storage_extra = extra;
+            self.market.insert(contract_and_token_id, &market_data);
+        }
+        let storage_extra = self.storage_extra.get(owner_id).unwrap_or(0);
+        self.storage_extra
+            .insert(owner_id, &storage_extra.saturating_add(extra));
+
+        let paid_storage = self.storage_deposits.get(owner_id).unwrap_or(0);
+        let storage_required = self.internal_storage_required(owner_id, 0);
+        require(
+            paid_storage >= storage_required,
+            ContractError::InsufficientStorage {
+                paid: paid_storage,
+                required: storage_required,
+                rate: STORAGE_ADD_MARKET_DATA,
+            },
+        );
     }
 
-    // private fn
+    fn assert_storage_available(&self, account_id: &AccountId) {
+        if let Err(error) = self.internal_check_storage_available(account_id) {
+            error.panic()
+        }
+    }
 
-    fn assert_owner(&self) {
-        assert_eq!(
-            env::predecessor_account_id(),
-            self.owner_id,
-            "Error: Owner only"
-        )
+    fn internal_check_storage_available(
+        &self,
+        account_id: &AccountId,
+    ) -> Result<(), ContractError> {
+        let storage_amount = self.storage_minimum_balance().0;
+        let paid_storage = self.storage_deposits.get(account_id).unwrap_or(0);
+        let storage_required = self.internal_storage_required(account_id, 1);
+        if paid_storage < storage_required {
+            return Err(ContractError::InsufficientStorage {
+                paid: paid_storage,
+                required: storage_required,
+                rate: storage_amount,
+            });
+        }
+        Ok(())
     }
 }
 
@@ -1362,12 +6434,14 @@ pub fn to_sec(timestamp: Timestamp) -> TimestampSec {
 }
 
 #[ext_contract(ext_self)]
-trait ExtSelf {
+pub trait ExtSelf {
     fn resolve_purchase(
         &mut self,
         buyer_id: AccountId,
         market_data: MarketData,
         price: U128,
+        losing_bids: Option<Bids>,
+        escrowed: bool,
     ) -> Promise;
 
     fn resolve_offer(
@@ -1375,23 +6449,266 @@ trait ExtSelf {
         seller_id: AccountId,
         offer_data: OfferData,
         token_id: TokenId,
+        fee_recipient: Option<AccountId>,
     ) -> Promise;
+
+    fn resolve_approve_nft_contracts(&mut self, nft_contract_ids: Vec<AccountId>) -> U64;
+
+    fn resolve_find_stale_listings(
+        &self,
+        nft_contract_id: AccountId,
+        token_ids: Vec<TokenId>,
+    ) -> Vec<TokenId>;
+
+    fn resolve_accept_bid(
+        &mut self,
+        seller_id: AccountId,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        listing_nonce: U64,
+    ) -> bool;
+
+    fn resolve_refund(
+        &mut self,
+        account_id: AccountId,
+        amount: U128,
+        ft_token_id: Option<AccountId>,
+    ) -> bool;
+
+    fn resolve_escheat_offer(
+        &mut self,
+        nft_contract_id: AccountId,
+        buyer_id: AccountId,
+        token_id: TokenId,
+        amount: U128,
+    ) -> bool;
+
+    fn resolve_usd_floor(
+        &mut self,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        ft_token_id: AccountId,
+        floor_usd_cents: U128,
+    ) -> Option<U128>;
 }
 
-fn add_accounts(accounts: Option<Vec<AccountId>>, set: &mut UnorderedSet<AccountId>) {
-    accounts.map(|ids| {
-        ids.iter().for_each(|id| {
-            set.insert(id);
-        })
-    });
+fn internal_transfer_payout(
+    market_data: MarketData,
+    buyer_id: AccountId,
+    receiver_id: Option<AccountId>,
+    price: u128,
+    losing_bids: Option<Bids>,
+    transfer_only: bool,
+    escrowed: bool,
+) -> Promise {
+    let resolve_gas = if losing_bids.as_ref().is_some_and(|bids| !bids.is_empty()) {
+        GAS_FOR_AUCTION_ROYALTIES
+    } else if market_data.ft_token_id.as_str() != NEAR {
+        GAS_FOR_FT_ROYALTIES
+    } else {
+        GAS_FOR_ROYALTIES
+    };
+
+    nft_transfer_for_sale(
+        market_data.nft_contract_id.clone(),
+        receiver_id.unwrap_or_else(|| buyer_id.clone()),
+        market_data.token_id.clone(),
+        market_data.approval_id,
+        price,
+        transfer_only,
+    )
+    .then(
+        ext_self::ext(env::current_account_id())
+            .with_attached_deposit(NO_DEPOSIT)
+            .with_static_gas(resolve_gas)
+            .resolve_purchase(buyer_id, market_data, price.into(), losing_bids, escrowed),
+    )
 }
 
-fn remove_accounts(accounts: Option<Vec<AccountId>>, set: &mut UnorderedSet<AccountId>) {
-    accounts.map(|ids| {
-        ids.iter().for_each(|id| {
-            set.remove(id);
-        })
-    });
+/// nft_transfer_payout, or nft_transfer for a transfer_only collection. The
+/// resolve callbacks tell a failed transfer from a successful one without a
+/// usable payout, so nft_transfer's empty result still pays the seller.
+fn nft_transfer_for_sale(
+    nft_contract_id: AccountId,
+    receiver_id: AccountId,
+    token_id: TokenId,
+    approval_id: u64,
+    price: u128,
+    transfer_only: bool,
+) -> Promise {
+    let nft_contract = ext_contract::ext(nft_contract_id)
+        .with_attached_deposit(1)
+        .with_static_gas(GAS_FOR_NFT_TRANSFER);
+    if transfer_only {
+        nft_contract.nft_transfer(
+            receiver_id,
+            token_id,
+            Some(approval_id),
+            Some(sale_memo(price)),
+        )
+    } else {
+        nft_contract.nft_transfer_payout(
+            receiver_id,
+            token_id,
+            Some(approval_id),
+            Some(sale_memo(price)),
+            Some(price.into()),
+            Some(10u32), // max length payout
+        )
+    }
+}
+
+/// What resolve_purchase returns once the NFT is transferred: the price for a
+/// NEAR sale, for an FT sale the unused amount ft_resolve_transfer refunds.
+fn purchase_result(market_data: &MarketData, price: U128) -> U128 {
+    if market_data.ft_token_id == near_account() {
+        price
+    } else {
+        U128(0)
+    }
+}
+
+/// `bps` of `amount` rounded down, split so the multiply can't overflow near u128::MAX
+fn bps_of(amount: Balance, bps: u16) -> Balance {
+    let bps = bps as u128;
+    amount / 10_000 * bps + amount % 10_000 * bps / 10_000
+}
+
+/// `fee` bps of `price`, rounded up with `round_up` but never above the price
+fn treasury_fee_of(price: Balance, fee: u16, round_up: bool) -> Balance {
+    let treasury_fee = bps_of(price, fee);
+    let exact = (price % 10_000 * fee as u128).is_multiple_of(10_000);
+    if round_up && !exact {
+        (treasury_fee + 1).min(price)
+    } else {
+        treasury_fee
+    }
+}
+
+fn auction_end_key(
+    market_data: &MarketData,
+    contract_and_token_id: &ContractAndTokenId,
+) -> Option<(u64, ContractAndTokenId)> {
+    match (market_data.is_auction, market_data.ended_at) {
+        (Some(true), Some(ended_at)) => Some((ended_at, contract_and_token_id.clone())),
+        _ => None,
+    }
+}
+
+fn assert_min_price(price: Balance, free_listing: bool) {
+    require(
+        price >= MIN_PRICE || free_listing,
+        ContractError::PriceTooLow { min: MIN_PRICE },
+    );
+}
+
+fn assert_valid_seller_note(seller_note: Option<&str>) {
+    if let Some(seller_note) = seller_note {
+        require(
+            seller_note.chars().count() <= MAX_SELLER_NOTE_LEN,
+            ContractError::SellerNoteTooLong {
+                max: MAX_SELLER_NOTE_LEN as u64,
+            },
+        );
+    }
+}
+
+/// keys join ids with DELIMETER, a token_id containing it could address another entry
+fn assert_valid_token_id(token_id: &str) {
+    require(!token_id.contains(DELIMETER), ContractError::InvalidTokenId);
+}
+
+fn assert_valid_time_window(started_at: Option<U64>, ended_at: Option<U64>) {
+    let current_time: u64 = env::block_timestamp();
+
+    if let Some(started_at) = started_at {
+        require(
+            started_at.0 >= current_time,
+            ContractError::InvalidStartedAt,
+        );
+
+        if let Some(ended_at) = ended_at {
+            require(started_at.0 < ended_at.0, ContractError::InvalidTimeRange);
+        }
+    }
+
+    if let Some(ended_at) = ended_at {
+        require(ended_at.0 >= current_time, ContractError::InvalidEndedAt);
+    }
+}
+
+fn assert_auction_duration_bounds(min_seconds: u64, max_seconds: u64) {
+    require(
+        max_seconds == 0 || min_seconds <= max_seconds,
+        ContractError::InvalidAuctionDurationBounds,
+    );
+}
+
+/// `is_auction` may only change while the listing holds no bids.
+fn assert_auction_transition(current: &MarketData, next: &MarketData) {
+    if current.is_auction.unwrap_or(false) != next.is_auction.unwrap_or(false) {
+        let has_bids = current.bids.as_ref().is_some_and(|bids| !bids.is_empty());
+        require(!has_bids, ContractError::AuctionModeLocked);
+    }
+}
+
+/// memo forwarded to nft_transfer_payout so the NFT transfer event links back to the sale
+fn sale_memo(price: u128) -> String {
+    json!({
+        "marketplace_id": env::current_account_id(),
+        "price": U128(price),
+    })
+    .to_string()
+}
+
+fn format_amount(amount: u128, decimals: u8) -> String {
+    let unit = 10u128.pow(decimals as u32);
+    let whole = amount / unit;
+    let fraction = amount % unit;
+    if fraction == 0 {
+        return whole.to_string();
+    }
+    let fraction = format!("{:0width$}", fraction, width = decimals as usize);
+    format!("{}.{}", whole, fraction.trim_end_matches('0'))
+}
+
+// both return (changed, ignored)
+fn add_accounts(
+    accounts: Option<Vec<AccountId>>,
+    set: &mut UnorderedSet<AccountId>,
+) -> (Vec<AccountId>, Vec<AccountId>) {
+    accounts
+        .unwrap_or_default()
+        .into_iter()
+        .partition(|id| set.insert(id))
+}
+
+fn remove_accounts(
+    accounts: Option<Vec<AccountId>>,
+    set: &mut UnorderedSet<AccountId>,
+) -> (Vec<AccountId>, Vec<AccountId>) {
+    accounts
+        .unwrap_or_default()
+        .into_iter()
+        .partition(|id| set.remove(id))
+}
+
+/// Parses an nft_transfer_payout result, either `{"payout": {..}}` or the
+/// bare map. The shares must not exceed `price` and may come short of it by
+/// at most `tolerance` yocto, anything else is None.
+pub fn validate_payout(value: &[u8], price: u128, tolerance: u128) -> Option<PayoutHashMap> {
+    let payout = near_sdk::serde_json::from_slice::<PayoutHashMap>(value)
+        .or_else(|_| near_sdk::serde_json::from_slice::<Payout>(value).map(|p| p.payout))
+        .ok()?;
+    let mut remainder = price;
+    for value in payout.values() {
+        remainder = remainder.checked_sub(value.0)?;
+    }
+    if remainder <= tolerance {
+        Some(payout)
+    } else {
+        None
+    }
 }
 
 fn make_triple(nft_contract_id: &AccountId, buyer_id: &AccountId, token: &str) -> String {
@@ -1399,4 +6716,4 @@ fn make_triple(nft_contract_id: &AccountId, buyer_id: &AccountId, token: &str) -
         "{}{}{}{}{}",
         nft_contract_id, DELIMETER, buyer_id, DELIMETER, token
     )
-}
\ No newline at end of file
+}
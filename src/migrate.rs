@@ -0,0 +1,262 @@
+use crate::*;
+
+// The contract as first deployed, before any of the fields appended since.
+// migrate reads it over the stored state and fills the rest with the values
+// new would give them, the collections keep their prefixes and entries.
+// Listings and offers were stored untagged and stay where they are: the maps
+// read them as V0 (see versioned.rs), and migrate_listings and
+// migrate_offers rewrite them as Current in batches and add them to the
+// indexes listing and offering now fill. Until then a V0 listing is found by
+// its key but not through get_markets or the collection and auction indexes.
+
+/// Bid as stored by the first deployment
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct BidV0 {
+    pub bidder_id: AccountId,
+    pub price: U128,
+}
+
+/// MarketData as stored by the first deployment, untagged
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct MarketDataV0 {
+    pub owner_id: AccountId,
+    pub approval_id: u64,
+    pub nft_contract_id: AccountId,
+    pub token_id: TokenId,
+    pub ft_token_id: AccountId,
+    pub price: u128,
+    pub bids: Option<Vec<BidV0>>,
+    pub started_at: Option<u64>,
+    pub ended_at: Option<u64>,
+    pub is_auction: Option<bool>,
+}
+
+impl MarketDataV0 {
+    pub(crate) fn into_current(self, listing_nonce: u64) -> MarketData {
+        let bids = self.bids.map(|bids| {
+            bids.into_iter()
+                .map(|bid| Bid {
+                    bidder_id: bid.bidder_id,
+                    price: bid.price,
+                    placed_at: U64(0),
+                    recipient: None,
+                })
+                .collect()
+        });
+        MarketData {
+            owner_id: self.owner_id,
+            approval_id: self.approval_id,
+            nft_contract_id: self.nft_contract_id,
+            token_id: self.token_id,
+            ft_token_id: self.ft_token_id,
+            price: self.price,
+            bids,
+            started_at: self.started_at,
+            ended_at: self.ended_at,
+            is_auction: self.is_auction,
+            alt_ft_token_id: None,
+            alt_price: None,
+            min_participants: None,
+            fee_recipient: None,
+            last_updated_at: None,
+            started_emitted: false,
+            seller_note: None,
+            min_offer_price: None,
+            listing_nonce,
+            // listed before the minimum price
+            free_listing: self.price < MIN_PRICE,
+            storage_extra: 0,
+            // every bid stayed escrowed until the auction settled
+            escrow_model: EscrowModel::HoldUntilSettlement,
+            buyout_price: None,
+            start_price: None,
+            reserve_price: None,
+        }
+    }
+}
+
+/// OfferData as stored by the first deployment, untagged
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct OfferDataV0 {
+    pub buyer_id: AccountId,
+    pub nft_contract_id: AccountId,
+    pub token_id: TokenId,
+    pub ft_token_id: AccountId,
+    pub price: u128,
+}
+
+impl OfferDataV0 {
+    /// The offer's age is not known, its dormancy counts from `created_at`
+    pub(crate) fn into_current(self, created_at: u64) -> OfferData {
+        OfferData {
+            buyer_id: self.buyer_id,
+            nft_contract_id: self.nft_contract_id,
+            token_id: self.token_id,
+            ft_token_id: self.ft_token_id,
+            price: self.price,
+            nonce: None,
+            created_at,
+            expires_at: None,
+        }
+    }
+}
+
+/// Contract state as stored by the first deployment
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct ContractV0 {
+    pub owner_id: AccountId,
+    pub treasury_id: AccountId,
+    pub market: UnorderedMap<ContractAndTokenId, MarketDataV0>,
+    pub approved_ft_token_ids: UnorderedSet<AccountId>,
+    pub approved_nft_contract_ids: UnorderedSet<AccountId>,
+    pub storage_deposits: LookupMap<AccountId, Balance>,
+    pub by_owner_id: LookupMap<AccountId, UnorderedSet<TokenId>>,
+    pub offers: UnorderedMap<ContractAccountIdTokenId, OfferDataV0>,
+    pub transaction_fee: u16,
+}
+
+/// A V0 listing's listing_nonce, taken from its key so it reads the same
+/// before and after migrate_listings. The top bit keeps it clear of the
+/// nonces next_listing_nonce hands out.
+pub fn legacy_listing_nonce(contract_and_token_id: &ContractAndTokenId) -> u64 {
+    let hash = env::sha256(contract_and_token_id.as_bytes());
+    let mut nonce = [0u8; 8];
+    nonce.copy_from_slice(&hash[..8]);
+    u64::from_le_bytes(nonce) | 1 << 63
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct MigrationProgress {
+    pub next_index: Option<U64>, // None once the map was walked to its end
+    pub remaining: U64,          // V0 entries still to rewrite
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Upgrades the first deployment's state, call it with the deploy of
+    /// this code. Listings and offers are left for migrate_listings and
+    /// migrate_offers.
+    #[private]
+    #[init(ignore_state)]
+    pub fn migrate() -> Self {
+        let old: ContractV0 =
+            env::state_read().unwrap_or_else(|| ContractError::NoStateToMigrate.panic());
+
+        Self {
+            market: MarketMap::from_legacy(old.market),
+            approved_ft_token_ids: old.approved_ft_token_ids,
+            approved_nft_contract_ids: old.approved_nft_contract_ids,
+            storage_deposits: old.storage_deposits,
+            by_owner_id: old.by_owner_id,
+            offers: OfferMap::from_legacy(old.offers, env::block_timestamp()),
+            transaction_fee: old.transaction_fee,
+            ..Self::with_defaults(old.owner_id, old.treasury_id)
+        }
+    }
+
+    /// Rewrites the V0 listings among up to `limit` entries of the listings
+    /// map from `from_index` and indexes them. A listing removed meanwhile
+    /// moves the map's last entry into its slot, so when next_index is None
+    /// while some remain, walk the map again from 0.
+    #[payable]
+    pub fn migrate_listings(&mut self, from_index: Option<U64>, limit: u64) -> MigrationProgress {
+        assert_one_yocto();
+        self.assert_owner();
+        let start = from_index.map_or(0, |x| x.0);
+        let end = start.saturating_add(limit).min(self.market.len());
+        for index in start..end {
+            let key = self.market.listings.keys_as_vector().get(index).unwrap();
+            let versioned = self.market.listings.get(&key).unwrap();
+            if !versioned.is_current() {
+                let market_data = versioned.into_current(&key);
+                self.market.insert(&key, &market_data);
+                self.internal_index_listing(&key, &market_data);
+            }
+        }
+        MigrationProgress {
+            next_index: (end < self.market.len()).then(|| end.into()),
+            remaining: self.market.legacy_len.into(),
+        }
+    }
+
+    /// migrate_listings for the offers map
+    #[payable]
+    pub fn migrate_offers(&mut self, from_index: Option<U64>, limit: u64) -> MigrationProgress {
+        assert_one_yocto();
+        self.assert_owner();
+        let start = from_index.map_or(0, |x| x.0);
+        let end = start.saturating_add(limit).min(self.offers.len());
+        for index in start..end {
+            let key = self.offers.offers.keys_as_vector().get(index).unwrap();
+            let versioned = self.offers.offers.get(&key).unwrap();
+            if !versioned.is_current() {
+                let offer_data = versioned.into_current(self.offers.migrated_at);
+                self.offers.insert(&key, &offer_data);
+                self.internal_index_offer(&key, &offer_data);
+            }
+        }
+        MigrationProgress {
+            next_index: (end < self.offers.len()).then(|| end.into()),
+            remaining: self.offers.legacy_len.into(),
+        }
+    }
+}
+
+impl Contract {
+    /// Indexes a rewritten V0 listing the way internal_add_market_data does.
+    /// by_owner_id already held it.
+    pub(crate) fn internal_index_listing(
+        &mut self,
+        contract_and_token_id: &ContractAndTokenId,
+        market_data: &MarketData,
+    ) {
+        let nft_contract_id = &market_data.nft_contract_id;
+        let mut listing_keys = self
+            .by_nft_contract
+            .get(nft_contract_id)
+            .unwrap_or_else(|| {
+                UnorderedSet::new(
+                    StorageKey::ByNFTContractInner {
+                        account_id_hash: hash_account_id(nft_contract_id),
+                    }
+                    .try_to_vec()
+                    .unwrap(),
+                )
+            });
+        listing_keys.insert(contract_and_token_id);
+        self.by_nft_contract.insert(nft_contract_id, &listing_keys);
+
+        if let Some(end_key) = auction_end_key(market_data, contract_and_token_id) {
+            self.auction_ends.insert(&end_key, &());
+        }
+        self.market_index_positions
+            .insert(contract_and_token_id, &self.market_index.len());
+        self.market_index.push(contract_and_token_id);
+    }
+
+    /// Indexes a rewritten V0 offer by collection. by_owner_id already held
+    /// it.
+    fn internal_index_offer(
+        &mut self,
+        contract_account_id_token_id: &ContractAccountIdTokenId,
+        offer_data: &OfferData,
+    ) {
+        let nft_contract_id = &offer_data.nft_contract_id;
+        let mut offer_keys = self
+            .by_nft_contract_offers
+            .get(nft_contract_id)
+            .unwrap_or_else(|| {
+                UnorderedSet::new(
+                    StorageKey::ByNFTContractOffersInner {
+                        account_id_hash: hash_account_id(nft_contract_id),
+                    }
+                    .try_to_vec()
+                    .unwrap(),
+                )
+            });
+        offer_keys.insert(contract_account_id_token_id);
+        self.by_nft_contract_offers
+            .insert(nft_contract_id, &offer_keys);
+    }
+}
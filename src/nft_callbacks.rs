@@ -5,6 +5,7 @@ use crate::*;
 #[derive(Serialize, Deserialize)]
 #[serde(crate = "near_sdk::serde")]
 pub struct MarketArgs {
+    #[serde(alias = "action")]
     pub market_type: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub price: Option<U128>,
@@ -18,9 +19,33 @@ pub struct MarketArgs {
     pub ended_at: Option<U64>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub is_auction: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fallback_to_sale: Option<bool>, // accept_best_offer without offers lists instead
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_accept_price: Option<U128>, // offers below this are declined
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub alt_ft_token_id: Option<AccountId>, // sale also settles in this currency
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub alt_price: Option<U128>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_participants: Option<u32>, // auction only
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fee_recipient: Option<AccountId>, // transaction fee goes here instead of the treasury
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seller_note: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub free_listing: Option<bool>, // allows a price of 0
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub escrow_model: Option<EscrowModel>, // auction only, hold_until_settlement by default
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub buyout_price: Option<U128>, // auction only, buy_now ends the auction at this price
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub start_price: Option<U128>, // Dutch listing, declines to price from started_at to ended_at
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reserve_price: Option<U128>, // auction only, lower bids can't win
 }
 
-trait NonFungibleTokenApprovalsReceiver {
+pub trait NonFungibleTokenApprovalsReceiver {
     fn nft_on_approve(
         &mut self,
         token_id: TokenId,
@@ -43,74 +68,146 @@ impl NonFungibleTokenApprovalsReceiver for Contract {
 
         let nft_contract_id = env::predecessor_account_id();
         let signer_id = env::signer_account_id();
-        assert_ne!(
-            env::current_account_id(), nft_contract_id,
-            "Error: nft_on_approve should only be called via cross-contract call"
+        require(
+            env::current_account_id() != nft_contract_id,
+            ContractError::NotCrossContractCall,
         );
-        assert_eq!(owner_id, signer_id, "Error: owner_id should be signer_id");
+        require(owner_id == signer_id, ContractError::OwnerNotSigner);
 
-        assert!(
-            self.approved_nft_contract_ids.contains(&nft_contract_id),
-            "Error: nft_contract_id is not approved"
+        require(
+            self.is_nft_contract_allowed(&nft_contract_id),
+            ContractError::NftContractNotApproved,
         );
 
-        let MarketArgs {
-            market_type,
-            price,
-            ft_token_id,
-            buyer_id,
-            started_at,
-            ended_at,
-            is_auction
-        } = near_sdk::serde_json::from_str(&msg).expect("Not valid MarketArgs");
-
-        if market_type == "sale" {
-            assert!(price.is_some(), "Error: price not specified");
-
-            self.internal_delete_market_data(&nft_contract_id, &token_id);
-
-            let storage_amount = self.storage_minimum_balance().0;
-            let owner_paid_storage = self.storage_deposits.get(&signer_id).unwrap_or(0);
-            let signer_storage_required =
-                (self.get_supply_by_owner_id(signer_id).0 + 1) as u128 * storage_amount;
-
-            assert!(
-                owner_paid_storage >= signer_storage_required,
-                "Insufficient storage paid: {}, for {} sales at {} rate of per sale",
-                owner_paid_storage,
-                signer_storage_required / storage_amount,
-                storage_amount,
-            );
-
-            let ft_token_id_res = ft_token_id.unwrap_or(near_account());
+        let args: MarketArgs = near_sdk::serde_json::from_str(&msg)
+            .unwrap_or_else(|_| ContractError::InvalidMarketArgs.panic());
 
-            if self.approved_ft_token_ids.contains(&ft_token_id_res) != true {
-                env::panic_str(&"Error: ft_token_id not approved");
-            }
-
-            self.internal_add_market_data(
+        if args.market_type == "sale" {
+            self.internal_list_approved_token(
                 owner_id,
                 approval_id,
                 nft_contract_id,
                 token_id,
-                ft_token_id_res,
-                price.unwrap(),
-                started_at,
-                ended_at,
-                is_auction,
+                args,
             );
-        } else if market_type == "accept_offer" {
-            assert!(buyer_id.is_some(), "Error: Account id is not specified");
-            assert!(price.is_some(), "Error: Price is not specified (for check)");
+        } else if args.market_type == "accept_offer" {
+            let buyer_id = args
+                .buyer_id
+                .unwrap_or_else(|| ContractError::BuyerNotSpecified.panic());
+            let price = args
+                .price
+                .unwrap_or_else(|| ContractError::PriceNotSpecified.panic());
+            assert_min_accept_price(price.0, args.min_accept_price);
 
             self.internal_accept_offer(
                 nft_contract_id,
-                buyer_id.unwrap(),
+                buyer_id,
                 token_id,
                 owner_id,
                 approval_id,
-                price.unwrap().0,
+                price.0,
+                args.fee_recipient,
             );
+        } else if args.market_type == "accept_best_offer" {
+            let ft_token_id = args.ft_token_id.clone().unwrap_or_else(near_account);
+            match self.internal_get_best_offer(&nft_contract_id, &token_id, &ft_token_id) {
+                Some(offer) => {
+                    assert_min_accept_price(offer.price, args.min_accept_price);
+                    self.internal_accept_offer(
+                        nft_contract_id,
+                        offer.buyer_id,
+                        token_id,
+                        owner_id,
+                        approval_id,
+                        offer.price,
+                        args.fee_recipient,
+                    );
+                }
+                None => {
+                    require(
+                        args.fallback_to_sale == Some(true),
+                        ContractError::OfferNotFound,
+                    );
+                    self.internal_list_approved_token(
+                        owner_id,
+                        approval_id,
+                        nft_contract_id,
+                        token_id,
+                        args,
+                    );
+                }
+            }
         }
     }
 }
+
+impl Contract {
+    pub(crate) fn internal_list_approved_token(
+        &mut self,
+        owner_id: AccountId,
+        approval_id: u64,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        args: MarketArgs,
+    ) {
+        let price = args
+            .price
+            .unwrap_or_else(|| ContractError::PriceNotSpecified.panic());
+
+        self.internal_delete_market_data(&nft_contract_id, &token_id);
+
+        self.assert_storage_available(&owner_id);
+
+        let ft_token_id_res = args.ft_token_id.unwrap_or_else(near_account);
+
+        require(
+            self.approved_ft_token_ids.contains(&ft_token_id_res),
+            ContractError::FtTokenNotApproved,
+        );
+
+        let alt_currency = match (args.alt_ft_token_id, args.alt_price) {
+            (Some(alt_ft_token_id), Some(alt_price)) => {
+                require(
+                    self.approved_ft_token_ids.contains(&alt_ft_token_id),
+                    ContractError::FtTokenNotApproved,
+                );
+                Some((alt_ft_token_id, alt_price))
+            }
+            (None, None) => None,
+            _ => ContractError::InvalidAltCurrency.panic(),
+        };
+
+        self.internal_add_market_data(
+            owner_id,
+            approval_id,
+            nft_contract_id,
+            token_id,
+            ft_token_id_res,
+            price,
+            args.started_at,
+            args.ended_at,
+            args.is_auction,
+            alt_currency,
+            args.min_participants,
+            args.fee_recipient,
+            args.seller_note,
+            args.free_listing == Some(true),
+            args.escrow_model.unwrap_or_default(),
+            args.buyout_price,
+            args.start_price,
+            args.reserve_price,
+            "add_market_data",
+        );
+    }
+}
+
+fn assert_min_accept_price(offer_price: u128, min_accept_price: Option<U128>) {
+    if let Some(min_accept_price) = min_accept_price {
+        require(
+            offer_price >= min_accept_price.0,
+            ContractError::OfferBelowMinimum {
+                min: min_accept_price.0,
+            },
+        );
+    }
+}
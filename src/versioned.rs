@@ -0,0 +1,250 @@
+use crate::*;
+use near_sdk::IntoStorageKey;
+use std::io;
+
+// Listings are stored as VersionedMarketData so a new MarketData field only
+// needs a new variant instead of migrating every listing. Older variants are
+// upgraded when they are read and written back as the current one the next
+// time the listing is saved. Offers are stored the same way.
+//
+// The first deployment stored listings and offers untagged. They start with
+// the length of an account id, at least 2 as a little endian u32, so a first
+// byte of 0 or 1 can only be a tag: those are read as the tagged variants and
+// anything else as V0. migrate_listings and migrate_offers rewrite the V0
+// entries as Current, until then they are upgraded on every read.
+
+/// Current is tag 0. When a MarketData field is added after a shape has
+/// shipped, that shape gets the next tag and a conversion to Current; tags
+/// above 1 would be read as V0.
+#[allow(clippy::large_enum_variant)]
+pub enum VersionedMarketData {
+    Current(MarketData),
+    V0(MarketDataV0), // untagged
+}
+
+impl VersionedMarketData {
+    pub fn is_current(&self) -> bool {
+        matches!(self, VersionedMarketData::Current(_))
+    }
+
+    /// A V0 listing's listing_nonce comes from its key, see legacy_listing_nonce
+    pub fn into_current(self, key: &ContractAndTokenId) -> MarketData {
+        match self {
+            VersionedMarketData::Current(market_data) => market_data,
+            VersionedMarketData::V0(v0) => v0.into_current(legacy_listing_nonce(key)),
+        }
+    }
+}
+
+impl BorshSerialize for VersionedMarketData {
+    fn serialize<W: io::Write>(&self, writer: &mut W) -> io::Result<()> {
+        match self {
+            VersionedMarketData::Current(market_data) => {
+                BorshSerialize::serialize(&0u8, writer)?;
+                BorshSerialize::serialize(market_data, writer)
+            }
+            VersionedMarketData::V0(v0) => BorshSerialize::serialize(v0, writer),
+        }
+    }
+}
+
+impl BorshDeserialize for VersionedMarketData {
+    fn deserialize(buf: &mut &[u8]) -> io::Result<Self> {
+        if buf.first() == Some(&0) {
+            *buf = &buf[1..];
+            return Ok(VersionedMarketData::Current(BorshDeserialize::deserialize(
+                buf,
+            )?));
+        }
+        Ok(VersionedMarketData::V0(BorshDeserialize::deserialize(buf)?))
+    }
+}
+
+/// The listings map, reads and writes the current MarketData
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct MarketMap {
+    pub listings: UnorderedMap<ContractAndTokenId, VersionedMarketData>,
+    pub legacy_len: u64, // V0 listings not rewritten yet
+}
+
+impl MarketMap {
+    pub fn new<S: IntoStorageKey>(prefix: S) -> Self {
+        MarketMap {
+            listings: UnorderedMap::new(prefix),
+            legacy_len: 0,
+        }
+    }
+
+    /// The first deployment's map, read in place. UnorderedMap's state
+    /// doesn't depend on its value type and every value reads as V0.
+    pub fn from_legacy(legacy: UnorderedMap<ContractAndTokenId, MarketDataV0>) -> Self {
+        MarketMap {
+            legacy_len: legacy.len(),
+            listings: UnorderedMap::try_from_slice(&legacy.try_to_vec().unwrap()).unwrap(),
+        }
+    }
+
+    pub fn get(&self, key: &ContractAndTokenId) -> Option<MarketData> {
+        self.listings
+            .get(key)
+            .map(|versioned| versioned.into_current(key))
+    }
+
+    /// True when this rewrote a V0 listing, which the indexes don't hold yet
+    pub fn insert(&mut self, key: &ContractAndTokenId, market_data: &MarketData) -> bool {
+        let replaced = self
+            .listings
+            .insert(key, &VersionedMarketData::Current(market_data.clone()));
+        let legacy = replaced.is_some_and(|versioned| !versioned.is_current());
+        if legacy {
+            self.legacy_len -= 1;
+        }
+        legacy
+    }
+
+    pub fn remove(&mut self, key: &ContractAndTokenId) -> Option<MarketData> {
+        let versioned = self.listings.remove(key)?;
+        if !versioned.is_current() {
+            self.legacy_len -= 1;
+        }
+        Some(versioned.into_current(key))
+    }
+
+    pub fn len(&self) -> u64 {
+        self.listings.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.listings.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (ContractAndTokenId, MarketData)> + '_ {
+        self.listings.iter().map(|(key, versioned)| {
+            let market_data = versioned.into_current(&key);
+            (key, market_data)
+        })
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = MarketData> + '_ {
+        self.iter().map(|(_, market_data)| market_data)
+    }
+}
+
+/// Tagged like VersionedMarketData
+pub enum VersionedOfferData {
+    Current(OfferData),
+    V0(OfferDataV0), // untagged
+}
+
+impl VersionedOfferData {
+    pub fn is_current(&self) -> bool {
+        matches!(self, VersionedOfferData::Current(_))
+    }
+
+    /// A V0 offer's dormancy counts from `migrated_at`
+    pub fn into_current(self, migrated_at: u64) -> OfferData {
+        match self {
+            VersionedOfferData::Current(offer_data) => offer_data,
+            VersionedOfferData::V0(v0) => v0.into_current(migrated_at),
+        }
+    }
+}
+
+impl BorshSerialize for VersionedOfferData {
+    fn serialize<W: io::Write>(&self, writer: &mut W) -> io::Result<()> {
+        match self {
+            VersionedOfferData::Current(offer_data) => {
+                BorshSerialize::serialize(&0u8, writer)?;
+                BorshSerialize::serialize(offer_data, writer)
+            }
+            VersionedOfferData::V0(v0) => BorshSerialize::serialize(v0, writer),
+        }
+    }
+}
+
+impl BorshDeserialize for VersionedOfferData {
+    fn deserialize(buf: &mut &[u8]) -> io::Result<Self> {
+        if buf.first() == Some(&0) {
+            *buf = &buf[1..];
+            return Ok(VersionedOfferData::Current(BorshDeserialize::deserialize(
+                buf,
+            )?));
+        }
+        Ok(VersionedOfferData::V0(BorshDeserialize::deserialize(buf)?))
+    }
+}
+
+/// The offers map, reads and writes the current OfferData
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct OfferMap {
+    pub offers: UnorderedMap<ContractAccountIdTokenId, VersionedOfferData>,
+    pub legacy_len: u64,  // V0 offers not rewritten yet
+    pub migrated_at: u64, // when migrate ran, 0 for a new contract
+}
+
+impl OfferMap {
+    pub fn new<S: IntoStorageKey>(prefix: S) -> Self {
+        OfferMap {
+            offers: UnorderedMap::new(prefix),
+            legacy_len: 0,
+            migrated_at: 0,
+        }
+    }
+
+    /// The first deployment's map, read in place like MarketMap::from_legacy
+    pub fn from_legacy(
+        legacy: UnorderedMap<ContractAccountIdTokenId, OfferDataV0>,
+        migrated_at: u64,
+    ) -> Self {
+        OfferMap {
+            legacy_len: legacy.len(),
+            offers: UnorderedMap::try_from_slice(&legacy.try_to_vec().unwrap()).unwrap(),
+            migrated_at,
+        }
+    }
+
+    pub fn get(&self, key: &ContractAccountIdTokenId) -> Option<OfferData> {
+        self.offers
+            .get(key)
+            .map(|versioned| versioned.into_current(self.migrated_at))
+    }
+
+    /// True when this rewrote a V0 offer, which the indexes don't hold yet
+    pub fn insert(&mut self, key: &ContractAccountIdTokenId, offer_data: &OfferData) -> bool {
+        let replaced = self
+            .offers
+            .insert(key, &VersionedOfferData::Current(offer_data.clone()));
+        let legacy = replaced.is_some_and(|versioned| !versioned.is_current());
+        if legacy {
+            self.legacy_len -= 1;
+        }
+        legacy
+    }
+
+    pub fn remove(&mut self, key: &ContractAccountIdTokenId) -> Option<OfferData> {
+        let versioned = self.offers.remove(key)?;
+        if !versioned.is_current() {
+            self.legacy_len -= 1;
+        }
+        Some(versioned.into_current(self.migrated_at))
+    }
+
+    pub fn len(&self) -> u64 {
+        self.offers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.offers.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (ContractAccountIdTokenId, OfferData)> + '_ {
+        let migrated_at = self.migrated_at;
+        self.offers
+            .iter()
+            .map(move |(key, versioned)| (key, versioned.into_current(migrated_at)))
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = OfferData> + '_ {
+        self.iter().map(|(_, offer_data)| offer_data)
+    }
+}
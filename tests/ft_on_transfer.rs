@@ -0,0 +1,105 @@
+//! ft_on_transfer message parsing and refunds.
+
+use astro_marketplace_contract::{Contract, FtAction, FtMessage, FungibleTokenReceiver};
+use near_sdk::json_types::U128;
+use near_sdk::serde_json::json;
+use near_sdk::test_utils::{get_logs, VMContextBuilder};
+use near_sdk::{testing_env, AccountId, PromiseOrValue};
+
+fn nft() -> AccountId {
+    "nft.near".parse().unwrap()
+}
+
+fn message(action: &str) -> String {
+    json!({
+        "version": "1",
+        "action": action,
+        "nft_contract_id": nft(),
+        "token_id": "1",
+    })
+    .to_string()
+}
+
+#[test]
+fn parses_each_v1_action() {
+    let token = || (nft(), "1".to_string());
+    let cases = [
+        (
+            "buy",
+            FtAction::Buy {
+                nft_contract_id: token().0,
+                token_id: token().1,
+            },
+        ),
+        (
+            "add_bid",
+            FtAction::AddBid {
+                nft_contract_id: token().0,
+                token_id: token().1,
+            },
+        ),
+        (
+            "add_offer",
+            FtAction::AddOffer {
+                nft_contract_id: token().0,
+                token_id: token().1,
+            },
+        ),
+    ];
+    for (action, expected) in cases {
+        assert_eq!(
+            FtMessage::parse(&message(action)),
+            Ok(FtMessage::V1(expected))
+        );
+    }
+}
+
+#[test]
+fn parses_market_type_as_the_action() {
+    let expected = Ok(FtMessage::V1(FtAction::Buy {
+        nft_contract_id: nft(),
+        token_id: "1".to_string(),
+    }));
+    let versioned = json!({
+        "version": "1",
+        "market_type": "buy",
+        "nft_contract_id": nft(),
+        "token_id": "1",
+    });
+    assert_eq!(FtMessage::parse(&versioned.to_string()), expected);
+    let unversioned = json!({
+        "market_type": "buy",
+        "nft_contract_id": nft(),
+        "token_id": "1",
+    });
+    assert_eq!(FtMessage::parse(&unversioned.to_string()), expected);
+}
+
+#[test]
+fn rejects_unknown_versions_and_actions() {
+    assert!(FtMessage::parse(&message("burn")).is_err());
+    let v2 = message("buy").replace("\"1\"", "\"2\"");
+    assert!(FtMessage::parse(&v2).is_err());
+    assert!(FtMessage::parse("buy").is_err());
+}
+
+#[test]
+fn malformed_message_is_refunded_in_full() {
+    let market: AccountId = "market.near".parse().unwrap();
+    let ft: AccountId = "ft.near".parse().unwrap();
+    testing_env!(VMContextBuilder::new()
+        .current_account_id(market.clone())
+        .predecessor_account_id(market.clone())
+        .build());
+    let mut contract = Contract::new(market.clone(), market, Some(vec![ft.clone()]), None);
+
+    testing_env!(VMContextBuilder::new().predecessor_account_id(ft).build());
+    let unused = contract.ft_on_transfer(
+        "buyer.near".parse().unwrap(),
+        U128(1_000),
+        "{not json".to_string(),
+    );
+
+    assert!(matches!(unused, PromiseOrValue::Value(U128(1_000))));
+    assert!(get_logs()[0].contains("ft_transfer_refunded"));
+}
@@ -0,0 +1,74 @@
+//! Boundaries of validate_payout, the check resolve_purchase and
+//! resolve_offer run on nft_transfer_payout results.
+
+use astro_marketplace_contract::validate_payout;
+use near_sdk::json_types::U128;
+use near_sdk::serde_json::json;
+
+const PRICE: u128 = 1_000_000;
+const TOLERANCE: u128 = 100;
+
+fn bare(seller: u128, creator: u128) -> Vec<u8> {
+    json!({ "seller.near": U128(seller), "creator.near": U128(creator) })
+        .to_string()
+        .into_bytes()
+}
+
+fn wrapped(seller: u128, creator: u128) -> Vec<u8> {
+    json!({ "payout": { "seller.near": U128(seller), "creator.near": U128(creator) } })
+        .to_string()
+        .into_bytes()
+}
+
+#[test]
+fn exact_payout_is_accepted() {
+    for value in [bare(PRICE - 10, 10), wrapped(PRICE - 10, 10)] {
+        let payout = validate_payout(&value, PRICE, TOLERANCE).unwrap();
+        assert_eq!(payout.len(), 2);
+        assert_eq!(payout[&"creator.near".parse().unwrap()].0, 10);
+    }
+}
+
+#[test]
+fn remainder_up_to_tolerance_is_accepted() {
+    for value in [
+        bare(PRICE - 10 - TOLERANCE, 10),
+        wrapped(PRICE - 10 - TOLERANCE, 10),
+    ] {
+        assert!(validate_payout(&value, PRICE, TOLERANCE).is_some());
+    }
+}
+
+#[test]
+fn remainder_above_tolerance_is_rejected() {
+    for value in [
+        bare(PRICE - 11 - TOLERANCE, 10),
+        wrapped(PRICE - 11 - TOLERANCE, 10),
+    ] {
+        assert!(validate_payout(&value, PRICE, TOLERANCE).is_none());
+    }
+}
+
+#[test]
+fn strict_tolerance_needs_the_full_price() {
+    assert!(validate_payout(&bare(PRICE - 10, 10), PRICE, 0).is_some());
+    assert!(validate_payout(&bare(PRICE - 11, 10), PRICE, 0).is_none());
+}
+
+#[test]
+fn payout_above_price_is_rejected() {
+    for value in [
+        bare(PRICE, 1),
+        wrapped(PRICE, 1),
+        bare(u128::MAX, u128::MAX),
+    ] {
+        assert!(validate_payout(&value, PRICE, TOLERANCE).is_none());
+    }
+}
+
+#[test]
+fn malformed_payout_is_rejected() {
+    for value in [&b"not json"[..], b"{\"payout\": 5}", b"[]"] {
+        assert!(validate_payout(value, PRICE, TOLERANCE).is_none());
+    }
+}
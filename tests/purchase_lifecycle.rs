@@ -0,0 +1,6413 @@
+//! Purchase lifecycle against the mocked blockchain: list through
+//! nft_on_approve, buy, then replay resolve_purchase with what the NFT
+//! contract's nft_transfer_payout would have returned.
+
+use astro_marketplace_contract::{
+    hash_account_id, legacy_listing_nonce, Bid, BidCheck, BidV0, BuyCheck, ConfigUpdate, Contract,
+    ContractV0, EscrowModel, FungibleTokenReceiver, MarketData, MarketDataJson, MarketDataV0,
+    NonFungibleTokenApprovalsReceiver, OfferData, OfferDataJson, OfferDataV0, StorageKey,
+    VersionedMarketData, VersionedOfferData,
+};
+use near_sdk::borsh::{BorshDeserialize, BorshSerialize};
+use near_sdk::collections::{LookupMap, UnorderedMap, UnorderedSet};
+use near_sdk::json_types::{U128, U64};
+use near_sdk::mock::VmAction;
+use near_sdk::serde_json::{json, Value};
+use near_sdk::test_utils::{get_created_receipts, get_logs, VMContextBuilder};
+use near_sdk::{
+    env, testing_env, AccountId, Balance, PromiseOrValue, PromiseResult, RuntimeFeesConfig,
+    VMConfig,
+};
+
+const ONE_NEAR: Balance = 10u128.pow(24);
+const PRICE: Balance = 10 * ONE_NEAR;
+const STORAGE: Balance = 8590000000000000000000;
+// an auction listing's bids and end index take more than the flat rate
+const AUCTION_STORAGE: Balance = 2 * STORAGE;
+const DAY: u64 = 86_400 * 10u64.pow(9);
+
+fn market() -> AccountId {
+    "market.near".parse().unwrap()
+}
+fn nft() -> AccountId {
+    "nft.near".parse().unwrap()
+}
+fn seller() -> AccountId {
+    "seller.near".parse().unwrap()
+}
+fn buyer() -> AccountId {
+    "buyer.near".parse().unwrap()
+}
+fn creator() -> AccountId {
+    "creator.near".parse().unwrap()
+}
+fn treasury() -> AccountId {
+    "treasury.near".parse().unwrap()
+}
+
+fn call_as(predecessor: AccountId, deposit: Balance) {
+    call_at(predecessor, deposit, 0);
+}
+
+fn call_at(predecessor: AccountId, deposit: Balance, block_timestamp: u64) {
+    testing_env!(VMContextBuilder::new()
+        .current_account_id(market())
+        .predecessor_account_id(predecessor.clone())
+        .signer_account_id(predecessor)
+        .attached_deposit(deposit)
+        .block_timestamp(block_timestamp)
+        .build());
+}
+
+fn callback_with(result: PromiseResult) {
+    callback_from(market(), result);
+}
+
+fn callback_from(predecessor: AccountId, result: PromiseResult) {
+    testing_env!(
+        VMContextBuilder::new()
+            .current_account_id(market())
+            .predecessor_account_id(predecessor)
+            .build(),
+        VMConfig::test(),
+        RuntimeFeesConfig::test(),
+        Default::default(),
+        vec![result],
+    );
+}
+
+fn transfers() -> Vec<(AccountId, Balance)> {
+    get_created_receipts()
+        .into_iter()
+        .flat_map(|receipt| {
+            let receiver_id = receipt.receiver_id;
+            receipt
+                .actions
+                .into_iter()
+                .filter_map(move |action| match action {
+                    VmAction::Transfer { deposit } => Some((receiver_id.clone(), deposit)),
+                    _ => None,
+                })
+        })
+        .collect()
+}
+
+/// NEP-297 events logged by the last call, as (event, params)
+fn events() -> Vec<(String, Value)> {
+    get_logs()
+        .iter()
+        .filter_map(|log| log.strip_prefix("EVENT_JSON:"))
+        .map(|log| {
+            let event: Value = near_sdk::serde_json::from_str(log).unwrap();
+            (
+                event["event"].as_str().unwrap().to_string(),
+                event["data"][0].clone(),
+            )
+        })
+        .collect()
+}
+
+fn received(transfers: &[(AccountId, Balance)], account_id: &AccountId) -> Balance {
+    transfers
+        .iter()
+        .filter(|(receiver_id, _)| receiver_id == account_id)
+        .map(|(_, amount)| amount)
+        .sum()
+}
+
+/// Runs `test` in a child test process and returns its output, for calls
+/// that must fail: a contract panic aborts instead of unwinding, so it can't
+/// be caught in-process. The child runs the named test with
+/// MARKET_EXPECT_PANIC set, the test calls this first and, when the variable
+/// is set, runs the failing call directly.
+fn expect_panic(name: &str, test: impl FnOnce()) -> String {
+    if std::env::var_os("MARKET_EXPECT_PANIC").is_some() {
+        test();
+        return String::new();
+    }
+    let output = std::process::Command::new(std::env::current_exe().unwrap())
+        .args([name, "--exact", "--nocapture", "--test-threads=1"])
+        .env("MARKET_EXPECT_PANIC", "1")
+        .output()
+        .unwrap();
+    assert!(!output.status.success(), "{} did not fail", name);
+    String::from_utf8_lossy(&output.stderr).into_owned()
+}
+
+/// expect_panic for a test that checks several failing calls: `case` numbers
+/// the call, the child runs only the one it was started for.
+fn expect_panic_case(name: &str, case: usize, test: impl FnOnce()) -> String {
+    match std::env::var("MARKET_EXPECT_PANIC_CASE") {
+        Ok(running) => {
+            if running == case.to_string() {
+                test();
+            }
+            String::new()
+        }
+        Err(_) => {
+            let output = std::process::Command::new(std::env::current_exe().unwrap())
+                .args([name, "--exact", "--nocapture", "--test-threads=1"])
+                .env("MARKET_EXPECT_PANIC_CASE", case.to_string())
+                .output()
+                .unwrap();
+            assert!(
+                !output.status.success(),
+                "{} case {} did not fail",
+                name,
+                case
+            );
+            String::from_utf8_lossy(&output.stderr).into_owned()
+        }
+    }
+}
+
+/// Lists token "1" for PRICE and returns the listing as buy will hand it to
+/// resolve_purchase.
+fn setup_listing() -> (Contract, MarketData) {
+    let contract = setup_listing_with(json!({}));
+    let market_data = contract
+        .market
+        .get(&format!("{}||1", nft()))
+        .expect("listing was not created");
+    (contract, market_data)
+}
+
+/// `extra` keys are added to `args`, replacing the ones already there
+fn with_args(mut args: Value, extra: Value) -> Value {
+    if let (Some(args), Some(extra)) = (args.as_object_mut(), extra.as_object()) {
+        args.extend(extra.clone());
+    }
+    args
+}
+
+/// Lists `token_id` for the seller at PRICE, `args` go into the nft_on_approve msg
+fn list_token(contract: &mut Contract, token_id: &str, args: Value) {
+    // nft_on_approve is called by the NFT contract inside the seller's transaction
+    testing_env!(VMContextBuilder::new()
+        .current_account_id(market())
+        .predecessor_account_id(nft())
+        .signer_account_id(seller())
+        .build());
+    contract.nft_on_approve(
+        token_id.to_string(),
+        seller(),
+        0,
+        with_args(json!({ "market_type": "sale", "price": U128(PRICE) }), args).to_string(),
+    );
+}
+
+/// A new market where the seller has listed token "1" with `args`
+fn setup_listing_with(args: Value) -> Contract {
+    call_as(market(), 0);
+    let mut contract = Contract::new(market(), treasury(), None, Some(vec![nft()]));
+
+    let storage = if args["is_auction"] == json!(true) {
+        AUCTION_STORAGE
+    } else {
+        STORAGE
+    };
+    call_as(seller(), storage);
+    contract.storage_deposit(None, None);
+
+    list_token(&mut contract, "1", args);
+    contract
+}
+
+/// Lists token "1" as an auction starting at PRICE.
+fn setup_auction() -> Contract {
+    setup_auction_with(json!({}))
+}
+
+fn setup_auction_with(args: Value) -> Contract {
+    setup_listing_with(with_args(
+        json!({ "is_auction": true, "ended_at": U64(10u64.pow(9) * 86_400) }),
+        args,
+    ))
+}
+
+#[test]
+fn buy_calls_nft_transfer_payout() {
+    let (mut contract, _) = setup_listing();
+
+    call_as(buyer(), PRICE);
+    contract.buy(nft(), "1".to_string(), None, None, None, None);
+
+    let receipts = get_created_receipts();
+    let calls_payout = receipts.iter().any(|receipt| {
+        receipt.receiver_id == nft()
+            && receipt.actions.iter().any(|action| {
+                matches!(action, VmAction::FunctionCall { function_name, .. }
+                    if function_name == "nft_transfer_payout")
+            })
+    });
+    assert!(calls_payout);
+    assert!(contract.market.get(&format!("{}||1", nft())).is_none());
+}
+
+#[test]
+fn strict_payout_honours_only_an_exact_sum() {
+    let (mut contract, _) = setup_listing();
+    call_as(seller(), 2 * STORAGE);
+    contract.storage_deposit(None, None);
+    list_token(&mut contract, "2", json!({}));
+    list_token(&mut contract, "3", json!({}));
+
+    // what seller and creator receive when token_id sells for a payout
+    // summing to PRICE - `short`
+    let mut settle = |token_id: &str, strict: bool, short: Balance| {
+        call_as(market(), 1);
+        contract.set_strict_payout(nft(), strict);
+        let market_data = contract
+            .market
+            .get(&format!("{}||{}", nft(), token_id))
+            .unwrap();
+        let payout = json!({ "payout": {
+            seller().to_string(): U128(9 * ONE_NEAR - short),
+            creator().to_string(): U128(ONE_NEAR),
+        }});
+        callback_with(PromiseResult::Successful(payout.to_string().into_bytes()));
+        contract.resolve_purchase(buyer(), market_data, U128(PRICE), None, true);
+        let paid = transfers();
+        (received(&paid, &seller()), received(&paid, &creator()))
+    };
+    let fee = PRICE * 200 / 10_000;
+
+    assert_eq!(settle("1", true, 0), (9 * ONE_NEAR - fee, ONE_NEAR));
+    // 50 yoctoNEAR short is within the default tolerance
+    assert_eq!(settle("2", false, 50), (9 * ONE_NEAR - fee - 50, ONE_NEAR));
+    // strict mode ignores it and pays the seller everything
+    assert_eq!(settle("3", true, 50), (PRICE - fee, 0));
+}
+
+#[test]
+fn buy_can_gift_the_nft_to_a_third_account() {
+    let (mut contract, market_data) = setup_listing();
+    let friend: AccountId = "friend.near".parse().unwrap();
+
+    call_as(buyer(), PRICE + ONE_NEAR);
+    contract.buy(
+        nft(),
+        "1".to_string(),
+        None,
+        None,
+        Some(friend.clone()),
+        Some(true),
+    );
+    assert_eq!(nft_receiver(), Some(friend.clone()));
+    // the payer gets the change
+    let paid = transfers();
+    assert_eq!(received(&paid, &buyer()), ONE_NEAR);
+    assert_eq!(received(&paid, &friend), 0);
+
+    // and a failed transfer is refunded to the payer too
+    callback_with(PromiseResult::Failed);
+    contract.resolve_purchase(buyer(), market_data, U128(PRICE), None, true);
+    assert_eq!(received(&transfers(), &buyer()), PRICE);
+}
+
+#[test]
+fn seller_cannot_buy_their_own_listing_as_a_gift() {
+    let (mut contract, _) = setup_listing();
+    let stderr = expect_panic("seller_cannot_buy_their_own_listing_as_a_gift", || {
+        call_as(seller(), PRICE);
+        contract.buy(
+            nft(),
+            "1".to_string(),
+            None,
+            None,
+            Some("friend.near".parse().unwrap()),
+            None,
+        );
+    });
+    assert!(stderr.contains("Cannot buy your own sale"));
+}
+
+#[test]
+fn listing_takes_an_optional_seller_note_up_to_280_chars() {
+    let mut contract = setup_two_listings();
+    // a note takes storage on top of the listing
+    call_as(seller(), 4 * STORAGE);
+    contract.storage_deposit(None, None);
+    // counted in chars, not bytes
+    let note = "é".repeat(280);
+    list_token(&mut contract, "3", json!({ "seller_note": note }));
+
+    let note_of = |token_id: &str| {
+        let listing = contract
+            .get_market_data(nft(), token_id.to_string())
+            .unwrap();
+        near_sdk::serde_json::to_value(&listing).unwrap()["seller_note"].clone()
+    };
+    assert_eq!(note_of("1"), Value::Null);
+    assert_eq!(note_of("3"), json!(note));
+
+    let stderr = expect_panic(
+        "listing_takes_an_optional_seller_note_up_to_280_chars",
+        || {
+            list_token(
+                &mut contract,
+                "4",
+                json!({ "seller_note": "a".repeat(281) }),
+            )
+        },
+    );
+    assert!(
+        stderr.contains("seller_note is longer than 280"),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn nft_transfer_payout_carries_the_sale_memo() {
+    let (mut contract, _) = setup_listing();
+
+    call_as(buyer(), PRICE);
+    contract.buy(nft(), "1".to_string(), None, None, None, None);
+
+    let memos: Vec<Value> = get_created_receipts()
+        .into_iter()
+        .filter(|receipt| receipt.receiver_id == nft())
+        .flat_map(|receipt| receipt.actions)
+        .filter_map(|action| match action {
+            VmAction::FunctionCall {
+                function_name,
+                args,
+                ..
+            } if function_name == "nft_transfer_payout" => {
+                let args: Value = near_sdk::serde_json::from_slice(&args).unwrap();
+                Some(near_sdk::serde_json::from_str(args["memo"].as_str().unwrap()).unwrap())
+            }
+            _ => None,
+        })
+        .collect();
+    assert_eq!(
+        memos,
+        vec![json!({ "marketplace_id": market(), "price": U128(PRICE) })]
+    );
+}
+
+#[test]
+fn compliant_payout_pays_seller_royalty_and_treasury() {
+    let (mut contract, market_data) = setup_listing();
+
+    call_as(buyer(), PRICE);
+    contract.buy(nft(), "1".to_string(), None, None, None, None);
+
+    let payout = json!({ "payout": {
+        seller().to_string(): U128(9 * ONE_NEAR),
+        creator().to_string(): U128(ONE_NEAR),
+    }});
+    callback_with(PromiseResult::Successful(payout.to_string().into_bytes()));
+    let paid = contract.resolve_purchase(buyer(), market_data, U128(PRICE), None, true);
+
+    let fee = PRICE * 200 / 10_000;
+    let transfers = transfers();
+    assert_eq!(paid.0, PRICE);
+    assert_eq!(received(&transfers, &seller()), 9 * ONE_NEAR - fee);
+    assert_eq!(received(&transfers, &creator()), ONE_NEAR);
+    assert_eq!(received(&transfers, &treasury()), fee);
+    assert_eq!(received(&transfers, &buyer()), 0);
+    assert_eq!(contract.get_treasury_accrued().0, fee);
+
+    let stats = contract.get_stats();
+    assert_eq!(stats.listings.0, 0);
+    assert_eq!(stats.total_volume.0, PRICE);
+    assert_eq!(stats.treasury_accrued.0, fee);
+}
+
+#[test]
+fn each_payout_share_is_logged_for_a_deleted_receiver() {
+    let (mut contract, market_data) = setup_listing();
+    // the transfer to an account that no longer exists fails after this
+    // call, the event is what operators reconcile from
+    let deleted: AccountId = "deleted.near".parse().unwrap();
+    let payout = json!({ "payout": {
+        seller().to_string(): U128(9 * ONE_NEAR),
+        deleted.to_string(): U128(ONE_NEAR),
+    }});
+    callback_with(PromiseResult::Successful(payout.to_string().into_bytes()));
+    contract.resolve_purchase(buyer(), market_data, U128(PRICE), None, true);
+
+    let fee = PRICE * 200 / 10_000;
+    let mut shares: Vec<(Value, Value)> = events()
+        .into_iter()
+        .filter(|(event, _)| event == "payout_transfer")
+        .map(|(_, data)| (data["receiver_id"].clone(), data["amount"].clone()))
+        .collect();
+    shares.sort_by_key(|(receiver_id, _)| receiver_id.to_string());
+    assert_eq!(
+        shares,
+        vec![
+            (json!(deleted), json!(U128(ONE_NEAR))),
+            (json!(seller()), json!(U128(9 * ONE_NEAR - fee))),
+        ]
+    );
+    assert_eq!(received(&transfers(), &deleted), ONE_NEAR);
+}
+
+#[test]
+fn non_compliant_payout_pays_seller_directly() {
+    let (mut contract, market_data) = setup_listing();
+
+    call_as(buyer(), PRICE);
+    contract.buy(nft(), "1".to_string(), None, None, None, None);
+
+    // shares add up to more than the price, so the payout is ignored
+    let payout = json!({ "payout": {
+        seller().to_string(): U128(PRICE),
+        creator().to_string(): U128(ONE_NEAR),
+    }});
+    callback_with(PromiseResult::Successful(payout.to_string().into_bytes()));
+    contract.resolve_purchase(buyer(), market_data, U128(PRICE), None, true);
+
+    let fee = PRICE * 200 / 10_000;
+    let transfers = transfers();
+    assert_eq!(received(&transfers, &seller()), PRICE - fee);
+    assert_eq!(received(&transfers, &creator()), 0);
+    assert_eq!(received(&transfers, &treasury()), fee);
+}
+
+#[test]
+fn unusable_payout_falls_back_to_the_creator_registry() {
+    let (mut contract, market_data) = setup_listing();
+    call_as(market(), 1);
+    contract.add_creator_royalty(nft(), creator(), 500);
+
+    callback_with(PromiseResult::Successful(b"{}".to_vec()));
+    contract.resolve_purchase(buyer(), market_data.clone(), U128(PRICE), None, true);
+
+    let fee = PRICE * 200 / 10_000;
+    let royalty = PRICE * 500 / 10_000;
+    let paid = transfers();
+    assert_eq!(received(&paid, &creator()), royalty);
+    assert_eq!(received(&paid, &seller()), PRICE - fee - royalty);
+    assert_eq!(received(&paid, &treasury()), fee);
+
+    // a compliant payout is paid as reported, the registry isn't used
+    let payout = json!({ "payout": { seller().to_string(): U128(PRICE) } });
+    callback_with(PromiseResult::Successful(payout.to_string().into_bytes()));
+    contract.resolve_purchase(buyer(), market_data.clone(), U128(PRICE), None, true);
+    assert_eq!(received(&transfers(), &creator()), 0);
+
+    call_as(market(), 1);
+    contract.remove_creator_royalty(nft());
+    callback_with(PromiseResult::Successful(b"{}".to_vec()));
+    contract.resolve_purchase(buyer(), market_data, U128(PRICE), None, true);
+    assert_eq!(received(&transfers(), &creator()), 0);
+}
+
+#[test]
+fn payout_without_the_seller_still_pays_the_treasury() {
+    let (mut contract, market_data) = setup_listing();
+
+    call_as(buyer(), PRICE);
+    contract.buy(nft(), "1".to_string(), None, None, None, None);
+
+    // the seller isn't in the payout, the fee comes out of the other shares
+    let holder: AccountId = "holder.near".parse().unwrap();
+    let payout = json!({ "payout": {
+        creator().to_string(): U128(6 * ONE_NEAR),
+        holder.to_string(): U128(4 * ONE_NEAR),
+    }});
+    callback_with(PromiseResult::Successful(payout.to_string().into_bytes()));
+    contract.resolve_purchase(buyer(), market_data, U128(PRICE), None, true);
+
+    let fee = PRICE * 200 / 10_000;
+    let transfers = transfers();
+    assert_eq!(received(&transfers, &seller()), 0);
+    assert_eq!(received(&transfers, &creator()), 6 * ONE_NEAR * 98 / 100);
+    assert_eq!(received(&transfers, &holder), 4 * ONE_NEAR * 98 / 100);
+    assert_eq!(received(&transfers, &treasury()), fee);
+    assert_eq!(contract.get_treasury_accrued().0, fee);
+}
+
+#[test]
+fn failed_transfer_refunds_buyer() {
+    let (mut contract, market_data) = setup_listing();
+
+    call_as(buyer(), PRICE);
+    contract.buy(nft(), "1".to_string(), None, None, None, None);
+
+    callback_with(PromiseResult::Failed);
+    contract.resolve_purchase(buyer(), market_data, U128(PRICE), None, true);
+
+    let transfers = transfers();
+    assert_eq!(received(&transfers, &buyer()), PRICE);
+    assert_eq!(received(&transfers, &seller()), 0);
+    assert_eq!(received(&transfers, &treasury()), 0);
+    assert_eq!(contract.get_stats().total_volume.0, 0);
+}
+
+// a panicking contract call aborts the mocked blockchain instead of
+// unwinding, so failed preconditions are checked through simulate_buy, which
+// runs the same checks as buy
+#[test]
+fn simulate_buy_reports_each_failing_check() {
+    let (mut contract, _) = setup_listing();
+    call_as(seller(), STORAGE);
+    contract.storage_deposit(None, None);
+    list_token(
+        &mut contract,
+        "2",
+        json!({ "started_at": U64(DAY), "ended_at": U64(2 * DAY) }),
+    );
+    let error = |contract: &Contract, token_id: &str, buyer_id: AccountId, ts: u64| {
+        call_at(buyer_id.clone(), 0, ts);
+        match contract.simulate_buy(nft(), token_id.to_string(), U128(PRICE), Some(buyer_id)) {
+            BuyCheck::Ok { .. } => String::new(),
+            BuyCheck::Failed { error } => error,
+        }
+    };
+    let owner_call = |contract: &mut Contract, call: fn(&mut Contract)| {
+        call_as(market(), 1);
+        call(contract);
+    };
+
+    assert!(error(&contract, "9", buyer(), 0).contains("Market data does not exist"));
+    assert!(error(&contract, "1", seller(), 0).contains("Cannot buy your own sale"));
+    assert!(error(&contract, "2", buyer(), 0).contains("Sale has not started yet"));
+    assert!(error(&contract, "2", buyer(), 3 * DAY).contains("Sale has ended"));
+    assert!(error(&contract, "2", buyer(), DAY).is_empty());
+
+    owner_call(&mut contract, |c| c.set_paused(true));
+    assert!(error(&contract, "1", buyer(), 0).contains("marketplace paused"));
+    owner_call(&mut contract, |c| c.set_paused(false));
+
+    owner_call(&mut contract, |c| c.pause_nft_contract(nft()));
+    assert!(error(&contract, "1", buyer(), 0).contains("trading is paused"));
+    owner_call(&mut contract, |c| c.unpause_nft_contract(nft()));
+
+    owner_call(&mut contract, |c| c.set_kyc_required(nft(), true));
+    assert!(error(&contract, "1", buyer(), 0).contains("KYC approved accounts"));
+    owner_call(&mut contract, |c| c.set_kyc_required(nft(), false));
+
+    owner_call(&mut contract, |c| {
+        c.remove_approved_nft_contract_ids(vec![nft()]);
+    });
+    assert!(error(&contract, "1", buyer(), 0).contains("nft_contract_id is not approved"));
+}
+
+#[test]
+fn buy_with_insufficient_deposit_fails() {
+    let (contract, _) = setup_listing();
+
+    call_as(buyer(), 0);
+    let check = contract.simulate_buy(nft(), "1".to_string(), U128(PRICE - 1), Some(buyer()));
+    assert!(
+        matches!(check, BuyCheck::Failed { error } if error.contains("Attached deposit is less than price"))
+    );
+
+    let check = contract.simulate_buy(nft(), "1".to_string(), U128(PRICE), Some(buyer()));
+    assert!(matches!(check, BuyCheck::Ok { price } if price.0 == PRICE));
+}
+
+#[test]
+fn reject_offer_logs_reason_and_refunds_buyer() {
+    let (mut contract, _) = setup_listing();
+
+    call_as(buyer(), STORAGE);
+    contract.storage_deposit(None, None);
+    call_as(buyer(), ONE_NEAR);
+    contract.add_offer(
+        nft(),
+        "1".to_string(),
+        "near".parse().unwrap(),
+        U128(ONE_NEAR),
+        None,
+        None,
+    );
+
+    call_as(seller(), 1);
+    contract.reject_offer(nft(), buyer(), "1".to_string(), Some("too_low".to_string()));
+
+    let logs = get_logs();
+    let event = logs
+        .iter()
+        .find(|log| log.contains("\"reject_offer\""))
+        .expect("reject_offer was not logged");
+    assert!(event.contains("\"reason\":\"too_low\""));
+    assert_eq!(received(&transfers(), &buyer()), ONE_NEAR);
+}
+
+#[test]
+fn allowlist_changes_log_one_summary_event() {
+    let (mut contract, _) = setup_listing();
+    let other: AccountId = "other.near".parse().unwrap();
+
+    call_as(market(), 1);
+    contract.add_approved_nft_contract_ids(vec![other.clone(), nft()], None);
+    let logged = events();
+    assert_eq!(logged.len(), 1);
+    let (event, params) = &logged[0];
+    assert_eq!(event, "update_approved_nft_contracts");
+    assert_eq!(params["added"], json!([other]));
+    assert_eq!(params["ignored"], json!([nft()]));
+    assert_eq!(params["size"], "2");
+
+    call_as(market(), 1);
+    contract.remove_approved_ft_token_ids(vec!["near".parse().unwrap()]);
+    let (event, params) = &events()[0];
+    assert_eq!(event, "update_approved_ft_tokens");
+    assert_eq!(params["removed"], json!([]));
+    assert_eq!(params["size"], "1");
+}
+
+#[test]
+fn removing_present_and_absent_ids_reports_each() {
+    let (mut contract, _) = setup_listing();
+    let absent: AccountId = "absent.near".parse().unwrap();
+
+    call_as(market(), 1);
+    let removed = contract.remove_approved_nft_contract_ids(vec![nft(), absent.clone()]);
+    assert_eq!(removed, U64(1));
+    assert!(contract.approved_nft_contract_ids().is_empty());
+    let (event, params) = &events()[0];
+    assert_eq!(event, "update_approved_nft_contracts");
+    assert_eq!(params["removed"], json!([nft()]));
+    assert_eq!(params["ignored"], json!([absent]));
+    assert_eq!(params["size"], "0");
+
+    call_as(market(), 1);
+    let added = contract.add_approved_ft_token_ids(vec![ft(), "near".parse().unwrap()]);
+    assert_eq!(added, U64(1));
+    let (_, params) = &events()[0];
+    assert_eq!(params["added"], json!([ft()]));
+    assert_eq!(params["ignored"], json!(["near"]));
+
+    call_as(market(), 1);
+    let removed = contract.remove_approved_ft_token_ids(vec![ft(), absent.clone()]);
+    assert_eq!(removed, U64(1));
+    let (event, params) = &events()[0];
+    assert_eq!(event, "update_approved_ft_tokens");
+    assert_eq!(params["removed"], json!([ft()]));
+    assert_eq!(params["ignored"], json!([absent]));
+    assert_eq!(params["size"], "1");
+}
+
+#[test]
+fn kyc_collection_only_sells_to_allowlisted_buyers() {
+    let (mut contract, _) = setup_listing();
+
+    call_as(market(), 1);
+    contract.set_kyc_required(nft(), true);
+
+    let check = contract.simulate_buy(nft(), "1".to_string(), U128(PRICE), Some(buyer()));
+    assert!(matches!(check, BuyCheck::Failed { error } if error.contains("KYC")));
+
+    call_as(market(), 1);
+    contract.add_kyc_accounts(vec![buyer()]);
+    assert!(contract.is_kyc_approved(buyer()));
+
+    let check = contract.simulate_buy(nft(), "1".to_string(), U128(PRICE), Some(buyer()));
+    assert!(matches!(check, BuyCheck::Ok { price } if price.0 == PRICE));
+}
+
+#[test]
+fn collection_config_reflects_overrides() {
+    let (mut contract, _) = setup_listing();
+
+    call_as(market(), 1);
+    contract.pause_nft_contract(nft());
+    call_as(market(), 1);
+    contract.set_kyc_required(nft(), true);
+    call_as(market(), 1);
+    contract.set_royalty_override(nft(), creator(), 500);
+
+    let config = contract.get_collection_config(nft());
+    assert!(config.approved && config.paused && config.kyc_required);
+    assert!(!config.strict_payout);
+    assert_eq!(config.royalty_override, Some((creator(), 500)));
+    assert_eq!(config.creator_royalty, None);
+
+    let configs = contract.get_all_collection_configs(None, None);
+    assert_eq!(configs.len(), 1);
+    assert_eq!(configs[0].nft_contract_id, nft());
+}
+
+#[test]
+fn bid_fails_when_current_price_moved() {
+    let mut contract = setup_auction();
+    let other: AccountId = "other.near".parse().unwrap();
+    for bidder in [buyer(), other.clone()] {
+        call_as(bidder.clone(), STORAGE);
+        contract.storage_deposit(None, None);
+    }
+
+    // buyer reads the starting price, other outbids before buyer's bid lands
+    let seen = U128(PRICE);
+    call_as(other, PRICE + ONE_NEAR / 2);
+    contract.add_bid(
+        nft(),
+        "near".parse().unwrap(),
+        "1".to_string(),
+        U128(PRICE + ONE_NEAR / 2),
+        None,
+        Some(seen),
+    );
+
+    let stderr = expect_panic("bid_fails_when_current_price_moved", || {
+        call_as(buyer(), PRICE + ONE_NEAR);
+        contract.add_bid(
+            nft(),
+            "near".parse().unwrap(),
+            "1".to_string(),
+            U128(PRICE + ONE_NEAR),
+            None,
+            Some(seen),
+        );
+    });
+    assert!(
+        stderr.contains("no longer matches expected_current_price"),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn escheat_offer_waits_for_dormancy_and_refunds_first() {
+    let (mut contract, _) = setup_listing();
+
+    call_as(buyer(), STORAGE);
+    contract.storage_deposit(None, None);
+    call_as(buyer(), ONE_NEAR);
+    contract.add_offer(
+        nft(),
+        "1".to_string(),
+        "near".parse().unwrap(),
+        U128(ONE_NEAR),
+        None,
+        None,
+    );
+
+    let stderr = expect_panic("escheat_offer_waits_for_dormancy_and_refunds_first", || {
+        call_as(market(), 1);
+        contract.escheat_offer(nft(), buyer(), "1".to_string());
+    });
+    assert!(stderr.contains("offer is younger than"), "{}", stderr);
+
+    let dormancy = contract.get_config().offer_dormancy;
+    testing_env!(VMContextBuilder::new()
+        .current_account_id(market())
+        .predecessor_account_id(market())
+        .block_timestamp(dormancy * 10u64.pow(9))
+        .attached_deposit(1)
+        .build());
+    contract.escheat_offer(nft(), buyer(), "1".to_string());
+    assert_eq!(received(&transfers(), &buyer()), ONE_NEAR);
+    assert_eq!(received(&transfers(), &treasury()), 0);
+
+    callback_with(PromiseResult::Failed);
+    let refunded = contract.resolve_escheat_offer(nft(), buyer(), "1".to_string(), U128(ONE_NEAR));
+    assert!(!refunded);
+    assert_eq!(received(&transfers(), &treasury()), ONE_NEAR);
+    assert_eq!(contract.get_escheated(buyer()).0, ONE_NEAR);
+}
+
+#[test]
+fn market_data_min_next_bid_matches_view() {
+    // get_market_data takes the contract by value, serialize the listing instead
+    let listing_json = |contract: &Contract| {
+        let market_data = contract
+            .get_market_data_raw(nft(), "1".to_string())
+            .unwrap();
+        near_sdk::serde_json::to_value(MarketDataJson::from(market_data)).unwrap()
+    };
+
+    let mut contract = setup_auction();
+    assert_eq!(listing_json(&contract)["min_next_bid"], json!(U128(PRICE)));
+
+    call_as(buyer(), STORAGE);
+    contract.storage_deposit(None, None);
+    call_as(buyer(), PRICE);
+    contract.add_bid(
+        nft(),
+        "near".parse().unwrap(),
+        "1".to_string(),
+        U128(PRICE),
+        None,
+        None,
+    );
+
+    let view = contract.get_minimum_next_bid(nft(), "1".to_string());
+    assert_eq!(view, Some(U128(PRICE + 1)));
+    assert_eq!(listing_json(&contract)["min_next_bid"], json!(view));
+}
+
+#[test]
+fn buyer_rebate_comes_out_of_the_treasury_fee() {
+    let (mut contract, market_data) = setup_listing();
+
+    call_as(market(), 1);
+    contract.set_config(ConfigUpdate {
+        buyer_rebate_bps: Some(2_500),
+        ..Default::default()
+    });
+
+    call_as(buyer(), PRICE);
+    contract.buy(nft(), "1".to_string(), None, None, None, None);
+
+    let payout = json!({ "payout": { seller().to_string(): U128(PRICE) } });
+    callback_with(PromiseResult::Successful(payout.to_string().into_bytes()));
+    contract.resolve_purchase(buyer(), market_data, U128(PRICE), None, true);
+
+    let fee = PRICE * 200 / 10_000;
+    let rebate = fee / 4;
+    let transfers = transfers();
+    assert_eq!(received(&transfers, &buyer()), rebate);
+    assert_eq!(received(&transfers, &treasury()), fee - rebate);
+    assert_eq!(received(&transfers, &seller()), PRICE - fee);
+    assert!(get_logs()
+        .iter()
+        .any(|log| log.contains(&format!("\"rebate\":\"{}\"", rebate))));
+}
+
+#[test]
+fn winner_claims_ended_auction_and_losers_are_refunded() {
+    let mut contract = setup_auction();
+    let loser: AccountId = "loser.near".parse().unwrap();
+    for (bidder, amount) in [(loser.clone(), PRICE), (buyer(), PRICE + ONE_NEAR)] {
+        call_as(bidder.clone(), STORAGE);
+        contract.storage_deposit(None, None);
+        call_as(bidder, amount);
+        contract.add_bid(
+            nft(),
+            "near".parse().unwrap(),
+            "1".to_string(),
+            U128(amount),
+            None,
+            None,
+        );
+    }
+    let mut market_data = contract
+        .get_market_data_raw(nft(), "1".to_string())
+        .unwrap();
+    let losing_bids = market_data.bids.replace(vec![]).map(|mut bids| {
+        bids.pop();
+        bids
+    });
+
+    let ended_at = market_data.ended_at.unwrap();
+    testing_env!(VMContextBuilder::new()
+        .current_account_id(market())
+        .predecessor_account_id(buyer())
+        .block_timestamp(ended_at + 1)
+        .attached_deposit(1)
+        .build());
+    contract.claim_won_auction(nft(), "1".to_string());
+
+    let transfers_nft_to_winner = get_created_receipts().iter().any(|receipt| {
+        receipt.receiver_id == nft()
+            && receipt.actions.iter().any(|action| {
+                matches!(action, VmAction::FunctionCall { function_name, args, .. }
+                    if function_name == "nft_transfer_payout"
+                        && String::from_utf8_lossy(args).contains(buyer().as_str()))
+            })
+    });
+    assert!(transfers_nft_to_winner);
+
+    let payout = json!({ "payout": { seller().to_string(): U128(PRICE + ONE_NEAR) } });
+    callback_with(PromiseResult::Successful(payout.to_string().into_bytes()));
+    contract.resolve_purchase(
+        buyer(),
+        market_data,
+        U128(PRICE + ONE_NEAR),
+        losing_bids,
+        true,
+    );
+
+    let transfers = transfers();
+    assert_eq!(received(&transfers, &loser), PRICE);
+    assert_eq!(received(&transfers, &buyer()), 0);
+}
+
+#[test]
+fn bid_on_fixed_price_listing_fails() {
+    let (mut contract, _) = setup_listing();
+    call_as(buyer(), STORAGE);
+    contract.storage_deposit(None, None);
+
+    let stderr = expect_panic("bid_on_fixed_price_listing_fails", || {
+        call_as(buyer(), PRICE);
+        contract.add_bid(
+            nft(),
+            "near".parse().unwrap(),
+            "1".to_string(),
+            U128(PRICE),
+            None,
+            None,
+        );
+    });
+    assert!(stderr.contains("the NFT is not on auction"), "{}", stderr);
+}
+
+#[test]
+fn far_future_started_at_is_rejected() {
+    let (mut contract, _) = setup_listing();
+    let horizon = contract.get_max_schedule_horizon();
+    let started_at = U64((horizon + 1) * 10u64.pow(9));
+
+    let stderr = expect_panic("far_future_started_at_is_rejected", || {
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(market())
+            .predecessor_account_id(nft())
+            .signer_account_id(seller())
+            .build());
+        contract.nft_on_approve(
+            "1".to_string(),
+            seller(),
+            0,
+            json!({ "market_type": "sale", "price": U128(PRICE), "started_at": started_at })
+                .to_string(),
+        );
+    });
+    assert!(
+        stderr.contains("started_at is further ahead than"),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn seller_relists_fixed_price_item_as_auction() {
+    let (mut contract, _) = setup_listing();
+
+    call_as(seller(), STORAGE);
+    contract.storage_deposit(None, None);
+    call_as(seller(), 1);
+    contract.relist(
+        nft(),
+        "1".to_string(),
+        U128(2 * PRICE),
+        None,
+        None,
+        Some(U64(10u64.pow(9) * 86_400)),
+        Some(true),
+    );
+
+    let logged = events();
+    assert_eq!(logged.len(), 1);
+    assert_eq!(logged[0].0, "relist");
+
+    let market_data = contract
+        .get_market_data_raw(nft(), "1".to_string())
+        .unwrap();
+    assert_eq!(market_data.is_auction, Some(true));
+    assert_eq!(market_data.price, 2 * PRICE);
+    assert!(market_data.bids.is_some_and(|bids| bids.is_empty()));
+    assert_eq!(contract.get_supply_by_owner_id(seller()).0, 1);
+}
+
+#[test]
+fn resolve_purchase_only_accepts_self_or_trusted_callers() {
+    let (mut contract, market_data) = setup_listing();
+    let helper: AccountId = "helper.near".parse().unwrap();
+
+    let stderr = expect_panic(
+        "resolve_purchase_only_accepts_self_or_trusted_callers",
+        || {
+            callback_from(helper.clone(), PromiseResult::Failed);
+            contract.resolve_purchase(buyer(), market_data, U128(PRICE), None, true);
+        },
+    );
+    assert!(
+        stderr.contains("only the marketplace or a trusted caller"),
+        "{}",
+        stderr
+    );
+
+    let market_data = contract
+        .get_market_data_raw(nft(), "1".to_string())
+        .unwrap();
+    call_as(market(), 1);
+    contract.add_trusted_callers(vec![helper.clone()]);
+    callback_from(helper, PromiseResult::Failed);
+    contract.resolve_purchase(buyer(), market_data, U128(PRICE), None, true);
+    assert_eq!(received(&transfers(), &buyer()), PRICE);
+}
+
+#[test]
+fn escrow_for_account_sums_offers_and_bids() {
+    let mut contract = setup_auction();
+
+    // a second auction on token "2"
+    call_as(seller(), AUCTION_STORAGE);
+    contract.storage_deposit(None, None);
+    testing_env!(VMContextBuilder::new()
+        .current_account_id(market())
+        .predecessor_account_id(nft())
+        .signer_account_id(seller())
+        .build());
+    contract.nft_on_approve(
+        "2".to_string(),
+        seller(),
+        0,
+        json!({
+            "market_type": "sale",
+            "price": U128(PRICE),
+            "is_auction": true,
+            "ended_at": U64(10u64.pow(9) * 86_400),
+        })
+        .to_string(),
+    );
+
+    call_as(buyer(), STORAGE);
+    contract.storage_deposit(None, None);
+    for (token_id, amount) in [("1", PRICE), ("2", PRICE + ONE_NEAR)] {
+        call_as(buyer(), amount);
+        contract.add_bid(
+            nft(),
+            "near".parse().unwrap(),
+            token_id.to_string(),
+            U128(amount),
+            None,
+            None,
+        );
+    }
+    call_as(buyer(), ONE_NEAR);
+    contract.add_offer(
+        nft(),
+        "3".to_string(),
+        "near".parse().unwrap(),
+        U128(ONE_NEAR),
+        None,
+        None,
+    );
+
+    assert_eq!(
+        contract.get_escrow_for_account(buyer()).0,
+        2 * PRICE + 2 * ONE_NEAR
+    );
+    assert_eq!(contract.get_escrow_for_account(seller()).0, 0);
+
+    // escrow is NEAR only, an offer in an FT isn't taken
+    let stderr = expect_panic("escrow_for_account_sums_offers_and_bids", || {
+        call_as(buyer(), ONE_NEAR);
+        contract.add_offer(nft(), "3".to_string(), ft(), U128(ONE_NEAR), None, None);
+    });
+    assert!(stderr.contains("Only NEAR is supported"), "{}", stderr);
+}
+
+#[test]
+fn a_token_keeps_one_listing_across_currencies_and_offers() {
+    let (mut contract, _) = setup_listing();
+    let ft: AccountId = "ft.near".parse().unwrap();
+    call_as(market(), 1);
+    contract.add_approved_ft_token_ids(vec![ft.clone()]);
+    // an offer lives in `offers`, never next to the listing in `market`
+    offer_from_creator(&mut contract, ONE_NEAR);
+
+    let relist = |contract: &mut Contract, msg: near_sdk::serde_json::Value| {
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(market())
+            .predecessor_account_id(nft())
+            .signer_account_id(seller())
+            .build());
+        contract.nft_on_approve("1".to_string(), seller(), 1, msg.to_string());
+    };
+    let counts = |contract: &Contract| {
+        let stats = contract.get_stats();
+        (stats.listings.0, stats.offers.0)
+    };
+
+    // NEAR with an FT alternative shares one entry
+    relist(
+        &mut contract,
+        json!({ "market_type": "sale", "price": U128(PRICE), "alt_ft_token_id": ft, "alt_price": U128(5) }),
+    );
+    assert_eq!(counts(&contract), (1, 1));
+    let market_data = contract
+        .get_market_data_raw(nft(), "1".to_string())
+        .unwrap();
+    assert_eq!(market_data.alt_ft_token_id, Some(ft.clone()));
+
+    // then FT only, replacing it
+    relist(
+        &mut contract,
+        json!({ "market_type": "sale", "price": U128(5), "ft_token_id": ft }),
+    );
+    assert_eq!(counts(&contract), (1, 1));
+    assert_eq!(contract.get_supply_by_owner_id(seller()).0, 1);
+    assert_eq!(contract.get_markets(None, None).len(), 1);
+    assert_eq!(contract.by_nft_contract.get(&nft()).unwrap().len(), 1);
+    let market_data = contract
+        .get_market_data_raw(nft(), "1".to_string())
+        .unwrap();
+    assert_eq!(market_data.ft_token_id, ft);
+    assert!(market_data.alt_ft_token_id.is_none());
+
+    // delisting leaves the offer and no listing behind it
+    call_as(seller(), 1);
+    contract.delete_market_data(nft(), "1".to_string());
+    assert_eq!(counts(&contract), (0, 1));
+    assert!(contract
+        .get_market_data_raw(nft(), "1".to_string())
+        .is_none());
+    assert!(contract.get_markets(None, None).is_empty());
+    assert_eq!(
+        contract
+            .get_offers(nft(), "1".to_string(), None, None, None)
+            .len(),
+        1
+    );
+}
+
+#[test]
+fn legacy_events_are_logged_until_turned_off() {
+    let (mut contract, _) = setup_listing();
+
+    call_as(market(), 1);
+    contract.pause_nft_contract(nft());
+    let logs = get_logs();
+    assert_eq!(logs.len(), 2);
+    assert!(logs[0].starts_with("{\"event\":\"pause_nft_contract\""));
+    assert_eq!(events()[0].0, "pause_nft_contract");
+
+    call_as(market(), 1);
+    contract.set_emit_legacy_events(false);
+    call_as(market(), 1);
+    contract.unpause_nft_contract(nft());
+    let logs = get_logs();
+    assert_eq!(logs.len(), 1);
+    assert!(logs[0].starts_with("EVENT_JSON:"));
+    assert_eq!(events()[0].0, "unpause_nft_contract");
+}
+
+#[test]
+fn offer_refund_is_wrapped_for_wnear_preference() {
+    let (mut contract, _) = setup_listing();
+    let wnear: AccountId = "wrap.near".parse().unwrap();
+
+    call_as(market(), 1);
+    contract.set_wnear_id(Some(wnear.clone()));
+    call_as(buyer(), 1);
+    contract.set_settlement_preference(Some(wnear.clone()));
+
+    call_as(buyer(), STORAGE);
+    contract.storage_deposit(None, None);
+    call_as(buyer(), ONE_NEAR);
+    contract.add_offer(
+        nft(),
+        "1".to_string(),
+        "near".parse().unwrap(),
+        U128(ONE_NEAR),
+        None,
+        None,
+    );
+
+    call_as(buyer(), 1);
+    contract.delete_offer(nft(), "1".to_string());
+
+    let receipts = get_created_receipts();
+    let calls: Vec<(String, Balance, String)> = receipts
+        .iter()
+        .filter(|receipt| receipt.receiver_id == wnear)
+        .flat_map(|receipt| receipt.actions.iter())
+        .filter_map(|action| match action {
+            VmAction::FunctionCall {
+                function_name,
+                args,
+                deposit,
+                ..
+            } => Some((
+                function_name.clone(),
+                *deposit,
+                String::from_utf8_lossy(args).into_owned(),
+            )),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(calls.len(), 2);
+    assert_eq!(
+        (calls[0].0.as_str(), calls[0].1),
+        ("near_deposit", ONE_NEAR)
+    );
+    assert_eq!((calls[1].0.as_str(), calls[1].1), ("ft_transfer", 1));
+    let args: Value = near_sdk::serde_json::from_str(&calls[1].2).unwrap();
+    assert_eq!(args["receiver_id"], buyer().to_string());
+    assert_eq!(args["amount"], ONE_NEAR.to_string());
+    assert_eq!(received(&transfers(), &buyer()), 0);
+}
+
+#[test]
+fn token_activity_combines_listing_offer_and_last_sale() {
+    let (mut contract, market_data) = setup_listing();
+
+    call_as(buyer(), PRICE);
+    contract.buy(nft(), "1".to_string(), None, None, None, None);
+    let payout = json!({ "payout": { seller().to_string(): U128(PRICE) } });
+    callback_with(PromiseResult::Successful(payout.to_string().into_bytes()));
+    contract.resolve_purchase(buyer(), market_data, U128(PRICE), None, true);
+
+    // the buyer lists it again and someone else makes an offer
+    call_as(buyer(), STORAGE);
+    contract.storage_deposit(None, None);
+    testing_env!(VMContextBuilder::new()
+        .current_account_id(market())
+        .predecessor_account_id(nft())
+        .signer_account_id(buyer())
+        .build());
+    contract.nft_on_approve(
+        "1".to_string(),
+        buyer(),
+        1,
+        json!({ "market_type": "sale", "price": U128(2 * PRICE) }).to_string(),
+    );
+    let collector: AccountId = "collector.near".parse().unwrap();
+    call_as(collector.clone(), STORAGE);
+    contract.storage_deposit(None, None);
+    call_as(collector.clone(), ONE_NEAR);
+    contract.add_offer(
+        nft(),
+        "1".to_string(),
+        "near".parse().unwrap(),
+        U128(ONE_NEAR),
+        None,
+        None,
+    );
+
+    let activity =
+        near_sdk::serde_json::to_value(contract.get_token_activity(nft(), "1".to_string()))
+            .unwrap();
+    assert_eq!(activity["listing"]["owner_id"], buyer().to_string());
+    assert_eq!(activity["listing"]["price"], (2 * PRICE).to_string());
+    assert_eq!(activity["offers"].as_array().unwrap().len(), 1);
+    assert_eq!(activity["offers"][0]["buyer_id"], collector.to_string());
+    assert_eq!(activity["top_bid"], Value::Null);
+    assert_eq!(activity["last_sale"]["seller_id"], seller().to_string());
+    assert_eq!(activity["last_sale"]["buyer_id"], buyer().to_string());
+    assert_eq!(activity["last_sale"]["price"], PRICE.to_string());
+}
+
+#[test]
+fn accept_bid_yocto_is_kept_as_sweepable_dust() {
+    let mut contract = setup_auction();
+
+    call_as(buyer(), STORAGE);
+    contract.storage_deposit(None, None);
+    call_as(buyer(), PRICE);
+    contract.add_bid(
+        nft(),
+        "near".parse().unwrap(),
+        "1".to_string(),
+        U128(PRICE),
+        None,
+        None,
+    );
+
+    call_as(seller(), 1);
+    contract.accept_bid(nft(), "1".to_string(), None);
+    assert_eq!(contract.get_dust_accrued().0, 1);
+
+    call_as(market(), 1);
+    let swept = contract.sweep_dust();
+    assert_eq!(swept.0, 1);
+    assert_eq!(received(&transfers(), &treasury()), 1);
+    assert_eq!(contract.get_dust_accrued().0, 0);
+}
+
+#[test]
+fn royalty_override_comes_out_of_the_seller_share() {
+    let mut contract = setup_two_listings();
+    let agent: AccountId = "agent.near".parse().unwrap();
+    call_as(market(), 1);
+    contract.set_royalty_override(nft(), agent.clone(), 500);
+
+    let mut settle = |token_id: &str, payout: Value| {
+        let market_data = contract
+            .market
+            .get(&format!("{}||{}", nft(), token_id))
+            .unwrap();
+        callback_with(PromiseResult::Successful(payout.to_string().into_bytes()));
+        contract.resolve_purchase(buyer(), market_data, U128(PRICE), None, true);
+        transfers()
+    };
+    let fee = PRICE * 200 / 10_000;
+    let royalty = PRICE * 500 / 10_000;
+
+    // on top of the NFT contract's payout
+    let paid = settle(
+        "1",
+        json!({ "payout": {
+            seller().to_string(): U128(9 * ONE_NEAR),
+            creator().to_string(): U128(ONE_NEAR),
+        }}),
+    );
+    assert_eq!(received(&paid, &agent), royalty);
+    assert_eq!(received(&paid, &creator()), ONE_NEAR);
+    assert_eq!(received(&paid, &seller()), 9 * ONE_NEAR - fee - royalty);
+    let (_, data) = events()
+        .into_iter()
+        .find(|(event, _)| event == "royalty_override")
+        .unwrap();
+    assert_eq!(data["receiver_id"], json!(agent));
+    assert_eq!(data["amount"], json!(U128(royalty)));
+
+    // and when there is no payout
+    let paid = settle("2", json!({}));
+    assert_eq!(received(&paid, &agent), royalty);
+    assert_eq!(received(&paid, &seller()), PRICE - fee - royalty);
+}
+
+#[test]
+fn sale_auction_and_offer_settle_identically() {
+    let (mut contract, _) = setup_listing();
+    let agent: AccountId = "agent.near".parse().unwrap();
+    call_as(market(), 1);
+    contract.set_royalty_override(nft(), agent, 300);
+
+    let settle = |contract: &mut Contract, path: &str, payout: &str| {
+        let sale = contract
+            .get_market_data_raw(nft(), "1".to_string())
+            .unwrap();
+        callback_with(PromiseResult::Successful(payout.as_bytes().to_vec()));
+        match path {
+            "sale" => contract.resolve_purchase(buyer(), sale, U128(PRICE), None, true),
+            "auction" => {
+                let auction = MarketData {
+                    is_auction: Some(true),
+                    bids: Some(vec![Bid {
+                        bidder_id: buyer(),
+                        price: U128(PRICE),
+                        placed_at: U64(0),
+                        recipient: None,
+                    }]),
+                    ..sale
+                };
+                contract.resolve_purchase(buyer(), auction, U128(PRICE), Some(vec![]), true)
+            }
+            _ => {
+                let offer = OfferData {
+                    buyer_id: buyer(),
+                    nft_contract_id: nft(),
+                    token_id: "1".to_string(),
+                    ft_token_id: "near".parse().unwrap(),
+                    price: PRICE,
+                    nonce: None,
+                    created_at: 0,
+                    expires_at: None,
+                };
+                contract.resolve_offer(seller(), offer, "1".to_string(), None)
+            }
+        };
+        let mut transfers = transfers();
+        transfers.sort();
+        transfers
+    };
+
+    let payouts = [
+        json!({ "payout": {
+            seller().to_string(): U128(9 * ONE_NEAR),
+            creator().to_string(): U128(ONE_NEAR),
+        }})
+        .to_string(),
+        // not a payout, the seller is paid directly
+        "{}".to_string(),
+    ];
+    for payout in &payouts {
+        let from_sale = settle(&mut contract, "sale", payout);
+        assert!(!from_sale.is_empty());
+        assert_eq!(from_sale, settle(&mut contract, "auction", payout));
+        assert_eq!(from_sale, settle(&mut contract, "offer", payout));
+    }
+}
+
+#[test]
+fn ranked_bids_are_highest_first() {
+    let mut contract = setup_auction();
+    let bidders: Vec<AccountId> = ["a.near", "b.near", "c.near"]
+        .iter()
+        .map(|id| id.parse().unwrap())
+        .collect();
+    for (step, bidder) in bidders.iter().enumerate() {
+        let amount = PRICE + step as u128 * ONE_NEAR;
+        call_as(bidder.clone(), STORAGE);
+        contract.storage_deposit(None, None);
+        call_as(bidder.clone(), amount);
+        contract.add_bid(
+            nft(),
+            "near".parse().unwrap(),
+            "1".to_string(),
+            U128(amount),
+            None,
+            None,
+        );
+    }
+
+    let ranked = contract.get_ranked_bids(nft(), "1".to_string(), None, None);
+    let ranks: Vec<(AccountId, u64, bool)> = ranked
+        .iter()
+        .map(|ranked| (ranked.bid.bidder_id.clone(), ranked.rank, ranked.is_winning))
+        .collect();
+    assert_eq!(
+        ranks,
+        vec![
+            (bidders[2].clone(), 1, true),
+            (bidders[1].clone(), 2, false),
+            (bidders[0].clone(), 3, false),
+        ]
+    );
+
+    let page = contract.get_ranked_bids(nft(), "1".to_string(), Some(U64(1)), Some(1));
+    assert_eq!(page.len(), 1);
+    assert_eq!(page[0].rank, 2);
+}
+
+#[test]
+fn zero_price_needs_free_listing() {
+    let (mut contract, _) = setup_listing();
+    let list_for_free = |contract: &mut Contract, free_listing: Option<bool>| {
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(market())
+            .predecessor_account_id(nft())
+            .signer_account_id(seller())
+            .build());
+        contract.nft_on_approve(
+            "1".to_string(),
+            seller(),
+            1,
+            json!({ "market_type": "sale", "price": U128(0), "free_listing": free_listing })
+                .to_string(),
+        );
+    };
+
+    let stderr = expect_panic("zero_price_needs_free_listing", || {
+        list_for_free(&mut contract, None);
+    });
+    assert!(stderr.contains("price needs free_listing"), "{}", stderr);
+
+    list_for_free(&mut contract, Some(true));
+    call_as(buyer(), 0);
+    let check = contract.simulate_buy(nft(), "1".to_string(), U128(0), Some(buyer()));
+    assert!(matches!(check, BuyCheck::Ok { price } if price.0 == 0));
+
+    // a zero price that slipped past listing still can't be bought
+    let key = format!("{}||1", nft());
+    let mut market_data = contract.market.get(&key).unwrap();
+    market_data.free_listing = false;
+    contract.market.insert(&key, &market_data);
+    let check = contract.simulate_buy(nft(), "1".to_string(), U128(0), Some(buyer()));
+    assert!(
+        matches!(check, BuyCheck::Failed { error } if error.contains("price needs free_listing"))
+    );
+}
+
+#[test]
+fn settleable_auctions_are_the_ended_ones() {
+    let mut contract = setup_auction();
+    let day = 10u64.pow(9) * 86_400;
+
+    call_as(seller(), 3 * STORAGE);
+    contract.storage_deposit(None, None);
+    for (token_id, is_auction, ended_at) in [
+        ("2", true, 3 * day),
+        ("3", true, 2 * day),
+        ("4", false, day),
+    ] {
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(market())
+            .predecessor_account_id(nft())
+            .signer_account_id(seller())
+            .build());
+        contract.nft_on_approve(
+            token_id.to_string(),
+            seller(),
+            0,
+            json!({
+                "market_type": "sale",
+                "price": U128(PRICE),
+                "is_auction": is_auction,
+                "ended_at": U64(ended_at),
+            })
+            .to_string(),
+        );
+    }
+
+    let ended = |contract: &Contract, now: u64| -> Vec<String> {
+        contract
+            .get_settleable_auctions(Some(U64(now)), None)
+            .into_iter()
+            .map(|(nft_contract_id, token_id)| {
+                assert_eq!(nft_contract_id, nft());
+                token_id
+            })
+            .collect()
+    };
+    assert!(ended(&contract, day / 2).is_empty());
+    assert_eq!(ended(&contract, day + 1), vec!["1"]);
+    assert_eq!(ended(&contract, 2 * day + 1), vec!["1", "3"]);
+    assert_eq!(ended(&contract, 3 * day + 1), vec!["1", "3", "2"]);
+
+    // nobody bid on "1", processing it deletes the listing
+    testing_env!(VMContextBuilder::new()
+        .current_account_id(market())
+        .block_timestamp(day + 1)
+        .build());
+    assert_eq!(contract.process_ended_auctions(10), 1);
+    assert_eq!(ended(&contract, 3 * day + 1), vec!["3", "2"]);
+}
+
+#[test]
+fn blocked_auctions_do_not_starve_process_ended_auctions() {
+    let mut contract = setup_auction();
+    call_as(seller(), AUCTION_STORAGE);
+    contract.storage_deposit(None, None);
+    list_token(
+        &mut contract,
+        "2",
+        json!({ "is_auction": true, "ended_at": U64(2 * DAY) }),
+    );
+    bid_from(&mut contract, buyer(), PRICE);
+    call_as(creator(), STORAGE);
+    contract.storage_deposit(None, None);
+    call_as(creator(), PRICE);
+    contract.add_bid(
+        nft(),
+        "near".parse().unwrap(),
+        "2".to_string(),
+        U128(PRICE),
+        None,
+        None,
+    );
+
+    // a price above the top bid, as state written by an older version could
+    // hold, blocks "1" from settling
+    let key = format!("{}||1", nft());
+    let mut market_data = contract.market.get(&key).unwrap();
+    market_data.price = 2 * PRICE;
+    contract.market.insert(&key, &market_data);
+
+    // "1" ended first but is skipped before the limit, "2" settles
+    call_at(market(), 0, 2 * DAY + 1);
+    assert_eq!(contract.process_ended_auctions(1), 1);
+    assert_eq!(nft_receiver(), Some(creator()));
+    assert!(contract
+        .get_market_data_raw(nft(), "1".to_string())
+        .is_some());
+}
+
+#[test]
+fn listing_offer_and_bid_events_carry_ft_token_id() {
+    let mut seen = Vec::new();
+    let check = |seen: &mut Vec<String>| {
+        for (event, params) in events() {
+            assert_eq!(
+                params["ft_token_id"], "near",
+                "{} without ft_token_id",
+                event
+            );
+            seen.push(event);
+        }
+    };
+    let (mut contract, market_data) = setup_listing();
+
+    call_as(buyer(), STORAGE);
+    contract.storage_deposit(None, None);
+    call_as(buyer(), ONE_NEAR);
+    contract.add_offer(
+        nft(),
+        "1".to_string(),
+        "near".parse().unwrap(),
+        U128(ONE_NEAR),
+        None,
+        None,
+    );
+    check(&mut seen);
+    call_as(buyer(), 1);
+    contract.delete_offer(nft(), "1".to_string());
+    check(&mut seen);
+
+    call_as(buyer(), PRICE);
+    contract.buy(nft(), "1".to_string(), None, None, None, None);
+    let payout = json!({ "payout": { seller().to_string(): U128(PRICE) } });
+    callback_with(PromiseResult::Successful(payout.to_string().into_bytes()));
+    contract.resolve_purchase(buyer(), market_data, U128(PRICE), None, true);
+    check(&mut seen);
+
+    call_as(seller(), AUCTION_STORAGE);
+    contract.storage_deposit(None, None);
+    testing_env!(VMContextBuilder::new()
+        .current_account_id(market())
+        .predecessor_account_id(nft())
+        .signer_account_id(seller())
+        .build());
+    contract.nft_on_approve(
+        "2".to_string(),
+        seller(),
+        0,
+        json!({
+            "market_type": "sale",
+            "price": U128(PRICE),
+            "is_auction": true,
+            "ended_at": U64(10u64.pow(9) * 86_400),
+        })
+        .to_string(),
+    );
+    check(&mut seen);
+    let loser: AccountId = "loser.near".parse().unwrap();
+    for (bidder, amount) in [(loser.clone(), PRICE), (buyer(), PRICE + ONE_NEAR)] {
+        call_as(bidder.clone(), STORAGE);
+        contract.storage_deposit(None, None);
+        call_as(bidder, amount);
+        contract.add_bid(
+            nft(),
+            "near".parse().unwrap(),
+            "2".to_string(),
+            U128(amount),
+            None,
+            None,
+        );
+        check(&mut seen);
+    }
+    call_as(loser.clone(), 1);
+    contract.cancel_bid(nft(), "2".to_string(), loser);
+    check(&mut seen);
+    call_as(seller(), 1);
+    contract.delete_market_data(nft(), "2".to_string());
+    check(&mut seen);
+
+    for event in [
+        "add_offer",
+        "delete_offer",
+        "resolve_purchase",
+        "payout_transfer",
+        "add_market_data",
+        "add_bid",
+        "outbid",
+        "cancel_bid",
+        "delete_market_data",
+    ] {
+        assert!(
+            seen.iter().any(|seen| seen == event),
+            "{} not logged",
+            event
+        );
+    }
+}
+
+#[test]
+fn auctions_are_charged_more_storage_than_fixed_listings() {
+    let (mut contract, _) = setup_listing();
+    let fixed = contract
+        .get_listing_storage_cost(nft(), "1".to_string())
+        .unwrap();
+    assert_eq!(fixed.0, STORAGE);
+
+    // another seller's first listing, this time an auction
+    let maker: AccountId = "maker.near".parse().unwrap();
+    let list_auction = |contract: &mut Contract| {
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(market())
+            .predecessor_account_id(nft())
+            .signer_account_id(maker.clone())
+            .build());
+        contract.nft_on_approve(
+            "2".to_string(),
+            maker.clone(),
+            0,
+            json!({
+                "market_type": "sale",
+                "price": U128(PRICE),
+                "is_auction": true,
+                "ended_at": U64(10u64.pow(9) * 86_400),
+            })
+            .to_string(),
+        );
+    };
+
+    call_as(maker.clone(), STORAGE);
+    contract.storage_deposit(None, None);
+    let stderr = expect_panic(
+        "auctions_are_charged_more_storage_than_fixed_listings",
+        || list_auction(&mut contract),
+    );
+    assert!(stderr.contains("Insufficient storage paid"), "{}", stderr);
+
+    call_as(maker.clone(), STORAGE);
+    contract.storage_deposit(None, None);
+    list_auction(&mut contract);
+    let auction = contract
+        .get_listing_storage_cost(nft(), "2".to_string())
+        .unwrap();
+    assert!(auction.0 > fixed.0);
+    assert_eq!(contract.storage_cost_for(maker.clone(), 0).0, 0);
+
+    // deleting the auction releases its whole charge
+    call_as(maker.clone(), 1);
+    contract.delete_market_data(nft(), "2".to_string());
+    call_as(maker, 1);
+    assert_eq!(contract.storage_withdraw(None).0, 2 * STORAGE);
+}
+
+#[test]
+fn extreme_prices_and_payouts_settle_without_overflow() {
+    let (mut contract, _) = setup_listing();
+    let agent: AccountId = "agent.near".parse().unwrap();
+    call_as(market(), 1);
+    contract.set_royalty_override(nft(), agent, 10_000);
+    call_as(market(), 1);
+    contract.set_config(ConfigUpdate {
+        buyer_rebate_bps: Some(10_000),
+        ..Default::default()
+    });
+
+    let max_price: Balance = 1_000_000_000 * ONE_NEAR;
+    for price in [max_price, max_price - 1, u128::MAX, u128::MAX - 1, 1] {
+        let payouts = [
+            json!({ "payout": { seller().to_string(): U128(price) } }),
+            // the seller's share is smaller than the treasury fee
+            json!({ "payout": {
+                seller().to_string(): U128(1),
+                creator().to_string(): U128(price - 1),
+            }}),
+            // not a payout, the seller is paid directly
+            json!({}),
+        ];
+        for payout in &payouts {
+            let market_data = MarketData {
+                price,
+                ..contract
+                    .get_market_data_raw(nft(), "1".to_string())
+                    .unwrap()
+            };
+            // the mocked runtime refuses transfers above the contract's balance
+            testing_env!(
+                VMContextBuilder::new()
+                    .current_account_id(market())
+                    .predecessor_account_id(market())
+                    .account_balance(u128::MAX)
+                    .build(),
+                VMConfig::test(),
+                RuntimeFeesConfig::test(),
+                Default::default(),
+                vec![PromiseResult::Successful(payout.to_string().into_bytes())],
+            );
+            let paid = contract.resolve_purchase(buyer(), market_data, U128(price), None, true);
+            assert_eq!(paid.0, price);
+
+            let paid_out = transfers()
+                .iter()
+                .try_fold(0u128, |total, (_, amount)| total.checked_add(*amount));
+            assert!(paid_out.is_some_and(|paid_out| paid_out <= price));
+        }
+    }
+}
+
+/// Places PRICE from "first.near", then PRICE + ONE_NEAR from the buyer, and
+/// returns what first.near was refunded by the second add_bid.
+fn outbid_first_bidder(contract: &mut Contract) -> Balance {
+    let first: AccountId = "first.near".parse().unwrap();
+    for (bidder, amount) in [(first.clone(), PRICE), (buyer(), PRICE + ONE_NEAR)] {
+        call_as(bidder.clone(), STORAGE);
+        contract.storage_deposit(None, None);
+        call_as(bidder, amount);
+        contract.add_bid(
+            nft(),
+            "near".parse().unwrap(),
+            "1".to_string(),
+            U128(amount),
+            None,
+            None,
+        );
+    }
+    received(&transfers(), &first)
+}
+
+#[test]
+fn hold_until_settlement_keeps_outbid_bids_escrowed() {
+    let mut contract = setup_auction_with(json!({ "escrow_model": "hold_until_settlement" }));
+    assert_eq!(outbid_first_bidder(&mut contract), 0);
+
+    let bids = contract
+        .get_market_data_raw(nft(), "1".to_string())
+        .and_then(|market_data| market_data.bids)
+        .unwrap();
+    assert_eq!(bids.len(), 2);
+}
+
+#[test]
+fn outbid_leader_stays_escrowed_until_cancel_bid_by_default() {
+    let mut contract = setup_auction();
+    assert_eq!(outbid_first_bidder(&mut contract), 0);
+
+    let first: AccountId = "first.near".parse().unwrap();
+    let bid = contract
+        .get_bid(nft(), "1".to_string(), first.clone())
+        .unwrap();
+    assert_eq!(bid.price, U128(PRICE));
+    assert_eq!(
+        contract.get_auction_escrow(nft(), "1".to_string()).0,
+        2 * PRICE + ONE_NEAR
+    );
+
+    call_as(first.clone(), 1);
+    contract.cancel_bid(nft(), "1".to_string(), first.clone());
+    assert_eq!(received(&transfers(), &first), PRICE);
+    assert!(contract.get_bid(nft(), "1".to_string(), first).is_none());
+    assert_eq!(
+        contract.get_auction_escrow(nft(), "1".to_string()).0,
+        PRICE + ONE_NEAR
+    );
+}
+
+#[test]
+fn leading_bid_cancels_only_when_allow_leader_cancel_is_set() {
+    let mut contract = setup_auction();
+    outbid_first_bidder(&mut contract);
+    assert!(!contract.get_allow_leader_cancel());
+
+    let stderr = expect_panic(
+        "leading_bid_cancels_only_when_allow_leader_cancel_is_set",
+        || {
+            call_as(buyer(), 1);
+            contract.cancel_bid(nft(), "1".to_string(), buyer());
+        },
+    );
+    if std::env::var_os("MARKET_EXPECT_PANIC").is_none() {
+        assert!(stderr.contains("Error: the highest bid cannot be cancelled"));
+    }
+
+    // the trailing bid is not locked
+    let first: AccountId = "first.near".parse().unwrap();
+    call_as(first.clone(), 1);
+    contract.cancel_bid(nft(), "1".to_string(), first.clone());
+    assert_eq!(received(&transfers(), &first), PRICE);
+
+    call_as(market(), 1);
+    contract.set_allow_leader_cancel(true);
+    call_as(buyer(), 1);
+    contract.cancel_bid(nft(), "1".to_string(), buyer());
+    assert_eq!(received(&transfers(), &buyer()), PRICE + ONE_NEAR);
+    assert!(contract.get_bid(nft(), "1".to_string(), buyer()).is_none());
+}
+
+#[test]
+fn auction_escrow_sums_every_escrowed_bid() {
+    let mut contract = setup_auction();
+    let escrow = |contract: &Contract| contract.get_auction_escrow(nft(), "1".to_string()).0;
+    assert_eq!(escrow(&contract), 0);
+
+    bid_from(&mut contract, "first.near".parse().unwrap(), PRICE);
+    bid_from(&mut contract, creator(), 2 * PRICE);
+    bid_from(&mut contract, buyer(), 3 * PRICE);
+    assert_eq!(escrow(&contract), 6 * PRICE);
+
+    // a bidder's new bid replaces their refunded one
+    bid_at(&mut contract, creator(), 4 * PRICE, 1);
+    assert_eq!(escrow(&contract), 8 * PRICE);
+}
+
+#[test]
+fn refund_on_outbid_repays_the_previous_leader_at_once() {
+    let mut contract = setup_auction_with(json!({ "escrow_model": "refund_on_outbid" }));
+    assert_eq!(outbid_first_bidder(&mut contract), PRICE);
+
+    let market_data = contract
+        .get_market_data_raw(nft(), "1".to_string())
+        .unwrap();
+    assert_eq!(market_data.escrow_model, EscrowModel::RefundOnOutbid);
+    let bids = market_data.bids.unwrap();
+    assert_eq!(bids.len(), 1);
+    assert_eq!(bids[0].bidder_id, buyer());
+}
+
+#[test]
+fn every_outbid_names_the_displaced_leader() {
+    let mut contract = setup_auction();
+    let first: AccountId = "first.near".parse().unwrap();
+    let outbids = || -> Vec<Value> {
+        events()
+            .into_iter()
+            .filter(|(event, _)| event == "outbid")
+            .map(|(_, data)| data)
+            .collect()
+    };
+    let highest = |contract: &Contract| contract.get_highest_bidder(nft(), "1".to_string());
+
+    bid_from(&mut contract, first.clone(), PRICE);
+    assert!(outbids().is_empty());
+    assert_eq!(highest(&contract), Some(first.clone()));
+
+    bid_from(&mut contract, buyer(), 2 * PRICE);
+    let events = outbids();
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0]["bidder_id"], json!(first));
+    assert_eq!(events[0]["amount"], json!(U128(PRICE)));
+    assert_eq!(events[0]["new_bidder_id"], json!(buyer()));
+    assert_eq!(highest(&contract), Some(buyer()));
+
+    bid_at(&mut contract, first.clone(), 3 * PRICE, 1);
+    let events = outbids();
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0]["bidder_id"], json!(buyer()));
+    assert_eq!(events[0]["amount"], json!(U128(2 * PRICE)));
+    assert_eq!(highest(&contract), Some(first.clone()));
+
+    // raising your own lead outbids nobody
+    bid_at(&mut contract, first.clone(), 4 * PRICE, 2);
+    assert!(outbids().is_empty());
+    assert_eq!(highest(&contract), Some(first));
+}
+
+#[test]
+fn offers_above_the_listing_price_are_flagged() {
+    let (mut contract, _) = setup_listing();
+    let low: AccountId = "low.near".parse().unwrap();
+    for (buyer_id, price) in [(low.clone(), ONE_NEAR), (buyer(), PRICE + ONE_NEAR)] {
+        call_as(buyer_id.clone(), STORAGE);
+        contract.storage_deposit(None, None);
+        call_as(buyer_id, price);
+        contract.add_offer(
+            nft(),
+            "1".to_string(),
+            "near".parse().unwrap(),
+            U128(price),
+            None,
+            None,
+        );
+    }
+
+    let flags = |contract: &Contract, token_id: &str| {
+        let offers = contract.get_offers_vs_listing(nft(), token_id.to_string(), None, None);
+        let mut flags: Vec<(String, bool)> = near_sdk::serde_json::to_value(offers)
+            .unwrap()
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|offer| {
+                (
+                    offer["buyer_id"].as_str().unwrap().to_string(),
+                    offer["exceeds_listing"].as_bool().unwrap(),
+                )
+            })
+            .collect();
+        flags.sort();
+        flags
+    };
+    assert_eq!(
+        flags(&contract, "1"),
+        vec![(buyer().to_string(), true), (low.to_string(), false)]
+    );
+
+    // without a listing nothing is above list
+    call_as(seller(), 1);
+    contract.delete_market_data(nft(), "1".to_string());
+    assert_eq!(
+        flags(&contract, "1"),
+        vec![(buyer().to_string(), false), (low.to_string(), false)]
+    );
+}
+
+#[test]
+fn crowded_auction_refunds_losers_in_one_event() {
+    let mut contract = setup_auction();
+    for step in 0..10u128 {
+        let bidder: AccountId = format!("bidder{}.near", step).parse().unwrap();
+        let amount = PRICE + step * ONE_NEAR;
+        call_as(bidder.clone(), STORAGE);
+        contract.storage_deposit(None, None);
+        call_as(bidder, amount);
+        contract.add_bid(
+            nft(),
+            "near".parse().unwrap(),
+            "1".to_string(),
+            U128(amount),
+            None,
+            None,
+        );
+    }
+    let mut market_data = contract
+        .get_market_data_raw(nft(), "1".to_string())
+        .unwrap();
+    let mut losing_bids = market_data.bids.replace(vec![]).unwrap();
+    let winner = losing_bids.pop().unwrap();
+
+    let payout = json!({ "payout": { seller().to_string(): winner.price } });
+    // the escrowed bids are more than the mocked runtime's default balance
+    testing_env!(
+        VMContextBuilder::new()
+            .current_account_id(market())
+            .predecessor_account_id(market())
+            .account_balance(1_000 * PRICE)
+            .build(),
+        VMConfig::test(),
+        RuntimeFeesConfig::test(),
+        Default::default(),
+        vec![PromiseResult::Successful(payout.to_string().into_bytes())],
+    );
+    contract.resolve_purchase(
+        winner.bidder_id,
+        market_data,
+        winner.price,
+        Some(losing_bids),
+        true,
+    );
+
+    let refunded: Vec<Value> = events()
+        .into_iter()
+        .filter(|(event, _)| event == "bids_refunded")
+        .map(|(_, data)| data)
+        .collect();
+    assert_eq!(refunded.len(), 1);
+    let refunds = refunded[0]["refunds"].as_array().unwrap();
+    assert_eq!(refunds.len(), 9);
+    assert_eq!(refunds[0]["bidder_id"], "bidder0.near");
+    assert_eq!(refunds[0]["amount"], json!(U128(PRICE)));
+}
+
+#[test]
+fn stale_listings_are_the_ones_the_seller_no_longer_owns() {
+    let (mut contract, _) = setup_listing();
+    call_as(seller(), STORAGE);
+    contract.storage_deposit(None, None);
+    testing_env!(VMContextBuilder::new()
+        .current_account_id(market())
+        .predecessor_account_id(nft())
+        .signer_account_id(seller())
+        .build());
+    contract.nft_on_approve(
+        "2".to_string(),
+        seller(),
+        0,
+        json!({ "market_type": "sale", "price": U128(PRICE) }).to_string(),
+    );
+
+    // "3" isn't listed so only "1" and "2" are looked up
+    call_as(buyer(), 0);
+    contract.find_stale_listings(
+        nft(),
+        vec!["1".to_string(), "2".to_string(), "3".to_string()],
+    );
+    let lookups = get_created_receipts()
+        .iter()
+        .flat_map(|receipt| receipt.actions.iter())
+        .filter(|action| {
+            matches!(action, VmAction::FunctionCall { function_name, .. }
+                if function_name == "nft_token")
+        })
+        .count();
+    assert_eq!(lookups, 2);
+
+    // the mock NFT contract reports "1" moved to the buyer, "2" still the seller's
+    let token = |owner_id: AccountId| {
+        PromiseResult::Successful(json!({ "owner_id": owner_id }).to_string().into_bytes())
+    };
+    testing_env!(
+        VMContextBuilder::new()
+            .current_account_id(market())
+            .predecessor_account_id(market())
+            .build(),
+        VMConfig::test(),
+        RuntimeFeesConfig::test(),
+        Default::default(),
+        vec![token(buyer()), token(seller())],
+    );
+    let stale = contract.resolve_find_stale_listings(nft(), vec!["1".to_string(), "2".to_string()]);
+    assert_eq!(stale, vec!["1".to_string()]);
+}
+
+#[test]
+fn accept_bid_waits_for_min_bid_age() {
+    let mut contract = setup_auction();
+    call_as(market(), 1);
+    contract.set_min_bid_age(60);
+
+    call_as(buyer(), STORAGE);
+    contract.storage_deposit(None, None);
+    call_as(buyer(), PRICE);
+    contract.add_bid(
+        nft(),
+        "near".parse().unwrap(),
+        "1".to_string(),
+        U128(PRICE),
+        None,
+        None,
+    );
+
+    let stderr = expect_panic("accept_bid_waits_for_min_bid_age", || {
+        call_as(seller(), 1);
+        contract.accept_bid(nft(), "1".to_string(), None);
+    });
+    assert!(
+        stderr.contains("can't be accepted before it is 60 seconds old"),
+        "{}",
+        stderr
+    );
+
+    testing_env!(VMContextBuilder::new()
+        .current_account_id(market())
+        .predecessor_account_id(seller())
+        .block_timestamp(60 * 10u64.pow(9))
+        .attached_deposit(1)
+        .build());
+    contract.accept_bid(nft(), "1".to_string(), None);
+    assert!(contract
+        .get_market_data_raw(nft(), "1".to_string())
+        .is_none());
+}
+
+#[test]
+fn removing_an_ft_leaves_every_bid_escrowed() {
+    let mut contract = setup_auction_with_bid();
+    let usdc: AccountId = "usdc.near".parse().unwrap();
+    call_as(market(), 1);
+    contract.add_approved_ft_token_ids(vec![usdc.clone()]);
+    call_as(seller(), STORAGE);
+    contract.storage_deposit(None, None);
+    list_token(&mut contract, "2", json!({ "ft_token_id": usdc }));
+
+    // bids are escrowed in NEAR only, the FT's listings have none to refund
+    call_as(market(), 1);
+    let next_index = contract.remove_approved_ft_token_ids_and_cleanup(vec![usdc], None, 10);
+    assert_eq!(next_index, None);
+    assert!(contract
+        .get_market_data_raw(nft(), "2".to_string())
+        .is_none());
+    assert!(transfers().is_empty());
+    assert!(resolve_refunds().is_empty());
+    let bid = contract.get_bid(nft(), "1".to_string(), buyer()).unwrap();
+    assert_eq!(bid.price, U128(PRICE));
+    assert_eq!(contract.get_auction_escrow(nft(), "1".to_string()).0, PRICE);
+}
+
+#[test]
+fn removing_an_ft_cleans_up_its_listings() {
+    let (mut contract, _) = setup_listing();
+    let usdc: AccountId = "usdc.near".parse().unwrap();
+    call_as(market(), 1);
+    contract.add_approved_ft_token_ids(vec![usdc.clone()]);
+
+    call_as(seller(), 2 * STORAGE);
+    contract.storage_deposit(None, None);
+    for (token_id, args) in [
+        (
+            "2",
+            json!({ "market_type": "sale", "price": U128(PRICE), "ft_token_id": usdc }),
+        ),
+        (
+            "3",
+            json!({
+                "market_type": "sale",
+                "price": U128(PRICE),
+                "alt_ft_token_id": usdc,
+                "alt_price": U128(PRICE),
+            }),
+        ),
+    ] {
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(market())
+            .predecessor_account_id(nft())
+            .signer_account_id(seller())
+            .build());
+        contract.nft_on_approve(token_id.to_string(), seller(), 0, args.to_string());
+    }
+
+    call_as(market(), 1);
+    let next_index = contract.remove_approved_ft_token_ids_and_cleanup(vec![usdc.clone()], None, 1);
+    // stopped right after token "2", the only listing it had to touch
+    assert_eq!(next_index, Some(U64(2)));
+    assert!(!contract.approved_ft_token_ids().contains(&usdc));
+    assert!(contract
+        .get_market_data_raw(nft(), "2".to_string())
+        .is_none());
+    assert!(events()
+        .iter()
+        .any(|(event, data)| event == "delete_market_data"
+            && data["token_id"] == "2"
+            && data["reason"] == "ft_token_removed"));
+
+    call_as(market(), 1);
+    let next_index = contract.remove_approved_ft_token_ids_and_cleanup(vec![usdc], next_index, 1);
+    assert_eq!(next_index, None);
+    let alt_listing = contract
+        .get_market_data_raw(nft(), "3".to_string())
+        .unwrap();
+    assert_eq!(alt_listing.alt_ft_token_id, None);
+    assert_eq!(alt_listing.alt_price, None);
+    assert!(contract
+        .get_market_data_raw(nft(), "1".to_string())
+        .is_some());
+}
+
+#[test]
+fn trusted_delegate_lists_for_seller_who_gets_the_proceeds() {
+    let (mut contract, _) = setup_listing();
+    let aggregator: AccountId = "aggregator.near".parse().unwrap();
+    call_as(market(), 1);
+    contract.add_trusted_delegates(vec![aggregator.clone()]);
+
+    call_as(seller(), STORAGE);
+    contract.storage_deposit(None, None);
+    call_as(aggregator, 0);
+    contract.delegate_list(
+        nft(),
+        "2".to_string(),
+        seller(),
+        3,
+        json!({ "market_type": "sale", "price": U128(PRICE) }).to_string(),
+    );
+    let market_data = contract
+        .get_market_data_raw(nft(), "2".to_string())
+        .expect("delegate listing was not created");
+    assert_eq!(market_data.owner_id, seller());
+    assert_eq!(market_data.approval_id, 3);
+
+    call_as(buyer(), PRICE);
+    contract.buy(nft(), "2".to_string(), None, None, None, None);
+    let payout = json!({ "payout": { seller().to_string(): U128(PRICE) } });
+    callback_with(PromiseResult::Successful(payout.to_string().into_bytes()));
+    contract.resolve_purchase(buyer(), market_data, U128(PRICE), None, true);
+
+    let fee = PRICE * 200 / 10_000;
+    assert_eq!(received(&transfers(), &seller()), PRICE - fee);
+}
+
+#[test]
+fn price_history_keeps_the_last_ten_sales() {
+    let (mut contract, _) = setup_listing();
+    for sale in 0..12u128 {
+        let price = ONE_NEAR + sale;
+        let market_data = MarketData {
+            price,
+            ..contract
+                .get_market_data_raw(nft(), "1".to_string())
+                .unwrap()
+        };
+        let payout = json!({ "payout": { seller().to_string(): U128(price) } });
+        callback_with(PromiseResult::Successful(payout.to_string().into_bytes()));
+        contract.resolve_purchase(buyer(), market_data, U128(price), None, true);
+    }
+
+    let prices: Vec<u128> = contract
+        .get_price_history(nft(), "1".to_string())
+        .iter()
+        .map(|(price, _)| price.0)
+        .collect();
+    assert_eq!(
+        prices,
+        (2..12).map(|sale| ONE_NEAR + sale).collect::<Vec<_>>()
+    );
+}
+
+/// setup_auction with one bid from the buyer
+fn setup_auction_with_bid() -> Contract {
+    let mut contract = setup_auction();
+    call_as(buyer(), STORAGE);
+    contract.storage_deposit(None, None);
+    call_as(buyer(), PRICE);
+    contract.add_bid(
+        nft(),
+        "near".parse().unwrap(),
+        "1".to_string(),
+        U128(PRICE),
+        None,
+        None,
+    );
+    contract
+}
+
+#[test]
+fn market_data_raw_matches_the_json_view() {
+    let contract = setup_auction_with_bid();
+    let raw = contract
+        .get_market_data_raw(nft(), "1".to_string())
+        .unwrap();
+    let json =
+        near_sdk::serde_json::to_value(contract.get_market_data(nft(), "1".to_string())).unwrap();
+    let number = |field: &str| json[field].as_str().unwrap().parse::<u128>().unwrap();
+
+    assert_eq!(json["owner_id"], json!(raw.owner_id));
+    assert_eq!(json["nft_contract_id"], json!(raw.nft_contract_id));
+    assert_eq!(json["token_id"], json!(raw.token_id));
+    assert_eq!(json["ft_token_id"], json!(raw.ft_token_id));
+    assert_eq!(number("approval_id"), raw.approval_id as u128);
+    assert_eq!(number("price"), raw.price);
+    assert_eq!(number("ended_at"), raw.ended_at.unwrap() as u128);
+    assert_eq!(number("listing_nonce"), raw.listing_nonce as u128);
+    assert_eq!(
+        json["started_at"].as_str().is_some(),
+        raw.started_at.is_some()
+    );
+    assert_eq!(json["is_auction"], json!(raw.is_auction));
+    assert_eq!(json["free_listing"], json!(raw.free_listing));
+    assert_eq!(json["escrow_model"], json!(raw.escrow_model));
+    assert_eq!(json["bids"], json!(raw.bids));
+    assert_eq!(raw.bids.unwrap()[0].price, U128(PRICE));
+}
+
+#[test]
+fn relisting_a_token_gets_a_new_listing_nonce() {
+    let (mut contract, market_data) = setup_listing();
+    let nonce = |contract: &Contract| {
+        contract
+            .get_market_data_raw(nft(), "1".to_string())
+            .unwrap()
+            .listing_nonce
+    };
+    assert_eq!(nonce(&contract), market_data.listing_nonce);
+
+    call_as(seller(), 1);
+    contract.delete_market_data(nft(), "1".to_string());
+    list_token(&mut contract, "1", json!({}));
+    let relisted = nonce(&contract);
+    assert_ne!(relisted, market_data.listing_nonce);
+    assert!(events()
+        .iter()
+        .any(|(_, data)| data["listing_nonce"] == json!(U64(relisted))));
+}
+
+#[test]
+fn update_market_data_needs_a_listing_without_bids() {
+    let mut contract = setup_auction_with_bid();
+    let stderr = expect_panic("update_market_data_needs_a_listing_without_bids", || {
+        call_as(seller(), 1);
+        contract.update_market_data(
+            nft(),
+            "1".to_string(),
+            "near".parse().unwrap(),
+            U128(2 * PRICE),
+            None,
+            None,
+            None,
+            None,
+        );
+    });
+    assert!(
+        stderr.contains("has already started or has bids"),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn accept_bid_checks_the_bid_against_the_current_price() {
+    let mut contract = setup_auction_with(json!({
+        "started_at": U64(DAY),
+        "ended_at": U64(2 * DAY),
+    }));
+    // lowered before the start, bids then only need the new price
+    call_as(seller(), 1);
+    contract.update_market_data(
+        nft(),
+        "1".to_string(),
+        "near".parse().unwrap(),
+        U128(PRICE / 2),
+        None,
+        None,
+        None,
+        None,
+    );
+    bid_at(&mut contract, buyer(), PRICE / 2, DAY);
+
+    // a price above the pending bid, as state written by an older version
+    // could hold, keeps accept_bid from settling it
+    let key = format!("{}||1", nft());
+    let mut market_data = contract.market.get(&key).unwrap();
+    market_data.price = PRICE;
+    contract.market.insert(&key, &market_data);
+    let stderr = expect_panic(
+        "accept_bid_checks_the_bid_against_the_current_price",
+        || {
+            call_at(seller(), 1, 2 * DAY + 1);
+            contract.accept_bid(nft(), "1".to_string(), None);
+        },
+    );
+    assert!(
+        stderr.contains("Can't pay less than starting price"),
+        "{}",
+        stderr
+    );
+
+    // back at the bid's price it settles
+    market_data.price = PRICE / 2;
+    contract.market.insert(&key, &market_data);
+    call_at(seller(), 1, 2 * DAY + 1);
+    contract.accept_bid(nft(), "1".to_string(), None);
+    assert_eq!(nft_receiver(), Some(buyer()));
+}
+
+#[test]
+fn relist_needs_a_listing_without_bids() {
+    let mut contract = setup_auction_with_bid();
+    let stderr = expect_panic("relist_needs_a_listing_without_bids", || {
+        call_as(seller(), 1);
+        contract.relist(nft(), "1".to_string(), U128(PRICE), None, None, None, None);
+    });
+    assert!(
+        stderr.contains("has already started or has bids"),
+        "{}",
+        stderr
+    );
+}
+
+/// update_market_data on token "1" to `price` in NEAR
+fn reprice(contract: &mut Contract, price: Balance) {
+    call_as(seller(), 1);
+    contract.update_market_data(
+        nft(),
+        "1".to_string(),
+        "near".parse().unwrap(),
+        U128(price),
+        None,
+        None,
+        None,
+        None,
+    );
+}
+
+#[test]
+fn update_market_data_keeps_the_price_below_the_buyout() {
+    let mut contract = setup_buyout_auction();
+    reprice(&mut contract, BUYOUT);
+    let stderr = expect_panic(
+        "update_market_data_keeps_the_price_below_the_buyout",
+        || {
+            reprice(&mut contract, BUYOUT + 1);
+        },
+    );
+    assert!(
+        stderr.contains("buyout_price is for auctions only"),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn update_market_data_keeps_the_price_below_the_reserve() {
+    let mut contract = setup_auction_with(json!({ "reserve_price": U128(2 * PRICE) }));
+    reprice(&mut contract, 2 * PRICE);
+    let stderr = expect_panic(
+        "update_market_data_keeps_the_price_below_the_reserve",
+        || {
+            reprice(&mut contract, 2 * PRICE + 1);
+        },
+    );
+    assert!(
+        stderr.contains("reserve_price is for auctions only"),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn change_listing_currency_moves_an_ft_listing_to_near() {
+    let (mut contract, _) = setup_ft_listing();
+
+    call_as(seller(), 1);
+    contract.change_listing_currency(
+        nft(),
+        "1".to_string(),
+        "near".parse().unwrap(),
+        U128(2 * PRICE),
+    );
+
+    let market_data = contract.market.get(&format!("{}||1", nft())).unwrap();
+    assert_eq!(market_data.ft_token_id.as_str(), "near");
+    assert_eq!(market_data.price, 2 * PRICE);
+    let (_, data) = events()
+        .into_iter()
+        .find(|(event, _)| event == "update_market_data")
+        .unwrap();
+    assert_eq!(data["ft_token_id"], json!("near"));
+    assert_eq!(data["price"], json!(U128(2 * PRICE)));
+
+    // and sells for NEAR
+    call_as(buyer(), 2 * PRICE);
+    contract.buy(nft(), "1".to_string(), None, None, None, None);
+    assert!(contract.market.get(&format!("{}||1", nft())).is_none());
+}
+
+#[test]
+fn change_listing_currency_checks_the_minimum_price() {
+    let (mut contract, _) = setup_ft_listing();
+    let stderr = expect_panic("change_listing_currency_checks_the_minimum_price", || {
+        call_as(seller(), 1);
+        contract.change_listing_currency(nft(), "1".to_string(), "near".parse().unwrap(), U128(0));
+    });
+    assert!(stderr.contains("price needs free_listing"), "{}", stderr);
+}
+
+#[test]
+fn change_listing_currency_rejects_prices_in_the_old_currency() {
+    let second = 10u64.pow(9);
+    let (mut contract, _) = setup_listing();
+    call_as(seller(), 2 * AUCTION_STORAGE);
+    contract.storage_deposit(None, None);
+    list_token(
+        &mut contract,
+        "2",
+        json!({
+            "start_price": U128(3 * PRICE),
+            "started_at": U64(100 * second),
+            "ended_at": U64(200 * second),
+        }),
+    );
+    list_token(
+        &mut contract,
+        "3",
+        json!({ "is_auction": true, "ended_at": U64(DAY), "reserve_price": U128(2 * PRICE) }),
+    );
+    for (case, token_id) in ["2", "3"].iter().enumerate() {
+        let stderr = expect_panic_case(
+            "change_listing_currency_rejects_prices_in_the_old_currency",
+            case,
+            || {
+                call_as(seller(), 1);
+                contract.change_listing_currency(
+                    nft(),
+                    token_id.to_string(),
+                    "near".parse().unwrap(),
+                    U128(2 * PRICE),
+                );
+            },
+        );
+        if std::env::var_os("MARKET_EXPECT_PANIC_CASE").is_none() {
+            assert!(
+                stderr.contains("relist the token instead"),
+                "{}: {}",
+                token_id,
+                stderr
+            );
+        }
+    }
+}
+
+#[test]
+fn change_listing_currency_keeps_offers_in_the_listing_currency() {
+    let (mut contract, _) = setup_listing();
+    let usdc: AccountId = "usdc.near".parse().unwrap();
+    call_as(market(), 1);
+    contract.add_approved_ft_token_ids(vec![usdc.clone()]);
+    call_as(buyer(), STORAGE);
+    contract.storage_deposit(None, None);
+    call_as(buyer(), ONE_NEAR);
+    contract.add_offer(
+        nft(),
+        "1".to_string(),
+        "near".parse().unwrap(),
+        U128(ONE_NEAR),
+        None,
+        None,
+    );
+
+    let stderr = expect_panic(
+        "change_listing_currency_keeps_offers_in_the_listing_currency",
+        || {
+            call_as(seller(), 1);
+            contract.change_listing_currency(nft(), "1".to_string(), usdc, U128(PRICE));
+        },
+    );
+    assert!(stderr.contains("offers in a currency"), "{}", stderr);
+
+    // repricing in the same currency is still fine
+    call_as(seller(), 1);
+    contract.change_listing_currency(
+        nft(),
+        "1".to_string(),
+        "near".parse().unwrap(),
+        U128(2 * PRICE),
+    );
+    let market_data = contract
+        .get_market_data_raw(nft(), "1".to_string())
+        .unwrap();
+    assert_eq!(market_data.price, 2 * PRICE);
+}
+
+#[test]
+fn sale_logs_an_nft_sale_event_for_indexers() {
+    let (mut contract, market_data) = setup_listing();
+    call_as(buyer(), PRICE);
+    contract.buy(nft(), "1".to_string(), None, None, None, None);
+
+    let payout = json!({ "payout": { seller().to_string(): U128(PRICE) } });
+    callback_with(PromiseResult::Successful(payout.to_string().into_bytes()));
+    contract.resolve_purchase(buyer(), market_data, U128(PRICE), None, true);
+
+    let sales: Vec<Value> = events()
+        .into_iter()
+        .filter(|(event, _)| event == "nft_sale")
+        .map(|(_, data)| data)
+        .collect();
+    assert_eq!(
+        sales,
+        vec![json!({
+            "market_type": "sale",
+            "nft_contract_id": nft(),
+            "token_id": "1",
+            "owner_id": seller(),
+            "buyer_id": buyer(),
+            "price": U128(PRICE),
+            "ft_token_id": "near",
+        })]
+    );
+}
+
+#[test]
+fn round_fee_up_takes_the_ceiling_of_uneven_fees() {
+    let (mut contract, _) = setup_listing();
+    let fee_paid = |contract: &mut Contract, price: Balance| {
+        let market_data = MarketData {
+            price,
+            ..contract
+                .get_market_data_raw(nft(), "1".to_string())
+                .unwrap()
+        };
+        let payout = json!({ "payout": { seller().to_string(): U128(price) } });
+        callback_with(PromiseResult::Successful(payout.to_string().into_bytes()));
+        contract.resolve_purchase(buyer(), market_data, U128(price), None, true);
+        let transfers = transfers();
+        assert_eq!(
+            received(&transfers, &seller()) + received(&transfers, &treasury()),
+            price
+        );
+        received(&transfers, &treasury())
+    };
+
+    // 2% of PRICE + 1 yocto isn't a whole number of yocto
+    let floor = PRICE * 200 / 10_000;
+    assert_eq!(fee_paid(&mut contract, PRICE + 1), floor);
+    assert_eq!(fee_paid(&mut contract, PRICE), floor);
+
+    call_as(market(), 1);
+    contract.set_round_fee_up(true);
+    assert_eq!(fee_paid(&mut contract, PRICE + 1), floor + 1);
+    assert_eq!(fee_paid(&mut contract, PRICE), floor);
+}
+
+#[test]
+fn second_accept_bid_waits_for_the_first_settlement() {
+    let mut contract = setup_auction_with_bid();
+    call_as(seller(), 1);
+    contract.accept_bid(nft(), "1".to_string(), Some(true));
+
+    let stderr = expect_panic("second_accept_bid_waits_for_the_first_settlement", || {
+        call_as(seller(), 1);
+        contract.accept_bid(nft(), "1".to_string(), None);
+    });
+    assert!(
+        stderr.contains("already waiting on nft_is_approved"),
+        "{}",
+        stderr
+    );
+
+    callback_with(PromiseResult::Successful(b"true".to_vec()));
+    assert!(contract.resolve_accept_bid(seller(), nft(), "1".to_string(), U64(0)));
+    let settled = events()
+        .iter()
+        .filter(|(event, _)| event == "settle_auction")
+        .count();
+    assert_eq!(settled, 1);
+    assert!(contract
+        .get_market_data_raw(nft(), "1".to_string())
+        .is_none());
+}
+
+#[test]
+fn revoked_approval_fails_accept_bid_before_any_refund() {
+    let mut contract = setup_auction_with_bid();
+    bid_at(&mut contract, creator(), 2 * PRICE, 1);
+    call_at(seller(), 1, 1);
+    contract.accept_bid(nft(), "1".to_string(), Some(true));
+    let calls: Vec<String> = get_created_receipts()
+        .into_iter()
+        .flat_map(|receipt| receipt.actions)
+        .filter_map(|action| match action {
+            VmAction::FunctionCall { function_name, .. } => Some(function_name),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(calls, ["nft_is_approved", "resolve_accept_bid"]);
+    assert!(transfers().is_empty());
+
+    callback_with(PromiseResult::Successful(b"false".to_vec()));
+    assert!(!contract.resolve_accept_bid(seller(), nft(), "1".to_string(), U64(0)));
+    assert!(transfers().is_empty());
+    assert!(events()
+        .iter()
+        .any(|(event, _)| event == "accept_bid_not_approved"));
+    let bids = contract
+        .get_market_data_raw(nft(), "1".to_string())
+        .and_then(|market_data| market_data.bids)
+        .unwrap();
+    assert_eq!(bids.len(), 2);
+    assert_eq!(
+        contract.get_auction_escrow(nft(), "1".to_string()).0,
+        3 * PRICE
+    );
+}
+
+fn bid_error(check: BidCheck) -> String {
+    match check {
+        BidCheck::Ok => panic!("bid would be accepted"),
+        BidCheck::Failed { error } => error,
+    }
+}
+
+#[test]
+fn can_bid_accepts_a_bid_add_bid_would_take() {
+    let mut contract = setup_auction_with_bid();
+    call_as(creator(), STORAGE);
+    contract.storage_deposit(None, None);
+
+    call_as(creator(), 0);
+    let check = contract.can_bid(nft(), "1".to_string(), U128(PRICE + 1), creator());
+    assert!(matches!(check, BidCheck::Ok));
+    // nothing was escrowed by the dry run
+    assert_eq!(
+        contract
+            .market
+            .get(&format!("{}||1", nft()))
+            .unwrap()
+            .bids
+            .unwrap()
+            .len(),
+        1
+    );
+}
+
+#[test]
+fn can_bid_reports_why_a_listing_takes_no_bid() {
+    let (contract, _) = setup_listing();
+    call_as(buyer(), 0);
+    assert_eq!(
+        bid_error(contract.can_bid(nft(), "1".to_string(), U128(PRICE), buyer())),
+        "Error: the NFT is not on auction"
+    );
+    assert_eq!(
+        bid_error(contract.can_bid(nft(), "2".to_string(), U128(PRICE), buyer())),
+        "Error: Market data does not exist"
+    );
+}
+
+#[test]
+fn can_bid_reports_an_ended_auction() {
+    let contract = setup_auction();
+    testing_env!(VMContextBuilder::new()
+        .current_account_id(market())
+        .predecessor_account_id(buyer())
+        .block_timestamp(10u64.pow(9) * 86_400 + 1)
+        .build());
+    assert_eq!(
+        bid_error(contract.can_bid(nft(), "1".to_string(), U128(PRICE), buyer())),
+        "Error: Sale has ended"
+    );
+}
+
+#[test]
+fn can_bid_reports_a_bid_on_the_own_listing() {
+    let contract = setup_auction();
+    call_as(seller(), 0);
+    assert_eq!(
+        bid_error(contract.can_bid(nft(), "1".to_string(), U128(PRICE), seller())),
+        "Error: Owner cannot bid their own token"
+    );
+}
+
+#[test]
+fn can_bid_reports_missing_storage() {
+    let contract = setup_auction();
+    call_as(buyer(), 0);
+    assert!(
+        bid_error(contract.can_bid(nft(), "1".to_string(), U128(PRICE), buyer()))
+            .starts_with("Error: Insufficient storage paid")
+    );
+}
+
+#[test]
+fn can_bid_reports_a_bid_below_the_starting_price() {
+    let mut contract = setup_auction();
+    call_as(buyer(), STORAGE);
+    contract.storage_deposit(None, None);
+    assert_eq!(
+        bid_error(contract.can_bid(nft(), "1".to_string(), U128(PRICE - 1), buyer())),
+        format!("Error: Can't pay less than starting price: {}", PRICE)
+    );
+}
+
+#[test]
+fn can_bid_reports_a_bid_not_above_the_leader() {
+    let mut contract = setup_auction_with_bid();
+    call_as(creator(), STORAGE);
+    contract.storage_deposit(None, None);
+    assert_eq!(
+        bid_error(contract.can_bid(nft(), "1".to_string(), U128(PRICE), creator())),
+        format!(
+            "Error: Can't pay less than or equal to current bid price: {}",
+            PRICE
+        )
+    );
+}
+
+#[test]
+fn storage_deposit_enforces_min_storage_deposit() {
+    call_as(market(), 1);
+    let mut contract = Contract::new(market(), treasury(), None, Some(vec![nft()]));
+    contract.set_min_storage_listings(3);
+    assert_eq!(contract.storage_balance_bounds().min, U128(3 * STORAGE));
+    assert_eq!(contract.get_config().min_storage_deposit, U128(3 * STORAGE));
+
+    // registration_only still takes one listing's worth and refunds the rest
+    call_as(seller(), 2 * STORAGE);
+    contract.storage_deposit(None, Some(true));
+    assert_eq!(contract.storage_balance_of(seller()), U128(STORAGE));
+    assert_eq!(received(&transfers(), &seller()), STORAGE);
+
+    call_as(buyer(), 3 * STORAGE);
+    contract.storage_deposit(None, None);
+    assert_eq!(contract.storage_balance_of(buyer()), U128(3 * STORAGE));
+
+    let stderr = expect_panic("storage_deposit_enforces_min_storage_deposit", || {
+        call_as(creator(), STORAGE);
+        contract.storage_deposit(None, None);
+    });
+    assert!(stderr.contains(&format!(
+        "Error: Requires minimum deposit of {}",
+        3 * STORAGE
+    )));
+}
+
+fn ft() -> AccountId {
+    "ft.near".parse().unwrap()
+}
+
+/// ft_transfer calls on `ft_token_id` created by the last call, as (receiver, amount)
+/// receiver of the nft_transfer_payout scheduled by the last call
+fn nft_receiver() -> Option<AccountId> {
+    get_created_receipts()
+        .into_iter()
+        .filter(|receipt| receipt.receiver_id == nft())
+        .flat_map(|receipt| receipt.actions)
+        .find_map(|action| match action {
+            VmAction::FunctionCall {
+                function_name,
+                args,
+                ..
+            } if function_name == "nft_transfer_payout" => {
+                let args: Value = near_sdk::serde_json::from_slice(&args).unwrap();
+                Some(args["receiver_id"].as_str().unwrap().parse().unwrap())
+            }
+            _ => None,
+        })
+}
+
+fn ft_transfers(ft_token_id: &AccountId) -> Vec<(AccountId, Balance)> {
+    get_created_receipts()
+        .into_iter()
+        .filter(|receipt| receipt.receiver_id == *ft_token_id)
+        .flat_map(|receipt| receipt.actions)
+        .filter_map(|action| match action {
+            VmAction::FunctionCall {
+                function_name,
+                args,
+                deposit,
+                ..
+            } if function_name == "ft_transfer" => {
+                assert_eq!(deposit, 1);
+                let args: Value = near_sdk::serde_json::from_slice(&args).unwrap();
+                Some((
+                    args["receiver_id"].as_str().unwrap().parse().unwrap(),
+                    args["amount"].as_str().unwrap().parse().unwrap(),
+                ))
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+/// Lists token "1" for PRICE of ft() and returns the listing.
+fn setup_ft_listing() -> (Contract, MarketData) {
+    call_as(market(), 0);
+    let mut contract = Contract::new(market(), treasury(), Some(vec![ft()]), Some(vec![nft()]));
+
+    call_as(seller(), STORAGE);
+    contract.storage_deposit(None, None);
+
+    testing_env!(VMContextBuilder::new()
+        .current_account_id(market())
+        .predecessor_account_id(nft())
+        .signer_account_id(seller())
+        .build());
+    contract.nft_on_approve(
+        "1".to_string(),
+        seller(),
+        0,
+        json!({ "market_type": "sale", "price": U128(PRICE), "ft_token_id": ft() }).to_string(),
+    );
+
+    let market_data = contract.market.get(&format!("{}||1", nft())).unwrap();
+    (contract, market_data)
+}
+
+fn ft_buy_message() -> String {
+    json!({
+        "version": "1",
+        "action": "buy",
+        "nft_contract_id": nft(),
+        "token_id": "1",
+    })
+    .to_string()
+}
+
+#[test]
+fn auctions_in_an_ft_are_rejected() {
+    let (mut contract, _) = setup_ft_listing();
+    call_as(seller(), 2 * AUCTION_STORAGE);
+    contract.storage_deposit(None, None);
+    list_token(
+        &mut contract,
+        "2",
+        json!({ "is_auction": true, "ended_at": U64(DAY) }),
+    );
+    call_as(buyer(), STORAGE);
+    contract.storage_deposit(None, None);
+
+    type FailingCall = (&'static str, fn(&mut Contract));
+    let calls: Vec<FailingCall> = vec![
+        ("Error: auctions are only listed in NEAR", |c| {
+            list_token(
+                c,
+                "3",
+                json!({ "is_auction": true, "ended_at": U64(DAY), "ft_token_id": ft() }),
+            );
+        }),
+        ("Error: auctions are only listed in NEAR", |c| {
+            call_as(seller(), 1);
+            c.update_market_data(
+                nft(),
+                "1".to_string(),
+                ft(),
+                U128(PRICE),
+                None,
+                None,
+                Some(U64(DAY)),
+                Some(true),
+            );
+        }),
+        ("Error: auctions are only listed in NEAR", |c| {
+            call_as(seller(), 1);
+            c.change_listing_currency(nft(), "2".to_string(), ft(), U128(PRICE));
+        }),
+        ("Error: Only NEAR is supported", |c| {
+            call_as(buyer(), PRICE);
+            c.add_bid(nft(), ft(), "2".to_string(), U128(PRICE), None, None);
+        }),
+    ];
+    for (case, (error, call)) in calls.into_iter().enumerate() {
+        let stderr = expect_panic_case("auctions_in_an_ft_are_rejected", case, || {
+            call(&mut contract)
+        });
+        if std::env::var_os("MARKET_EXPECT_PANIC_CASE").is_none() {
+            assert!(stderr.contains(error), "case {}: {}", case, stderr);
+        }
+    }
+}
+
+#[test]
+fn ft_buy_pays_every_share_in_the_token() {
+    let (mut contract, market_data) = setup_ft_listing();
+
+    call_as(ft(), 0);
+    let unused = contract.ft_on_transfer(buyer(), U128(PRICE), ft_buy_message());
+    assert!(matches!(unused, PromiseOrValue::Promise(_)));
+    drop(unused); // the promise is only scheduled once dropped
+    let transfers_nft = get_created_receipts().iter().any(|receipt| {
+        receipt.receiver_id == nft()
+            && receipt.actions.iter().any(|action| {
+                matches!(action, VmAction::FunctionCall { function_name, .. }
+                    if function_name == "nft_transfer_payout")
+            })
+    });
+    assert!(transfers_nft);
+    assert!(contract.market.get(&format!("{}||1", nft())).is_none());
+
+    let payout = json!({ "payout": {
+        seller().to_string(): U128(9 * ONE_NEAR),
+        creator().to_string(): U128(ONE_NEAR),
+    }});
+    callback_with(PromiseResult::Successful(payout.to_string().into_bytes()));
+    let unused = contract.resolve_purchase(buyer(), market_data, U128(PRICE), None, false);
+
+    let fee = PRICE * 200 / 10_000;
+    let ft_transfers = ft_transfers(&ft());
+    assert_eq!(unused, U128(0));
+    assert_eq!(received(&ft_transfers, &seller()), 9 * ONE_NEAR - fee);
+    assert_eq!(received(&ft_transfers, &creator()), ONE_NEAR);
+    assert_eq!(received(&ft_transfers, &treasury()), fee);
+    assert!(transfers().is_empty());
+    // treasury_accrued and the volume count NEAR only
+    assert_eq!(contract.get_treasury_accrued().0, 0);
+    assert_eq!(contract.get_stats().total_volume.0, 0);
+}
+
+/// Token "1" for PRICE in NEAR or 5 ft
+fn setup_alt_currency_listing() -> Contract {
+    call_as(market(), 0);
+    let mut contract = Contract::new(market(), treasury(), Some(vec![ft()]), Some(vec![nft()]));
+    call_as(seller(), STORAGE);
+    contract.storage_deposit(None, None);
+    list_token(
+        &mut contract,
+        "1",
+        json!({ "alt_ft_token_id": ft(), "alt_price": U128(5) }),
+    );
+    contract
+}
+
+#[test]
+fn alt_currency_listing_sells_for_near() {
+    let mut contract = setup_alt_currency_listing();
+    call_as(buyer(), PRICE);
+    contract.buy(nft(), "1".to_string(), None, None, None, None);
+    assert!(contract.market.get(&format!("{}||1", nft())).is_none());
+
+    // the listing went with the first purchase, the ft is refunded
+    call_as(ft(), 0);
+    let unused = contract.ft_on_transfer(buyer(), U128(5), ft_buy_message());
+    assert!(matches!(unused, PromiseOrValue::Value(U128(5))));
+}
+
+#[test]
+fn alt_currency_listing_sells_for_its_alt_price() {
+    let mut contract = setup_alt_currency_listing();
+    call_as(ft(), 0);
+    let purchase = contract.ft_on_transfer(buyer(), U128(5), ft_buy_message());
+    assert!(matches!(purchase, PromiseOrValue::Promise(_)));
+    assert!(contract.market.get(&format!("{}||1", nft())).is_none());
+
+    let stderr = expect_panic("alt_currency_listing_sells_for_its_alt_price", || {
+        call_as(buyer(), PRICE);
+        contract.buy(nft(), "1".to_string(), None, None, None, None);
+    });
+    assert!(stderr.contains("Market data does not exist"), "{}", stderr);
+}
+
+#[test]
+fn failed_ft_purchase_leaves_the_price_unused() {
+    let (mut contract, market_data) = setup_ft_listing();
+
+    call_as(ft(), 0);
+    contract.ft_on_transfer(buyer(), U128(PRICE), ft_buy_message());
+
+    callback_with(PromiseResult::Failed);
+    let unused = contract.resolve_purchase(buyer(), market_data, U128(PRICE), None, false);
+
+    assert_eq!(unused, U128(PRICE));
+    assert!(ft_transfers(&ft()).is_empty());
+    assert!(transfers().is_empty());
+}
+
+#[test]
+fn failed_purchase_refunds_an_escrowed_ft_price() {
+    let (mut contract, market_data) = setup_ft_listing();
+
+    // the market already held the price, nothing returns it on its own
+    callback_with(PromiseResult::Failed);
+    contract.resolve_purchase(buyer(), market_data, U128(PRICE), None, true);
+
+    assert_eq!(received(&ft_transfers(&ft()), &buyer()), PRICE);
+    assert!(transfers().is_empty());
+    assert!(events()
+        .iter()
+        .any(|(name, data)| name == "resolve_purchase_fail" && data["ft_token_id"] == json!(ft())));
+}
+
+#[test]
+fn ft_buy_refunds_a_wrong_amount_or_currency() {
+    let (mut contract, _) = setup_ft_listing();
+
+    call_as(ft(), 0);
+    let unused = contract.ft_on_transfer(buyer(), U128(PRICE + 1), ft_buy_message());
+    assert!(matches!(unused, PromiseOrValue::Value(U128(amount)) if amount == PRICE + 1));
+    let (event, data) = events().pop().unwrap();
+    assert_eq!(event, "ft_transfer_refunded");
+    assert_eq!(
+        data["reason"],
+        format!("Error: transferred amount must equal the price {}", PRICE)
+    );
+
+    // NEAR can't buy a listing priced only in the token
+    call_as(buyer(), PRICE);
+    assert!(matches!(
+        contract.simulate_buy(nft(), "1".to_string(), U128(PRICE), None),
+        BuyCheck::Failed { error } if error == "Error: ft_token_id differs"
+    ));
+    assert!(contract.market.get(&format!("{}||1", nft())).is_some());
+}
+
+#[test]
+fn prune_expired_by_contract_only_touches_that_collection() {
+    let other_nft: AccountId = "other-nft.near".parse().unwrap();
+    call_as(market(), 0);
+    let mut contract = Contract::new(
+        market(),
+        treasury(),
+        None,
+        Some(vec![nft(), other_nft.clone()]),
+    );
+    call_as(seller(), 3 * STORAGE);
+    contract.storage_deposit(None, None);
+
+    let ended_at = 100 * 10u64.pow(9);
+    for (nft_contract_id, token_id, ended_at) in [
+        (nft(), "1", Some(U64(ended_at))),
+        (nft(), "2", None),
+        (other_nft.clone(), "1", Some(U64(ended_at))),
+    ] {
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(market())
+            .predecessor_account_id(nft_contract_id)
+            .signer_account_id(seller())
+            .build());
+        contract.nft_on_approve(
+            token_id.to_string(),
+            seller(),
+            0,
+            json!({ "market_type": "sale", "price": U128(PRICE), "ended_at": ended_at })
+                .to_string(),
+        );
+    }
+
+    testing_env!(VMContextBuilder::new()
+        .current_account_id(market())
+        .predecessor_account_id(buyer())
+        .block_timestamp(ended_at + 1)
+        .build());
+    assert_eq!(contract.prune_expired_by_contract(nft(), 10), 1);
+    let (event, data) = events().pop().unwrap();
+    assert_eq!(event, "delete_market_data");
+    assert_eq!(data["reason"], "expired");
+
+    assert!(contract.market.get(&format!("{}||1", nft())).is_none());
+    assert!(contract.market.get(&format!("{}||2", nft())).is_some());
+    assert!(contract.market.get(&format!("{}||1", other_nft)).is_some());
+    assert_eq!(contract.prune_expired_by_contract(nft(), 10), 0);
+    assert_eq!(contract.prune_expired_by_contract(other_nft, 10), 1);
+}
+
+#[test]
+fn listings_page_across_the_market_and_by_owner() {
+    let (mut contract, _) = setup_listing();
+    call_as(seller(), 2 * STORAGE);
+    contract.storage_deposit(None, None);
+
+    // the seller's offer on another token shares their by_owner_id set
+    call_as(seller(), ONE_NEAR);
+    contract.add_offer(
+        nft(),
+        "9".to_string(),
+        "near".parse().unwrap(),
+        U128(ONE_NEAR),
+        None,
+        None,
+    );
+    testing_env!(VMContextBuilder::new()
+        .current_account_id(market())
+        .predecessor_account_id(nft())
+        .signer_account_id(seller())
+        .build());
+    contract.nft_on_approve(
+        "2".to_string(),
+        seller(),
+        0,
+        json!({ "market_type": "sale", "price": U128(PRICE) }).to_string(),
+    );
+
+    assert_eq!(contract.get_supply_market_datas(), U64(2));
+    assert_eq!(contract.get_supply_by_owner_id(seller()), U64(3));
+    let token_ids = |listings: Vec<MarketDataJson>| -> Vec<String> {
+        listings
+            .into_iter()
+            .map(|listing| {
+                near_sdk::serde_json::to_value(listing).unwrap()["token_id"]
+                    .as_str()
+                    .unwrap()
+                    .to_string()
+            })
+            .collect()
+    };
+    assert_eq!(token_ids(contract.get_market_datas(None, None)), ["1", "2"]);
+    assert_eq!(
+        token_ids(contract.get_market_data_by_owner_id(seller(), None, None)),
+        ["1", "2"]
+    );
+    assert_eq!(
+        token_ids(contract.get_market_data_by_owner_id(seller(), Some(U64(1)), Some(5))),
+        ["2"]
+    );
+    assert!(contract
+        .get_market_data_by_owner_id(buyer(), None, None)
+        .is_empty());
+}
+
+/// creator() offers `price` on token "1"
+fn offer_from_creator(contract: &mut Contract, price: Balance) {
+    offer_from(contract, creator(), price);
+}
+
+/// `buyer_id` registers storage and offers `price` NEAR for token "1"
+fn offer_from(contract: &mut Contract, buyer_id: AccountId, price: Balance) {
+    call_as(buyer_id.clone(), STORAGE);
+    contract.storage_deposit(None, None);
+    call_as(buyer_id, price);
+    contract.add_offer(
+        nft(),
+        "1".to_string(),
+        "near".parse().unwrap(),
+        U128(price),
+        None,
+        None,
+    );
+}
+
+#[test]
+fn token_id_with_the_key_delimiter_fails() {
+    // its listing key would equal buyer.near's offer key on token "1"
+    let token_id = format!("{}||1", buyer());
+    let (mut contract, _) = setup_listing();
+    call_as(seller(), STORAGE);
+    contract.storage_deposit(None, None);
+    call_as(creator(), STORAGE);
+    contract.storage_deposit(None, None);
+
+    let calls: Vec<fn(&mut Contract, &str)> = vec![
+        |c, token_id| list_token(c, token_id, json!({})),
+        |c, token_id| {
+            call_as(creator(), ONE_NEAR);
+            c.add_offer(
+                nft(),
+                token_id.to_string(),
+                "near".parse().unwrap(),
+                U128(ONE_NEAR),
+                None,
+                None,
+            );
+        },
+    ];
+    for (case, call) in calls.into_iter().enumerate() {
+        let stderr = expect_panic_case("token_id_with_the_key_delimiter_fails", case, || {
+            call(&mut contract, &token_id)
+        });
+        if std::env::var_os("MARKET_EXPECT_PANIC_CASE").is_none() {
+            assert!(
+                stderr.contains("token_id must not contain ||"),
+                "case {}: {}",
+                case,
+                stderr
+            );
+        }
+    }
+}
+
+#[test]
+fn paused_offers_leave_listings_and_offer_refunds_working() {
+    let (mut contract, _) = setup_listing();
+    offer_from_creator(&mut contract, ONE_NEAR);
+    call_as(market(), 1);
+    contract.set_offers_paused(true);
+    assert!(contract.are_offers_paused());
+
+    let stderr = expect_panic(
+        "paused_offers_leave_listings_and_offer_refunds_working",
+        || offer_from(&mut contract, buyer(), ONE_NEAR),
+    );
+    assert!(stderr.contains("offers are paused"));
+
+    // the escrowed offer can still be taken back
+    call_as(creator(), 1);
+    contract.delete_offer(nft(), "1".to_string());
+    assert_eq!(received(&transfers(), &creator()), ONE_NEAR);
+
+    call_as(buyer(), PRICE);
+    contract.buy(nft(), "1".to_string(), None, None, None, None);
+    assert!(contract.market.get(&format!("{}||1", nft())).is_none());
+}
+
+#[test]
+fn offers_are_listed_per_collection() {
+    let (mut contract, _) = setup_listing();
+    let art: AccountId = "art.near".parse().unwrap();
+    call_as(market(), 1);
+    contract.add_approved_nft_contract_ids(vec![art.clone()], None);
+    call_as(creator(), 2 * STORAGE);
+    contract.storage_deposit(None, None);
+    call_as(buyer(), STORAGE);
+    contract.storage_deposit(None, None);
+    for (buyer_id, nft_contract_id, token_id) in [
+        (creator(), nft(), "1"),
+        (buyer(), nft(), "2"),
+        (creator(), art.clone(), "1"),
+    ] {
+        call_as(buyer_id, ONE_NEAR);
+        contract.add_offer(
+            nft_contract_id,
+            token_id.to_string(),
+            "near".parse().unwrap(),
+            U128(ONE_NEAR),
+            None,
+            None,
+        );
+    }
+    let offers_on = |contract: &Contract, nft_contract_id: &AccountId| {
+        let mut offers: Vec<(String, String)> = contract
+            .get_offers_by_contract(nft_contract_id.clone(), None, None)
+            .into_iter()
+            .map(|offer| {
+                let offer = near_sdk::serde_json::to_value(offer).unwrap();
+                (
+                    offer["buyer_id"].as_str().unwrap().to_string(),
+                    offer["token_id"].as_str().unwrap().to_string(),
+                )
+            })
+            .collect();
+        offers.sort();
+        offers
+    };
+    let offer = |buyer_id: AccountId, token_id: &str| (buyer_id.to_string(), token_id.to_string());
+
+    assert_eq!(
+        offers_on(&contract, &nft()),
+        [offer(buyer(), "2"), offer(creator(), "1")]
+    );
+    assert_eq!(offers_on(&contract, &art), [offer(creator(), "1")]);
+    assert_eq!(
+        contract
+            .get_offers_by_contract(nft(), Some(U64(1)), Some(5))
+            .len(),
+        1
+    );
+
+    call_as(creator(), 1);
+    contract.delete_offer(nft(), "1".to_string());
+    assert_eq!(offers_on(&contract, &nft()), [offer(buyer(), "2")]);
+    assert_eq!(offers_on(&contract, &art), [offer(creator(), "1")]);
+}
+
+#[test]
+fn offer_context_joins_the_offer_and_the_listing_price() {
+    let mut contract = setup_two_listings();
+    offer_from_creator(&mut contract, ONE_NEAR);
+    call_as(seller(), 1);
+    contract.delete_market_data(nft(), "2".to_string());
+    call_as(creator(), STORAGE);
+    contract.storage_deposit(None, None);
+    call_as(creator(), ONE_NEAR);
+    contract.add_offer(
+        nft(),
+        "2".to_string(),
+        "near".parse().unwrap(),
+        U128(ONE_NEAR),
+        None,
+        None,
+    );
+
+    let context = |token_id: &str| {
+        near_sdk::serde_json::to_value(contract.get_offer_context(
+            nft(),
+            creator(),
+            token_id.to_string(),
+        ))
+        .unwrap()
+    };
+    let listed = context("1");
+    assert_eq!(listed["offer"]["price"], json!(U128(ONE_NEAR)));
+    assert_eq!(listed["listing_price"], json!(U128(PRICE)));
+    assert_eq!(listed["listing_ft_token_id"], json!("near"));
+
+    let unlisted = context("2");
+    assert_eq!(unlisted["offer"]["token_id"], json!("2"));
+    assert_eq!(unlisted["listing_price"], Value::Null);
+    assert_eq!(unlisted["listing_ft_token_id"], Value::Null);
+}
+
+#[test]
+fn offer_retried_with_the_same_nonce_escrows_once() {
+    let (mut contract, _) = setup_listing();
+    call_as(creator(), STORAGE);
+    contract.storage_deposit(None, None);
+    let offer = |contract: &mut Contract, nonce: u64| {
+        call_as(creator(), ONE_NEAR);
+        contract.add_offer(
+            nft(),
+            "1".to_string(),
+            "near".parse().unwrap(),
+            U128(ONE_NEAR),
+            Some(U64(nonce)),
+            None,
+        );
+        events()
+            .into_iter()
+            .filter(|(event, _)| event == "add_offer")
+            .count()
+    };
+    let key = format!("{}||{}||1", nft(), creator());
+
+    assert_eq!(offer(&mut contract, 7), 1);
+    assert!(transfers().is_empty());
+
+    // the retry's deposit goes straight back, the offer stays as it was
+    assert_eq!(offer(&mut contract, 7), 0);
+    assert_eq!(transfers(), vec![(creator(), ONE_NEAR)]);
+    assert_eq!(contract.offers.get(&key).unwrap().nonce, Some(7));
+    assert_eq!(contract.get_supply_by_owner_id(creator()).0, 1);
+
+    // a new nonce is a new offer, replacing the old one
+    assert_eq!(offer(&mut contract, 8), 1);
+    assert_eq!(contract.offers.get(&key).unwrap().nonce, Some(8));
+}
+
+#[test]
+fn cancel_all_offers_refunds_every_offer_in_batches() {
+    let (mut contract, _) = setup_listing();
+    let art: AccountId = "art.near".parse().unwrap();
+    call_as(market(), 1);
+    contract.add_approved_nft_contract_ids(vec![art.clone()], None);
+    call_as(creator(), 3 * STORAGE);
+    contract.storage_deposit(None, None);
+    for (nft_contract_id, token_id) in [(nft(), "1"), (nft(), "2"), (art, "1")] {
+        call_as(creator(), ONE_NEAR);
+        contract.add_offer(
+            nft_contract_id,
+            token_id.to_string(),
+            "near".parse().unwrap(),
+            U128(ONE_NEAR),
+            None,
+            None,
+        );
+    }
+    // someone else's offer is left alone
+    offer_from(&mut contract, buyer(), ONE_NEAR);
+    let deleted = || {
+        events()
+            .into_iter()
+            .filter(|(event, _)| event == "delete_offer")
+            .count()
+    };
+
+    call_as(creator(), 1);
+    assert_eq!(contract.cancel_all_offers(Some(2)).0, 1);
+    assert_eq!(received(&transfers(), &creator()), 2 * ONE_NEAR);
+    assert_eq!(deleted(), 2);
+
+    call_as(creator(), 1);
+    assert_eq!(contract.cancel_all_offers(Some(2)).0, 0);
+    assert_eq!(received(&transfers(), &creator()), ONE_NEAR);
+    assert_eq!(deleted(), 1);
+    assert_eq!(contract.get_supply_by_owner_id(creator()).0, 0);
+    assert_eq!(contract.get_supply_by_owner_id(buyer()).0, 1);
+}
+
+#[test]
+fn usd_floor_is_converted_with_the_oracle_price() {
+    let (mut contract, _) = setup_listing();
+    let oracle: AccountId = "oracle.near".parse().unwrap();
+    call_as(market(), 1);
+    contract.set_price_oracle(Some(oracle.clone()));
+
+    call_as(seller(), 1);
+    drop(contract.set_usd_floor(nft(), "1".to_string(), U128(500)));
+    let asked = get_created_receipts().into_iter().any(|receipt| {
+        receipt.receiver_id == oracle
+            && receipt.actions.iter().any(|action| {
+                matches!(action, VmAction::FunctionCall { function_name, args, .. }
+                    if function_name == "get_price"
+                        && String::from_utf8_lossy(args).contains("\"near\""))
+            })
+    });
+    assert!(asked);
+
+    // the mocked oracle: one USD is 0.4 NEAR, so $5 is 2 NEAR
+    let units_per_usd = U128(4 * ONE_NEAR / 10);
+    callback_with(PromiseResult::Successful(
+        json!(units_per_usd).to_string().into_bytes(),
+    ));
+    let floor =
+        contract.resolve_usd_floor(nft(), "1".to_string(), "near".parse().unwrap(), U128(500));
+    assert_eq!(floor, Some(U128(2 * ONE_NEAR)));
+    let key = format!("{}||1", nft());
+    assert_eq!(
+        contract.market.get(&key).unwrap().min_offer_price,
+        Some(2 * ONE_NEAR)
+    );
+
+    // a stale or failed answer leaves the stored floor as it was
+    callback_with(PromiseResult::Failed);
+    let floor =
+        contract.resolve_usd_floor(nft(), "1".to_string(), "near".parse().unwrap(), U128(100));
+    assert_eq!(floor, None);
+    assert_eq!(
+        contract.market.get(&key).unwrap().min_offer_price,
+        Some(2 * ONE_NEAR)
+    );
+
+    let stderr = expect_panic("usd_floor_is_converted_with_the_oracle_price", || {
+        offer_from_creator(&mut contract, ONE_NEAR)
+    });
+    assert!(
+        stderr.contains("Offer is below min_accept_price"),
+        "{}",
+        stderr
+    );
+    offer_from_creator(&mut contract, 2 * ONE_NEAR);
+}
+
+fn accept_creator_offer(contract: &mut Contract, price: Balance) {
+    testing_env!(VMContextBuilder::new()
+        .current_account_id(market())
+        .predecessor_account_id(nft())
+        .signer_account_id(seller())
+        .build());
+    contract.nft_on_approve(
+        "1".to_string(),
+        seller(),
+        1,
+        json!({ "market_type": "accept_offer", "buyer_id": creator(), "price": U128(price) })
+            .to_string(),
+    );
+}
+
+#[test]
+fn accepting_an_offer_during_an_auction_refunds_its_bids() {
+    let mut contract = setup_auction_with_bid();
+    offer_from_creator(&mut contract, 2 * PRICE);
+    assert!(contract
+        .get_market_data_raw(nft(), "1".to_string())
+        .is_some());
+
+    accept_creator_offer(&mut contract, 2 * PRICE);
+
+    assert!(contract
+        .get_market_data_raw(nft(), "1".to_string())
+        .is_none());
+    assert_eq!(received(&transfers(), &buyer()), PRICE);
+    let refunded = events()
+        .into_iter()
+        .find(|(event, _)| event == "bids_refunded")
+        .expect("bids were not refunded");
+    assert_eq!(refunded.1["refunds"][0]["bidder_id"], buyer().to_string());
+    let transfers_to_offerer = get_created_receipts().iter().any(|receipt| {
+        receipt.receiver_id == nft()
+            && receipt.actions.iter().any(|action| {
+                matches!(action, VmAction::FunctionCall { function_name, args, .. }
+                    if function_name == "nft_transfer_payout"
+                        && String::from_utf8_lossy(args).contains(creator().as_str()))
+            })
+    });
+    assert!(transfers_to_offerer);
+}
+
+#[test]
+fn approving_with_accept_best_offer_settles_the_highest_offer() {
+    let (mut contract, _) = setup_listing();
+    offer_from_creator(&mut contract, ONE_NEAR);
+    offer_from(&mut contract, buyer(), 2 * ONE_NEAR);
+
+    testing_env!(VMContextBuilder::new()
+        .current_account_id(market())
+        .predecessor_account_id(nft())
+        .signer_account_id(seller())
+        .build());
+    contract.nft_on_approve(
+        "1".to_string(),
+        seller(),
+        1,
+        json!({ "market_type": "accept_best_offer" }).to_string(),
+    );
+
+    let transfers_to_buyer = get_created_receipts().iter().any(|receipt| {
+        receipt.receiver_id == nft()
+            && receipt.actions.iter().any(|action| {
+                matches!(action, VmAction::FunctionCall { function_name, args, .. }
+                    if function_name == "nft_transfer_payout"
+                        && String::from_utf8_lossy(args).contains(buyer().as_str()))
+            })
+    });
+    assert!(transfers_to_buyer);
+    assert!(contract
+        .offers
+        .get(&format!("{}||{}||1", nft(), buyer()))
+        .is_none());
+    assert!(contract
+        .offers
+        .get(&format!("{}||{}||1", nft(), creator()))
+        .is_some());
+}
+
+#[test]
+fn best_offer_below_min_accept_price_is_declined() {
+    let (mut contract, _) = setup_listing();
+    offer_from_creator(&mut contract, ONE_NEAR);
+    offer_from(&mut contract, buyer(), 2 * ONE_NEAR);
+
+    let stderr = expect_panic("best_offer_below_min_accept_price_is_declined", || {
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(market())
+            .predecessor_account_id(nft())
+            .signer_account_id(seller())
+            .build());
+        contract.nft_on_approve(
+            "1".to_string(),
+            seller(),
+            1,
+            json!({
+                "market_type": "accept_best_offer",
+                "min_accept_price": U128(3 * ONE_NEAR),
+            })
+            .to_string(),
+        );
+    });
+    assert!(
+        stderr.contains("Offer is below min_accept_price"),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn accepting_an_offer_waits_for_an_auction_settlement_in_flight() {
+    let mut contract = setup_auction_with_bid();
+    offer_from_creator(&mut contract, 2 * PRICE);
+    call_as(seller(), 1);
+    contract.accept_bid(nft(), "1".to_string(), Some(true));
+
+    let stderr = expect_panic(
+        "accepting_an_offer_waits_for_an_auction_settlement_in_flight",
+        || accept_creator_offer(&mut contract, 2 * PRICE),
+    );
+    assert!(
+        stderr.contains("already waiting on nft_is_approved"),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn late_bids_extend_the_auction() {
+    let mut contract = setup_auction();
+    call_as(market(), 1);
+    contract.set_auction_extension(600);
+    assert_eq!(contract.get_config().auction_extension_seconds, 600);
+    call_as(buyer(), STORAGE);
+    contract.storage_deposit(None, None);
+    call_as(creator(), STORAGE);
+    contract.storage_deposit(None, None);
+
+    let second = 10u64.pow(9);
+    let ended_at = 86_400 * second;
+    let bid_at = |bidder_id: AccountId, at: u64, amount: Balance| {
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(market())
+            .predecessor_account_id(bidder_id)
+            .attached_deposit(amount)
+            .block_timestamp(at)
+            .build());
+    };
+    let ended_at_now = |contract: &Contract| {
+        contract
+            .get_market_data_raw(nft(), "1".to_string())
+            .unwrap()
+            .ended_at
+    };
+
+    // an early bid leaves ended_at alone
+    bid_at(buyer(), ended_at - 3_600 * second, PRICE);
+    contract.add_bid(
+        nft(),
+        "near".parse().unwrap(),
+        "1".to_string(),
+        U128(PRICE),
+        None,
+        None,
+    );
+    assert_eq!(ended_at_now(&contract), Some(ended_at));
+
+    bid_at(creator(), ended_at - 300 * second, PRICE + 1);
+    contract.add_bid(
+        nft(),
+        "near".parse().unwrap(),
+        "1".to_string(),
+        U128(PRICE + 1),
+        None,
+        None,
+    );
+    let extended = ended_at + 600 * second;
+    assert_eq!(ended_at_now(&contract), Some(extended));
+    let (_, add_bid) = events()
+        .into_iter()
+        .find(|(event, _)| event == "add_bid")
+        .unwrap();
+    assert_eq!(add_bid["ended_at"], extended.to_string());
+    assert_eq!(
+        contract.get_settleable_auctions(Some(U64(ended_at + 1)), None),
+        Vec::<(AccountId, String)>::new()
+    );
+}
+
+#[test]
+fn open_mode_lifts_the_nft_allowlist_until_turned_off() {
+    let testnet_market: AccountId = "market.testnet".parse().unwrap();
+    let call_testnet = |predecessor: AccountId, deposit: Balance| {
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(testnet_market.clone())
+            .predecessor_account_id(predecessor.clone())
+            .signer_account_id(predecessor)
+            .attached_deposit(deposit)
+            .build());
+    };
+    call_testnet(testnet_market.clone(), 1);
+    let mut contract = Contract::new(testnet_market.clone(), treasury(), None, None);
+    contract.set_open_mode(true);
+    let (event, data) = events().pop().unwrap();
+    assert_eq!(event, "open_mode");
+    assert_eq!(data["enabled"], true);
+    assert!(contract.get_config().open_mode);
+
+    call_testnet(seller(), STORAGE);
+    contract.storage_deposit(None, None);
+    testing_env!(VMContextBuilder::new()
+        .current_account_id(testnet_market.clone())
+        .predecessor_account_id(nft())
+        .signer_account_id(seller())
+        .build());
+    contract.nft_on_approve(
+        "1".to_string(),
+        seller(),
+        0,
+        json!({ "market_type": "sale", "price": U128(PRICE) }).to_string(),
+    );
+    assert!(contract.market.get(&format!("{}||1", nft())).is_some());
+
+    call_testnet(buyer(), STORAGE);
+    contract.storage_deposit(None, None);
+    call_testnet(buyer(), ONE_NEAR);
+    contract.add_offer(
+        nft(),
+        "1".to_string(),
+        "near".parse().unwrap(),
+        U128(ONE_NEAR),
+        None,
+        None,
+    );
+    assert!(matches!(
+        contract.simulate_buy(nft(), "1".to_string(), U128(PRICE), None),
+        BuyCheck::Ok { .. }
+    ));
+
+    call_testnet(testnet_market.clone(), 1);
+    contract.set_open_mode(false);
+    assert!(matches!(
+        contract.simulate_buy(nft(), "1".to_string(), U128(PRICE), None),
+        BuyCheck::Failed { error } if error == "Error: nft_contract_id is not approved"
+    ));
+}
+
+#[test]
+fn open_mode_stays_off_outside_testnet() {
+    call_as(market(), 1);
+    let mut contract = Contract::new(market(), treasury(), None, None);
+    let stderr = expect_panic("open_mode_stays_off_outside_testnet", || {
+        call_as(market(), 1);
+        contract.set_open_mode(true);
+    });
+    assert!(stderr.contains("open mode is only available on testnet"));
+}
+
+#[test]
+fn counter_bids_must_clear_min_bid_increment_bps() {
+    let mut contract = setup_auction();
+    call_as(market(), 1);
+    contract.set_min_bid_increment_bps(500);
+    assert_eq!(contract.get_config().min_bid_increment_bps, 500);
+    call_as(buyer(), STORAGE);
+    contract.storage_deposit(None, None);
+    call_as(creator(), STORAGE);
+    contract.storage_deposit(None, None);
+
+    // the first bid only needs the starting price
+    assert_eq!(
+        contract.get_minimum_next_bid(nft(), "1".to_string()),
+        Some(U128(PRICE))
+    );
+    assert!(matches!(
+        contract.can_bid(nft(), "1".to_string(), U128(PRICE), buyer()),
+        BidCheck::Ok
+    ));
+    call_as(buyer(), PRICE);
+    contract.add_bid(
+        nft(),
+        "near".parse().unwrap(),
+        "1".to_string(),
+        U128(PRICE),
+        None,
+        None,
+    );
+
+    let min = PRICE + PRICE * 500 / 10_000;
+    assert_eq!(
+        contract.get_minimum_next_bid(nft(), "1".to_string()),
+        Some(U128(min))
+    );
+    assert_eq!(
+        bid_error(contract.can_bid(nft(), "1".to_string(), U128(min - 1), creator())),
+        format!(
+            "Error: bid is below the minimum increment, the next bid must be at least {}",
+            min
+        )
+    );
+
+    call_as(creator(), min);
+    contract.add_bid(
+        nft(),
+        "near".parse().unwrap(),
+        "1".to_string(),
+        U128(min),
+        None,
+        None,
+    );
+    let bids = contract
+        .market
+        .get(&format!("{}||1", nft()))
+        .unwrap()
+        .bids
+        .unwrap();
+    assert_eq!(bids.last().unwrap().price, U128(min));
+}
+
+/// resolve_refund receipts scheduled by the last call, as their args
+fn resolve_refunds() -> Vec<Value> {
+    get_created_receipts()
+        .into_iter()
+        .flat_map(|receipt| receipt.actions)
+        .filter_map(|action| match action {
+            VmAction::FunctionCall {
+                function_name,
+                args,
+                ..
+            } if function_name == "resolve_refund" => {
+                Some(near_sdk::serde_json::from_slice(&args).unwrap())
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+#[test]
+fn near_refunds_are_tracked_when_they_bounce() {
+    let mut contract = setup_auction_with_bid();
+    // a second bid from the leader refunds the first one
+    testing_env!(VMContextBuilder::new()
+        .current_account_id(market())
+        .predecessor_account_id(buyer())
+        .attached_deposit(PRICE + 1)
+        .block_timestamp(1)
+        .build());
+    contract.add_bid(
+        nft(),
+        "near".parse().unwrap(),
+        "1".to_string(),
+        U128(PRICE + 1),
+        None,
+        None,
+    );
+    assert_eq!(received(&transfers(), &buyer()), PRICE);
+    let resolves = resolve_refunds();
+    assert_eq!(resolves.len(), 1);
+    assert_eq!(resolves[0]["account_id"], json!(buyer()));
+    assert_eq!(resolves[0]["amount"], json!(U128(PRICE)));
+    assert_eq!(resolves[0]["ft_token_id"], json!("near"));
+
+    callback_with(PromiseResult::Failed);
+    assert!(!contract.resolve_refund(buyer(), U128(PRICE), Some("near".parse().unwrap())));
+    assert_eq!(contract.get_failed_refund(buyer()), U128(PRICE));
+    assert_eq!(contract.get_failed_ft_refund(buyer(), ft()), U128(0));
+}
+
+#[test]
+fn ft_refunds_are_tracked_per_token_and_claimed_with_ft_transfer() {
+    call_as(market(), 0);
+    let mut contract = Contract::new(market(), treasury(), Some(vec![ft()]), Some(vec![nft()]));
+
+    callback_with(PromiseResult::Failed);
+    assert!(!contract.resolve_refund(buyer(), U128(PRICE), Some(ft())));
+    assert_eq!(contract.get_failed_ft_refund(buyer(), ft()), U128(PRICE));
+    assert_eq!(contract.get_failed_refund(buyer()), U128(0));
+    let (name, data) = events().pop().unwrap();
+    assert_eq!(name, "refund_failed");
+    assert_eq!(data["ft_token_id"], json!(ft()));
+
+    call_as(buyer(), 1);
+    assert_eq!(contract.claim_failed_ft_refund(ft(), None), U128(PRICE));
+    assert_eq!(contract.get_failed_ft_refund(buyer(), ft()), U128(0));
+    assert_eq!(ft_transfers(&ft()), vec![(buyer(), PRICE)]);
+    assert!(transfers().is_empty());
+    let resolves = resolve_refunds();
+    assert_eq!(resolves.len(), 1);
+    assert_eq!(resolves[0]["ft_token_id"], json!(ft()));
+}
+
+#[test]
+fn get_market_data_is_none_for_an_unlisted_token() {
+    let (contract, _) = setup_listing();
+    let listed = contract.get_market_data(nft(), "1".to_string()).unwrap();
+    let listed = near_sdk::serde_json::to_value(&listed).unwrap();
+    assert_eq!(listed["token_id"], json!("1"));
+    assert_eq!(listed["price"], json!(U128(PRICE)));
+    assert!(contract.get_market_data(nft(), "2".to_string()).is_none());
+}
+
+#[test]
+fn removed_ft_tokens_leave_the_allowlist_but_near_stays() {
+    let (mut contract, _) = setup_listing();
+    let usdc: AccountId = "usdc.near".parse().unwrap();
+    call_as(market(), 1);
+    contract.add_approved_ft_token_ids(vec![usdc.clone()]);
+    assert!(contract.approved_ft_token_ids().contains(&usdc));
+
+    call_as(market(), 1);
+    let size = contract.remove_approved_ft_token_ids(vec![
+        usdc.clone(),
+        "near".parse().unwrap(),
+        "never-added.near".parse().unwrap(),
+    ]);
+    assert_eq!(size, U64(1));
+    assert_eq!(
+        contract.approved_ft_token_ids(),
+        vec!["near".parse::<AccountId>().unwrap()]
+    );
+    let (_, params) = &events()[0];
+    assert_eq!(params["removed"], json!([usdc]));
+    assert_eq!(params["ignored"], json!(["never-added.near", "near"]));
+}
+
+#[test]
+fn collection_without_offers_still_lists() {
+    let (mut contract, _) = setup_listing();
+    call_as(market(), 1);
+    contract.set_collection_offers_enabled(nft(), false);
+    assert!(!contract.get_collection_config(nft()).offers_enabled);
+
+    call_as(seller(), STORAGE);
+    contract.storage_deposit(None, None);
+    testing_env!(VMContextBuilder::new()
+        .current_account_id(market())
+        .predecessor_account_id(nft())
+        .signer_account_id(seller())
+        .build());
+    contract.nft_on_approve(
+        "2".to_string(),
+        seller(),
+        0,
+        json!({ "market_type": "sale", "price": U128(PRICE) }).to_string(),
+    );
+    assert!(contract.get_market_data(nft(), "2".to_string()).is_some());
+
+    let stderr = expect_panic("collection_without_offers_still_lists", || {
+        offer_from_creator(&mut contract, PRICE);
+    });
+    assert!(stderr.contains("Error: this collection doesn't take offers"));
+}
+
+const BUYOUT: Balance = 3 * PRICE;
+
+fn setup_buyout_auction() -> Contract {
+    let mut contract = setup_auction_with(json!({ "buyout_price": U128(BUYOUT) }));
+    call_as(buyer(), STORAGE);
+    contract.storage_deposit(None, None);
+    contract
+}
+
+#[test]
+fn buy_now_refunds_the_bids_and_buys_at_the_buyout() {
+    let mut contract = setup_buyout_auction();
+    call_as(buyer(), PRICE);
+    contract.add_bid(
+        nft(),
+        "near".parse().unwrap(),
+        "1".to_string(),
+        U128(PRICE),
+        None,
+        None,
+    );
+
+    call_as(creator(), BUYOUT + 1);
+    let purchase = contract.buy_now(nft(), "1".to_string());
+    drop(purchase);
+    let sent = transfers();
+    assert_eq!(received(&sent, &buyer()), PRICE);
+    assert_eq!(received(&sent, &creator()), 1);
+    let (_, data) = events()
+        .into_iter()
+        .find(|(name, _)| name == "buy_now")
+        .unwrap();
+    assert_eq!(data["buyer_id"], json!(creator()));
+    assert_eq!(data["price"], json!(U128(BUYOUT)));
+    assert_eq!(data["refunded_bids"], json!(1));
+    assert!(contract.get_market_data(nft(), "1".to_string()).is_none());
+    let transfer_price = get_created_receipts()
+        .into_iter()
+        .flat_map(|receipt| receipt.actions)
+        .find_map(|action| match action {
+            VmAction::FunctionCall {
+                function_name,
+                args,
+                ..
+            } if function_name == "nft_transfer_payout" => {
+                let args: Value = near_sdk::serde_json::from_slice(&args).unwrap();
+                Some(args["balance"].clone())
+            }
+            _ => None,
+        });
+    assert_eq!(transfer_price, Some(json!(U128(BUYOUT))));
+}
+
+#[test]
+fn buy_now_leaves_a_bid_at_the_buyout_to_win() {
+    let mut contract = setup_buyout_auction();
+    call_as(buyer(), BUYOUT);
+    contract.add_bid(
+        nft(),
+        "near".parse().unwrap(),
+        "1".to_string(),
+        U128(BUYOUT),
+        None,
+        None,
+    );
+    let stderr = expect_panic("buy_now_leaves_a_bid_at_the_buyout_to_win", || {
+        call_as(creator(), BUYOUT);
+        contract.buy_now(nft(), "1".to_string());
+    });
+    assert!(stderr.contains(&format!(
+        "Error: a bid already reaches the buyout price, settle the auction instead: {}",
+        BUYOUT
+    )));
+}
+
+#[test]
+fn buy_now_needs_an_auction() {
+    let (mut contract, _) = setup_listing();
+    let stderr = expect_panic("buy_now_needs_an_auction", || {
+        call_as(buyer(), BUYOUT);
+        contract.buy_now(nft(), "1".to_string());
+    });
+    assert!(stderr.contains("Error: the NFT is not on auction"));
+}
+
+#[test]
+fn buyout_auction_in_an_ft_is_rejected() {
+    let (mut contract, _) = setup_ft_listing();
+    call_as(seller(), AUCTION_STORAGE);
+    contract.storage_deposit(None, None);
+    // buy_now takes the buyout as an attached NEAR deposit
+    let stderr = expect_panic("buyout_auction_in_an_ft_is_rejected", || {
+        list_token(
+            &mut contract,
+            "2",
+            json!({
+                "is_auction": true,
+                "ended_at": U64(DAY),
+                "ft_token_id": ft(),
+                "buyout_price": U128(BUYOUT),
+            }),
+        );
+    });
+    assert!(stderr.contains("Error: auctions are only listed in NEAR"));
+}
+
+#[test]
+fn current_listings_and_offers_keep_the_first_tag() {
+    let (_, market_data) = setup_listing();
+    let stored = VersionedMarketData::Current(market_data.clone())
+        .try_to_vec()
+        .unwrap();
+    // Current is the only shape deployed, a new variant must not move it
+    assert_eq!(stored[0], 0);
+    let read = VersionedMarketData::try_from_slice(&stored).unwrap();
+    assert!(read.is_current());
+    assert_eq!(
+        read.into_current(&format!("{}||1", nft())).listing_nonce,
+        market_data.listing_nonce
+    );
+    // a first deployment listing starts with its owner id's length
+    let stored = v0_listing("1").try_to_vec().unwrap();
+    assert!(stored[0] > 1);
+    assert!(!VersionedMarketData::try_from_slice(&stored)
+        .unwrap()
+        .is_current());
+
+    let offer = OfferData {
+        buyer_id: buyer(),
+        nft_contract_id: nft(),
+        token_id: "1".to_string(),
+        ft_token_id: "near".parse().unwrap(),
+        price: ONE_NEAR,
+        nonce: None,
+        created_at: 0,
+        expires_at: None,
+    };
+    let stored = VersionedOfferData::Current(offer).try_to_vec().unwrap();
+    assert_eq!(stored[0], 0);
+}
+
+#[test]
+fn settled_auction_keeps_its_winner() {
+    let mut contract = setup_auction_with_bid();
+    let mut market_data = contract.market.get(&format!("{}||1", nft())).unwrap();
+    market_data.bids = Some(vec![]);
+    assert!(contract
+        .get_last_auction_result(nft(), "1".to_string())
+        .is_none());
+
+    let settled_at = market_data.ended_at.unwrap() + 1;
+    testing_env!(VMContextBuilder::new()
+        .current_account_id(market())
+        .predecessor_account_id(buyer())
+        .block_timestamp(settled_at)
+        .attached_deposit(1)
+        .build());
+    contract.claim_won_auction(nft(), "1".to_string());
+
+    let payout = json!({ "payout": { seller().to_string(): U128(PRICE) } });
+    testing_env!(
+        VMContextBuilder::new()
+            .current_account_id(market())
+            .predecessor_account_id(market())
+            .block_timestamp(settled_at)
+            .build(),
+        VMConfig::test(),
+        RuntimeFeesConfig::test(),
+        Default::default(),
+        vec![PromiseResult::Successful(payout.to_string().into_bytes())],
+    );
+    contract.resolve_purchase(buyer(), market_data, U128(PRICE), Some(vec![]), true);
+
+    assert!(contract.get_market_data(nft(), "1".to_string()).is_none());
+    let result = contract
+        .get_last_auction_result(nft(), "1".to_string())
+        .unwrap();
+    assert_eq!(result.winner_id, buyer());
+    assert_eq!(result.price, U128(PRICE));
+    assert_eq!(result.settled_at, U64(settled_at));
+}
+
+#[test]
+fn paused_marketplace_still_returns_funds() {
+    let (mut contract, _) = setup_listing();
+    offer_from_creator(&mut contract, PRICE);
+    call_as(market(), 1);
+    contract.set_paused(true);
+    assert!(contract.is_paused());
+    assert!(contract.get_config().paused);
+
+    let check = contract.simulate_buy(nft(), "1".to_string(), U128(PRICE), Some(buyer()));
+    assert!(matches!(check, BuyCheck::Failed { error } if error == "Error: marketplace paused"));
+
+    call_as(creator(), 1);
+    contract.delete_offer(nft(), "1".to_string());
+    assert_eq!(received(&transfers(), &creator()), PRICE);
+    call_as(seller(), 1);
+    contract.delete_market_data(nft(), "1".to_string());
+    assert!(contract.get_market_data(nft(), "1".to_string()).is_none());
+    assert_eq!(contract.process_ended_auctions(10), 0);
+}
+
+#[test]
+fn paused_marketplace_takes_no_new_listings() {
+    let (mut contract, _) = setup_listing();
+    call_as(market(), 1);
+    contract.set_paused(true);
+    call_as(seller(), STORAGE);
+    contract.storage_deposit(None, None);
+    let stderr = expect_panic("paused_marketplace_takes_no_new_listings", || {
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(market())
+            .predecessor_account_id(nft())
+            .signer_account_id(seller())
+            .build());
+        contract.nft_on_approve(
+            "2".to_string(),
+            seller(),
+            0,
+            json!({ "market_type": "sale", "price": U128(PRICE) }).to_string(),
+        );
+    });
+    assert!(stderr.contains("Error: marketplace paused"));
+}
+
+#[test]
+fn overpaying_past_max_overpay_factor_needs_confirm_overpay() {
+    let (mut contract, _) = setup_listing();
+    call_as(market(), 1);
+    contract.set_max_overpay_factor(10);
+
+    // confirmed, the excess is refunded
+    call_as(buyer(), 10 * PRICE + 1);
+    contract.buy(nft(), "1".to_string(), None, None, None, Some(true));
+    assert_eq!(received(&transfers(), &buyer()), 9 * PRICE + 1);
+}
+
+#[test]
+fn overpaying_past_max_overpay_factor_fails_unconfirmed() {
+    let (mut contract, _) = setup_listing();
+    call_as(market(), 1);
+    contract.set_max_overpay_factor(10);
+    let stderr = expect_panic(
+        "overpaying_past_max_overpay_factor_fails_unconfirmed",
+        || {
+            call_as(buyer(), 10 * PRICE + 1);
+            contract.buy(nft(), "1".to_string(), None, None, None, None);
+        },
+    );
+    assert!(stderr.contains(&format!(
+        "Error: attached deposit is far above the price, set confirm_overpay to pay more than {}",
+        10 * PRICE
+    )));
+}
+
+#[test]
+fn storage_withdraw_without_listings_returns_everything() {
+    call_as(market(), 0);
+    let mut contract = Contract::new(market(), treasury(), None, Some(vec![nft()]));
+    call_as(seller(), 2 * STORAGE);
+    contract.storage_deposit(None, None);
+
+    call_as(seller(), 1);
+    assert_eq!(contract.storage_withdraw(None), U128(2 * STORAGE));
+    assert_eq!(received(&transfers(), &seller()), 2 * STORAGE);
+    assert_eq!(contract.storage_balance_of(seller()), U128(0));
+}
+
+#[test]
+fn storage_withdraw_takes_part_of_the_free_balance() {
+    let (mut contract, _) = setup_listing();
+    call_as(seller(), 2 * STORAGE);
+    contract.storage_deposit(None, None);
+
+    call_as(seller(), 1);
+    assert_eq!(
+        contract.storage_withdraw(Some(U128(ONE_NEAR / 1000))),
+        U128(ONE_NEAR / 1000)
+    );
+    assert_eq!(
+        contract.storage_balance_of(seller()),
+        U128(3 * STORAGE - ONE_NEAR / 1000)
+    );
+    // the listing's share stays behind
+    call_as(seller(), 1);
+    assert_eq!(
+        contract.storage_withdraw(None),
+        U128(2 * STORAGE - ONE_NEAR / 1000)
+    );
+    assert_eq!(contract.storage_balance_of(seller()), U128(STORAGE));
+}
+
+#[test]
+fn storage_deposit_batch_registers_three_accounts() {
+    let (mut contract, _) = setup_listing();
+    let accounts = vec![buyer(), creator(), "new.near".parse().unwrap()];
+
+    // split evenly, the remainder goes back to the sponsor
+    call_as(treasury(), 3 * STORAGE + 2);
+    contract.storage_deposit_batch(accounts.clone(), None);
+    for account_id in &accounts {
+        assert_eq!(contract.storage_balance_of(account_id.clone()).0, STORAGE);
+    }
+    assert_eq!(transfers(), vec![(treasury(), 2)]);
+
+    // or per account
+    call_as(treasury(), 6 * STORAGE);
+    contract.storage_deposit_batch(
+        accounts.clone(),
+        Some(vec![U128(STORAGE), U128(2 * STORAGE), U128(3 * STORAGE)]),
+    );
+    let balances: Vec<Balance> = accounts
+        .into_iter()
+        .map(|account_id| contract.storage_balance_of(account_id).0)
+        .collect();
+    assert_eq!(balances, [2 * STORAGE, 3 * STORAGE, 4 * STORAGE]);
+    assert!(transfers().is_empty());
+}
+
+#[test]
+fn storage_withdraw_returns_what_it_transfers() {
+    let (mut contract, _) = setup_listing();
+    call_as(seller(), STORAGE);
+    contract.storage_deposit(None, None);
+
+    call_as(seller(), 1);
+    let withdrawn = contract.storage_withdraw(None);
+    assert_eq!(withdrawn, U128(STORAGE));
+    assert_eq!(transfers(), vec![(seller(), STORAGE)]);
+    let (_, data) = events()
+        .into_iter()
+        .find(|(event, _)| event == "storage_withdraw")
+        .unwrap();
+    assert_eq!(data["amount"], json!(U128(STORAGE)));
+    assert_eq!(data["remaining"], json!(U128(STORAGE)));
+
+    // more keys than the balance covers leaves nothing free, not an underflow
+    let mut token_ids = contract.by_owner_id.get(&seller()).unwrap();
+    token_ids.insert(&format!("{}||9", nft()));
+    contract.by_owner_id.insert(&seller(), &token_ids);
+    call_as(seller(), 1);
+    assert_eq!(contract.storage_withdraw(None), U128(0));
+    assert!(transfers().is_empty());
+    assert_eq!(contract.storage_balance_of(seller()), U128(STORAGE));
+}
+
+#[test]
+fn storage_withdraw_above_the_free_balance_fails() {
+    let (mut contract, _) = setup_listing();
+    call_as(seller(), STORAGE);
+    contract.storage_deposit(None, None);
+    let stderr = expect_panic("storage_withdraw_above_the_free_balance_fails", || {
+        call_as(seller(), 1);
+        contract.storage_withdraw(Some(U128(STORAGE + 1)));
+    });
+    assert!(stderr.contains(&format!(
+        "Error: amount is more than the storage balance not backing listings, available: {}",
+        STORAGE
+    )));
+}
+
+#[test]
+fn event_schema_lists_every_logged_event() {
+    let (contract, _) = setup_listing();
+    let schema: Value = near_sdk::serde_json::from_str(&contract.get_event_schema()).unwrap();
+    assert_eq!(schema["standard"], json!("astro_marketplace"));
+    let events = schema["events"].as_array().unwrap();
+    let params_of = |name: &str| {
+        events
+            .iter()
+            .find(|event| event["event"] == json!(name))
+            .unwrap_or_else(|| panic!("{} is missing from the schema", name))["params"]
+            .clone()
+    };
+
+    // every event name passed to log_event as a literal, plus the listing
+    // events internal_add_market_data gets from its callers
+    let mut emitted = vec!["add_market_data".to_string(), "relist".to_string()];
+    for source in [
+        include_str!("../src/lib.rs"),
+        include_str!("../src/ft_callbacks.rs"),
+    ] {
+        for marker in ["log_event(", "log_allowlist_change("] {
+            for call in source.split(marker).skip(1) {
+                if let Some(rest) = call.trim_start().strip_prefix('"') {
+                    emitted.push(rest[..rest.find('"').unwrap()].to_string());
+                }
+            }
+        }
+    }
+    assert!(emitted.len() > 40);
+    for name in &emitted {
+        assert!(params_of(name).is_object());
+    }
+
+    assert_eq!(params_of("add_bid")["amount"], json!("U128"));
+    assert_eq!(params_of("delete_market_data")["reason"], json!("string?"));
+    assert_eq!(params_of("resolve_purchase")["is_offer"], json!("bool?"));
+}
+
+/// Lists token "1" as a Dutch listing declining from 3 * PRICE at 100s to
+/// PRICE at 200s.
+fn setup_dutch_listing() -> Contract {
+    let second = 10u64.pow(9);
+    setup_listing_with(json!({
+        "start_price": U128(3 * PRICE),
+        "started_at": U64(100 * second),
+        "ended_at": U64(200 * second),
+    }))
+}
+
+#[test]
+fn dutch_price_declines_and_buy_refunds_above_it() {
+    let mut contract = setup_dutch_listing();
+    let at = |seconds: u64, deposit: Balance| {
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(market())
+            .predecessor_account_id(buyer())
+            .attached_deposit(deposit)
+            .block_timestamp(seconds * 10u64.pow(9))
+            .build());
+    };
+
+    // clamped outside the window, and not for sale before it
+    at(50, 0);
+    assert_eq!(
+        contract.current_price(nft(), "1".to_string()),
+        U128(3 * PRICE)
+    );
+    let check = contract.simulate_buy(nft(), "1".to_string(), U128(3 * PRICE), Some(buyer()));
+    assert!(matches!(check, BuyCheck::Failed { error } if error.contains("Sale has not started")));
+    at(200, 0);
+    assert_eq!(contract.current_price(nft(), "1".to_string()), U128(PRICE));
+
+    at(150, 3 * PRICE);
+    assert_eq!(
+        contract.current_price(nft(), "1".to_string()),
+        U128(2 * PRICE)
+    );
+    let check = contract.simulate_buy(nft(), "1".to_string(), U128(2 * PRICE - 1), Some(buyer()));
+    assert!(matches!(check, BuyCheck::Failed { .. }));
+    let listing = contract.get_market_data(nft(), "1".to_string()).unwrap();
+    assert_eq!(
+        near_sdk::serde_json::to_value(&listing).unwrap()["start_price"],
+        json!(U128(3 * PRICE))
+    );
+
+    // the price the buyer saw is a ceiling, the difference goes back
+    contract.buy(
+        nft(),
+        "1".to_string(),
+        None,
+        Some(U128(3 * PRICE)),
+        None,
+        None,
+    );
+    assert_eq!(received(&transfers(), &buyer()), PRICE);
+    assert!(contract.get_market_data(nft(), "1".to_string()).is_none());
+}
+
+#[test]
+fn effective_price_follows_each_listing_mode() {
+    let second = 10u64.pow(9);
+    let (mut contract, _) = setup_listing();
+    call_as(seller(), STORAGE + AUCTION_STORAGE);
+    contract.storage_deposit(None, None);
+    list_token(
+        &mut contract,
+        "2",
+        json!({
+            "start_price": U128(3 * PRICE),
+            "started_at": U64(100 * second),
+            "ended_at": U64(200 * second),
+        }),
+    );
+    list_token(
+        &mut contract,
+        "3",
+        json!({ "is_auction": true, "ended_at": U64(DAY) }),
+    );
+
+    call_at(buyer(), 0, 150 * second);
+    let price_of = |token_id: &str| match contract.simulate_buy(
+        nft(),
+        token_id.to_string(),
+        U128(3 * PRICE),
+        Some(buyer()),
+    ) {
+        BuyCheck::Ok { price } => Ok(price.0),
+        BuyCheck::Failed { error } => Err(error),
+    };
+    assert_eq!(price_of("1"), Ok(PRICE));
+    assert_eq!(price_of("2"), Ok(2 * PRICE));
+    assert!(price_of("3")
+        .unwrap_err()
+        .contains("place a bid with add_bid"));
+
+    // buy charges the effective price and refunds the rest
+    call_at(buyer(), 3 * PRICE, 150 * second);
+    contract.buy(nft(), "1".to_string(), None, None, None, None);
+    assert_eq!(received(&transfers(), &buyer()), 2 * PRICE);
+    call_at(buyer(), 3 * PRICE, 150 * second);
+    contract.buy(nft(), "2".to_string(), None, None, None, None);
+    assert_eq!(received(&transfers(), &buyer()), PRICE);
+}
+
+#[test]
+fn dutch_start_price_cannot_be_an_auction() {
+    let stderr = expect_panic("dutch_start_price_cannot_be_an_auction", || {
+        setup_auction_with(json!({ "start_price": U128(3 * PRICE) }));
+    });
+    assert!(stderr.contains("Error: start_price needs started_at and ended_at"));
+}
+
+/// `buyer()` offers ONE_NEAR on token "1", good until 100s
+fn add_expiring_offer(contract: &mut Contract) {
+    call_as(buyer(), STORAGE);
+    contract.storage_deposit(None, None);
+    call_as(buyer(), ONE_NEAR);
+    contract.add_offer(
+        nft(),
+        "1".to_string(),
+        "near".parse().unwrap(),
+        U128(ONE_NEAR),
+        None,
+        Some(U64(100 * 10u64.pow(9))),
+    );
+}
+
+#[test]
+fn expired_offers_are_pruned_by_anyone() {
+    let (mut contract, _) = setup_listing();
+    add_expiring_offer(&mut contract);
+    let at = |seconds: u64| {
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(market())
+            .predecessor_account_id(creator())
+            .block_timestamp(seconds * 10u64.pow(9))
+            .build());
+    };
+
+    at(100);
+    assert!(contract
+        .get_best_offer(nft(), "1".to_string(), None)
+        .is_some());
+    at(101);
+    assert!(contract
+        .get_best_offer(nft(), "1".to_string(), None)
+        .is_none());
+
+    contract.prune_offer(nft(), buyer(), "1".to_string());
+    assert_eq!(received(&transfers(), &buyer()), ONE_NEAR);
+    let (_, pruned) = events()
+        .into_iter()
+        .find(|(name, _)| name == "delete_offer")
+        .unwrap();
+    assert_eq!(pruned["reason"], json!("expired"));
+    assert_eq!(contract.get_supply_by_owner_id(buyer()), U64(0));
+}
+
+#[test]
+fn expired_offer_cannot_be_accepted() {
+    let (mut contract, _) = setup_listing();
+    add_expiring_offer(&mut contract);
+    let stderr = expect_panic("expired_offer_cannot_be_accepted", || {
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(market())
+            .predecessor_account_id(nft())
+            .signer_account_id(seller())
+            .block_timestamp(101 * 10u64.pow(9))
+            .build());
+        contract.nft_on_approve(
+            "1".to_string(),
+            seller(),
+            1,
+            json!({ "market_type": "accept_offer", "buyer_id": buyer(), "price": U128(ONE_NEAR) })
+                .to_string(),
+        );
+    });
+    assert!(stderr.contains("Error: the offer has expired"));
+}
+
+#[test]
+fn offers_are_listed_per_token_and_per_buyer() {
+    let (mut contract, _) = setup_listing();
+    let offer = |contract: &mut Contract, buyer_id: AccountId, token_id: &str| {
+        call_as(buyer_id.clone(), STORAGE);
+        contract.storage_deposit(None, None);
+        call_as(buyer_id, ONE_NEAR);
+        contract.add_offer(
+            nft(),
+            token_id.to_string(),
+            "near".parse().unwrap(),
+            U128(ONE_NEAR),
+            None,
+            None,
+        );
+    };
+    offer(&mut contract, buyer(), "1");
+    offer(&mut contract, creator(), "1");
+    offer(&mut contract, buyer(), "2");
+    // the seller's listing shares by_owner_id with its offer
+    offer(&mut contract, seller(), "2");
+
+    assert_eq!(contract.get_offer_supply(), U64(4));
+    let on_token = contract.get_offers_by_token(nft(), "1".to_string(), None, None);
+    assert_eq!(on_token.len(), 2);
+    assert_eq!(
+        contract
+            .get_offers_by_token(nft(), "1".to_string(), Some(U64(1)), Some(10))
+            .len(),
+        1
+    );
+    assert_eq!(contract.get_offers_by_buyer(buyer(), None, None).len(), 2);
+    assert_eq!(
+        contract
+            .get_offers_by_buyer(buyer(), Some(U64(1)), None)
+            .len(),
+        1
+    );
+    let by_seller = contract.get_offers_by_buyer(seller(), None, Some(1));
+    assert_eq!(by_seller.len(), 1);
+    assert_eq!(
+        near_sdk::serde_json::to_value(&by_seller[0]).unwrap()["token_id"],
+        json!("2")
+    );
+    assert!(contract
+        .get_offers_by_buyer(treasury(), None, None)
+        .is_empty());
+}
+
+#[test]
+fn cancelled_bid_refund_is_tracked_and_claimable() {
+    let mut contract = setup_auction_with_bid();
+    call_as(creator(), STORAGE);
+    contract.storage_deposit(None, None);
+    testing_env!(VMContextBuilder::new()
+        .current_account_id(market())
+        .predecessor_account_id(creator())
+        .attached_deposit(2 * PRICE)
+        .block_timestamp(1)
+        .build());
+    contract.add_bid(
+        nft(),
+        "near".parse().unwrap(),
+        "1".to_string(),
+        U128(2 * PRICE),
+        None,
+        None,
+    );
+
+    call_as(buyer(), 1);
+    contract.cancel_bid(nft(), "1".to_string(), buyer());
+    let resolves = resolve_refunds();
+    assert_eq!(resolves.len(), 1);
+    assert_eq!(resolves[0]["account_id"], json!(buyer()));
+    assert_eq!(resolves[0]["amount"], json!(U128(PRICE)));
+
+    callback_with(PromiseResult::Failed);
+    assert!(!contract.resolve_refund(buyer(), U128(PRICE), Some("near".parse().unwrap())));
+    call_as(buyer(), 1);
+    assert_eq!(contract.claim_failed_refund(None), U128(PRICE));
+    assert_eq!(received(&transfers(), &buyer()), PRICE);
+    assert_eq!(contract.get_failed_refund(buyer()), U128(0));
+}
+
+#[test]
+fn failed_delete_market_data_refund_is_recorded_and_claimed_later() {
+    let mut contract = setup_auction();
+    outbid_first_bidder(&mut contract);
+    let first: AccountId = "first.near".parse().unwrap();
+
+    call_as(market(), 1);
+    contract.delete_market_data(nft(), "1".to_string());
+    let resolves = resolve_refunds();
+    assert_eq!(resolves.len(), 2);
+    assert_eq!(resolves[0]["account_id"], json!(first));
+    assert_eq!(resolves[0]["amount"], json!(U128(PRICE)));
+    assert_eq!(resolves[1]["account_id"], json!(buyer()));
+
+    // first.near was deleted before its refund arrived
+    callback_with(PromiseResult::Failed);
+    assert!(!contract.resolve_refund(first.clone(), U128(PRICE), None));
+    assert!(events()
+        .iter()
+        .any(|(name, data)| name == "refund_failed" && data["account_id"] == json!(first)));
+    assert_eq!(contract.get_failed_refund(first.clone()), U128(PRICE));
+
+    call_as(first.clone(), 1);
+    assert_eq!(contract.claim_failed_refund(None), U128(PRICE));
+    assert_eq!(received(&transfers(), &first), PRICE);
+    assert_eq!(contract.get_failed_refund(first), U128(0));
+}
+
+#[test]
+fn claim_failed_refund_pays_another_account_once() {
+    let mut contract = setup_listing().0;
+    callback_with(PromiseResult::Failed);
+    contract.resolve_refund(buyer(), U128(PRICE), None);
+
+    call_as(buyer(), 1);
+    assert_eq!(contract.claim_failed_refund(Some(creator())), U128(PRICE));
+    let transfers = transfers();
+    assert_eq!(received(&transfers, &creator()), PRICE);
+    assert_eq!(received(&transfers, &buyer()), 0);
+    let claims: Vec<_> = events()
+        .into_iter()
+        .filter(|(name, _)| name == "claim_failed_refund")
+        .collect();
+    assert_eq!(claims.len(), 1);
+    assert_eq!(claims[0].1["account_id"], json!(buyer()));
+    assert_eq!(claims[0].1["receiver_id"], json!(creator()));
+    assert_eq!(claims[0].1["amount"], json!(U128(PRICE)));
+
+    // the balance was cleared before the transfer went out
+    assert_eq!(contract.get_failed_refund(buyer()), U128(0));
+    let stderr = expect_panic("claim_failed_refund_pays_another_account_once", || {
+        call_as(buyer(), 1);
+        contract.claim_failed_refund(Some(creator()));
+    });
+    if std::env::var_os("MARKET_EXPECT_PANIC").is_none() {
+        assert!(stderr.contains("Error: no failed refund to claim"));
+    }
+}
+
+/// An auction starting at PRICE with a reserve of 2 * PRICE, `buyer()` bid `bid`
+fn setup_reserve_auction(bid: Balance) -> Contract {
+    let mut contract = setup_auction_with(json!({ "reserve_price": U128(2 * PRICE) }));
+    call_as(buyer(), STORAGE);
+    contract.storage_deposit(None, None);
+    call_as(buyer(), bid);
+    contract.add_bid(
+        nft(),
+        "near".parse().unwrap(),
+        "1".to_string(),
+        U128(bid),
+        None,
+        None,
+    );
+    contract
+}
+
+fn reserve_met(contract: &Contract) -> Value {
+    let listing = contract.get_market_data(nft(), "1".to_string()).unwrap();
+    near_sdk::serde_json::to_value(&listing).unwrap()["reserve_met"].clone()
+}
+
+#[test]
+fn auction_without_a_reserve_reports_none() {
+    assert_eq!(reserve_met(&setup_auction_with_bid()), Value::Null);
+}
+
+#[test]
+fn reserve_met_flips_once_a_bid_crosses_the_hidden_reserve() {
+    let mut contract = setup_reserve_auction(PRICE);
+    assert_eq!(reserve_met(&contract), json!(false));
+    bid_from(&mut contract, creator(), 2 * PRICE - 1);
+    assert_eq!(reserve_met(&contract), json!(false));
+    bid_at(&mut contract, buyer(), 2 * PRICE, 1);
+    assert_eq!(reserve_met(&contract), json!(true));
+
+    let listing = contract.get_market_data(nft(), "1".to_string()).unwrap();
+    let listing = near_sdk::serde_json::to_value(&listing).unwrap();
+    // the reserve itself stays hidden
+    assert!(listing.get("reserve_price").is_none());
+}
+
+#[test]
+fn auction_reaching_its_reserve_settles() {
+    let mut contract = setup_reserve_auction(2 * PRICE);
+    assert_eq!(reserve_met(&contract), json!(true));
+    call_as(seller(), 1);
+    contract.accept_bid(nft(), "1".to_string(), None);
+    assert!(events().iter().any(|(name, _)| name == "settle_auction"));
+}
+
+#[test]
+fn auction_below_its_reserve_cannot_be_accepted() {
+    let mut contract = setup_reserve_auction(PRICE);
+    assert_eq!(reserve_met(&contract), json!(false));
+    let stderr = expect_panic("auction_below_its_reserve_cannot_be_accepted", || {
+        call_as(seller(), 1);
+        contract.accept_bid(nft(), "1".to_string(), None);
+    });
+    assert!(stderr.contains("Error: reserve not met"));
+}
+
+#[test]
+fn ended_auction_below_its_reserve_is_cancelled_and_refunded() {
+    let mut contract = setup_reserve_auction(PRICE);
+    testing_env!(VMContextBuilder::new()
+        .current_account_id(market())
+        .predecessor_account_id(seller())
+        .attached_deposit(1)
+        .block_timestamp(10u64.pow(9) * 86_400 + 1)
+        .build());
+    contract.cancel_unmet_reserve(nft(), "1".to_string());
+
+    assert_eq!(received(&transfers(), &buyer()), PRICE);
+    let (_, cancelled) = events()
+        .into_iter()
+        .find(|(name, _)| name == "cancel_auction")
+        .unwrap();
+    assert_eq!(cancelled["reason"], json!("reserve_not_met"));
+    assert!(contract.get_market_data(nft(), "1".to_string()).is_none());
+}
+
+#[test]
+fn auctions_store_their_start_and_take_configurable_duration_bounds() {
+    let mut contract = setup_auction();
+    let listing = contract
+        .get_market_data_raw(nft(), "1".to_string())
+        .unwrap();
+    assert_eq!(listing.started_at, Some(0));
+    assert_eq!(contract.get_auction_duration_bounds(), (3_600, 0));
+
+    call_as(market(), 1);
+    contract.set_auction_duration_bounds(60, 2 * 86_400);
+    assert_eq!(contract.get_auction_duration_bounds(), (60, 2 * 86_400));
+    call_as(market(), 1);
+    contract.set_config(ConfigUpdate {
+        max_auction_duration: Some(0),
+        ..Default::default()
+    });
+    let config = contract.get_config();
+    assert_eq!(
+        (config.min_auction_duration, config.max_auction_duration),
+        (60, 0)
+    );
+}
+
+#[test]
+fn auction_longer_than_max_duration_is_rejected() {
+    call_as(market(), 0);
+    let mut contract = Contract::new(market(), treasury(), None, Some(vec![nft()]));
+    call_as(market(), 1);
+    contract.set_auction_duration_bounds(3_600, 86_400);
+    call_as(seller(), AUCTION_STORAGE);
+    contract.storage_deposit(None, None);
+    let stderr = expect_panic("auction_longer_than_max_duration_is_rejected", || {
+        list_token(
+            &mut contract,
+            "1",
+            json!({ "is_auction": true, "ended_at": U64(10u64.pow(9) * 86_401) }),
+        );
+    });
+    assert!(stderr.contains("Error: auction window is longer than 86400 seconds"));
+}
+
+#[test]
+fn set_config_changes_only_the_fields_given() {
+    let (mut contract, _) = setup_listing();
+    let before = contract.get_config();
+
+    call_as(market(), 1);
+    contract.set_config(ConfigUpdate {
+        treasury_id: Some(creator()),
+        max_offers_per_buyer: Some(3),
+        price_update_cooldown: Some(60),
+        allow_leader_cancel: Some(!before.allow_leader_cancel),
+        min_bid_increment_bps: Some(250),
+        ..Default::default()
+    });
+
+    let config = contract.get_config();
+    assert_eq!(config.treasury_id, creator());
+    assert_eq!(config.max_offers_per_buyer, Some(3));
+    assert_eq!(config.price_update_cooldown, 60);
+    assert_eq!(config.allow_leader_cancel, !before.allow_leader_cancel);
+    assert_eq!(config.min_bid_increment_bps, 250);
+    assert_eq!(config.transaction_fee, before.transaction_fee);
+    assert_eq!(config.offer_dormancy, before.offer_dormancy);
+    assert_eq!(config.max_auction_duration, before.max_auction_duration);
+    assert_eq!(config.owner_id, market());
+}
+
+#[test]
+fn price_updates_within_the_cooldown_fail() {
+    let (mut contract, _) = setup_listing();
+    call_as(market(), 1);
+    contract.set_price_update_cooldown(60);
+    let second = 10u64.pow(9);
+    let reprice = |contract: &mut Contract, price: Balance, ts: u64| {
+        call_at(seller(), 1, ts);
+        contract.update_market_data(
+            nft(),
+            "1".to_string(),
+            "near".parse().unwrap(),
+            U128(price),
+            None,
+            None,
+            None,
+            None,
+        );
+    };
+
+    reprice(&mut contract, 2 * PRICE, 10 * second);
+    let (_, data) = events()
+        .into_iter()
+        .find(|(event, _)| event == "update_market_data")
+        .unwrap();
+    assert_eq!(data["last_updated_at"], json!(U64(10 * second)));
+
+    let stderr = expect_panic("price_updates_within_the_cooldown_fail", || {
+        reprice(&mut contract, 3 * PRICE, 69 * second)
+    });
+    assert!(stderr.contains("listing was updated too recently, cooldown 60"));
+
+    reprice(&mut contract, 3 * PRICE, 70 * second);
+    assert_eq!(
+        contract.market.get(&format!("{}||1", nft())).unwrap().price,
+        3 * PRICE
+    );
+}
+
+#[test]
+fn huge_price_update_cooldown_rejects_without_overflow() {
+    let (mut contract, _) = setup_listing();
+    call_as(seller(), 1);
+    contract.update_market_data(
+        nft(),
+        "1".to_string(),
+        "near".parse().unwrap(),
+        U128(2 * PRICE),
+        None,
+        None,
+        None,
+        None,
+    );
+    call_as(market(), 1);
+    contract.set_price_update_cooldown(u64::MAX);
+
+    let stderr = expect_panic(
+        "huge_price_update_cooldown_rejects_without_overflow",
+        || {
+            call_at(seller(), 1, DAY);
+            contract.update_market_data(
+                nft(),
+                "1".to_string(),
+                "near".parse().unwrap(),
+                U128(3 * PRICE),
+                None,
+                None,
+                None,
+                None,
+            );
+        },
+    );
+    assert!(
+        stderr.contains("listing was updated too recently"),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn verified_approval_adds_only_contracts_that_answer() {
+    let (mut contract, _) = setup_listing();
+    let art: AccountId = "art.near".parse().unwrap();
+    let typo: AccountId = "atr.near".parse().unwrap();
+
+    call_as(market(), 1);
+    let pending =
+        contract.add_approved_nft_contract_ids(vec![art.clone(), typo.clone()], Some(true));
+    assert!(matches!(pending, PromiseOrValue::Promise(_)));
+    drop(pending);
+    for nft_contract_id in [&art, &typo] {
+        let asks_metadata = get_created_receipts().iter().any(|receipt| {
+            receipt.receiver_id == *nft_contract_id
+                && receipt.actions.iter().any(|action| {
+                    matches!(action, VmAction::FunctionCall { function_name, .. }
+                        if function_name == "nft_metadata")
+                })
+        });
+        assert!(asks_metadata, "{}", nft_contract_id);
+    }
+    assert!(!contract.approved_nft_contract_ids.contains(&art));
+
+    // art.near returns its metadata, nothing is deployed at the typo
+    let metadata = json!({ "spec": "nft-1.0.0", "name": "Art", "symbol": "ART" });
+    testing_env!(
+        VMContextBuilder::new()
+            .current_account_id(market())
+            .predecessor_account_id(market())
+            .build(),
+        VMConfig::test(),
+        RuntimeFeesConfig::test(),
+        Default::default(),
+        vec![
+            PromiseResult::Successful(metadata.to_string().into_bytes()),
+            PromiseResult::Failed,
+        ],
+    );
+    assert_eq!(
+        contract.resolve_approve_nft_contracts(vec![art.clone(), typo.clone()]),
+        U64(1)
+    );
+    assert!(contract.approved_nft_contract_ids.contains(&art));
+    assert!(!contract.approved_nft_contract_ids.contains(&typo));
+    let (_, data) = events()
+        .into_iter()
+        .find(|(event, _)| event == "verify_nft_contract")
+        .unwrap();
+    assert_eq!(data["verified"], json!([art]));
+    assert_eq!(data["unverified"], json!([typo]));
+}
+
+/// setup_listing plus token "2" from the same seller
+fn setup_two_listings() -> Contract {
+    let (mut contract, _) = setup_listing();
+    call_as(seller(), STORAGE);
+    contract.storage_deposit(None, None);
+    list_token(&mut contract, "2", json!({}));
+    contract
+}
+
+#[test]
+fn listings_are_read_and_deleted_in_batches() {
+    let mut contract = setup_two_listings();
+    let keys: Vec<(AccountId, String)> = ["1", "2", "3"]
+        .iter()
+        .map(|token_id| (nft(), token_id.to_string()))
+        .collect();
+    let found: Vec<bool> = contract
+        .get_market_datas_batch(keys.clone())
+        .iter()
+        .map(Option::is_some)
+        .collect();
+    assert_eq!(found, vec![true, true, false]);
+
+    call_as(seller(), 1);
+    contract.delete_market_data_batch(keys[..2].to_vec());
+    let deleted = events()
+        .into_iter()
+        .filter(|(name, _)| name == "delete_market_data")
+        .count();
+    assert_eq!(deleted, 2);
+    assert!(contract
+        .get_market_datas_batch(keys)
+        .iter()
+        .all(Option::is_none));
+}
+
+#[test]
+fn delete_batch_with_someone_elses_listing_fails() {
+    let mut contract = setup_two_listings();
+    let stderr = expect_panic("delete_batch_with_someone_elses_listing_fails", || {
+        call_as(buyer(), 1);
+        contract.delete_market_data_batch(vec![(nft(), "1".to_string()), (nft(), "2".to_string())]);
+    });
+    assert!(stderr.contains("Error: Seller or owner only"), "{}", stderr);
+}
+
+#[test]
+fn update_market_data_turns_a_sale_into_an_auction() {
+    let (mut contract, _) = setup_listing();
+    call_as(seller(), 1);
+    contract.update_market_data(
+        nft(),
+        "1".to_string(),
+        "near".parse().unwrap(),
+        U128(PRICE),
+        None,
+        None,
+        Some(U64(10u64.pow(9) * 86_400)),
+        Some(true),
+    );
+    let market_data = contract.market.get(&format!("{}||1", nft())).unwrap();
+    assert_eq!(market_data.is_auction, Some(true));
+    assert_eq!(market_data.started_at, Some(0));
+    assert!(market_data.bids.as_ref().is_some_and(Vec::is_empty));
+    let (_, data) = events()
+        .into_iter()
+        .find(|(name, _)| name == "update_market_data")
+        .unwrap();
+    assert_eq!(data["is_auction"], json!(true));
+    assert_eq!(data["ended_at"], json!(U64(10u64.pow(9) * 86_400)));
+
+    call_as(buyer(), STORAGE);
+    contract.storage_deposit(None, None);
+    call_as(buyer(), PRICE);
+    contract.add_bid(
+        nft(),
+        "near".parse().unwrap(),
+        "1".to_string(),
+        U128(PRICE),
+        None,
+        None,
+    );
+    let market_data = contract.market.get(&format!("{}||1", nft())).unwrap();
+    assert_eq!(market_data.bids.unwrap().len(), 1);
+}
+
+#[test]
+fn auction_with_bids_cannot_turn_into_a_sale() {
+    let mut contract = setup_auction_with_bid();
+    let stderr = expect_panic("auction_with_bids_cannot_turn_into_a_sale", || {
+        call_as(seller(), 1);
+        contract.update_market_data(
+            nft(),
+            "1".to_string(),
+            "near".parse().unwrap(),
+            U128(PRICE),
+            None,
+            None,
+            None,
+            Some(false),
+        );
+    });
+    assert!(
+        stderr.contains("has already started or has bids"),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn auction_without_bids_turns_back_into_a_sale() {
+    let mut contract = setup_auction();
+    call_as(seller(), 1);
+    contract.update_market_data(
+        nft(),
+        "1".to_string(),
+        "near".parse().unwrap(),
+        U128(PRICE),
+        None,
+        None,
+        None,
+        Some(false),
+    );
+    let market_data = contract.market.get(&format!("{}||1", nft())).unwrap();
+    assert_eq!(market_data.is_auction, Some(false));
+    assert!(market_data.bids.is_none());
+    assert!(contract.auction_ends.is_empty());
+}
+
+#[test]
+fn update_market_data_rejects_an_inverted_window() {
+    let (mut contract, _) = setup_listing();
+    let stderr = expect_panic("update_market_data_rejects_an_inverted_window", || {
+        call_as(seller(), 1);
+        contract.update_market_data(
+            nft(),
+            "1".to_string(),
+            "near".parse().unwrap(),
+            U128(PRICE),
+            None,
+            Some(U64(10u64.pow(9) * 7_200)),
+            Some(U64(10u64.pow(9) * 3_600)),
+            None,
+        );
+    });
+    assert!(
+        stderr.contains("Error: started_at must be before ended_at"),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn transfer_only_collection_sells_through_nft_transfer() {
+    let (mut contract, market_data) = setup_listing();
+    call_as(market(), 1);
+    contract.set_transfer_only(nft(), true);
+    assert!(contract.get_collection_config(nft()).transfer_only);
+
+    call_as(buyer(), PRICE);
+    contract.buy(nft(), "1".to_string(), None, None, None, None);
+    let called: Vec<String> = get_created_receipts()
+        .into_iter()
+        .filter(|receipt| receipt.receiver_id == nft())
+        .flat_map(|receipt| receipt.actions)
+        .filter_map(|action| match action {
+            VmAction::FunctionCall { function_name, .. } => Some(function_name),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(called, vec!["nft_transfer".to_string()]);
+
+    // NEP-171 nft_transfer succeeds without returning anything
+    callback_with(PromiseResult::Successful(vec![]));
+    let paid = contract.resolve_purchase(buyer(), market_data, U128(PRICE), None, true);
+
+    let fee = PRICE * 200 / 10_000;
+    let transfers = transfers();
+    assert_eq!(paid.0, PRICE);
+    assert_eq!(received(&transfers, &seller()), PRICE - fee);
+    assert_eq!(received(&transfers, &treasury()), fee);
+    assert_eq!(received(&transfers, &buyer()), 0);
+    assert_eq!(contract.get_stats().total_volume.0, PRICE);
+}
+
+fn accept_bid_with_approval_check(contract: &mut Contract) -> U64 {
+    call_as(seller(), 1);
+    contract.accept_bid(nft(), "1".to_string(), Some(true));
+    assert!(!contract.settlements_in_flight.is_empty());
+    U64(contract
+        .market
+        .get(&format!("{}||1", nft()))
+        .unwrap()
+        .listing_nonce)
+}
+
+fn approved_callback(contract: &mut Contract, listing_nonce: U64) -> bool {
+    callback_with(PromiseResult::Successful(b"true".to_vec()));
+    contract.resolve_accept_bid(seller(), nft(), "1".to_string(), listing_nonce)
+}
+
+fn accept_bid_failure(contract: &Contract) -> String {
+    assert!(contract.settlements_in_flight.is_empty());
+    let (_, data) = events()
+        .into_iter()
+        .find(|(name, _)| name == "accept_bid_failed")
+        .expect("accept_bid_failed was not logged");
+    data["error"].as_str().unwrap().to_string()
+}
+
+#[test]
+fn accept_bid_checks_the_reserve_before_waiting_on_the_approval() {
+    let mut contract = setup_reserve_auction(PRICE);
+    let stderr = expect_panic(
+        "accept_bid_checks_the_reserve_before_waiting_on_the_approval",
+        || {
+            call_as(seller(), 1);
+            contract.accept_bid(nft(), "1".to_string(), Some(true));
+        },
+    );
+    assert!(stderr.contains("Error: reserve not met"), "{}", stderr);
+}
+
+#[test]
+fn accept_bid_callback_logs_a_pause_and_clears_the_flag() {
+    let mut contract = setup_auction_with_bid();
+    let listing_nonce = accept_bid_with_approval_check(&mut contract);
+    call_as(market(), 1);
+    contract.set_paused(true);
+
+    assert!(!approved_callback(&mut contract, listing_nonce));
+    assert!(accept_bid_failure(&contract).contains("paused"));
+
+    // the auction can still be settled once the market is back
+    call_as(market(), 1);
+    contract.set_paused(false);
+    call_as(seller(), 1);
+    contract.accept_bid(nft(), "1".to_string(), None);
+    assert!(events().iter().any(|(name, _)| name == "settle_auction"));
+}
+
+#[test]
+fn accept_bid_callback_logs_a_bid_too_recent() {
+    let mut contract = setup_auction_with_bid();
+    let listing_nonce = accept_bid_with_approval_check(&mut contract);
+    call_as(market(), 1);
+    contract.set_min_bid_age(600);
+
+    assert!(!approved_callback(&mut contract, listing_nonce));
+    assert!(accept_bid_failure(&contract).contains("600"));
+}
+
+#[test]
+fn deleting_a_listing_drops_its_settlement_in_flight() {
+    let mut contract = setup_auction_with_bid();
+    let listing_nonce = accept_bid_with_approval_check(&mut contract);
+    call_as(seller(), 1);
+    contract.delete_market_data(nft(), "1".to_string());
+    assert!(contract.settlements_in_flight.is_empty());
+
+    // the late callback leaves the token alone
+    assert!(!approved_callback(&mut contract, listing_nonce));
+    assert!(accept_bid_failure(&contract).contains("Market data does not exist"));
+}
+
+#[test]
+fn loosened_fee_limits_dont_govern_the_same_fee_change() {
+    let (mut contract, _) = setup_listing();
+    let stderr = expect_panic(
+        "loosened_fee_limits_dont_govern_the_same_fee_change",
+        || {
+            call_as(market(), 1);
+            contract.set_config(ConfigUpdate {
+                fee_change_delay: Some(0),
+                max_fee_step: Some(10_000),
+                transaction_fee: Some(9_999),
+                ..Default::default()
+            });
+        },
+    );
+    assert!(
+        stderr.contains("Error: fee change is larger than max step 500"),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn looser_fee_limits_wait_out_the_current_delay() {
+    let (mut contract, _) = setup_listing();
+    let day = 86_400 * 10u64.pow(9);
+    call_as(market(), 1);
+    contract.set_fee_change_delay(0);
+    assert_eq!(contract.get_fee_change_delay(), 86_400);
+    let pending = contract.get_pending_fee_limits().unwrap();
+    assert_eq!(pending.fee_change_delay, 0);
+    assert_eq!(pending.effective_at.0, day);
+
+    call_at(market(), 1, day);
+    assert_eq!(contract.get_fee_change_delay(), 0);
+    assert!(contract.get_config().pending_fee_limits.is_none());
+
+    // tightening applies right away
+    contract.set_max_fee_step(100);
+    assert_eq!(contract.get_max_fee_step(), 100);
+    assert!(contract.get_pending_fee_limits().is_none());
+}
+
+#[test]
+fn escheat_offer_with_an_endless_dormancy_never_overflows() {
+    let (mut contract, _) = setup_listing();
+    offer_from_creator(&mut contract, ONE_NEAR);
+    call_as(market(), 1);
+    contract.set_config(ConfigUpdate {
+        offer_dormancy: Some(u64::MAX),
+        ..Default::default()
+    });
+    let stderr = expect_panic(
+        "escheat_offer_with_an_endless_dormancy_never_overflows",
+        || {
+            call_at(market(), 1, u64::MAX / 2);
+            contract.escheat_offer(nft(), creator(), "1".to_string());
+        },
+    );
+    assert!(stderr.contains("offer is younger than"), "{}", stderr);
+}
+
+#[test]
+fn unbounded_schedule_horizon_takes_any_started_at() {
+    let (mut contract, _) = setup_listing();
+    call_as(market(), 1);
+    contract.set_max_schedule_horizon(u64::MAX);
+    let started_at = U64(u64::MAX / 2);
+    list_token(&mut contract, "1", json!({ "started_at": started_at }));
+    let listing = contract
+        .get_market_data_raw(nft(), "1".to_string())
+        .unwrap();
+    assert_eq!(listing.started_at, Some(started_at.0));
+}
+
+/// Writes the first deployment's state: ft approved, the nft contract
+/// approved, a storage deposit for the seller, a 3.5% fee, `listings` and
+/// `offers`.
+fn write_v0_state(listings: Vec<MarketDataV0>, offers: Vec<OfferDataV0>) {
+    let mut approved_ft_token_ids = UnorderedSet::new(StorageKey::FTTokenIds);
+    approved_ft_token_ids.insert(&ft());
+    let mut approved_nft_contract_ids = UnorderedSet::new(StorageKey::NFTContractIds);
+    approved_nft_contract_ids.insert(&nft());
+    let mut storage_deposits = LookupMap::new(StorageKey::StorageDeposits);
+    storage_deposits.insert(&seller(), &STORAGE);
+    let mut by_owner_id = LookupMap::new(StorageKey::ByOwnerId);
+    let mut index_owner = |account_id: &AccountId, key: &String| {
+        let mut keys = by_owner_id.get(account_id).unwrap_or_else(|| {
+            UnorderedSet::new(
+                StorageKey::ByOwnerIdInner {
+                    account_id_hash: hash_account_id(account_id),
+                }
+                .try_to_vec()
+                .unwrap(),
+            )
+        });
+        keys.insert(key);
+        by_owner_id.insert(account_id, &keys);
+    };
+    let mut v0_market = UnorderedMap::new(StorageKey::Market);
+    for market_data in listings {
+        let key = format!("{}||{}", market_data.nft_contract_id, market_data.token_id);
+        index_owner(&market_data.owner_id, &key);
+        v0_market.insert(&key, &market_data);
+    }
+    let mut v0_offers = UnorderedMap::new(StorageKey::Offers);
+    for offer_data in offers {
+        let key = format!(
+            "{}||{}||{}",
+            offer_data.nft_contract_id, offer_data.buyer_id, offer_data.token_id
+        );
+        index_owner(&offer_data.buyer_id, &key);
+        v0_offers.insert(&key, &offer_data);
+    }
+
+    env::state_write(&ContractV0 {
+        owner_id: market(),
+        treasury_id: treasury(),
+        market: v0_market,
+        approved_ft_token_ids,
+        approved_nft_contract_ids,
+        storage_deposits,
+        by_owner_id,
+        offers: v0_offers,
+        transaction_fee: 350,
+    });
+}
+
+fn v0_listing(token_id: &str) -> MarketDataV0 {
+    MarketDataV0 {
+        owner_id: seller(),
+        approval_id: 0,
+        nft_contract_id: nft(),
+        token_id: token_id.to_string(),
+        ft_token_id: "near".parse().unwrap(),
+        price: PRICE,
+        bids: None,
+        started_at: None,
+        ended_at: None,
+        is_auction: None,
+    }
+}
+
+#[test]
+fn migrate_keeps_the_v0_fields_and_defaults_the_rest() {
+    call_as(market(), 0);
+    write_v0_state(Vec::new(), Vec::new());
+
+    let contract = Contract::migrate();
+
+    assert_eq!(contract.get_owner(), market());
+    assert_eq!(contract.get_treasury(), treasury());
+    assert_eq!(contract.get_transaction_fee(), 350);
+    // new adds near, migrate keeps the approved set as it was
+    assert_eq!(contract.approved_ft_token_ids(), vec![ft()]);
+    assert_eq!(contract.approved_nft_contract_ids(), vec![nft()]);
+    assert_eq!(contract.storage_balance_of(seller()).0, STORAGE);
+    assert!(!contract.is_paused());
+    assert!(contract.get_pending_fee().is_none());
+    assert_eq!(contract.get_treasury_accrued().0, 0);
+}
+
+#[test]
+fn migrate_needs_existing_state() {
+    let stderr = expect_panic("migrate_needs_existing_state", || {
+        call_as(market(), 0);
+        Contract::migrate();
+    });
+    assert!(
+        stderr.contains("no contract state to migrate"),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn migrate_rewrites_v0_listings_and_indexes_them() {
+    let auction = MarketDataV0 {
+        bids: Some(vec![BidV0 {
+            bidder_id: buyer(),
+            price: U128(PRICE),
+        }]),
+        started_at: Some(0),
+        ended_at: Some(100),
+        is_auction: Some(true),
+        ..v0_listing("2")
+    };
+    call_as(market(), 0);
+    write_v0_state(vec![v0_listing("1"), auction], Vec::new());
+
+    let mut contract = Contract::migrate();
+
+    // read in place before migrate_listings, but not indexed yet
+    let sale_key = format!("{}||1", nft());
+    let sale = contract.market.get(&sale_key).unwrap();
+    assert_eq!((sale.owner_id, sale.price), (seller(), PRICE));
+    assert_eq!(sale.listing_nonce, legacy_listing_nonce(&sale_key));
+    assert!(contract.get_markets(None, None).is_empty());
+    assert_eq!(contract.next_listing_nonce, 0);
+
+    call_as(market(), 1);
+    let progress = contract.migrate_listings(None, 10);
+    assert_eq!((progress.next_index, progress.remaining), (None, U64(0)));
+
+    let sale = contract.market.get(&sale_key).unwrap();
+    assert_eq!(sale.listing_nonce, legacy_listing_nonce(&sale_key));
+    let auction_key = format!("{}||2", nft());
+    let auction = contract.market.get(&auction_key).unwrap();
+    assert_eq!(auction.listing_nonce, legacy_listing_nonce(&auction_key));
+    assert_ne!(auction.listing_nonce, sale.listing_nonce);
+    assert_eq!(auction.escrow_model, EscrowModel::HoldUntilSettlement);
+    let bids = auction.bids.unwrap();
+    assert_eq!(
+        (bids[0].bidder_id.clone(), bids[0].price),
+        (buyer(), U128(PRICE))
+    );
+    assert_eq!(bids[0].placed_at, U64(0));
+    assert!(contract
+        .market
+        .listings
+        .values()
+        .all(|listing| listing.is_current()));
+
+    assert_eq!(contract.next_listing_nonce, 0);
+    assert_eq!(contract.get_markets(None, None).len(), 2);
+    assert_eq!(contract.by_nft_contract.get(&nft()).unwrap().len(), 2);
+    assert_eq!(
+        contract.auction_ends.to_vec(),
+        vec![((100, auction_key), ())]
+    );
+    assert_eq!(contract.get_supply_by_owner_id(seller()).0, 2);
+}
+
+#[test]
+fn migrate_listings_resumes_from_the_index_it_returned() {
+    call_as(market(), 0);
+    write_v0_state(
+        vec![v0_listing("1"), v0_listing("2"), v0_listing("3")],
+        Vec::new(),
+    );
+    let mut contract = Contract::migrate();
+
+    call_as(market(), 1);
+    let progress = contract.migrate_listings(None, 2);
+    assert_eq!(
+        (progress.next_index, progress.remaining),
+        (Some(U64(2)), U64(1))
+    );
+    assert_eq!(contract.get_markets(None, None).len(), 2);
+
+    call_as(market(), 1);
+    let progress = contract.migrate_listings(progress.next_index, 2);
+    assert_eq!((progress.next_index, progress.remaining), (None, U64(0)));
+    assert_eq!(contract.get_markets(None, None).len(), 3);
+
+    // a second pass finds nothing left to rewrite
+    call_as(market(), 1);
+    contract.migrate_listings(None, 10);
+    assert_eq!(contract.get_markets(None, None).len(), 3);
+    assert_eq!(contract.by_nft_contract.get(&nft()).unwrap().len(), 3);
+}
+
+#[test]
+fn v0_listing_saved_before_migrate_listings_is_indexed_once() {
+    call_as(market(), 0);
+    write_v0_state(vec![v0_listing("1"), v0_listing("2")], Vec::new());
+    let mut contract = Contract::migrate();
+
+    call_as(seller(), 1);
+    contract.update_market_data(
+        nft(),
+        "1".to_string(),
+        "near".parse().unwrap(),
+        U128(2 * PRICE),
+        None,
+        None,
+        None,
+        None,
+    );
+    assert_eq!(contract.market.legacy_len, 1);
+    assert_eq!(contract.get_markets(None, None).len(), 1);
+
+    call_as(market(), 1);
+    let progress = contract.migrate_listings(None, 10);
+    assert_eq!(progress.remaining, U64(0));
+    assert_eq!(contract.get_markets(None, None).len(), 2);
+    assert_eq!(
+        contract.market.get(&format!("{}||1", nft())).unwrap().price,
+        2 * PRICE
+    );
+}
+
+#[test]
+fn v0_listing_removed_before_migrate_listings_leaves_nothing_to_rewrite() {
+    call_as(market(), 0);
+    write_v0_state(vec![v0_listing("1")], Vec::new());
+    let mut contract = Contract::migrate();
+
+    call_as(seller(), 1);
+    contract.delete_market_data(nft(), "1".to_string());
+    assert_eq!(contract.market.legacy_len, 0);
+    assert_eq!(contract.get_supply_by_owner_id(seller()).0, 0);
+
+    call_as(market(), 1);
+    let progress = contract.migrate_listings(None, 10);
+    assert_eq!((progress.next_index, progress.remaining), (None, U64(0)));
+    assert!(contract.get_markets(None, None).is_empty());
+}
+
+#[test]
+fn get_markets_pages_stay_put_when_an_early_listing_is_deleted() {
+    let (mut contract, _) = setup_listing();
+    call_as(seller(), 3 * STORAGE);
+    contract.storage_deposit(None, None);
+    for token_id in ["2", "3", "4"] {
+        list_token(&mut contract, token_id, json!({}));
+    }
+    let token_ids = |page: Vec<MarketDataJson>| -> Vec<String> {
+        page.into_iter()
+            .map(|market_data| {
+                let market_data = near_sdk::serde_json::to_value(market_data).unwrap();
+                market_data["token_id"].as_str().unwrap().to_string()
+            })
+            .collect()
+    };
+    assert_eq!(token_ids(contract.get_markets(None, Some(2))), ["1", "2"]);
+
+    call_as(seller(), 1);
+    contract.delete_market_data(nft(), "1".to_string());
+
+    // the next page starts where the first ended, nothing skipped
+    assert_eq!(
+        token_ids(contract.get_markets(Some(U64(2)), Some(2))),
+        ["3", "4"]
+    );
+    assert_eq!(token_ids(contract.get_markets(None, Some(2))), ["2"]);
+}
+
+#[test]
+fn migrate_rewrites_v0_offers_and_indexes_them() {
+    let offer = OfferDataV0 {
+        buyer_id: buyer(),
+        nft_contract_id: nft(),
+        token_id: "1".to_string(),
+        ft_token_id: "near".parse().unwrap(),
+        price: ONE_NEAR,
+    };
+    call_at(market(), 0, 500);
+    write_v0_state(vec![v0_listing("1")], vec![offer]);
+
+    let mut contract = Contract::migrate();
+
+    let key = format!("{}||{}||1", nft(), buyer());
+    assert_eq!(contract.offers.get(&key).unwrap().created_at, 500);
+    assert!(contract.by_nft_contract_offers.get(&nft()).is_none());
+
+    call_at(market(), 1, 900);
+    let progress = contract.migrate_offers(None, 10);
+    assert_eq!((progress.next_index, progress.remaining), (None, U64(0)));
+
+    let offer = contract.offers.get(&key).unwrap();
+    assert_eq!((offer.buyer_id, offer.price), (buyer(), ONE_NEAR));
+    // dormancy counts from the migration
+    assert_eq!(offer.created_at, 500);
+    assert_eq!((offer.nonce, offer.expires_at), (None, None));
+    assert!(contract
+        .offers
+        .offers
+        .values()
+        .all(|offer| offer.is_current()));
+
+    let offers = contract.get_offers(nft(), "1".to_string(), None, None, None);
+    assert_eq!(offers.len(), 1);
+    assert_eq!(
+        contract.by_nft_contract_offers.get(&nft()).unwrap().len(),
+        1
+    );
+    assert_eq!(contract.get_supply_by_owner_id(buyer()).0, 1);
+}
+
+#[test]
+fn one_yocto_methods_revert_with_zero_deposit() {
+    type YoctoCall = (&'static str, fn(&mut Contract));
+    let calls: Vec<YoctoCall> = vec![
+        ("set_treasury", |c| {
+            c.set_treasury(buyer());
+        }),
+        ("set_transaction_fee", |c| {
+            c.set_transaction_fee(250);
+        }),
+        ("set_fee_change_delay", |c| {
+            c.set_fee_change_delay(0);
+        }),
+        ("set_max_fee_step", |c| {
+            c.set_max_fee_step(10_000);
+        }),
+        ("set_max_offers_per_buyer", |c| {
+            c.set_max_offers_per_buyer(Some(1));
+        }),
+        ("set_price_update_cooldown", |c| {
+            c.set_price_update_cooldown(1);
+        }),
+        ("set_max_schedule_horizon", |c| {
+            c.set_max_schedule_horizon(1);
+        }),
+        ("set_min_bid_age", |c| {
+            c.set_min_bid_age(1);
+        }),
+        ("set_open_mode", |c| {
+            c.set_open_mode(true);
+        }),
+        ("set_min_bid_increment_bps", |c| {
+            c.set_min_bid_increment_bps(1);
+        }),
+        ("set_auction_extension", |c| {
+            c.set_auction_extension(1);
+        }),
+        ("set_max_overpay_factor", |c| {
+            c.set_max_overpay_factor(1);
+        }),
+        ("set_auction_duration_bounds", |c| {
+            c.set_auction_duration_bounds(1, 2);
+        }),
+        ("set_min_storage_listings", |c| {
+            c.set_min_storage_listings(1);
+        }),
+        ("set_round_fee_up", |c| {
+            c.set_round_fee_up(true);
+        }),
+        ("set_emit_legacy_events", |c| {
+            c.set_emit_legacy_events(false);
+        }),
+        ("set_price_oracle", |c| {
+            c.set_price_oracle(Some(buyer()));
+        }),
+        ("set_wnear_id", |c| {
+            c.set_wnear_id(Some(ft()));
+        }),
+        ("set_allow_leader_cancel", |c| {
+            c.set_allow_leader_cancel(true);
+        }),
+        ("set_config", |c| {
+            c.set_config(ConfigUpdate::default());
+        }),
+        ("transfer_ownership", |c| {
+            c.transfer_ownership(buyer());
+        }),
+        ("add_approved_nft_contract_ids", |c| {
+            c.add_approved_nft_contract_ids(vec![buyer()], None);
+        }),
+        ("remove_approved_nft_contract_ids", |c| {
+            c.remove_approved_nft_contract_ids(vec![nft()]);
+        }),
+        ("add_approved_ft_token_ids", |c| {
+            c.add_approved_ft_token_ids(vec![ft()]);
+        }),
+        ("remove_approved_ft_token_ids", |c| {
+            c.remove_approved_ft_token_ids(vec![ft()]);
+        }),
+        ("add_trusted_callers", |c| {
+            c.add_trusted_callers(vec![buyer()]);
+        }),
+        ("remove_trusted_callers", |c| {
+            c.remove_trusted_callers(vec![buyer()]);
+        }),
+        ("add_trusted_delegates", |c| {
+            c.add_trusted_delegates(vec![buyer()]);
+        }),
+        ("remove_trusted_delegates", |c| {
+            c.remove_trusted_delegates(vec![buyer()]);
+        }),
+        ("set_ft_token_decimals", |c| {
+            c.set_ft_token_decimals(ft(), 6);
+        }),
+        ("set_resale_cooldown", |c| {
+            c.set_resale_cooldown(nft(), Some(1));
+        }),
+        ("add_creator_royalty", |c| {
+            c.add_creator_royalty(nft(), creator(), 100);
+        }),
+        ("remove_creator_royalty", |c| {
+            c.remove_creator_royalty(nft());
+        }),
+        ("set_royalty_override", |c| {
+            c.set_royalty_override(nft(), creator(), 100);
+        }),
+        ("remove_royalty_override", |c| {
+            c.remove_royalty_override(nft());
+        }),
+        ("set_strict_payout", |c| {
+            c.set_strict_payout(nft(), true);
+        }),
+        ("set_transfer_only", |c| {
+            c.set_transfer_only(nft(), true);
+        }),
+        ("pause_nft_contract", |c| {
+            c.pause_nft_contract(nft());
+        }),
+        ("unpause_nft_contract", |c| {
+            c.unpause_nft_contract(nft());
+        }),
+        ("set_paused", |c| {
+            c.set_paused(true);
+        }),
+        ("set_offers_paused", |c| {
+            c.set_offers_paused(true);
+        }),
+        ("set_collection_offers_enabled", |c| {
+            c.set_collection_offers_enabled(nft(), false);
+        }),
+        ("set_kyc_required", |c| {
+            c.set_kyc_required(nft(), true);
+        }),
+        ("add_kyc_accounts", |c| {
+            c.add_kyc_accounts(vec![buyer()]);
+        }),
+        ("remove_kyc_accounts", |c| {
+            c.remove_kyc_accounts(vec![buyer()]);
+        }),
+        ("escheat_offer", |c| {
+            c.escheat_offer(nft(), creator(), "1".to_string());
+        }),
+        ("set_refund_gas", |c| {
+            c.set_refund_gas(U64(1));
+        }),
+        ("reconcile_owner", |c| {
+            c.reconcile_owner(seller());
+        }),
+        ("prune_account", |c| {
+            c.prune_account(seller());
+        }),
+        ("sweep_dust", |c| {
+            c.sweep_dust();
+        }),
+        ("migrate_listings", |c| {
+            c.migrate_listings(None, 1);
+        }),
+        ("migrate_offers", |c| {
+            c.migrate_offers(None, 1);
+        }),
+        // not owner-only, but they move funds or listings all the same
+        ("accept_bid", |c| {
+            c.accept_bid(nft(), "1".to_string(), None);
+        }),
+        ("delete_offer", |c| {
+            c.delete_offer(nft(), "1".to_string());
+        }),
+        ("cancel_bid", |c| {
+            c.cancel_bid(nft(), "1".to_string(), buyer());
+        }),
+    ];
+
+    let (mut contract, _) = setup_listing();
+    offer_from_creator(&mut contract, ONE_NEAR);
+    for (case, (method, call)) in calls.into_iter().enumerate() {
+        let stderr = expect_panic_case("one_yocto_methods_revert_with_zero_deposit", case, || {
+            call_as(market(), 0);
+            call(&mut contract);
+        });
+        if std::env::var_os("MARKET_EXPECT_PANIC_CASE").is_none() {
+            assert!(
+                stderr.contains("Requires attached deposit of exactly 1 yoctoNEAR"),
+                "{}: {}",
+                method,
+                stderr
+            );
+        }
+    }
+}
+
+#[test]
+fn prune_account_clears_what_a_departed_seller_left() {
+    let (mut contract, _) = setup_listing();
+    call_as(seller(), 1);
+    assert!(!contract.prune_account(seller()));
+
+    call_as(seller(), 1);
+    contract.delete_market_data(nft(), "1".to_string());
+    assert_eq!(contract.get_supply_by_owner_id(seller()).0, 0);
+
+    // the balance is still there, only the owner may refund it
+    let stderr = expect_panic("prune_account_clears_what_a_departed_seller_left", || {
+        call_as(buyer(), 1);
+        contract.prune_account(seller());
+    });
+    assert!(stderr.contains("Owner only"));
+
+    call_as(market(), 1);
+    assert!(contract.prune_account(seller()));
+    assert_eq!(transfers(), vec![(seller(), STORAGE)]);
+    assert!(contract.by_owner_id.get(&seller()).is_none());
+    assert!(contract.storage_deposits.get(&seller()).is_none());
+
+    // nothing left, anyone may call it again
+    call_as(buyer(), 1);
+    assert!(!contract.prune_account(seller()));
+}
+
+/// The buyer bought token "1" at time 0 and the seller listed it again
+fn setup_resale(cooldown_sec: u64) -> Contract {
+    let (mut contract, market_data) = setup_listing();
+    call_as(market(), 1);
+    contract.set_resale_cooldown(nft(), Some(cooldown_sec));
+
+    call_as(buyer(), PRICE);
+    contract.buy(nft(), "1".to_string(), None, None, None, None);
+    callback_with(PromiseResult::Successful(b"{}".to_vec()));
+    contract.resolve_purchase(buyer(), market_data, U128(PRICE), None, true);
+
+    list_token(&mut contract, "1", json!({}));
+    contract
+}
+
+#[test]
+fn resale_cooldown_rejects_a_rebuy_within_it() {
+    let mut contract = setup_resale(100);
+    let stderr = expect_panic("resale_cooldown_rejects_a_rebuy_within_it", || {
+        call_at(buyer(), PRICE, 99 * 10u64.pow(9));
+        contract.buy(nft(), "1".to_string(), None, None, None, None);
+    });
+    assert!(stderr.contains("resale cooldown active"), "{}", stderr);
+}
+
+#[test]
+fn resale_cooldown_allows_a_rebuy_after_it() {
+    let mut contract = setup_resale(100);
+    call_at(buyer(), PRICE, 100 * 10u64.pow(9));
+    contract.buy(nft(), "1".to_string(), None, None, None, None);
+    assert!(contract.market.get(&format!("{}||1", nft())).is_none());
+}
+
+#[test]
+fn endless_resale_cooldown_never_overflows() {
+    let mut contract = setup_resale(u64::MAX);
+    let stderr = expect_panic("endless_resale_cooldown_never_overflows", || {
+        call_at(buyer(), PRICE, u64::MAX / 2);
+        contract.buy(nft(), "1".to_string(), None, None, None, None);
+    });
+    assert!(stderr.contains("resale cooldown active"), "{}", stderr);
+}
+
+#[test]
+fn reconcile_owner_drops_an_orphaned_key() {
+    let (mut contract, _) = setup_listing();
+    let mut token_ids = contract.by_owner_id.get(&seller()).unwrap();
+    token_ids.insert(&format!("{}||9", nft()));
+    contract.by_owner_id.insert(&seller(), &token_ids);
+    assert_eq!(contract.get_supply_by_owner_id(seller()).0, 2);
+
+    call_as(market(), 1);
+    let len = contract.reconcile_owner(seller());
+
+    assert_eq!(len.0, 1);
+    assert_eq!(contract.get_supply_by_owner_id(seller()).0, 1);
+    let token_ids = contract.by_owner_id.get(&seller()).unwrap();
+    assert!(token_ids.contains(&format!("{}||1", nft())));
+    let (_, data) = events()
+        .into_iter()
+        .find(|(event, _)| event == "reconcile_owner")
+        .unwrap();
+    assert_eq!(
+        (data["previous_len"].clone(), data["len"].clone()),
+        (json!(2), json!(1))
+    );
+}
+
+#[test]
+fn max_offers_per_buyer_caps_a_buyers_open_offers() {
+    let (mut contract, _) = setup_listing();
+    call_as(market(), 1);
+    contract.set_max_offers_per_buyer(Some(1));
+    assert_eq!(contract.get_max_offers_per_buyer(), Some(1));
+    offer_from_creator(&mut contract, ONE_NEAR);
+    // replacing the offer on the same token stays within the cap
+    offer_from_creator(&mut contract, 2 * ONE_NEAR);
+
+    let stderr = expect_panic("max_offers_per_buyer_caps_a_buyers_open_offers", || {
+        call_as(creator(), ONE_NEAR);
+        contract.add_offer(
+            nft(),
+            "2".to_string(),
+            "near".parse().unwrap(),
+            U128(ONE_NEAR),
+            None,
+            None,
+        );
+    });
+    assert!(
+        stderr.contains("Too many offers, cancel an existing offer first. Max offers per buyer: 1"),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn treasury_accrued_adds_each_treasury_fee() {
+    let (mut contract, market_data) = setup_listing();
+    callback_with(PromiseResult::Successful(b"{}".to_vec()));
+    contract.resolve_purchase(buyer(), market_data, U128(PRICE), None, true);
+    let sale_fee = PRICE * 200 / 10_000;
+    assert_eq!(contract.get_treasury_accrued().0, sale_fee);
+
+    let offer = OfferData {
+        buyer_id: creator(),
+        nft_contract_id: nft(),
+        token_id: "1".to_string(),
+        ft_token_id: "near".parse().unwrap(),
+        price: ONE_NEAR,
+        nonce: None,
+        created_at: 0,
+        expires_at: None,
+    };
+    callback_with(PromiseResult::Successful(b"{}".to_vec()));
+    contract.resolve_offer(seller(), offer.clone(), "1".to_string(), None);
+    let offer_fee = ONE_NEAR * 200 / 10_000;
+    assert_eq!(contract.get_treasury_accrued().0, sale_fee + offer_fee);
+
+    // a fee paid to a listing's fee_recipient is not treasury income
+    callback_with(PromiseResult::Successful(b"{}".to_vec()));
+    contract.resolve_offer(seller(), offer, "1".to_string(), Some(buyer()));
+    assert_eq!(contract.get_treasury_accrued().0, sale_fee + offer_fee);
+}
+
+#[test]
+fn listing_fee_recipient_takes_the_fee_instead_of_the_treasury() {
+    let mut contract = setup_listing_with(json!({ "fee_recipient": creator() }));
+    let market_data = contract.market.get(&format!("{}||1", nft())).unwrap();
+    assert_eq!(market_data.fee_recipient, Some(creator()));
+
+    let payout = json!({ "payout": { seller().to_string(): U128(PRICE) } });
+    callback_with(PromiseResult::Successful(payout.to_string().into_bytes()));
+    contract.resolve_purchase(buyer(), market_data, U128(PRICE), None, true);
+
+    let fee = PRICE * 200 / 10_000;
+    let paid = transfers();
+    assert_eq!(received(&paid, &creator()), fee);
+    assert_eq!(received(&paid, &treasury()), 0);
+    assert_eq!(received(&paid, &seller()), PRICE - fee);
+    assert_eq!(contract.get_treasury_accrued().0, 0);
+    let (_, data) = events()
+        .into_iter()
+        .find(|(event, _)| event == "resolve_purchase")
+        .unwrap();
+    assert_eq!(data["fee_recipient"], json!(creator()));
+}
+
+#[test]
+fn first_time_buyer_offers_and_registers_in_one_call() {
+    let (mut contract, _) = setup_listing();
+    let storage = contract.storage_cost_for(creator(), 1).0;
+    assert!(storage > 0);
+
+    call_as(creator(), ONE_NEAR + storage);
+    contract.add_offer(
+        nft(),
+        "1".to_string(),
+        "near".parse().unwrap(),
+        U128(ONE_NEAR),
+        None,
+        None,
+    );
+
+    let offer = contract
+        .offers
+        .get(&format!("{}||{}||1", nft(), creator()))
+        .unwrap();
+    assert_eq!(offer.price, ONE_NEAR);
+    assert_eq!(contract.storage_balance_of(creator()).0, storage);
+}
+
+#[test]
+fn offer_with_a_partial_storage_deposit_fails() {
+    let (mut contract, _) = setup_listing();
+    let storage = contract.storage_cost_for(creator(), 1).0;
+    let stderr = expect_panic("offer_with_a_partial_storage_deposit_fails", || {
+        call_as(creator(), ONE_NEAR + storage / 2);
+        contract.add_offer(
+            nft(),
+            "1".to_string(),
+            "near".parse().unwrap(),
+            U128(ONE_NEAR),
+            None,
+            None,
+        );
+    });
+    assert!(
+        stderr.contains("Attached deposit != price (plus missing storage)"),
+        "{}",
+        stderr
+    );
+}
+
+/// `bidder_id` registers storage and bids `amount` NEAR on token "1"
+fn bid_from(contract: &mut Contract, bidder_id: AccountId, amount: Balance) {
+    bid_at(contract, bidder_id, amount, 0);
+}
+
+fn bid_at(contract: &mut Contract, bidder_id: AccountId, amount: Balance, block_timestamp: u64) {
+    call_at(bidder_id.clone(), STORAGE, block_timestamp);
+    contract.storage_deposit(None, None);
+    call_at(bidder_id, amount, block_timestamp);
+    contract.add_bid(
+        nft(),
+        "near".parse().unwrap(),
+        "1".to_string(),
+        U128(amount),
+        None,
+        None,
+    );
+}
+
+#[test]
+fn auction_without_an_end_or_long_enough_window_fails() {
+    let cases = vec![
+        (json!({}), "auction requires ended_at"),
+        (
+            json!({ "started_at": U64(DAY), "ended_at": U64(DAY) }),
+            "started_at must be before ended_at",
+        ),
+        (
+            json!({ "ended_at": U64(10u64.pow(9) * 3_599) }),
+            "auction window is shorter than 3600 seconds",
+        ),
+    ];
+    for (case, (args, error)) in cases.into_iter().enumerate() {
+        let stderr = expect_panic_case(
+            "auction_without_an_end_or_long_enough_window_fails",
+            case,
+            || {
+                setup_listing_with(with_args(json!({ "is_auction": true }), args));
+            },
+        );
+        if std::env::var_os("MARKET_EXPECT_PANIC_CASE").is_none() {
+            assert!(stderr.contains(error), "case {}: {}", case, stderr);
+        }
+    }
+
+    // exactly the minimum window is enough
+    let contract = setup_listing_with(json!({
+        "is_auction": true,
+        "ended_at": U64(10u64.pow(9) * 3_600),
+    }));
+    assert!(contract.market.get(&format!("{}||1", nft())).is_some());
+}
+
+#[test]
+fn second_bid_from_a_bidder_in_the_same_block_fails() {
+    let mut contract = setup_auction();
+    bid_from(&mut contract, buyer(), PRICE);
+
+    let stderr = expect_panic("second_bid_from_a_bidder_in_the_same_block_fails", || {
+        bid_from(&mut contract, buyer(), 2 * PRICE);
+    });
+    assert!(stderr.contains("Only one bid per bidder per block"));
+
+    // the next block takes it
+    bid_at(&mut contract, buyer(), 2 * PRICE, 1);
+    let bids = contract.market.get(&format!("{}||1", nft())).unwrap().bids;
+    assert_eq!(bids.unwrap().len(), 1);
+}
+
+#[test]
+fn auction_started_is_emitted_once_at_the_opening() {
+    let mut contract = setup_auction_with(json!({
+        "started_at": U64(DAY),
+        "ended_at": U64(2 * DAY),
+    }));
+    let started = || {
+        events()
+            .into_iter()
+            .filter(|(event, _)| event == "auction_started")
+            .count()
+    };
+    assert_eq!(started(), 0);
+
+    let stderr = expect_panic("auction_started_is_emitted_once_at_the_opening", || {
+        call_at(buyer(), 0, DAY - 1);
+        contract.mark_auction_started(nft(), "1".to_string());
+    });
+    assert!(stderr.contains("Sale has not started yet"));
+
+    call_at(buyer(), 0, DAY);
+    assert!(contract.mark_auction_started(nft(), "1".to_string()));
+    assert_eq!(started(), 1);
+
+    call_at(creator(), 0, DAY + 1);
+    assert!(!contract.mark_auction_started(nft(), "1".to_string()));
+    assert_eq!(started(), 0);
+    // the first bid doesn't open it again either
+    bid_at(&mut contract, buyer(), PRICE, DAY + 2);
+    assert_eq!(started(), 0);
+}
+
+#[test]
+fn winning_bid_delivers_to_its_recipient() {
+    let mut contract = setup_auction();
+    let dao: AccountId = "dao.near".parse().unwrap();
+    bid_from(&mut contract, creator(), PRICE);
+    call_as(buyer(), STORAGE);
+    contract.storage_deposit(None, None);
+    call_as(buyer(), 2 * PRICE);
+    contract.add_bid(
+        nft(),
+        "near".parse().unwrap(),
+        "1".to_string(),
+        U128(2 * PRICE),
+        Some(dao.clone()),
+        None,
+    );
+
+    let mut market_data = contract.market.get(&format!("{}||1", nft())).unwrap();
+    let losing_bids = market_data.bids.replace(vec![]).map(|mut bids| {
+        bids.pop();
+        bids
+    });
+
+    call_at(seller(), 1, DAY + 1);
+    contract.accept_bid(nft(), "1".to_string(), None);
+    assert_eq!(nft_receiver(), Some(dao.clone()));
+    let (_, data) = events()
+        .into_iter()
+        .find(|(event, _)| event == "settle_auction")
+        .unwrap();
+    assert_eq!(data["bidder_id"], json!(buyer()));
+    assert_eq!(data["recipient"], json!(dao));
+
+    // the losing bid goes back to its bidder once the transfer went through
+    callback_with(PromiseResult::Successful(b"{}".to_vec()));
+    contract.resolve_purchase(buyer(), market_data, U128(2 * PRICE), losing_bids, true);
+    let paid = transfers();
+    assert_eq!(received(&paid, &creator()), PRICE);
+    assert_eq!(received(&paid, &dao), 0);
+}
+
+#[test]
+fn auction_short_of_min_participants_is_cancelled_and_refunded() {
+    let mut contract = setup_auction_with(json!({ "min_participants": 2 }));
+    bid_from(&mut contract, buyer(), PRICE);
+    // a second bid from the same bidder is still one participant
+    bid_at(&mut contract, buyer(), PRICE + ONE_NEAR, 1);
+
+    call_as(seller(), 1);
+    contract.accept_bid(nft(), "1".to_string(), None);
+
+    assert!(contract.market.get(&format!("{}||1", nft())).is_none());
+    let (_, data) = events()
+        .into_iter()
+        .find(|(event, _)| event == "cancel_auction")
+        .unwrap();
+    assert_eq!(data["reason"], "min_participants");
+    assert_eq!(received(&transfers(), &buyer()), PRICE + ONE_NEAR);
+}
+
+#[test]
+fn auction_with_min_participants_settles() {
+    let mut contract = setup_auction_with(json!({ "min_participants": 2 }));
+    bid_from(&mut contract, creator(), PRICE);
+    bid_from(&mut contract, buyer(), PRICE + ONE_NEAR);
+
+    call_as(seller(), 1);
+    contract.accept_bid(nft(), "1".to_string(), None);
+
+    let logged = events();
+    assert!(logged.iter().any(|(event, _)| event == "settle_auction"));
+    assert!(!logged.iter().any(|(event, _)| event == "cancel_auction"));
+    assert!(transfers().is_empty());
+}
+
+#[test]
+fn auction_not_started_is_rescheduled() {
+    let mut contract = setup_auction_with(json!({
+        "started_at": U64(DAY),
+        "ended_at": U64(2 * DAY),
+    }));
+    call_as(seller(), 1);
+    contract.reschedule_auction(nft(), "1".to_string(), U64(2 * DAY), Some(U64(3 * DAY)));
+
+    let market_data = contract.market.get(&format!("{}||1", nft())).unwrap();
+    assert_eq!(
+        (market_data.started_at, market_data.ended_at),
+        (Some(2 * DAY), Some(3 * DAY))
+    );
+    assert_eq!(
+        contract.auction_ends.to_vec(),
+        vec![((3 * DAY, format!("{}||1", nft())), ())]
+    );
+    let (_, data) = events()
+        .into_iter()
+        .find(|(event, _)| event == "reschedule_auction")
+        .unwrap();
+    assert_eq!(data["started_at"], json!(U64(2 * DAY)));
+}
+
+#[test]
+fn live_auction_cannot_be_rescheduled() {
+    let mut contract = setup_auction();
+    let stderr = expect_panic("live_auction_cannot_be_rescheduled", || {
+        call_as(seller(), 1);
+        contract.reschedule_auction(nft(), "1".to_string(), U64(DAY), Some(U64(2 * DAY)));
+    });
+    assert!(
+        stderr.contains("has already started or has bids"),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn only_the_seller_reschedules_an_auction() {
+    let mut contract = setup_auction_with(json!({
+        "started_at": U64(DAY),
+        "ended_at": U64(2 * DAY),
+    }));
+    let stderr = expect_panic("only_the_seller_reschedules_an_auction", || {
+        call_as(buyer(), 1);
+        contract.reschedule_auction(nft(), "1".to_string(), U64(2 * DAY), Some(U64(3 * DAY)));
+    });
+    assert!(stderr.contains("Seller only"), "{}", stderr);
+}
+
+/// Stores `offer` as add_offer would, for currencies add_offer doesn't take yet
+fn plant_offer(contract: &mut Contract, offer: OfferData) {
+    let key = format!(
+        "{}||{}||{}",
+        offer.nft_contract_id, offer.buyer_id, offer.token_id
+    );
+    let mut offer_keys = contract
+        .by_nft_contract_offers
+        .get(&offer.nft_contract_id)
+        .unwrap_or_else(|| {
+            UnorderedSet::new(
+                StorageKey::ByNFTContractOffersInner {
+                    account_id_hash: hash_account_id(&offer.nft_contract_id),
+                }
+                .try_to_vec()
+                .unwrap(),
+            )
+        });
+    offer_keys.insert(&key);
+    contract
+        .by_nft_contract_offers
+        .insert(&offer.nft_contract_id, &offer_keys);
+    contract.offers.insert(&key, &offer);
+}
+
+#[test]
+fn offers_are_filtered_and_ranked_per_currency() {
+    let (mut contract, _) = setup_listing();
+    offer_from(&mut contract, buyer(), 2 * ONE_NEAR);
+    plant_offer(
+        &mut contract,
+        OfferData {
+            buyer_id: creator(),
+            nft_contract_id: nft(),
+            token_id: "1".to_string(),
+            ft_token_id: ft(),
+            price: 5 * ONE_NEAR,
+            nonce: None,
+            created_at: 0,
+            expires_at: None,
+        },
+    );
+    let buyer_of = |offer: Option<OfferDataJson>| {
+        near_sdk::serde_json::to_value(offer.unwrap()).unwrap()["buyer_id"].clone()
+    };
+
+    let offers = |ft_token_id: Option<AccountId>| {
+        contract
+            .get_offers(nft(), "1".to_string(), ft_token_id, None, None)
+            .len()
+    };
+    assert_eq!(offers(None), 2);
+    assert_eq!(offers(Some("near".parse().unwrap())), 1);
+    assert_eq!(offers(Some(ft())), 1);
+
+    // the larger ft amount doesn't outrank the NEAR offer
+    let best_near = contract.get_best_offer(nft(), "1".to_string(), None);
+    assert_eq!(buyer_of(best_near), json!(buyer()));
+    let best_ft = contract.get_best_offer(nft(), "1".to_string(), Some(ft()));
+    assert_eq!(buyer_of(best_ft), json!(creator()));
+}
+
+#[test]
+fn paused_collection_halts_while_another_trades() {
+    let (mut contract, _) = setup_listing();
+    let other: AccountId = "other.near".parse().unwrap();
+    call_as(market(), 1);
+    contract.add_approved_nft_contract_ids(vec![other.clone()], None);
+    call_as(seller(), STORAGE);
+    contract.storage_deposit(None, None);
+    testing_env!(VMContextBuilder::new()
+        .current_account_id(market())
+        .predecessor_account_id(other.clone())
+        .signer_account_id(seller())
+        .build());
+    contract.nft_on_approve(
+        "1".to_string(),
+        seller(),
+        0,
+        json!({ "market_type": "sale", "price": U128(PRICE) }).to_string(),
+    );
+
+    call_as(market(), 1);
+    contract.pause_nft_contract(nft());
+    assert!(contract.is_contract_paused(nft()));
+    assert!(!contract.is_contract_paused(other.clone()));
+
+    call_as(buyer(), PRICE);
+    contract.buy(other.clone(), "1".to_string(), None, None, None, None);
+    assert!(contract.market.get(&format!("{}||1", other)).is_none());
+
+    let stderr = expect_panic("paused_collection_halts_while_another_trades", || {
+        call_as(buyer(), PRICE);
+        contract.buy(nft(), "1".to_string(), None, None, None, None);
+    });
+    assert!(
+        stderr.contains("trading is paused for this nft_contract_id"),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn paused_collection_listing_can_still_be_withdrawn() {
+    let (mut contract, _) = setup_listing();
+    call_as(market(), 1);
+    contract.pause_nft_contract(nft());
+
+    call_as(seller(), 1);
+    contract.delete_market_data(nft(), "1".to_string());
+    assert!(contract.market.get(&format!("{}||1", nft())).is_none());
+}